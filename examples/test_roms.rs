@@ -0,0 +1,166 @@
+// Runs a directory of Blargg's test ROMs, such as the dmg_sound singles, and
+// compares the results against a baseline so that CI only fails when a ROM
+// that used to pass stops passing, or is missing from the baseline. The ROMs
+// report their results over the serial port, ending with "Passed" or
+// "Failed".
+//
+// cargo run --release --example test_roms -- path/to/rom_singles ci/dmg_sound.txt
+//
+// With --update the baseline is rewritten with the current results instead.
+extern crate iogb;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+
+use iogb::{bootrom, cartridge};
+use iogb::gameboy::GameBoy;
+
+// Give up on a ROM after this many emulated seconds.
+const MAX_SECONDS: u32 = 120;
+const FRAMES_PER_SECOND: u32 = 60;
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let update = match args.iter().position(|a| a == "--update") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    if args.len() != 2 {
+        println!("Usage: test_roms [--update] ROM_DIR BASELINE");
+        process::exit(2);
+    }
+    let (rom_dir, baseline_path) = (Path::new(&args[0]), Path::new(&args[1]));
+
+    let mut roms: Vec<_> = match fs::read_dir(rom_dir) {
+        Ok(entries) => {
+            entries.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |e| e == "gb"))
+                .collect()
+        }
+        Err(e) => {
+            println!("Failed to read {}: {}", rom_dir.display(), e);
+            process::exit(2);
+        }
+    };
+    roms.sort();
+
+    let mut results = BTreeMap::new();
+    for rom in &roms {
+        let name = rom.file_name().unwrap().to_string_lossy().into_owned();
+        let result = match run_rom(rom) {
+            Ok(result) => result,
+            Err(e) => format!("error: {}", e),
+        };
+        println!("{}: {}", name, result);
+        results.insert(name, result);
+    }
+
+    if update {
+        if let Err(e) = write_baseline(baseline_path, &results) {
+            println!("Failed to write {}: {}", baseline_path.display(), e);
+            process::exit(2);
+        }
+        return;
+    }
+
+    let baseline = match read_baseline(baseline_path) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("Failed to read {}: {}", baseline_path.display(), e);
+            process::exit(2);
+        }
+    };
+
+    // A ROM that the baseline doesn't know about could be a regression that
+    // nobody recorded, so the baseline has to cover every ROM.
+    let unrecorded: Vec<&String> = results.keys().filter(|n| !baseline.contains_key(*n)).collect();
+    if !unrecorded.is_empty() {
+        for name in &unrecorded {
+            println!("{} isn't in {}", name, baseline_path.display());
+        }
+        println!("Run with --update to record a baseline for every ROM");
+        process::exit(1);
+    }
+
+    let mut regressions = 0;
+    for (name, result) in &results {
+        let passed_before = baseline.get(name).map_or(false, |r| r == "passed");
+        if passed_before && result != "passed" {
+            println!("REGRESSION: {} used to pass, now {}", name, result);
+            regressions += 1;
+        } else if !passed_before && result == "passed" {
+            println!("{} passes now, run with --update to keep it that way", name);
+        }
+    }
+    let passed = results.values().filter(|r| *r == "passed").count();
+    println!("{} of {} passed, {} regressions", passed, results.len(), regressions);
+    if regressions > 0 {
+        process::exit(1);
+    }
+}
+
+// Returns "passed", "failed" followed by anything else the ROM said,
+// "crashed" or "timed out".
+fn run_rom(path: &Path) -> Result<String, String> {
+    let mut data = Vec::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("{}", e)));
+    let cart = try!(cartridge::Cartridge::from_bytes(data));
+    let mut gb = GameBoy::new(cart, bootrom::Bootrom::empty());
+
+    for _ in 0..MAX_SECONDS {
+        // Anything that panics, or stops the CPU, counts as a crash.
+        if panic::catch_unwind(AssertUnwindSafe(|| gb.run_frames(FRAMES_PER_SECOND))).is_err() ||
+           gb.fault().is_some() {
+            return Ok("crashed".to_string());
+        }
+
+        let output = gb.serial_output();
+        if output.contains("Passed") {
+            return Ok("passed".to_string());
+        }
+        if let Some(i) = output.find("Failed") {
+            let detail: Vec<&str> = output[i + "Failed".len()..].split_whitespace().collect();
+            return Ok(format!("failed {}", detail.join(" ")).trim().to_string());
+        }
+    }
+    Ok("timed out".to_string())
+}
+
+// One "rom result" line per ROM. Blank lines and lines starting with # are
+// ignored.
+fn read_baseline(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("{}", e)));
+
+    let mut baseline = BTreeMap::new();
+    for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let mut parts = line.splitn(2, ' ');
+        let name = parts.next().unwrap();
+        let result = parts.next().unwrap_or("").trim();
+        baseline.insert(name.to_string(), result.to_string());
+    }
+    Ok(baseline)
+}
+
+fn write_baseline(path: &Path, results: &BTreeMap<String, String>) -> Result<(), String> {
+    let mut out = String::from("# Generated by the test_roms example with --update.\n");
+    for (name, result) in results {
+        out.push_str(&format!("{} {}\n", name, result));
+    }
+    File::create(path)
+        .and_then(|mut f| f.write_all(out.as_bytes()))
+        .map_err(|e| format!("{}", e))
+}
@@ -0,0 +1,89 @@
+// Running emulation on its own thread. The emulation thread owns the GameBoy
+// and sends finished frames to the render thread over a channel, while the
+// render thread publishes the pressed buttons through an atomic bitmask.
+//
+// cargo run --example threaded -- path/to/rom.gb
+extern crate iogb;
+extern crate minifb;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use minifb::{Key, Window, WindowOptions};
+
+use iogb::{bootrom, cartridge};
+use iogb::gameboy::{GameBoy, Button, SCREEN_W, SCREEN_H};
+
+const BUTTONS: [(Key, Button); 8] = [(Key::Right, Button::Right),
+                                     (Key::Left, Button::Left),
+                                     (Key::Up, Button::Up),
+                                     (Key::Down, Button::Down),
+                                     (Key::Z, Button::A),
+                                     (Key::X, Button::B),
+                                     (Key::Backspace, Button::Select),
+                                     (Key::Enter, Button::Start)];
+
+fn main() {
+    let rom = match env::args().nth(1) {
+        Some(r) => PathBuf::from(r),
+        None => {
+            println!("Usage: threaded <rom>");
+            process::exit(1)
+        }
+    };
+
+    let cart = cartridge::Cartridge::new(&rom).unwrap_or_else(|e| panic!("{}", e));
+    let mut gb = GameBoy::new(cart, bootrom::Bootrom::empty());
+
+    let input = Arc::new(AtomicUsize::new(0));
+    let running = Arc::new(AtomicBool::new(true));
+    // Only buffer a couple of frames, so that emulation can't run ahead of
+    // the display.
+    let (frames_tx, frames_rx) = mpsc::sync_channel(2);
+
+    let emu_input = input.clone();
+    let emu_running = running.clone();
+    let emulation = thread::spawn(move || {
+        while emu_running.load(Ordering::Relaxed) {
+            let pressed = emu_input.load(Ordering::Relaxed);
+            for (i, &(_, button)) in BUTTONS.iter().enumerate() {
+                gb.set_button(button, pressed & (1 << i) != 0);
+            }
+
+            gb.step_frame();
+            let mut frame = vec![0; SCREEN_W * SCREEN_H];
+            gb.frame_into_xrgb(&mut frame);
+            if frames_tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut window = Window::new("iogb", SCREEN_W, SCREEN_H, WindowOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    while window.is_open() {
+        let mut pressed = 0;
+        for (i, &(key, _)) in BUTTONS.iter().enumerate() {
+            if window.is_key_down(key) {
+                pressed |= 1 << i;
+            }
+        }
+        input.store(pressed, Ordering::Relaxed);
+
+        match frames_rx.recv() {
+            Ok(frame) => window.update_with_buffer(&frame[..]),
+            Err(_) => break,
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+    // Unblock the emulation thread if it's waiting to send a frame.
+    drop(frames_rx);
+    emulation.join().unwrap();
+}
@@ -0,0 +1,51 @@
+// A minimal example of running iogb in a browser. Build it with:
+//
+// cargo build --release --example wasm --no-default-features \
+//     --target wasm32-unknown-unknown
+//
+// and serve index.html next to the resulting wasm.wasm.
+//
+// The exports are only called from index.html, which always passes buffers
+// from iogb_alloc and GameBoys from iogb_new.
+#![allow(clippy::missing_safety_doc)]
+
+extern crate iogb;
+
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use iogb::{bootrom, cartridge};
+use iogb::gameboy::{GameBoy, SCREEN_W, SCREEN_H};
+
+const FRAME_SZ: usize = SCREEN_W * SCREEN_H * 4;
+
+// Allocate a buffer that the host can copy a ROM or frame into.
+#[no_mangle]
+pub extern "C" fn iogb_alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::with_capacity(len);
+    let p = buf.as_mut_ptr();
+    mem::forget(buf);
+    p
+}
+
+#[no_mangle]
+pub extern "C" fn iogb_frame_len() -> usize {
+    FRAME_SZ
+}
+
+// Takes ownership of a ROM previously copied into an iogb_alloc buffer.
+#[no_mangle]
+pub unsafe extern "C" fn iogb_new(rom: *mut u8, len: usize) -> *mut GameBoy {
+    let rom = Vec::from_raw_parts(rom, len, len);
+    match cartridge::Cartridge::from_bytes(rom) {
+        Ok(cart) => Box::into_raw(Box::new(GameBoy::new(cart, bootrom::Bootrom::empty()))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn iogb_run_frame(gb: *mut GameBoy, frame: *mut u8) {
+    let frame = slice::from_raw_parts_mut(frame, FRAME_SZ);
+    (*gb).run_frame_into_rgba(frame);
+}
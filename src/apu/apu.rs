@@ -0,0 +1,252 @@
+use std::fmt;
+
+use apu::buffer::SampleBuffer;
+use apu::noise::NoiseChannel;
+use apu::square::SquareChannel;
+use apu::wave::WaveChannel;
+use savestate::{StateWriter, StateReader};
+
+// Four channels at the loudest level and the highest volume come to 480, so
+// this scales the mix to almost fill an i16.
+const SAMPLE_SCALE: i16 = 64;
+
+// Bits that always read back as 1 for 0xFF10 -> 0xFF2F. Write-only bits, such
+// as the length loads and trigger bits, and unused registers all read as 1.
+const READ_MASKS: [u8; 0x20] = [0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 -> NR14
+                                0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20 -> NR24
+                                0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 -> NR34
+                                0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40 -> NR44
+                                0x00, 0x00, 0x70, // NR50 -> NR52
+                                0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+pub struct Apu {
+    square1: SquareChannel, // 0xFF10 -> 0xFF14
+    square2: SquareChannel, // 0xFF16 -> 0xFF19
+    wave: WaveChannel, // 0xFF1A -> 0xFF1E and wave RAM
+    noise: NoiseChannel, // 0xFF20 -> 0xFF23
+    nr50: u8,
+    nr51: u8,
+    enabled: bool, // NR52 bit 7
+    frame_step: u8,
+    samples: SampleBuffer,
+    sample_cycles: u32, // Cycles since the last sample was taken
+}
+
+impl fmt::Debug for Apu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Apu")
+            .field("enabled", &self.enabled)
+            .field("nr50", &format_args!("0x{:02x}", self.nr50))
+            .field("nr51", &format_args!("0x{:02x}", self.nr51))
+            .field("nr52", &format_args!("0x{:02x}", self.read_nr52()))
+            .field("frame_step", &self.frame_step)
+            .finish()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            nr50: 0,
+            nr51: 0,
+            enabled: false,
+            frame_step: 0,
+            samples: SampleBuffer::new(),
+            sample_cycles: 0,
+        }
+    }
+
+    pub fn readb(&self, addr: u16) -> u8 {
+        let val = match addr {
+            0xFF10...0xFF14 => self.square1.read(addr - 0xFF10),
+            0xFF16...0xFF19 => self.square2.read(addr - 0xFF15),
+            0xFF1A...0xFF1E => self.wave.read(addr - 0xFF1A),
+            0xFF20...0xFF23 => self.noise.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => self.read_nr52(),
+            0xFF15 | 0xFF1F | 0xFF27...0xFF2F => 0xFF,
+            0xFF30...0xFF3F => return self.wave.read_ram(addr),
+            _ => panic!("Can't read 0x{:04x} from the APU", addr),
+        };
+        val | READ_MASKS[addr as usize - 0xFF10]
+    }
+
+    pub fn writeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF10...0xFF25 if !self.enabled => {
+                // Only the length counters can be loaded while the APU is
+                // off, and then only on a DMG.
+                match addr {
+                    0xFF11 => self.square1.write_length(val),
+                    0xFF16 => self.square2.write_length(val),
+                    0xFF1B => self.wave.write_length(val),
+                    0xFF20 => self.noise.write_length(val),
+                    _ => {}
+                }
+            }
+            0xFF10...0xFF14 => self.square1.write(addr - 0xFF10, val, self.frame_step),
+            0xFF16...0xFF19 => self.square2.write(addr - 0xFF15, val, self.frame_step),
+            0xFF1A...0xFF1E => self.wave.write(addr - 0xFF1A, val, self.frame_step),
+            0xFF20...0xFF23 => self.noise.write(addr - 0xFF1F, val, self.frame_step),
+            0xFF24 => self.nr50 = val,
+            0xFF25 => self.nr51 = val,
+            0xFF26 => self.write_nr52(val),
+            0xFF15 | 0xFF1F | 0xFF27...0xFF2F => {}
+            // Wave RAM can be used whether or not the APU is powered.
+            0xFF30...0xFF3F => self.wave.write_ram(addr, val),
+            _ => panic!("Can't write 0x{:02x} to 0x{:04x} in the APU", val, addr),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if self.samples.rate() == 0 {
+            self.step_channels(cycles);
+            return;
+        }
+
+        // Sample every 4 cycles. The output is still sampled while the APU is
+        // off, so that the frontend gets silence rather than nothing.
+        self.sample_cycles += cycles;
+        while self.sample_cycles >= 4 {
+            self.sample_cycles -= 4;
+            self.step_channels(4);
+            let (left, right) = self.output();
+            self.samples.push(left, right);
+        }
+    }
+
+    // The rate that audio_samples resamples to, or 0 to not keep any.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.samples.set_rate(rate);
+    }
+
+    // Take the buffered audio as interleaved left and right samples.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.samples.drain()
+    }
+
+    pub fn dropped_samples(&self) -> u64 {
+        self.samples.dropped()
+    }
+
+    fn step_channels(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        self.square1.step(cycles);
+        self.square2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+    }
+
+    // The current left and right output. Each channel's DAC turns its 0 to
+    // 15 output into a level from -15 to 15, NR51 chooses which sides each
+    // channel goes to, and NR50 sets the volume of each side from 1 to 8.
+    // The VIN bits of NR50 are kept but ignored, as there is no cartridge audio.
+    pub fn output(&self) -> (i16, i16) {
+        let channels = [dac(self.square1.output(), self.square1.dac_enabled()),
+                        dac(self.square2.output(), self.square2.dac_enabled()),
+                        dac(self.wave.output(), self.wave.dac_enabled()),
+                        dac(self.noise.output(), self.noise.dac_enabled())];
+        let (mut left, mut right) = (0, 0);
+        for (i, level) in channels.iter().enumerate() {
+            if self.nr51 & (0x10 << i) != 0 {
+                left += level;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                right += level;
+            }
+        }
+
+        let left_volume = ((self.nr50 >> 4) & 0x07) as i16 + 1;
+        let right_volume = (self.nr50 & 0x07) as i16 + 1;
+        (left * left_volume * SAMPLE_SCALE, right * right_volume * SAMPLE_SCALE)
+    }
+
+    // The frame sequencer is moved on by DIV, at 512Hz unless DIV is written
+    // to. Lengths are clocked on every other step, the sweep on steps 2 and 6
+    // and the envelopes on step 7.
+    pub fn div_falling_edge(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.frame_step % 2 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // Whether the APU is powered on, and so clocked by DIV.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn read_nr52(&self) -> u8 {
+        (self.enabled as u8) << 7 | (self.noise.active() as u8) << 3 |
+        (self.wave.active() as u8) << 2 | (self.square2.active() as u8) << 1 |
+        self.square1.active() as u8
+    }
+
+    fn write_nr52(&mut self, val: u8) {
+        let enabled = val & 0x80 != 0;
+        if self.enabled && !enabled {
+            // Powering off clears every register apart from NR52, but leaves
+            // wave RAM alone.
+            self.square1.power_off();
+            self.square2.power_off();
+            self.wave.power_off();
+            self.noise.power_off();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.enabled && enabled {
+            self.frame_step = 0;
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.square1.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.write_u8(self.nr50);
+        w.write_u8(self.nr51);
+        w.write_bool(self.enabled);
+        w.write_u8(self.frame_step);
+        w.write_u32(self.sample_cycles);
+        self.samples.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        try!(self.square1.load_state(r));
+        try!(self.square2.load_state(r));
+        try!(self.wave.load_state(r));
+        try!(self.noise.load_state(r));
+        self.nr50 = try!(r.read_u8());
+        self.nr51 = try!(r.read_u8());
+        self.enabled = try!(r.read_bool());
+        self.frame_step = try!(r.read_u8()) % 8;
+        self.sample_cycles = try!(r.read_u32()) % 4;
+        self.samples.load_state(r)
+    }
+}
+
+fn dac(output: u8, enabled: bool) -> i16 {
+    if enabled { output as i16 * 2 - 15 } else { 0 }
+}
@@ -0,0 +1,114 @@
+use std::cmp;
+use std::collections::VecDeque;
+
+use gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+use savestate::{StateWriter, StateReader};
+
+// The APU is sampled every 4 cycles.
+pub const INTERNAL_RATE: u32 = CPU_HZ / 4;
+// How many frames' worth of audio is kept before the oldest is dropped.
+const BUFFER_FRAMES: usize = 4;
+
+// Stereo samples waiting for the frontend, resampled from the APU's rate to
+// the host's by averaging each group of samples.
+pub struct SampleBuffer {
+    samples: VecDeque<(i16, i16)>,
+    capacity: usize,
+    rate: u32,
+    phase: u32,
+    sum: (i32, i32),
+    count: i32,
+    dropped: u64,
+}
+
+impl SampleBuffer {
+    pub fn new() -> SampleBuffer {
+        SampleBuffer {
+            samples: VecDeque::new(),
+            capacity: 0,
+            rate: 0,
+            phase: 0,
+            sum: (0, 0),
+            count: 0,
+            dropped: 0,
+        }
+    }
+
+    // The rate to resample to, or 0 to not produce any samples.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    // There's no more audio than the APU's own samples, so a higher rate is
+    // the same as INTERNAL_RATE. Above it the phase would never catch up.
+    pub fn set_rate(&mut self, rate: u32) {
+        let rate = cmp::min(rate, INTERNAL_RATE);
+        self.rate = rate;
+        self.capacity = (rate as u64 * CYCLES_PER_FRAME as u64 * BUFFER_FRAMES as u64 /
+                         CPU_HZ as u64) as usize + 1;
+        self.samples = VecDeque::with_capacity(self.capacity);
+        self.phase = 0;
+        self.sum = (0, 0);
+        self.count = 0;
+    }
+
+    // Add a sample at INTERNAL_RATE.
+    pub fn push(&mut self, left: i16, right: i16) {
+        if self.rate == 0 {
+            return;
+        }
+        self.sum.0 += left as i32;
+        self.sum.1 += right as i32;
+        self.count += 1;
+
+        self.phase += self.rate;
+        if self.phase >= INTERNAL_RATE {
+            self.phase -= INTERNAL_RATE;
+            let sample = ((self.sum.0 / self.count) as i16, (self.sum.1 / self.count) as i16);
+            self.sum = (0, 0);
+            self.count = 0;
+
+            // If the frontend isn't keeping up, the oldest audio is lost.
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+                self.dropped += 1;
+            }
+            self.samples.push_back(sample);
+        }
+    }
+
+    // Everything that's been buffered, as interleaved left and right samples.
+    pub fn drain(&mut self) -> Vec<i16> {
+        let mut out = Vec::with_capacity(self.samples.len() * 2);
+        for (left, right) in self.samples.drain(..) {
+            out.push(left);
+            out.push(right);
+        }
+        out
+    }
+
+    // How many samples have been dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Only the resampler's progress is saved, so that a loaded state produces
+    // exactly the same samples as it did the first time. The rate is the
+    // frontend's choice, and buffered audio belongs to the timeline being
+    // left behind so it's thrown away.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.phase);
+        w.write_u32(self.sum.0 as u32);
+        w.write_u32(self.sum.1 as u32);
+        w.write_u32(self.count as u32);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.samples.clear();
+        self.phase = try!(r.read_u32()) % INTERNAL_RATE;
+        self.sum.0 = try!(r.read_u32()) as i32;
+        self.sum.1 = try!(r.read_u32()) as i32;
+        self.count = try!(r.read_u32()) as i32;
+        Ok(())
+    }
+}
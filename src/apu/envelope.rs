@@ -0,0 +1,77 @@
+use savestate::{StateWriter, StateReader};
+
+// The volume envelope shared by the square and noise channels, set up by
+// NRx2. It's clocked at 64Hz by the frame sequencer.
+pub struct Envelope {
+    initial: u8,
+    add: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            add: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.initial << 4 | (self.add as u8) << 3 | self.period
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.add = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    // The channel's DAC is powered whenever any of the top 5 bits are set.
+    pub fn dac_enabled(&self) -> bool {
+        self.read() & 0xF8 != 0
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.add && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.add && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.read());
+        w.write_u8(self.volume);
+        w.write_u8(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.write(try!(r.read_u8()));
+        self.volume = try!(r.read_u8()) & 0x0F;
+        self.timer = try!(r.read_u8());
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use savestate::{StateWriter, StateReader};
+
+// The length counter shared by all four channels, which turns its channel off
+// once it has been clocked down to zero. It's clocked at 256Hz by the frame
+// sequencer.
+#[derive(Clone, Copy)]
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    // max is 64, or 256 for the wave channel.
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max: max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Writing NRx1 reloads the counter straight away.
+    pub fn load(&mut self, val: u8) {
+        self.counter = self.max - (val as u16 & (self.max - 1));
+    }
+
+    // Handle the length enable and trigger bits of a write to NRx4, given
+    // the frame sequencer step that will be run next. Returns true if the
+    // channel should be turned off.
+    //
+    // If the next step doesn't clock lengths then enabling the counter clocks
+    // it once straight away, and a trigger that reloads an expired counter
+    // loads one less than the maximum.
+    pub fn write_control(&mut self, enable: bool, trigger: bool, frame_step: u8) -> bool {
+        let extra_clock = frame_step % 2 == 1;
+        let mut expired = false;
+        if extra_clock && !self.enabled && enable && self.counter > 0 {
+            self.counter -= 1;
+            expired = self.counter == 0;
+        }
+        self.enabled = enable;
+
+        if trigger && self.counter == 0 {
+            self.counter = if extra_clock && enable { self.max - 1 } else { self.max };
+        }
+        expired && !trigger
+    }
+
+    // Returns true if the channel should be turned off.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // The counter itself survives the APU powering off on a DMG.
+    pub fn power_off(&mut self) {
+        self.enabled = false;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.counter = try!(r.read_u16());
+        if self.counter > self.max {
+            return Err(format!("Invalid length counter in save state: {}", self.counter));
+        }
+        self.enabled = try!(r.read_bool());
+        Ok(())
+    }
+}
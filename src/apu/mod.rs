@@ -0,0 +1,12 @@
+mod apu;
+mod buffer;
+mod envelope;
+mod length;
+mod noise;
+mod square;
+mod wave;
+
+pub use self::apu::Apu;
+
+#[cfg(test)]
+mod tests;
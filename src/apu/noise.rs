@@ -0,0 +1,171 @@
+use apu::envelope::Envelope;
+use apu::length::LengthCounter;
+use savestate::{StateWriter, StateReader};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Shift the LFSR along once. The new top bit is the XOR of the bottom two,
+// and in 7-bit mode it's copied into bit 6 too.
+pub fn lfsr_step(lfsr: u16, short: bool) -> u16 {
+    let bit = (lfsr ^ (lfsr >> 1)) & 1;
+    let lfsr = (lfsr >> 1) | bit << 14;
+    if short {
+        (lfsr & !(1 << 6)) | bit << 6
+    } else {
+        lfsr
+    }
+}
+
+// Channel 4, which plays pseudo-random noise from a linear feedback shift
+// register. Registers are numbered from 1 for NR41 to 4 for NR44.
+pub struct NoiseChannel {
+    envelope: Envelope,
+    shift: u8,
+    short: bool, // 7-bit LFSR
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+    length: LengthCounter,
+    enabled: bool,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            envelope: Envelope::new(),
+            shift: 0,
+            short: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: LengthCounter::new(64),
+            enabled: false,
+        }
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            1 => 0,
+            2 => self.envelope.read(),
+            3 => self.shift << 4 | (self.short as u8) << 3 | self.divisor,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => panic!("Invalid noise channel register {}", reg),
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            1 => self.write_length(val),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = val >> 4;
+                self.short = val & 0x08 != 0;
+                self.divisor = val & 0x07;
+            }
+            4 => {
+                let trigger = val & 0x80 != 0;
+                if self.length.write_control(val & 0x40 != 0, trigger, frame_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid noise channel register {}", reg),
+        }
+    }
+
+    pub fn write_length(&mut self, val: u8) {
+        self.length.load(val);
+    }
+
+    pub fn active(&self) -> bool {
+        self.enabled
+    }
+
+    // A channel whose DAC is off is silent rather than outputting 0.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // Everything but the length counter is cleared when the APU powers off.
+    pub fn power_off(&mut self) {
+        let mut length = self.length;
+        length.power_off();
+        *self = NoiseChannel::new();
+        self.length = length;
+    }
+
+    // The current digital output, from 0 to 15. The output is high when the
+    // bottom bit of the LFSR is clear.
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        // The LFSR never gets clocked with the two largest shifts.
+        if self.shift >= 14 {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.lfsr = lfsr_step(self.lfsr, self.short);
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Cycles between shifts of the LFSR.
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_u8(self.read(3));
+        w.write_u16(self.lfsr);
+        w.write_u32(self.timer);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        try!(self.envelope.load_state(r));
+        try!(self.length.load_state(r));
+        let nr43 = try!(r.read_u8());
+        self.shift = nr43 >> 4;
+        self.short = nr43 & 0x08 != 0;
+        self.divisor = nr43 & 0x07;
+        self.lfsr = try!(r.read_u16()) & 0x7FFF;
+        self.timer = try!(r.read_u32());
+        self.enabled = try!(r.read_bool());
+        Ok(())
+    }
+}
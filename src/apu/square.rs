@@ -0,0 +1,283 @@
+use apu::envelope::Envelope;
+use apu::length::LengthCounter;
+use savestate::{StateWriter, StateReader};
+
+// Which of the 8 steps of a period the output is high for, at 12.5%, 25%,
+// 50% and 75% duty.
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Channel 1's frequency sweep, set up by NR10 and clocked at 128Hz.
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    // Clearing negate after a subtraction has been done disables the
+    // channel.
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow: 0,
+            negated: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        self.period << 4 | (self.negate as u8) << 3 | self.shift
+    }
+
+    // A period of 0 is treated as 8 by the timer.
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_freq(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.read());
+        w.write_u8(self.timer);
+        w.write_bool(self.enabled);
+        w.write_u16(self.shadow);
+        w.write_bool(self.negated);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let val = try!(r.read_u8());
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 != 0;
+        self.shift = val & 0x07;
+        self.timer = try!(r.read_u8());
+        self.enabled = try!(r.read_bool());
+        self.shadow = try!(r.read_u16()) & 0x7FF;
+        self.negated = try!(r.read_bool());
+        Ok(())
+    }
+}
+
+// Channels 1 and 2, which only differ in that channel 1 has a sweep unit.
+// Registers are numbered from 0 for NRx0 to 4 for NRx4.
+pub struct SquareChannel {
+    sweep: Option<Sweep>,
+    envelope: Envelope,
+    duty: u8,
+    duty_step: u8,
+    freq: u16,
+    timer: u32,
+    length: LengthCounter,
+    enabled: bool,
+}
+
+impl SquareChannel {
+    pub fn new(has_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            sweep: if has_sweep { Some(Sweep::new()) } else { None },
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_step: 0,
+            freq: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            enabled: false,
+        }
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0, |s| s.read()),
+            1 => self.duty << 6,
+            2 => self.envelope.read(),
+            3 => 0,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => panic!("Invalid square channel register {}", reg),
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                if let Some(ref mut sweep) = self.sweep {
+                    sweep.period = (val >> 4) & 0x07;
+                    sweep.negate = val & 0x08 != 0;
+                    sweep.shift = val & 0x07;
+                    if sweep.negated && !sweep.negate {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.write_length(val);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x0700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0x00FF) | ((val as u16 & 0x07) << 8);
+                let trigger = val & 0x80 != 0;
+                if self.length.write_control(val & 0x40 != 0, trigger, frame_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid square channel register {}", reg),
+        }
+    }
+
+    // Only the length half of NRx1.
+    pub fn write_length(&mut self, val: u8) {
+        self.length.load(val);
+    }
+
+    pub fn active(&self) -> bool {
+        self.enabled
+    }
+
+    // A channel whose DAC is off is silent rather than outputting 0.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // Everything but the length counter is cleared when the APU powers off.
+    pub fn power_off(&mut self) {
+        let mut length = self.length;
+        length.power_off();
+        *self = SquareChannel::new(self.sweep.is_some());
+        self.length = length;
+    }
+
+    // The current digital output, from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if self.enabled && DUTY_PATTERNS[self.duty as usize] & (0x80 >> self.duty_step) != 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let mut disable = false;
+        if let Some(ref mut sweep) = self.sweep {
+            if sweep.timer > 0 {
+                sweep.timer -= 1;
+            }
+            if sweep.timer != 0 {
+                return;
+            }
+            sweep.reload_timer();
+            if !sweep.enabled || sweep.period == 0 {
+                return;
+            }
+
+            let freq = sweep.next_freq();
+            if freq > 0x7FF {
+                disable = true;
+            } else if sweep.shift != 0 {
+                sweep.shadow = freq;
+                self.freq = freq;
+                // The new frequency is checked for overflow again straight
+                // away, but not used.
+                disable = sweep.next_freq() > 0x7FF;
+            }
+        }
+        if disable {
+            self.enabled = false;
+        }
+    }
+
+    // Cycles between steps through the duty pattern.
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        let freq = self.freq;
+        let mut disable = false;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.shadow = freq;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 {
+                disable = sweep.next_freq() > 0x7FF;
+            }
+        }
+        if disable {
+            self.enabled = false;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        if let Some(ref sweep) = self.sweep {
+            sweep.save_state(w);
+        }
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_u8(self.duty);
+        w.write_u8(self.duty_step);
+        w.write_u16(self.freq);
+        w.write_u32(self.timer);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if let Some(ref mut sweep) = self.sweep {
+            try!(sweep.load_state(r));
+        }
+        try!(self.envelope.load_state(r));
+        try!(self.length.load_state(r));
+        self.duty = try!(r.read_u8()) & 0x03;
+        self.duty_step = try!(r.read_u8()) & 0x07;
+        self.freq = try!(r.read_u16()) & 0x7FF;
+        self.timer = try!(r.read_u32());
+        self.enabled = try!(r.read_bool());
+        Ok(())
+    }
+}
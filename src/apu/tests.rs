@@ -0,0 +1,625 @@
+// The APU on its own, driven through its registers and DIV edges rather than
+// the rest of the GameBoy.
+use gameboy::CYCLES_PER_FRAME;
+use savestate::{StateWriter, StateReader};
+use super::Apu;
+use super::buffer::{SampleBuffer, INTERNAL_RATE};
+use super::length::LengthCounter;
+use super::noise::lfsr_step;
+
+// For 0xFF10 -> 0xFF2F, the value read back after writing 0x00 and 0xFF
+// while powered on, then after writing 0x00 and 0xFF while powered off.
+// While off, only NR52 and the length counters can be written, and the
+// length counters read back as 1s anyway. Writing NR52 switches the power.
+const REGISTERS: [(u16, [u8; 4]); 0x20] = [(0xFF10, [0x80, 0xFF, 0x80, 0x80]), // NR10
+                                            (0xFF11, [0x3F, 0xFF, 0x3F, 0x3F]), // NR11
+                                            (0xFF12, [0x00, 0xFF, 0x00, 0x00]), // NR12
+                                            (0xFF13, [0xFF, 0xFF, 0xFF, 0xFF]), // NR13
+                                            (0xFF14, [0xBF, 0xFF, 0xBF, 0xBF]), // NR14
+                                            (0xFF15, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF16, [0x3F, 0xFF, 0x3F, 0x3F]), // NR21
+                                            (0xFF17, [0x00, 0xFF, 0x00, 0x00]), // NR22
+                                            (0xFF18, [0xFF, 0xFF, 0xFF, 0xFF]), // NR23
+                                            (0xFF19, [0xBF, 0xFF, 0xBF, 0xBF]), // NR24
+                                            (0xFF1A, [0x7F, 0xFF, 0x7F, 0x7F]), // NR30
+                                            (0xFF1B, [0xFF, 0xFF, 0xFF, 0xFF]), // NR31
+                                            (0xFF1C, [0x9F, 0xFF, 0x9F, 0x9F]), // NR32
+                                            (0xFF1D, [0xFF, 0xFF, 0xFF, 0xFF]), // NR33
+                                            (0xFF1E, [0xBF, 0xFF, 0xBF, 0xBF]), // NR34
+                                            (0xFF1F, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF20, [0xFF, 0xFF, 0xFF, 0xFF]), // NR41
+                                            (0xFF21, [0x00, 0xFF, 0x00, 0x00]), // NR42
+                                            (0xFF22, [0x00, 0xFF, 0x00, 0x00]), // NR43
+                                            (0xFF23, [0xBF, 0xFF, 0xBF, 0xBF]), // NR44
+                                            (0xFF24, [0x00, 0xFF, 0x00, 0x00]), // NR50
+                                            (0xFF25, [0x00, 0xFF, 0x00, 0x00]), // NR51
+                                            (0xFF26, [0x70, 0xF0, 0x70, 0xF0]), // NR52
+                                            (0xFF27, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF28, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF29, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2A, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2B, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2C, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2D, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2E, [0xFF, 0xFF, 0xFF, 0xFF]),
+                                            (0xFF2F, [0xFF, 0xFF, 0xFF, 0xFF])];
+
+// The columns of REGISTERS: whether the APU is on, and the value written.
+const CASES: [(bool, u8); 4] = [(true, 0x00), (true, 0xFF), (false, 0x00), (false, 0xFF)];
+
+// An APU that has had every register set and then, unless on, been powered
+// off again, which clears them.
+fn apu(on: bool) -> Apu {
+    let mut apu = Apu::new();
+    apu.writeb(0xFF26, 0x80);
+    for addr in 0xFF10..0xFF26 {
+        apu.writeb(addr, 0xFF);
+    }
+    if on {
+        // Start from cleared registers in both states.
+        apu.writeb(0xFF26, 0x00);
+        apu.writeb(0xFF26, 0x80);
+    } else {
+        apu.writeb(0xFF26, 0x00);
+    }
+    apu
+}
+
+// What 0xFF10 -> 0xFF3F read back after writing 0x00 and 0xFF to each of
+// them, with the APU powered on and off. This is what games, and Blargg's
+// dmg_sound 01-registers, use to tell the hardware apart.
+#[test]
+fn register_read_back() {
+    for &(addr, expected) in REGISTERS.iter() {
+        for (i, &(on, val)) in CASES.iter().enumerate() {
+            let mut apu = apu(on);
+            apu.writeb(addr, val);
+            assert!(apu.readb(addr) == expected[i],
+                    "0x{:04x} read 0x{:02x} after writing 0x{:02x} with the APU {}, expected \
+                     0x{:02x}",
+                    addr,
+                    apu.readb(addr),
+                    val,
+                    if on { "on" } else { "off" },
+                    expected[i]);
+        }
+    }
+}
+
+// Wave RAM is all there, and can be used whether or not the APU is on.
+#[test]
+fn wave_ram_read_back() {
+    for addr in 0xFF30..0xFF40 {
+        for &on in &[true, false] {
+            for &val in &[0x00, 0xFF, 0x5A] {
+                let mut apu = apu(on);
+                apu.writeb(addr, val);
+                assert_eq!(apu.readb(addr), val, "0x{:04x}", addr);
+            }
+        }
+    }
+}
+
+// Powering off leaves wave RAM alone and clears everything else, and only
+// the length counters can be loaded until it's powered on again.
+#[test]
+fn power_off() {
+    let mut apu = Apu::new();
+    apu.writeb(0xFF26, 0x80);
+    apu.writeb(0xFF30, 0x12);
+    // A DAC, so that the channel can play.
+    apu.writeb(0xFF12, 0xF0);
+    apu.writeb(0xFF26, 0x00);
+    assert_eq!(apu.readb(0xFF30), 0x12);
+    assert_eq!(apu.readb(0xFF12), 0x00);
+
+    // A length of 1 loaded while off is used when the channel is triggered
+    // with its length enabled after powering on.
+    apu.writeb(0xFF11, 0x3F);
+    apu.writeb(0xFF12, 0xF0);
+    apu.writeb(0xFF26, 0x80);
+    assert_eq!(apu.readb(0xFF12), 0x00);
+    apu.writeb(0xFF12, 0xF0);
+    apu.writeb(0xFF14, 0xC0);
+    assert_eq!(apu.readb(0xFF26), 0xF1);
+    // The length counter is clocked every other step of the frame
+    // sequencer, which DIV drives at 512Hz.
+    for _ in 0..2 {
+        apu.div_falling_edge();
+    }
+    assert_eq!(apu.readb(0xFF26), 0xF0);
+}
+
+// An APU that has just been powered on, so that the frame sequencer is about
+// to run step 0.
+fn powered() -> Apu {
+    let mut apu = Apu::new();
+    apu.writeb(0xFF26, 0x80);
+    apu
+}
+
+// Whether each channel is on, from NR52.
+fn active(apu: &Apu) -> [bool; 4] {
+    let nr52 = apu.readb(0xFF26);
+    [nr52 & 0x01 != 0, nr52 & 0x02 != 0, nr52 & 0x04 != 0, nr52 & 0x08 != 0]
+}
+
+// Channel 2 has no sweep unit, so 0xFF15 is nothing. A sweep that would
+// overflow straight away turns channel 1 off, and is ignored by channel 2.
+#[test]
+fn square2_has_no_sweep() {
+    let mut apu = powered();
+    apu.writeb(0xFF10, 0x17);
+    apu.writeb(0xFF12, 0xF0);
+    apu.writeb(0xFF13, 0xFF);
+    apu.writeb(0xFF14, 0x87);
+    assert!(!active(&apu)[0]);
+
+    apu.writeb(0xFF15, 0x17);
+    assert_eq!(apu.readb(0xFF15), 0xFF);
+    apu.writeb(0xFF17, 0xF0);
+    apu.writeb(0xFF18, 0xFF);
+    apu.writeb(0xFF19, 0x87);
+    for _ in 0..64 {
+        apu.div_falling_edge();
+    }
+    assert!(active(&apu)[1]);
+}
+
+#[test]
+fn square2_length_expiry() {
+    let mut apu = powered();
+    apu.writeb(0xFF17, 0xF0);
+    // A length of 2.
+    apu.writeb(0xFF16, 0x3E);
+    apu.writeb(0xFF19, 0xC0);
+    assert_eq!(active(&apu), [false, true, false, false]);
+
+    // Lengths are clocked on steps 0 and 2.
+    apu.div_falling_edge();
+    apu.div_falling_edge();
+    assert!(active(&apu)[1]);
+    apu.div_falling_edge();
+    assert_eq!(active(&apu), [false; 4]);
+    assert_eq!(apu.readb(0xFF26), 0xF0);
+}
+
+// Channel 3 playing ram at the highest frequency, panned to both sides at
+// the lowest master volume. Each sample lasts 2 cycles.
+fn wave(ram: [u8; 16], nr32: u8) -> Apu {
+    let mut apu = powered();
+    for (i, &b) in ram.iter().enumerate() {
+        apu.writeb(0xFF30 + i as u16, b);
+    }
+    apu.writeb(0xFF25, 0x44);
+    apu.writeb(0xFF1A, 0x80);
+    apu.writeb(0xFF1C, nr32);
+    apu.writeb(0xFF1D, 0xFF);
+    apu.writeb(0xFF1E, 0x87);
+    // Past the sample that was latched before the trigger.
+    apu.step(2);
+    apu
+}
+
+// NR32 shifts every sample right by 0, 1 or 2, or mutes them. The DAC turns
+// 0 to 15 into -15 to 15.
+#[test]
+fn wave_volume() {
+    for &(nr32, level) in &[(0x20, 15), (0x40, -1), (0x60, -9), (0x00, -15)] {
+        let apu = wave([0xFF; 16], nr32);
+        assert_eq!(apu.output(), (level * 64, level * 64), "NR32 0x{:02x}", nr32);
+    }
+}
+
+#[test]
+fn wave_length_expiry() {
+    let mut apu = wave([0xFF; 16], 0x20);
+    // A length of 2.
+    apu.writeb(0xFF1B, 0xFE);
+    apu.writeb(0xFF1E, 0xC7);
+    apu.div_falling_edge();
+    apu.div_falling_edge();
+    assert!(active(&apu)[2]);
+    apu.div_falling_edge();
+    assert!(!active(&apu)[2]);
+    assert_eq!(apu.output(), (-15 * 64, -15 * 64));
+}
+
+// While the channel plays, every wave RAM address is the byte it's playing.
+#[test]
+fn wave_ram_while_active() {
+    let mut ram = [0; 16];
+    for (i, b) in ram.iter_mut().enumerate() {
+        *b = i as u8 * 0x11;
+    }
+    let mut apu = wave(ram, 0x20);
+    // Samples 4 and 5 are in byte 2.
+    apu.step(2 * 4);
+    assert_eq!(apu.readb(0xFF30), 0x22);
+    assert_eq!(apu.readb(0xFF3F), 0x22);
+    apu.writeb(0xFF3F, 0xAB);
+    assert_eq!(apu.readb(0xFF32), 0xAB);
+
+    // Once the DAC is off the addresses mean what they say again.
+    apu.writeb(0xFF1A, 0x00);
+    assert!(!active(&apu)[2]);
+    assert_eq!(apu.readb(0xFF32), 0xAB);
+    assert_eq!(apu.readb(0xFF3F), 0xFF);
+}
+
+// The bits that come out of the bottom of the LFSR, starting from all 1s as
+// a trigger leaves it.
+fn lfsr_output(short: bool, count: usize) -> Vec<u16> {
+    let mut lfsr = 0x7FFF;
+    (0..count)
+        .map(|_| {
+            lfsr = lfsr_step(lfsr, short);
+            lfsr & 1
+        })
+        .collect()
+}
+
+// The 1s are shifted out until the first 0 feeds back in from the top.
+#[test]
+fn lfsr_15_bit() {
+    assert_eq!(lfsr_step(0x7FFF, false), 0x3FFF);
+    assert_eq!(lfsr_step(0x0001, false), 0x4000);
+    assert_eq!(lfsr_step(0x0002, false), 0x4001);
+    assert_eq!(lfsr_output(false, 32),
+               [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 1, 0, 0, 0]);
+
+    // The longest sequence that 15 bits can make.
+    let mut lfsr = lfsr_step(0x7FFF, false);
+    let mut period = 1;
+    while lfsr != 0x7FFF {
+        lfsr = lfsr_step(lfsr, false);
+        period += 1;
+    }
+    assert_eq!(period, 32767);
+}
+
+// In 7-bit mode the feedback goes into bit 6 as well, so the bottom 7 bits
+// repeat every 127 steps.
+#[test]
+fn lfsr_7_bit() {
+    assert_eq!(lfsr_step(0x7FFF, true), 0x3FBF);
+    assert_eq!(lfsr_step(0x0001, true), 0x4040);
+    assert_eq!(lfsr_output(true, 32),
+               [1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 1,
+                0, 0, 0, 1, 1]);
+
+    // Once the initial 1s above bit 6 have been shifted out.
+    let output = lfsr_output(true, 400);
+    assert_eq!(&output[14..141], &output[141..268]);
+    assert!(output[14..141].contains(&0) && output[14..141].contains(&1));
+    // Whereas the 15-bit sequence doesn't.
+    let output = lfsr_output(false, 400);
+    assert!(output[14..141] != output[141..268]);
+}
+
+#[test]
+fn noise_active() {
+    let mut apu = powered();
+    // Triggering with the DAC off doesn't start the channel.
+    apu.writeb(0xFF23, 0x80);
+    assert!(!active(&apu)[3]);
+
+    apu.writeb(0xFF21, 0xF0);
+    apu.writeb(0xFF23, 0x80);
+    assert_eq!(active(&apu), [false, false, false, true]);
+    assert_eq!(apu.readb(0xFF26), 0xF8);
+
+    // Turning the DAC off stops it.
+    apu.writeb(0xFF21, 0x00);
+    assert!(!active(&apu)[3]);
+
+    // As does its length running out.
+    apu.writeb(0xFF21, 0xF0);
+    apu.writeb(0xFF20, 0x3F);
+    apu.writeb(0xFF23, 0xC0);
+    assert!(active(&apu)[3]);
+    apu.div_falling_edge();
+    assert!(!active(&apu)[3]);
+}
+
+// Run edges DIV falling edges, i.e. steps of the frame sequencer.
+fn div_edges(apu: &mut Apu, edges: u32) {
+    for _ in 0..edges {
+        apu.div_falling_edge();
+    }
+}
+
+// Lengths are clocked on steps 0, 2, 4 and 6, so the longest length of 64
+// lasts until the 127th step.
+#[test]
+fn sequencer_length_steps() {
+    let mut apu = powered();
+    apu.writeb(0xFF17, 0xF0);
+    apu.writeb(0xFF16, 0x00);
+    apu.writeb(0xFF19, 0xC0);
+    div_edges(&mut apu, 126);
+    assert!(active(&apu)[1]);
+    apu.div_falling_edge();
+    assert!(!active(&apu)[1]);
+}
+
+// The sweep is clocked on steps 2 and 6. With a period of 1 its first clock
+// takes 0x500 to 0x780, which would overflow on the next, so the channel
+// turns off.
+#[test]
+fn sequencer_sweep_steps() {
+    for &(before, step) in &[(0, 2), (3, 6)] {
+        let mut apu = powered();
+        div_edges(&mut apu, before);
+        apu.writeb(0xFF10, 0x11);
+        apu.writeb(0xFF12, 0xF0);
+        apu.writeb(0xFF13, 0x00);
+        apu.writeb(0xFF14, 0x85);
+        for _ in before..step {
+            apu.div_falling_edge();
+            assert!(active(&apu)[0]);
+        }
+        apu.div_falling_edge();
+        assert!(!active(&apu)[0], "step {}", step);
+    }
+}
+
+// Envelopes are clocked on step 7.
+#[test]
+fn sequencer_envelope_steps() {
+    let mut apu = powered();
+    apu.writeb(0xFF25, 0x11);
+    // 50% duty, which is high until channel 1 is stepped.
+    apu.writeb(0xFF11, 0x80);
+    // Starting at 0 and going up every clock.
+    apu.writeb(0xFF12, 0x09);
+    apu.writeb(0xFF14, 0x80);
+
+    let volume = |apu: &Apu| (apu.output().0 / 64 + 15) / 2;
+    for &expected in &[0, 1, 2] {
+        for _ in 0..7 {
+            assert_eq!(volume(&apu), expected);
+            apu.div_falling_edge();
+        }
+        assert_eq!(volume(&apu), expected);
+        apu.div_falling_edge();
+    }
+    assert_eq!(volume(&apu), 3);
+}
+
+// How many more clocks an enabled counter takes to turn its channel off.
+fn clocks_left(length: &LengthCounter) -> u16 {
+    let mut length = *length;
+    let mut clocks = 1;
+    while !length.clock() {
+        clocks += 1;
+        assert!(clocks <= 256);
+    }
+    clocks
+}
+
+// Writing NRx1 loads the max minus the value, straight away, and the counter
+// only counts down while it's enabled.
+#[test]
+fn length_load() {
+    let mut length = LengthCounter::new(64);
+    length.load(0x3E);
+    assert!(!length.clock());
+    assert!(!length.write_control(true, false, 0));
+    assert_eq!(clocks_left(&length), 2);
+    // The duty bits are ignored.
+    length.load(0xC0);
+    assert_eq!(clocks_left(&length), 64);
+
+    let mut length = LengthCounter::new(256);
+    length.load(0x00);
+    length.write_control(true, false, 0);
+    assert_eq!(clocks_left(&length), 256);
+}
+
+// Enabling the counter when the next step won't clock it clocks it once
+// straight away, which turns the channel off if that's the last.
+#[test]
+fn length_enable_extra_clock() {
+    let mut length = LengthCounter::new(64);
+    length.load(0x3D);
+    assert!(!length.write_control(true, false, 1));
+    assert_eq!(clocks_left(&length), 2);
+
+    // Not when it's already enabled.
+    assert!(!length.write_control(true, false, 1));
+    assert_eq!(clocks_left(&length), 2);
+
+    // Nor when the next step clocks it anyway.
+    let mut length = LengthCounter::new(64);
+    length.load(0x3D);
+    assert!(!length.write_control(true, false, 2));
+    assert_eq!(clocks_left(&length), 3);
+
+    let mut length = LengthCounter::new(64);
+    length.load(0x3F);
+    assert!(length.write_control(true, false, 3));
+}
+
+// Triggering reloads an expired counter with the max, or one less if
+// it's enabled by the same write on a step that doesn't clock it.
+#[test]
+fn length_trigger_reload() {
+    for &(enable, step, clocks) in &[(false, 0, 64), (false, 1, 64), (true, 0, 64), (true, 1, 63)] {
+        let mut length = LengthCounter::new(64);
+        assert!(!length.write_control(enable, true, step));
+        length.write_control(true, false, 0);
+        assert_eq!(clocks_left(&length), clocks, "enable {} step {}", enable, step);
+    }
+
+    // A counter that hasn't expired is left alone.
+    let mut length = LengthCounter::new(64);
+    length.load(0x3C);
+    length.write_control(false, true, 0);
+    length.write_control(true, false, 0);
+    assert_eq!(clocks_left(&length), 4);
+}
+
+// If the extra clock expires the counter, the trigger in the same write
+// reloads it with one less than the max and the channel stays on.
+#[test]
+fn length_extra_clock_then_trigger() {
+    let mut length = LengthCounter::new(64);
+    length.load(0x3F);
+    assert!(!length.write_control(true, true, 1));
+    assert_eq!(clocks_left(&length), 63);
+
+    let mut length = LengthCounter::new(256);
+    length.load(0xFF);
+    assert!(!length.write_control(true, true, 5));
+    assert_eq!(clocks_left(&length), 255);
+}
+
+// Channel 2 alone, held high at volume 15 by not stepping it, with NR50 and
+// NR51 as given.
+fn mixed(nr50: u8, nr51: u8) -> (i16, i16) {
+    let mut apu = powered();
+    apu.writeb(0xFF16, 0x80);
+    apu.writeb(0xFF17, 0xF0);
+    apu.writeb(0xFF19, 0x80);
+    apu.writeb(0xFF24, nr50);
+    apu.writeb(0xFF25, nr51);
+    apu.output()
+}
+
+// NR51 bits 4-7 send each channel left and bits 0-3 send it right.
+#[test]
+fn mixer_panning() {
+    assert_eq!(mixed(0x00, 0x20), (15 * 64, 0));
+    assert_eq!(mixed(0x00, 0x02), (0, 15 * 64));
+    assert_eq!(mixed(0x00, 0x22), (15 * 64, 15 * 64));
+    // Other channels' bits don't bring channel 2 in.
+    assert_eq!(mixed(0x00, 0xDD), (0, 0));
+}
+
+// NR50 scales each side by 1 to 8, and its VIN bits are kept but do
+// nothing.
+#[test]
+fn mixer_volume() {
+    assert_eq!(mixed(0x70, 0x22), (15 * 64 * 8, 15 * 64));
+    assert_eq!(mixed(0x03, 0x22), (15 * 64, 15 * 64 * 4));
+    assert_eq!(mixed(0x88, 0x22), mixed(0x00, 0x22));
+
+    let mut apu = powered();
+    apu.writeb(0xFF24, 0xFF);
+    assert_eq!(apu.readb(0xFF24), 0xFF);
+}
+
+// Push an emulated second of samples, from f, draining them a frame at a
+// time as a frontend would.
+fn resample<F: Fn(u32) -> i16>(rate: u32, f: F) -> Vec<i16> {
+    let mut buffer = SampleBuffer::new();
+    buffer.set_rate(rate);
+    let mut out = Vec::new();
+    for i in 0..INTERNAL_RATE {
+        buffer.push(f(i), -f(i));
+        if i % (CYCLES_PER_FRAME / 4) == 0 {
+            out.extend(buffer.drain());
+        }
+    }
+    out.extend(buffer.drain());
+    assert_eq!(buffer.dropped(), 0);
+    out
+}
+
+// A second of emulation is a second of audio at the host's rate.
+#[test]
+fn resample_rates() {
+    for &rate in &[44_100, 48_000] {
+        let out = resample(rate, |_| 1000);
+        assert_eq!(out.len(), rate as usize * 2);
+        assert!(out.chunks(2).all(|s| s == [1000, -1000]));
+    }
+}
+
+// Each host sample is the average of the APU's since the last, so a step
+// comes out as a step with at most one sample in between.
+#[test]
+fn resample_step() {
+    let out = resample(48_000, |i| if i < INTERNAL_RATE / 2 { 1000 } else { -1000 });
+    let left: Vec<i16> = out.iter().cloned().step_by(2).collect();
+    let high = left.iter().take_while(|&&s| s == 1000).count();
+    assert!(high == 23_999 || high == 24_000, "{}", high);
+    assert!(left[high + 1..].iter().all(|&s| s == -1000));
+}
+
+// Above the APU's own rate every sample is passed straight through.
+#[test]
+fn resample_above_internal_rate() {
+    let mut buffer = SampleBuffer::new();
+    buffer.set_rate(INTERNAL_RATE * 2);
+    assert_eq!(buffer.rate(), INTERNAL_RATE);
+    for i in 0..100 {
+        buffer.push(i, -i);
+    }
+    let out = buffer.drain();
+    assert_eq!(out.len(), 200);
+    assert_eq!(&out[..4], &[0, 0, 1, -1]);
+}
+
+// All four channels playing, with the envelopes and sweep moving, sampled at
+// 48kHz.
+fn song() -> Apu {
+    let mut apu = powered();
+    apu.set_sample_rate(48_000);
+    for (i, addr) in (0xFF30..0xFF40).enumerate() {
+        apu.writeb(addr, (i as u8).wrapping_mul(0x1F));
+    }
+    let writes = [(0xFF24, 0x75), (0xFF25, 0xB7), // NR50, NR51
+                  (0xFF10, 0x23), (0xFF11, 0x90), (0xFF12, 0xF3), // Channel 1
+                  (0xFF13, 0x40), (0xFF14, 0xC3),
+                  (0xFF16, 0x40), (0xFF17, 0x4A), (0xFF18, 0x90), // Channel 2
+                  (0xFF19, 0x85),
+                  (0xFF1A, 0x80), (0xFF1B, 0x20), (0xFF1C, 0x40), // Channel 3
+                  (0xFF1D, 0x10), (0xFF1E, 0xC6),
+                  (0xFF21, 0xA2), (0xFF22, 0x5B), (0xFF23, 0x80)]; // Channel 4
+    for &(addr, val) in writes.iter() {
+        apu.writeb(addr, val);
+    }
+    apu
+}
+
+// Play for cycles, with a DIV edge every 8192 as the timer would give, and
+// return the samples.
+fn play(apu: &mut Apu, cycles: u32) -> Vec<i16> {
+    let mut out = Vec::new();
+    for i in 0..cycles / 1024 {
+        apu.step(1024);
+        if i % 8 == 7 {
+            apu.div_falling_edge();
+        }
+        out.extend(apu.take_samples());
+    }
+    out
+}
+
+// Loading a state taken mid-song carries on with exactly the same samples
+// as if it had never been saved.
+#[test]
+fn save_state_round_trip() {
+    let mut apu = song();
+    // Part way through a sample, so that the resampler has some in hand.
+    play(&mut apu, 300 * 1024);
+    apu.step(6);
+    let mut w = StateWriter::new(0);
+    apu.save_state(&mut w);
+    let state = w.into_bytes();
+    let saved = format!("{:?}", apu);
+    let expected = play(&mut apu, 200 * 1024);
+
+    let mut loaded = Apu::new();
+    loaded.set_sample_rate(48_000);
+    let mut r = StateReader::new(&state, 0).unwrap();
+    loaded.load_state(&mut r).unwrap();
+    r.finish().unwrap();
+    assert_eq!(format!("{:?}", loaded), saved);
+    let samples = play(&mut loaded, 200 * 1024);
+    assert!(expected.iter().any(|&s| s != expected[0]));
+    assert!(samples == expected);
+}
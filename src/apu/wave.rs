@@ -0,0 +1,181 @@
+use apu::length::LengthCounter;
+use savestate::{StateWriter, StateReader};
+
+const WAVE_RAM_SZ: usize = 0x10;
+
+// Channel 3, which plays back 32 4-bit samples from wave RAM. Registers are
+// numbered from 0 for NR30 to 4 for NR34.
+pub struct WaveChannel {
+    ram: [u8; WAVE_RAM_SZ], // 0xFF30 -> 0xFF3F
+    dac_enabled: bool,
+    volume: u8, // 0 is mute, then 100%, 50% and 25%
+    freq: u16,
+    timer: u32,
+    position: usize,
+    sample: u8,
+    length: LengthCounter,
+    enabled: bool,
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            ram: [0; WAVE_RAM_SZ],
+            dac_enabled: false,
+            volume: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: LengthCounter::new(256),
+            enabled: false,
+        }
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => (self.dac_enabled as u8) << 7,
+            1 | 3 => 0,
+            2 => self.volume << 5,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => panic!("Invalid wave channel register {}", reg),
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.write_length(val),
+            2 => self.volume = (val >> 5) & 0x03,
+            3 => self.freq = (self.freq & 0x0700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0x00FF) | ((val as u16 & 0x07) << 8);
+                let trigger = val & 0x80 != 0;
+                if self.length.write_control(val & 0x40 != 0, trigger, frame_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid wave channel register {}", reg),
+        }
+    }
+
+    pub fn write_length(&mut self, val: u8) {
+        self.length.load(val);
+    }
+
+    // While the channel is playing, wave RAM accesses go to whichever byte it
+    // is reading from, regardless of the address.
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[self.ram_index(addr)]
+    }
+
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        let i = self.ram_index(addr);
+        self.ram[i] = val;
+    }
+
+    pub fn active(&self) -> bool {
+        self.enabled
+    }
+
+    // A channel whose DAC is off is silent rather than outputting 0.
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    // Everything but wave RAM and the length counter is cleared when the
+    // APU powers off.
+    pub fn power_off(&mut self) {
+        let (ram, mut length) = (self.ram, self.length);
+        length.power_off();
+        *self = WaveChannel::new();
+        self.ram = ram;
+        self.length = length;
+    }
+
+    // The current digital output, from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.volume != 0 {
+            self.sample >> (self.volume - 1)
+        } else {
+            0
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            self.sample = self.current_sample();
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // Cycles between samples.
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    // The high nibble of each byte is played first.
+    fn current_sample(&self) -> u8 {
+        let byte = self.ram[self.position / 2];
+        if self.position % 2 == 0 { byte >> 4 } else { byte & 0x0F }
+    }
+
+    fn ram_index(&self, addr: u16) -> usize {
+        if self.enabled {
+            self.position / 2
+        } else {
+            addr as usize & 0x0F
+        }
+    }
+
+    // Playback restarts from the first sample, but the sample that was
+    // already latched is what's heard until the position moves on.
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+        self.length.save_state(w);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.volume);
+        w.write_u16(self.freq);
+        w.write_u32(self.timer);
+        w.write_u8(self.position as u8);
+        w.write_u8(self.sample);
+        w.write_bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        try!(r.read_bytes(&mut self.ram));
+        try!(self.length.load_state(r));
+        self.dac_enabled = try!(r.read_bool());
+        self.volume = try!(r.read_u8()) & 0x03;
+        self.freq = try!(r.read_u16()) & 0x7FF;
+        self.timer = try!(r.read_u32());
+        self.position = try!(r.read_u8()) as usize % 32;
+        self.sample = try!(r.read_u8()) & 0x0F;
+        self.enabled = try!(r.read_bool());
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+// Command line parsing for the desktop frontend. Everything ends up in Args,
+// with anything that wasn't given left as None, so main never has to care
+// about argparse.
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use argparse::{ArgumentParser, List, Parse, Print, StoreOption, StoreTrue};
+
+// Which end of a link cable over TCP this instance is.
+pub enum Link {
+    Listen(u16),
+    Connect(String),
+}
+
+pub struct Args {
+    // Only empty when writing the config file.
+    pub rom: PathBuf,
+    pub bootrom: Option<PathBuf>,
+    pub skip_bootrom: bool,
+    pub model: Option<String>,
+    pub scale: Option<u32>,
+    pub headless: bool,
+    pub frames: u32,
+    pub expect_hash: Option<String>,
+    pub expect_serial: Option<String>,
+    pub bench_secs: u32,
+    pub load_state: Option<PathBuf>,
+    pub sav: Option<PathBuf>,
+    pub save_dir: Option<PathBuf>,
+    pub screenshot_dir: Option<PathBuf>,
+    pub palette: Option<String>,
+    pub palette_custom: Option<String>,
+    pub trace_file: Option<PathBuf>,
+    pub trace_after: u64,
+    pub trace_format: String,
+    // Set by --doctor-log, along with the trace file and format.
+    pub stub_ly: bool,
+    pub no_history: bool,
+    pub profile: bool,
+    pub link: Option<Link>,
+    pub printer_dir: Option<PathBuf>,
+    pub debug_windows: bool,
+    pub cheats: Option<PathBuf>,
+    pub breakpoints: Vec<u16>,
+    pub config: Option<PathBuf>,
+    pub write_config: bool,
+    pub no_audio: bool,
+}
+
+// Parse argv, including the program name. On failure (or after printing
+// --help or --version) the error is the exit code to use.
+pub fn parse(argv: Vec<String>) -> Result<Args, i32> {
+    let mut rom = String::new();
+    let mut bootrom = String::new();
+    let mut skip_bootrom = false;
+    let mut model = String::new();
+    let mut scale: Option<u32> = None;
+    let mut headless = false;
+    let mut frames: u32 = 0;
+    let mut expect_hash = String::new();
+    let mut expect_serial = String::new();
+    let mut bench_secs: u32 = 0;
+    let mut load_state = String::new();
+    let mut sav = String::new();
+    let mut save_dir = String::new();
+    let mut screenshot_dir = String::new();
+    let mut palette = String::new();
+    let mut palette_custom = String::new();
+    let mut trace_file = String::new();
+    let mut trace_after: u64 = 0;
+    let mut trace_format = "plain".to_string();
+    let mut doctor_log = String::new();
+    let mut no_history = false;
+    let mut profile = false;
+    let mut link: Vec<String> = Vec::new();
+    let mut printer_dir = String::new();
+    let mut debug_windows = false;
+    let mut cheats = String::new();
+    let mut breakpoints: Vec<String> = Vec::new();
+    let mut config = String::new();
+    let mut write_config = false;
+    let mut no_audio = false;
+
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("A GameBoy emulator written in Rust");
+        parser.add_option(&["-v", "--version"],
+                          Print(format!("iogb: v{}", env!("CARGO_PKG_VERSION"))),
+                          "Show version");
+        // -r is still accepted so that existing scripts keep working.
+        parser.refer(&mut rom)
+            .add_argument("rom", Parse, "Path to ROM file")
+            .add_option(&["-r", "--rom"], Parse, "Path to ROM file");
+        parser.refer(&mut scale).add_option(&["-s", "--scale"], StoreOption, "Display scaling");
+        parser.refer(&mut bootrom)
+            .add_option(&["-b", "--bootrom"], Parse, "Path to boot ROM file");
+        parser.refer(&mut skip_bootrom).add_option(&["--skip-bootrom"],
+                                                   StoreTrue,
+                                                   "Start after the boot ROM would have \
+                                                    finished, even if one is loaded");
+        parser.refer(&mut model).add_option(&["--model"],
+                                            Parse,
+                                            "Hardware to start as when skipping the boot ROM: \
+                                             dmg, mgb or cgb");
+        parser.refer(&mut headless)
+            .add_option(&["--headless"], StoreTrue, "Run without a window");
+        parser.refer(&mut frames)
+            .add_option(&["--frames"], Parse, "Number of frames to run when headless");
+        parser.refer(&mut expect_hash).add_option(&["--expect-hash"],
+                                                  Parse,
+                                                  "Expected hash of the final frame when headless");
+        parser.refer(&mut expect_serial)
+            .add_option(&["--expect-serial"],
+                        Parse,
+                        "Text that the serial output must contain when headless");
+        parser.refer(&mut bench_secs).add_option(&["--bench"],
+                                                 Parse,
+                                                 "Emulate this many seconds as fast as possible and \
+                                                  report the speed");
+        parser.refer(&mut load_state)
+            .add_option(&["--load-state"], Parse, "Start from a save state file");
+        parser.refer(&mut sav)
+            .add_option(&["--sav"], Parse, "Path to the battery save file");
+        parser.refer(&mut save_dir).add_option(&["--save-dir"],
+                                               Parse,
+                                               "Directory for saves, save states and \
+                                                screenshots, instead of next to the ROM");
+        parser.refer(&mut screenshot_dir)
+            .add_option(&["--screenshot-dir"], Parse, "Directory to save screenshots in");
+        parser.refer(&mut palette).add_option(&["--palette"],
+                                              Parse,
+                                              "Colour palette: grey, dmg-green, pocket, light \
+                                               or inverted");
+        parser.refer(&mut palette_custom).add_option(&["--palette-custom"],
+                                                     Parse,
+                                                     "Custom colour palette as \
+                                                      RRGGBB,RRGGBB,RRGGBB,RRGGBB, lightest \
+                                                      first");
+        parser.refer(&mut trace_file)
+            .add_option(&["--trace-file"], Parse, "Log every instruction executed to a file");
+        parser.refer(&mut trace_after).add_option(&["--trace-after"],
+                                                  Parse,
+                                                  "Only start tracing after this many cycles");
+        parser.refer(&mut trace_format)
+            .add_option(&["--trace-format"], Parse, "Trace format: plain or doctor");
+        parser.refer(&mut doctor_log).add_option(&["--doctor-log"],
+                                                 Parse,
+                                                 "Write a Gameboy Doctor log to a file, with LY \
+                                                  stubbed as it expects");
+        parser.refer(&mut no_history).add_option(&["--no-history"],
+                                                 StoreTrue,
+                                                 "Don't keep the last instructions executed for \
+                                                  crash reports, which is a little faster");
+        parser.refer(&mut profile).add_option(&["--profile"],
+                                              StoreTrue,
+                                              "Count the instructions executed and print the \
+                                               most expensive on exit");
+        parser.refer(&mut link).add_option(&["--link"],
+                                           List,
+                                           "Link to another iogb over TCP: listen PORT or \
+                                            connect HOST:PORT");
+        parser.refer(&mut printer_dir).add_option(&["--printer-dir"],
+                                                  Parse,
+                                                  "Plug in a Game Boy Printer that saves its \
+                                                   pages in this directory");
+        parser.refer(&mut debug_windows)
+            .add_option(&["--debug-windows"], StoreTrue, "Show a window with VRAM's contents");
+        parser.refer(&mut cheats)
+            .add_option(&["--cheats"], Parse, "Load Game Genie and GameShark codes from a file");
+        parser.refer(&mut breakpoints).add_option(&["--break"],
+                                                  List,
+                                                  "Pause before executing the instruction at \
+                                                   these addresses, given in hex");
+        parser.refer(&mut no_audio).add_option(&["--no-audio"],
+                                               StoreTrue,
+                                               "Don't play sound, and pace emulation with a \
+                                                timer instead");
+        parser.refer(&mut config)
+            .add_option(&["--config"], Parse, "Path to the config file");
+        parser.refer(&mut write_config).add_option(&["--write-config"],
+                                                   StoreTrue,
+                                                   "Write the effective configuration to the \
+                                                    config file and exit");
+        try!(parser.parse(argv, &mut io::stdout(), &mut io::stderr()));
+    }
+
+    // The ROM would be marked as required, but writing the config file
+    // doesn't need one.
+    if rom.is_empty() && !write_config {
+        let _ = writeln!(io::stderr(), "iogb: Argument rom is required");
+        return Err(2);
+    }
+
+    let link = match parse_link(&link) {
+        Ok(link) => link,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "iogb: {}", e);
+            return Err(2);
+        }
+    };
+
+    // A doctor log is a doctor trace, with LY stubbed so that the values
+    // games read from it match Gameboy Doctor's logs.
+    let stub_ly = !doctor_log.is_empty();
+    if stub_ly {
+        if !trace_file.is_empty() {
+            let _ = writeln!(io::stderr(),
+                             "iogb: --doctor-log and --trace-file can't be used together");
+            return Err(2);
+        }
+        trace_file = doctor_log;
+        trace_format = "doctor".to_string();
+    }
+
+    let breakpoints = match parse_breakpoints(&breakpoints) {
+        Ok(b) => b,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "iogb: {}", e);
+            return Err(2);
+        }
+    };
+
+    // Both would be plugged into the same link port.
+    if link.is_some() && !printer_dir.is_empty() {
+        let _ = writeln!(io::stderr(), "iogb: --link and --printer-dir can't be used together");
+        return Err(2);
+    }
+
+    Ok(Args {
+        rom: if rom.is_empty() { PathBuf::new() } else { absolute(&rom) },
+        bootrom: optional_path(bootrom),
+        skip_bootrom: skip_bootrom,
+        model: optional(model),
+        scale: scale,
+        headless: headless,
+        frames: frames,
+        expect_hash: optional(expect_hash),
+        expect_serial: optional(expect_serial),
+        bench_secs: bench_secs,
+        load_state: optional_path(load_state),
+        sav: optional_path(sav),
+        save_dir: optional_path(save_dir),
+        screenshot_dir: optional_path(screenshot_dir),
+        palette: optional(palette),
+        palette_custom: optional(palette_custom),
+        trace_file: optional_path(trace_file),
+        trace_after: trace_after,
+        trace_format: trace_format,
+        stub_ly: stub_ly,
+        no_history: no_history,
+        profile: profile,
+        link: link,
+        printer_dir: optional_path(printer_dir),
+        debug_windows: debug_windows,
+        cheats: optional_path(cheats),
+        breakpoints: breakpoints,
+        config: optional_path(config),
+        write_config: write_config,
+        no_audio: no_audio,
+    })
+}
+
+fn parse_link(words: &[String]) -> Result<Option<Link>, String> {
+    if words.is_empty() {
+        return Ok(None);
+    }
+    if words.len() != 2 {
+        return Err("--link takes listen PORT or connect HOST:PORT".to_string());
+    }
+    match words[0].as_str() {
+        "listen" => {
+            words[1]
+                .parse()
+                .map(|port| Some(Link::Listen(port)))
+                .map_err(|_| format!("Invalid port for --link: {}", words[1]))
+        }
+        "connect" => Ok(Some(Link::Connect(words[1].clone()))),
+        mode => Err(format!("Invalid --link mode: {}", mode)),
+    }
+}
+
+fn parse_breakpoints(addrs: &[String]) -> Result<Vec<u16>, String> {
+    addrs.iter()
+        .map(|a| {
+            u16::from_str_radix(a.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("Invalid address for --break: {}", a))
+        })
+        .collect()
+}
+
+fn optional(s: String) -> Option<String> {
+    if s.is_empty() { None } else { Some(s) }
+}
+
+fn optional_path(s: String) -> Option<PathBuf> {
+    optional(s).map(|s| absolute(&s))
+}
+
+// Every path is resolved against the working directory up front, so error
+// messages show exactly which file was meant.
+fn absolute<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::{absolute, parse, Args, Link};
+
+    fn args(words: &[&str]) -> Result<Args, i32> {
+        let mut argv = vec!["iogb".to_string()];
+        argv.extend(words.iter().map(|w| w.to_string()));
+        parse(argv)
+    }
+
+    #[test]
+    fn trace_flags() {
+        let a = args(&["game.gb"]).ok().unwrap();
+        assert_eq!(a.trace_file, None);
+        assert_eq!(a.trace_after, 0);
+        assert_eq!(a.trace_format, "plain");
+        assert!(!a.stub_ly);
+
+        let a = args(&["game.gb",
+                       "--trace-file",
+                       "trace.log",
+                       "--trace-after",
+                       "70224",
+                       "--trace-format",
+                       "doctor"])
+            .ok()
+            .unwrap();
+        assert_eq!(a.trace_file, Some(absolute("trace.log")));
+        assert_eq!(a.trace_after, 70224);
+        assert_eq!(a.trace_format, "doctor");
+        assert!(!a.stub_ly);
+
+        // --doctor-log is a doctor trace with LY stubbed.
+        let a = args(&["game.gb", "--doctor-log", "doctor.log"]).ok().unwrap();
+        assert_eq!(a.trace_file, Some(absolute("doctor.log")));
+        assert_eq!(a.trace_format, "doctor");
+        assert!(a.stub_ly);
+    }
+
+    #[test]
+    fn bad_trace_flags() {
+        assert_eq!(args(&["game.gb", "--trace-after", "soon"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--trace-after", "-1"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--trace-file", "a.log", "--doctor-log", "b.log"]).err(),
+                   Some(2));
+    }
+
+    // The ROM can be given either way, and every path is made absolute
+    // against the working directory.
+    #[test]
+    fn rom() {
+        let cwd = env::current_dir().unwrap();
+        let a = args(&["roms/game.gb"]).ok().unwrap();
+        assert_eq!(a.rom, cwd.join("roms/game.gb"));
+        let a = args(&["-r", "roms/game.gb"]).ok().unwrap();
+        assert_eq!(a.rom, cwd.join("roms/game.gb"));
+        let a = args(&["--rom", "/roms/game.gb"]).ok().unwrap();
+        assert_eq!(a.rom, PathBuf::from("/roms/game.gb"));
+
+        let a = args(&["game.gb", "--bootrom", "dmg_boot.bin"]).ok().unwrap();
+        assert_eq!(a.bootrom, Some(cwd.join("dmg_boot.bin")));
+        let a = args(&["game.gb", "-b", "/boot/dmg_boot.bin"]).ok().unwrap();
+        assert_eq!(a.bootrom, Some(PathBuf::from("/boot/dmg_boot.bin")));
+    }
+
+    #[test]
+    fn defaults() {
+        let a = args(&["game.gb"]).ok().unwrap();
+        assert_eq!(a.bootrom, None);
+        assert_eq!(a.scale, None);
+        assert_eq!(a.palette, None);
+        assert_eq!(a.save_dir, None);
+        assert!(!a.headless && !a.write_config && !a.no_audio && !a.skip_bootrom);
+        assert!(a.link.is_none());
+        assert!(a.breakpoints.is_empty());
+    }
+
+    // Only writing the config file gets away without a ROM.
+    #[test]
+    fn rom_required() {
+        assert_eq!(args(&[]).err(), Some(2));
+        assert_eq!(args(&["--scale", "3"]).err(), Some(2));
+        let a = args(&["--write-config", "--scale", "3"]).ok().unwrap();
+        assert_eq!(a.rom, PathBuf::new());
+        assert_eq!(a.scale, Some(3));
+    }
+
+    #[test]
+    fn link() {
+        match args(&["game.gb", "--link", "listen", "8765"]).ok().unwrap().link {
+            Some(Link::Listen(8765)) => {}
+            _ => panic!("expected to listen on 8765"),
+        }
+        match args(&["game.gb", "--link", "connect", "host:8765"]).ok().unwrap().link {
+            Some(Link::Connect(ref addr)) if addr == "host:8765" => {}
+            _ => panic!("expected to connect to host:8765"),
+        }
+        assert_eq!(args(&["game.gb", "--link", "listen"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--link", "listen", "port"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--link", "serve", "8765"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--link", "listen", "8765", "--printer-dir", "out"]).err(),
+                   Some(2));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let a = args(&["game.gb", "--break", "0x150", "C000", "ff80"]).ok().unwrap();
+        assert_eq!(a.breakpoints, [0x0150, 0xC000, 0xFF80]);
+        assert_eq!(args(&["game.gb", "--break", "0x10000"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--break", "main"]).err(), Some(2));
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_eq!(args(&["game.gb", "--scale", "big"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--frames", "-5"]).err(), Some(2));
+        assert_eq!(args(&["game.gb", "--no-such-flag"]).err(), Some(2));
+    }
+}
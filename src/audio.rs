@@ -0,0 +1,272 @@
+// Audio output for the desktop frontend. Samples go from the emulator into a
+// ring that the cpal callback drains, and the emulator is paced by how full
+// the ring is rather than by a timer: it sleeps whenever more than a few
+// frames' worth is queued. That way it runs at exactly the rate the sound
+// card plays at, so the pitch is right and nothing drifts over time.
+use std::time::Duration;
+
+use cpal;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+use pacer::Clock;
+use ring::{self, Consumer, Producer};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// How many frames of audio to keep queued. More survives longer hiccups but
+// delays the sound further behind the picture.
+const TARGET_FRAMES: usize = 3;
+const CAPACITY_FRAMES: usize = 8;
+
+// Interleaved left and right samples in a frame at the given rate, rounded
+// up.
+fn samples_per_frame(rate: u32) -> usize {
+    let frames = (rate as u64 * CYCLES_PER_FRAME as u64 + CPU_HZ as u64 - 1) / CPU_HZ as u64;
+    frames as usize * 2
+}
+
+pub struct AudioSync<C: Clock> {
+    producer: Producer,
+    clock: C,
+    rate: u32,
+    target: usize,
+    // The underrun count when last checked, or None if they aren't worth
+    // reporting because the ring hasn't filled up since the last reset.
+    underruns: Option<usize>,
+}
+
+impl<C: Clock> AudioSync<C> {
+    pub fn new(producer: Producer, rate: u32, clock: C) -> AudioSync<C> {
+        AudioSync {
+            producer: producer,
+            clock: clock,
+            rate: rate,
+            target: samples_per_frame(rate) * TARGET_FRAMES,
+            underruns: None,
+        }
+    }
+
+    // Call after a gap in the audio, e.g. while paused or fast-forwarding,
+    // when the ring will have run dry on purpose.
+    pub fn reset(&mut self) {
+        self.underruns = None;
+    }
+
+    // Call once per emulated frame with its audio. Queues it up and then
+    // sleeps until only TARGET_FRAMES are left to play.
+    pub fn wait(&mut self, samples: &[i16]) {
+        let pushed = self.producer.push(samples);
+        if pushed < samples.len() {
+            debug!("Audio buffer full, dropped {} samples", samples.len() - pushed);
+        }
+
+        let underruns = self.producer.underruns();
+        if let Some(seen) = self.underruns {
+            if underruns != seen {
+                debug!("Audio underran {} times, playing silence", underruns - seen);
+            }
+        }
+
+        let queued = self.producer.len();
+        if queued >= self.target || self.underruns.is_some() {
+            self.underruns = Some(underruns);
+        }
+        if queued > self.target {
+            let frames = ((queued - self.target) / 2) as u64;
+            let nanos = frames * NANOS_PER_SEC / self.rate as u64;
+            let duration = Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+            self.clock.sleep(duration);
+        }
+    }
+}
+
+// The stream stops when this is dropped.
+pub struct Output {
+    _stream: cpal::Stream,
+    rate: u32,
+}
+
+impl Output {
+    // The rate that the device plays at, and so the one that the GameBoy
+    // should produce.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+// Start playing silence on the default device, at its own rate and format,
+// and return the end of the ring to push samples into.
+pub fn open() -> Result<(Output, Producer), String> {
+    let host = cpal::default_host();
+    let device = match host.default_output_device() {
+        Some(d) => d,
+        None => return Err("No audio output device".to_string()),
+    };
+    let supported = try!(device.default_output_config().map_err(|e| e.to_string()));
+    let format = supported.sample_format();
+    let config: cpal::StreamConfig = supported.into();
+    let rate = config.sample_rate.0;
+
+    let (producer, consumer) = ring::ring(samples_per_frame(rate) * CAPACITY_FRAMES);
+    let stream = try!(match format {
+        cpal::SampleFormat::I16 => build::<i16>(&device, &config, consumer),
+        cpal::SampleFormat::U16 => build::<u16>(&device, &config, consumer),
+        cpal::SampleFormat::F32 => build::<f32>(&device, &config, consumer),
+    });
+    try!(stream.play().map_err(|e| e.to_string()));
+
+    Ok((Output {
+            _stream: stream,
+            rate: rate,
+        },
+        producer))
+}
+
+// The GameBoy is always stereo, so it's mixed down for mono devices and any
+// channels beyond the first two are left silent.
+fn build<T>(device: &cpal::Device,
+            config: &cpal::StreamConfig,
+            mut consumer: Consumer)
+            -> Result<cpal::Stream, String>
+    where T: cpal::Sample
+{
+    let channels = config.channels as usize;
+    let mut stereo = Vec::new();
+    let callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        stereo.resize(data.len() / channels * 2, 0);
+        consumer.pop(&mut stereo);
+        for (out, lr) in data.chunks_mut(channels).zip(stereo.chunks(2)) {
+            if channels == 1 {
+                out[0] = T::from(&(((lr[0] as i32 + lr[1] as i32) / 2) as i16));
+                continue;
+            }
+            for (i, s) in out.iter_mut().enumerate() {
+                *s = T::from(&lr.get(i).cloned().unwrap_or(0));
+            }
+        }
+    };
+    device.build_output_stream(config, callback, |e| error!("Audio stream failed: {}", e))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+    use pacer::{self, Clock};
+    use ring::{self, Consumer};
+    use super::{AudioSync, NANOS_PER_SEC, TARGET_FRAMES, samples_per_frame};
+
+    // A sound card that plays at rate, in frames per second, as time passes.
+    struct Device {
+        now: Duration,
+        consumer: Consumer,
+        rate: u64,
+        // Fractional frames, in units of 1 / NANOS_PER_SEC.
+        remainder: u64,
+        played: u64,
+    }
+
+    impl Device {
+        fn advance(&mut self, duration: Duration) {
+            self.now += duration;
+            let nanos = duration.as_secs() * NANOS_PER_SEC + duration.subsec_nanos() as u64;
+            self.remainder += nanos * self.rate;
+            let frames = self.remainder / NANOS_PER_SEC;
+            self.remainder %= NANOS_PER_SEC;
+            let mut out = vec![0; frames as usize * 2];
+            self.played += self.consumer.pop(&mut out) as u64 / 2;
+        }
+    }
+
+    struct DeviceClock(Rc<RefCell<Device>>);
+
+    impl Clock for DeviceClock {
+        fn now(&self) -> Duration {
+            self.0.borrow().now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.0.borrow_mut().advance(duration);
+        }
+    }
+
+    // Run frames of emulation that each produce audio at produced Hz and take
+    // work to emulate, against a device that plays at played Hz. Returns the
+    // device, the most samples that were ever queued and the underrun count.
+    fn run(produced: u32, played: u32, work: Duration, frames: u32) -> (Device, usize, usize) {
+        let (producer, consumer) = ring::ring(samples_per_frame(played) * 8);
+        let device = Rc::new(RefCell::new(Device {
+            now: Duration::new(0, 0),
+            consumer: consumer,
+            rate: played as u64,
+            remainder: 0,
+            played: 0,
+        }));
+        let mut sync = AudioSync::new(producer, played, DeviceClock(device.clone()));
+
+        let mut remainder = 0;
+        let mut most = 0;
+        for _ in 0..frames {
+            device.borrow_mut().advance(work);
+            remainder += produced as u64 * CYCLES_PER_FRAME as u64;
+            let samples = vec![0x100; (remainder / CPU_HZ as u64) as usize * 2];
+            remainder %= CPU_HZ as u64;
+            sync.wait(&samples);
+            most = cmp::max(most, sync.producer.len());
+        }
+        let underruns = sync.producer.underruns();
+        drop(sync);
+        let device = Rc::try_unwrap(device).ok().unwrap().into_inner();
+        (device, most, underruns)
+    }
+
+    fn secs(d: Duration) -> f64 {
+        d.as_secs() as f64 + d.subsec_nanos() as f64 / NANOS_PER_SEC as f64
+    }
+
+    // With the rates matching, emulation runs at the speed of a real GameBoy
+    // and the device never runs dry once it's started.
+    #[test]
+    fn matched_rates() {
+        let frames = 3000;
+        let (device, most, underruns) = run(48000, 48000, Duration::from_millis(2), frames);
+        let emulated = secs(pacer::frame_duration()) * frames as f64;
+        let real = secs(device.now);
+        assert!((real / emulated - 1.0).abs() < 0.001, "{} vs {}", real, emulated);
+        assert!(most <= samples_per_frame(48000) * (TARGET_FRAMES + 1));
+        // Only the very first callback, before anything was queued.
+        assert_eq!(underruns, 1);
+    }
+
+    // A device that plays slower than it claims to slows emulation down to
+    // match, rather than letting the queue grow until samples are dropped.
+    #[test]
+    fn slow_device() {
+        let frames = 3000;
+        let (device, most, underruns) = run(48000, 47000, Duration::from_millis(2), frames);
+        let expected = 48000.0 * secs(pacer::frame_duration()) * frames as f64 / 47000.0;
+        let real = secs(device.now);
+        assert!((real / expected - 1.0).abs() < 0.001, "{} vs {}", real, expected);
+        assert!(most <= samples_per_frame(47000) * (TARGET_FRAMES + 1));
+        assert_eq!(underruns, 1);
+    }
+
+    // When emulation can't keep up the device plays silence in the gaps, and
+    // waiting doesn't slow things down any further.
+    #[test]
+    fn slow_host() {
+        let frames = 600;
+        let work = Duration::from_millis(20);
+        let (device, _, underruns) = run(48000, 48000, work, frames);
+        assert_eq!(device.now, work * frames);
+        assert!(device.played < device.now.as_secs() * 48000);
+        assert!(underruns > 100);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::time::Duration;
+
+use gameboy::CPU_HZ;
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+// How quickly a run was emulated compared to real hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchReport {
+    pub cycles: u64,
+    pub frames: u64,
+    pub instructions: u64,
+    pub wall: Duration,
+}
+
+impl BenchReport {
+    pub fn emulated(&self) -> Duration {
+        let secs = self.cycles / CPU_HZ as u64;
+        let nanos = (self.cycles % CPU_HZ as u64) * 1_000_000_000 / CPU_HZ as u64;
+        Duration::new(secs, nanos as u32)
+    }
+
+    // Emulated seconds per wall second, so 1.0 is full speed. A run too short
+    // for the clock to notice has no meaningful speed, and reports 0.
+    pub fn speed(&self) -> f64 {
+        if self.wall == Duration::new(0, 0) {
+            return 0.0;
+        }
+        as_secs_f64(self.emulated()) / as_secs_f64(self.wall)
+    }
+
+    pub fn avg_frame_time(&self) -> Duration {
+        if self.frames == 0 {
+            return Duration::new(0, 0);
+        }
+        let nanos = (self.wall.as_secs() * 1_000_000_000 + self.wall.subsec_nanos() as u64) /
+                    self.frames;
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+
+    pub fn instructions_per_sec(&self) -> f64 {
+        if self.wall == Duration::new(0, 0) {
+            return 0.0;
+        }
+        self.instructions as f64 / as_secs_f64(self.wall)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame_time = self.avg_frame_time();
+        try!(writeln!(f,
+                      "Emulated {:.2}s in {:.2}s ({:.2}x)",
+                      as_secs_f64(self.emulated()),
+                      as_secs_f64(self.wall),
+                      self.speed()));
+        try!(writeln!(f,
+                      "{} frames, {:.3}ms per frame",
+                      self.frames,
+                      frame_time.subsec_nanos() as f64 / 1_000_000.0 +
+                      frame_time.as_secs() as f64 * 1000.0));
+        write!(f, "{:.0} instructions per second", self.instructions_per_sec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::BenchReport;
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use gameboy::{GameBoy, CPU_HZ, CYCLES_PER_FRAME};
+
+    fn report(cycles: u64, frames: u64, instructions: u64, wall: Duration) -> BenchReport {
+        BenchReport {
+            cycles: cycles,
+            frames: frames,
+            instructions: instructions,
+            wall: wall,
+        }
+    }
+
+    // A ROM that spins at 0x0100 forever.
+    fn spin() -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xFE;
+        GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty())
+    }
+
+    #[test]
+    fn rates() {
+        let r = report(CPU_HZ as u64 * 2, 120, 1_000_000, Duration::from_millis(500));
+        assert_eq!(r.emulated(), Duration::from_secs(2));
+        assert_eq!(r.speed(), 4.0);
+        assert_eq!(r.instructions_per_sec(), 2_000_000.0);
+        assert_eq!(r.avg_frame_time(), Duration::new(0, 500_000_000 / 120));
+    }
+
+    #[test]
+    fn no_time_measured() {
+        let r = report(CPU_HZ as u64, 60, 1000, Duration::new(0, 0));
+        assert_eq!(r.speed(), 0.0);
+        assert_eq!(r.instructions_per_sec(), 0.0);
+        assert_eq!(r.avg_frame_time(), Duration::new(0, 0));
+        // Nor does it print inf or NaN.
+        let text = format!("{}", r);
+        assert!(!text.contains("inf") && !text.contains("NaN"), "{}", text);
+    }
+
+    #[test]
+    fn no_frames() {
+        let r = report(0, 0, 0, Duration::from_secs(1));
+        assert_eq!(r.speed(), 0.0);
+        assert_eq!(r.avg_frame_time(), Duration::new(0, 0));
+    }
+
+    // The clock is only read at the start and the end of the run, so a mock
+    // that advances a fixed amount per call gives a known wall time.
+    #[test]
+    fn bench_run_uses_clock() {
+        let mut gb = spin();
+        let now = Cell::new(Duration::from_secs(100));
+        let calls = Cell::new(0);
+        let r = gb.bench_run(CPU_HZ as u64 / 2, || {
+            calls.set(calls.get() + 1);
+            now.set(now.get() + Duration::from_millis(250));
+            now.get()
+        });
+
+        assert_eq!(calls.get(), 2);
+        assert_eq!(r.wall, Duration::from_millis(250));
+        assert!(r.cycles >= CPU_HZ as u64 / 2 && r.cycles < CPU_HZ as u64 / 2 + 32,
+                "{} cycles",
+                r.cycles);
+        assert_eq!(r.frames, r.cycles / CYCLES_PER_FRAME as u64);
+        assert!(r.instructions > 0);
+        assert!((r.speed() - 2.0).abs() < 0.001, "{}x", r.speed());
+    }
+
+    #[test]
+    fn bench_run_continues_from_earlier_runs() {
+        let mut gb = spin();
+        let clock = || Duration::from_secs(1);
+        gb.bench_run(CYCLES_PER_FRAME as u64 * 3, clock);
+        let r = gb.bench_run(CYCLES_PER_FRAME as u64 * 2, clock);
+        assert_eq!(r.wall, Duration::new(0, 0));
+        assert!(r.cycles >= CYCLES_PER_FRAME as u64 * 2 &&
+                r.cycles < CYCLES_PER_FRAME as u64 * 2 + 32,
+                "{} cycles",
+                r.cycles);
+        assert_eq!(r.speed(), 0.0);
+    }
+}
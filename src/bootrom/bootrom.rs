@@ -1,5 +1,8 @@
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
 use std::path;
+#[cfg(not(target_arch = "wasm32"))]
 use std::io::Read;
 
 // TODO: Move these constants into their own file
@@ -10,18 +13,31 @@ pub struct Bootrom {
 }
 
 impl Bootrom {
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn from_pathbuf(path: Option<&path::PathBuf>) -> Result<Bootrom, String> {
         match path {
             Some(p) => {
                 let mut buf = [0; BOOTROM_SZ];
                 let mut file = try!(File::open(p).map_err(|e| format!("{}", e)));
                 try!(file.read_exact(&mut buf).map_err(|e| format!("{}", e)));
+                info!("Loaded bootrom from {}", p.display());
                 Ok(Bootrom { buf: Some(buf.to_vec()) })
             }
             None => Ok(Bootrom { buf: None }),
         }
     }
 
+    pub fn from_bytes(buf: &[u8]) -> Result<Bootrom, String> {
+        if buf.len() != BOOTROM_SZ {
+            return Err(format!("Expected 0x{:x} bytes, got 0x{:x}", BOOTROM_SZ, buf.len()));
+        }
+        Ok(Bootrom { buf: Some(buf.to_vec()) })
+    }
+
+    pub fn empty() -> Bootrom {
+        Bootrom { buf: None }
+    }
+
     pub fn readb(&self, addr: u16) -> u8 {
         match self.buf {
             Some(ref b) => b[addr as usize],
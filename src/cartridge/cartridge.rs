@@ -1,10 +1,18 @@
+use std::cmp;
 use std::fmt;
 use std::str;
 use std::iter;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
 use std::path;
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
 use std::io::Read;
 
+use savestate::{StateWriter, StateReader};
+use super::rtc::Rtc;
+
 const ROM_BANK_SZ: usize = 0x4000;
 const RAM_BANK_SZ: usize = 0x2000;
 
@@ -13,62 +21,245 @@ const RAM_BANK_SZ: usize = 0x2000;
 enum Mbc {
     None,
     One,
+    Three,
 }
 
 impl Mbc {
-    // TODO: Return an Option, so we can have nice error handling...
-    fn from_header(byte: u8) -> Mbc {
+    fn from_header(byte: u8) -> Result<Mbc, String> {
         match byte {
-            0x00 => Mbc::None,
-            0x01 | 0x02 | 0x03 => Mbc::One,
-            inv => panic!("Unknown MBC type: 0x{:02x}", inv),
+            0x00 => Ok(Mbc::None),
+            0x01...0x03 => Ok(Mbc::One),
+            0x0F...0x13 => Ok(Mbc::Three),
+            inv => Err(format!("Unsupported cartridge type: 0x{:02x}", inv)),
         }
     }
 }
 
-pub struct Cartridge {
+// The cartridge header lives at 0x0100 -> 0x014F of the ROM.
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
     pub title: String,
+    pub cgb_flag: u8,
+    pub new_licensee_code: u16,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination_code: u8,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    fn from_rom(buf: &[u8]) -> Result<CartridgeHeader, String> {
+        let title = try!(str::from_utf8(&buf[0x134..0x143])
+            .map_err(|e| format!("Invalid title in header: {}", e)));
+
+        Ok(CartridgeHeader {
+            title: title.trim_end_matches('\0').to_string(),
+            cgb_flag: buf[0x143],
+            new_licensee_code: (buf[0x144] as u16) << 8 | buf[0x145] as u16,
+            sgb_flag: buf[0x146],
+            cartridge_type: buf[0x147],
+            rom_size: buf[0x148],
+            ram_size: buf[0x149],
+            destination_code: buf[0x14A],
+            old_licensee_code: buf[0x14B],
+            version: buf[0x14C],
+            header_checksum: buf[0x14D],
+            global_checksum: (buf[0x14E] as u16) << 8 | buf[0x14F] as u16,
+        })
+    }
+}
+
+pub struct Cartridge {
+    header: CartridgeHeader,
     mbc: Mbc,
     rom: Vec<u8>,
+    // Taken before any cheats patch the ROM.
+    rom_hash: u64,
     rom_bank: u8,
     ram: Vec<u8>,
     ram_bank: u8,
     ram_enable: bool,
     rom_mode_select: bool,
+    rtc: Option<Rtc>,
 }
 
 impl Cartridge {
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn new(rom_name: &path::PathBuf) -> Result<Cartridge, String> {
         let buf = match Cartridge::open_rom(rom_name) {
             Ok(b) => b,
             Err(e) => return Err(e),
         };
+        Cartridge::from_bytes(buf)
+    }
 
-        let mbc = Mbc::from_header(buf[0x147]);
-        let ram_sz = match buf[0x149] {
-            0x00 => 0,
-            inv => panic!("Unknown ram size: 0x{:02x}", inv),
-        };
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Cartridge, String> {
+        if buf.len() < 0x150 {
+            return Err(format!("ROM is too small to contain a header: 0x{:x} bytes",
+                               buf.len()));
+        }
 
-        let title_buf = buf[0x134..0x143].to_vec();
+        let header = try!(CartridgeHeader::from_rom(&buf));
+        let checksum = buf[0x134..0x14D]
+            .iter()
+            .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+        if checksum != header.header_checksum {
+            warn!("Header checksum mismatch: expected 0x{:02x}, calculated 0x{:02x}",
+                  header.header_checksum,
+                  checksum);
+        }
+        info!("Loaded cartridge \"{}\": type 0x{:02x}, 0x{:x} bytes of ROM",
+              header.title,
+              header.cartridge_type,
+              buf.len());
 
-        let title = match str::from_utf8(&title_buf) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid utf8 {}", e),
+        let mbc = try!(Mbc::from_header(header.cartridge_type));
+        let rtc = match header.cartridge_type {
+            0x0F | 0x10 => Some(Rtc::new()),
+            _ => None,
+        };
+        let ram_sz = match header.ram_size {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SZ,
+            0x03 => RAM_BANK_SZ * 4,
+            0x04 => RAM_BANK_SZ * 16,
+            0x05 => RAM_BANK_SZ * 8,
+            inv => return Err(format!("Unknown RAM size: 0x{:02x}", inv)),
         };
 
         Ok(Cartridge {
-            title: title.trim_right_matches('\0').to_string(),
+            header: header,
             mbc: mbc,
+            rom_hash: hash(&buf),
             rom: buf,
             rom_bank: 1,
             ram: iter::repeat(0).take(ram_sz).collect(),
             ram_bank: 0,
             ram_enable: false,
             rom_mode_select: false,
+            rtc: rtc,
         })
     }
 
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn title(&self) -> &str {
+        &self.header.title
+    }
+
+    // The ROM bank mapped at 0x4000.
+    pub fn rom_bank(&self) -> u8 {
+        self.rom_bank
+    }
+
+    pub fn ram_bank(&self) -> u8 {
+        self.ram_bank
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // Whether the cartridge RAM is battery backed, and so should be saved.
+    pub fn has_battery(&self) -> bool {
+        match self.header.cartridge_type {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF => {
+                !self.ram.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    // Restore RAM from a save file.
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.ram.len() {
+            return Err(format!("Expected 0x{:x} bytes of RAM, got 0x{:x}",
+                               self.ram.len(),
+                               data.len()));
+        }
+        self.ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    // Bring the cartridge's clock, if it has one, up to now.
+    pub fn set_time(&mut self, now: Duration) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.set_time(now);
+        }
+    }
+
+    // A 64 bit FNV-1a hash of the whole ROM, used to tie save states to the
+    // ROM that they were made with. Cheats don't change it.
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    // Make reads of addr return value, as a Game Genie does. An address in
+    // the switchable bank is patched in every bank, and with compare only
+    // where the ROM holds that value. The bytes that were replaced are
+    // returned, to be put back by unpatch_rom.
+    pub fn patch_rom(&mut self, addr: u16, value: u8, compare: Option<u8>) -> Vec<(usize, u8)> {
+        let offset = addr as usize & (ROM_BANK_SZ - 1);
+        let banks = if (addr as usize) < ROM_BANK_SZ {
+            0..1
+        } else {
+            1..cmp::max(self.rom.len() / ROM_BANK_SZ, 2)
+        };
+
+        let mut originals = Vec::new();
+        for bank in banks {
+            let a = (bank * ROM_BANK_SZ + offset) & (self.rom.len() - 1);
+            let original = self.rom[a];
+            if compare.map_or(true, |c| c == original) {
+                originals.push((a, original));
+                self.rom[a] = value;
+            }
+        }
+        originals
+    }
+
+    pub fn unpatch_rom(&mut self, originals: &[(usize, u8)]) {
+        for &(a, original) in originals.iter().rev() {
+            self.rom[a] = original;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.ram_enable);
+        w.write_bool(self.rom_mode_select);
+        w.write_bytes(&self.ram);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.rom_bank = try!(r.read_u8());
+        self.ram_bank = try!(r.read_u8());
+        self.ram_enable = try!(r.read_bool());
+        self.rom_mode_select = try!(r.read_bool());
+        try!(r.read_bytes(&mut self.ram));
+        match self.rtc {
+            Some(ref mut rtc) => rtc.load_state(r),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
     fn open_rom(path: &path::PathBuf) -> Result<Vec<u8>, String> {
         let mut data = vec![];
         let mut file = try!(File::open(path).map_err(|e| format!("{}", e)));
@@ -80,7 +271,7 @@ impl Cartridge {
     pub fn read_rom(&self, addr: u16) -> u8 {
         let a = match self.mbc {
             Mbc::None => addr as usize,
-            Mbc::One => {
+            Mbc::One | Mbc::Three => {
                 let uaddr = addr as usize;
                 if uaddr < ROM_BANK_SZ {
                     uaddr
@@ -92,14 +283,24 @@ impl Cartridge {
         self.rom[a & (self.rom.len() - 1)]
     }
 
+    fn ram_offset(&self, addr: u16) -> usize {
+        let bank = if self.rom_mode_select {
+            0
+        } else {
+            self.ram_bank
+        };
+        // Carts with less than a bank of RAM mirror it across the bank.
+        ((addr as usize & (RAM_BANK_SZ - 1)) + (bank as usize * RAM_BANK_SZ)) % self.ram.len()
+    }
+
     pub fn read_ram(&self, addr: u16) -> u8 {
-        if self.ram_enable == true {
-            let bank = if self.rom_mode_select {
-                0
-            } else {
-                self.ram_bank
-            };
-            self.ram[((addr as usize & (RAM_BANK_SZ - 1)) + (bank as usize * RAM_BANK_SZ))]
+        if self.ram_bank >= 0x08 {
+            match (self.ram_enable, self.rtc.as_ref()) {
+                (true, Some(rtc)) => rtc.read(self.ram_bank),
+                _ => 0,
+            }
+        } else if self.ram_enable && !self.ram.is_empty() {
+            self.ram[self.ram_offset(addr)]
         } else {
             0 //TODO: Is this correct?
         }
@@ -110,7 +311,7 @@ impl Cartridge {
             Mbc::None => {}
             Mbc::One => {
                 match addr {
-                    0x0000...0x1FFF => self.ram_enable = (val == 0xA),
+                    0x0000...0x1FFF => self.ram_enable = val == 0xA,
                     0x2000...0x3FFF => {
                         // Clear the lower bits, but keep the upper rom bank bits.
                         self.rom_bank &= 0x60;
@@ -132,20 +333,125 @@ impl Cartridge {
                     _ => panic!("Can't write 0x{:02x} to 0x{:04x}", val, addr),
                 }
             }
+            Mbc::Three => {
+                match addr {
+                    0x0000...0x1FFF => self.ram_enable = val & 0x0F == 0xA,
+                    0x2000...0x3FFF => {
+                        self.rom_bank = if val & 0x7F == 0 {
+                            1
+                        } else {
+                            val & 0x7F
+                        }
+                    }
+                    // 0x00 - 0x03 select a RAM bank, 0x08 - 0x0C a clock
+                    // register.
+                    0x4000...0x5FFF => self.ram_bank = val & 0x0F,
+                    0x6000...0x7FFF => {
+                        if let Some(ref mut rtc) = self.rtc {
+                            rtc.write_latch(val);
+                        }
+                    }
+                    _ => panic!("Can't write 0x{:02x} to 0x{:04x}", val, addr),
+                }
+            }
         }
     }
 
-    pub fn write_ram(&self, addr: u16, val: u8) {}
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram_bank >= 0x08 {
+            if let (true, Some(rtc)) = (self.ram_enable, self.rtc.as_mut()) {
+                rtc.write(self.ram_bank, val);
+            }
+        } else if self.ram_enable && !self.ram.is_empty() {
+            let offset = self.ram_offset(addr);
+            self.ram[offset] = val;
+        }
+    }
 }
 
 impl fmt::Debug for Cartridge {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Cartridge")
-            .field("title", &self.title)
+            .field("header", &self.header)
             .field("mbc", &self.mbc)
             .field("ram_enable", &self.ram_enable)
             .field("ram_bank", &self.ram_bank)
             .field("rom_bank", &self.rom_bank)
+            .field("rtc", &self.rtc)
             .finish()
     }
 }
+
+fn hash(rom: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in rom.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+
+    use super::Cartridge;
+
+    // Collects warnings logged on each thread, so that tests running at the
+    // same time don't see each other's.
+    struct Capture;
+
+    thread_local!(static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+    impl log::Log for Capture {
+        fn enabled(&self, metadata: &LogMetadata) -> bool {
+            metadata.level() <= LogLevel::Warn
+        }
+
+        fn log(&self, record: &LogRecord) {
+            if record.level() == LogLevel::Warn {
+                WARNINGS.with(|w| w.borrow_mut().push(format!("{}", record.args())));
+            }
+        }
+    }
+
+    fn warnings<F: FnOnce()>(f: F) -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(|max| {
+                    max.set(LogLevelFilter::Warn);
+                    Box::new(Capture)
+                })
+                .unwrap();
+        });
+        WARNINGS.with(|w| w.borrow_mut().clear());
+        f();
+        WARNINGS.with(|w| w.borrow_mut().split_off(0))
+    }
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // The header checksum of an all zero header.
+        rom[0x14D] = 0xE7;
+        rom
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        assert!(warnings(|| {
+                Cartridge::from_bytes(rom()).unwrap();
+            })
+            .is_empty());
+
+        let mut bad = rom();
+        bad[0x14D] = 0xE8;
+        let logged = warnings(|| {
+            Cartridge::from_bytes(bad).unwrap();
+        });
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        assert!(logged[0].contains("checksum"), "{}", logged[0]);
+    }
+}
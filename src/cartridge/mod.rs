@@ -1,3 +1,4 @@
 mod cartridge;
+mod rtc;
 
-pub use self::cartridge::Cartridge;
+pub use self::cartridge::{Cartridge, CartridgeHeader};
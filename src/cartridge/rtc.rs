@@ -0,0 +1,215 @@
+// The real time clock on MBC3 cartridges. It counts seconds, minutes, hours
+// and up to 512 days, and the game reads a copy that it latches by writing 0
+// then 1 to 0x6000. The clock never reads the time itself - the GameBoy
+// passes in the current time from its TimeSource, so that the clock stays
+// deterministic unless the host clock was asked for.
+use std::time::Duration;
+
+use savestate::{StateWriter, StateReader};
+
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAYS_LO: usize = 3;
+const DAYS_HI: usize = 4;
+
+// Bits of DAYS_HI, the rest of which always read as 0.
+const DAY_BIT_8: u8 = 0x01;
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const MAX_DAYS: u64 = 512;
+
+#[derive(Debug)]
+pub struct Rtc {
+    regs: [u8; 5],
+    latched: [u8; 5],
+    // The time that the clock was last brought up to date with.
+    now: Duration,
+    // Nanoseconds towards the next second.
+    subsec: u32,
+    // The last value written to 0x6000 - 0x7FFF.
+    latch: u8,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            regs: [0; 5],
+            latched: [0; 5],
+            now: Duration::new(0, 0),
+            subsec: 0,
+            latch: 0xFF,
+        }
+    }
+
+    // Bring the clock up to now. Time going backwards, e.g. after loading a
+    // save state or changing the TimeSource, doesn't change the clock.
+    pub fn set_time(&mut self, now: Duration) {
+        if now > self.now && self.regs[DAYS_HI] & HALT == 0 {
+            let elapsed = now - self.now;
+            let nanos = self.subsec as u64 + elapsed.subsec_nanos() as u64;
+            self.subsec = (nanos % 1_000_000_000) as u32;
+            self.tick(elapsed.as_secs() + nanos / 1_000_000_000);
+        }
+        self.now = now;
+    }
+
+    fn tick(&mut self, secs: u64) {
+        if secs == 0 {
+            return;
+        }
+        let days = (self.regs[DAYS_HI] as u64 & DAY_BIT_8 as u64) << 8 |
+                   self.regs[DAYS_LO] as u64;
+        let total = ((days * 24 + (self.regs[HOURS] & 0x1F) as u64) * 60 +
+                     (self.regs[MINUTES] & 0x3F) as u64) * 60 +
+                    (self.regs[SECONDS] & 0x3F) as u64 + secs;
+
+        let mut days = total / SECS_PER_DAY;
+        let mut hi = self.regs[DAYS_HI] & (HALT | DAY_CARRY);
+        if days >= MAX_DAYS {
+            hi |= DAY_CARRY;
+            days %= MAX_DAYS;
+        }
+        self.regs[SECONDS] = (total % 60) as u8;
+        self.regs[MINUTES] = (total / 60 % 60) as u8;
+        self.regs[HOURS] = (total / 3600 % 24) as u8;
+        self.regs[DAYS_LO] = days as u8;
+        self.regs[DAYS_HI] = hi | (days >> 8) as u8;
+    }
+
+    // A write to 0x6000 - 0x7FFF. Writing 0 then 1 copies the clock into the
+    // registers that the game reads.
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch == 0 && val == 1 {
+            self.latched = self.regs;
+        }
+        self.latch = val;
+    }
+
+    // Reads see the latched copy. reg is 0x08 - 0x0C, as written to
+    // 0x4000 - 0x5FFF.
+    pub fn read(&self, reg: u8) -> u8 {
+        match self.latched.get(reg.wrapping_sub(0x08) as usize) {
+            Some(&v) => v,
+            None => 0xFF,
+        }
+    }
+
+    // Writes go straight to the clock. Writing the seconds restarts the
+    // current second.
+    pub fn write(&mut self, reg: u8, val: u8) {
+        let i = reg.wrapping_sub(0x08) as usize;
+        match i {
+            SECONDS => {
+                self.subsec = 0;
+                self.regs[i] = val & 0x3F;
+            }
+            MINUTES => self.regs[i] = val & 0x3F,
+            HOURS => self.regs[i] = val & 0x1F,
+            DAYS_LO => self.regs[i] = val,
+            DAYS_HI => self.regs[i] = val & (DAY_BIT_8 | HALT | DAY_CARRY),
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.regs);
+        w.write_bytes(&self.latched);
+        w.write_u64(self.now.as_secs());
+        w.write_u32(self.now.subsec_nanos());
+        w.write_u32(self.subsec);
+        w.write_u8(self.latch);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        try!(r.read_bytes(&mut self.regs));
+        try!(r.read_bytes(&mut self.latched));
+        let secs = try!(r.read_u64());
+        let nanos = try!(r.read_u32());
+        if nanos >= 1_000_000_000 {
+            return Err(format!("Invalid RTC time: {}ns", nanos));
+        }
+        self.now = Duration::new(secs, nanos);
+        self.subsec = try!(r.read_u32());
+        if self.subsec >= 1_000_000_000 {
+            return Err(format!("Invalid RTC time: {}ns", self.subsec));
+        }
+        self.latch = try!(r.read_u8());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Rtc;
+
+    fn latch(rtc: &mut Rtc) -> [u8; 5] {
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        [rtc.read(0x08), rtc.read(0x09), rtc.read(0x0A), rtc.read(0x0B), rtc.read(0x0C)]
+    }
+
+    #[test]
+    fn counts() {
+        let mut rtc = Rtc::new();
+        rtc.set_time(Duration::new(59, 999_999_999));
+        assert_eq!(latch(&mut rtc), [59, 0, 0, 0, 0]);
+        rtc.set_time(Duration::from_secs(60));
+        assert_eq!(latch(&mut rtc), [0, 1, 0, 0, 0]);
+        rtc.set_time(Duration::from_secs(300 * 86400 + 23 * 3600 + 60));
+        assert_eq!(latch(&mut rtc), [0, 1, 23, 44, 0x01]);
+    }
+
+    #[test]
+    fn reads_are_latched() {
+        let mut rtc = Rtc::new();
+        rtc.set_time(Duration::from_secs(5));
+        assert_eq!(latch(&mut rtc)[0], 5);
+        rtc.set_time(Duration::from_secs(10));
+        assert_eq!(rtc.read(0x08), 5);
+        // Only a write of 0 then 1 latches.
+        rtc.write_latch(1);
+        assert_eq!(rtc.read(0x08), 5);
+        assert_eq!(latch(&mut rtc)[0], 10);
+    }
+
+    #[test]
+    fn halt() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0C, 0x40);
+        rtc.set_time(Duration::from_secs(100));
+        assert_eq!(latch(&mut rtc), [0, 0, 0, 0, 0x40]);
+        rtc.write(0x0C, 0x00);
+        rtc.set_time(Duration::from_secs(101));
+        assert_eq!(latch(&mut rtc), [1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn day_carry() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.write(0x0A, 23);
+        rtc.write(0x09, 59);
+        rtc.write(0x08, 59);
+        rtc.set_time(Duration::from_secs(1));
+        assert_eq!(latch(&mut rtc), [0, 0, 0, 0, 0x80]);
+        // The carry stays set until it's cleared.
+        rtc.set_time(Duration::from_secs(86401));
+        assert_eq!(latch(&mut rtc), [0, 0, 0, 1, 0x80]);
+    }
+
+    #[test]
+    fn time_going_backwards() {
+        let mut rtc = Rtc::new();
+        rtc.set_time(Duration::from_secs(10));
+        rtc.set_time(Duration::from_secs(3));
+        assert_eq!(latch(&mut rtc)[0], 10);
+        rtc.set_time(Duration::from_secs(4));
+        assert_eq!(latch(&mut rtc)[0], 11);
+    }
+}
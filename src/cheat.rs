@@ -0,0 +1,212 @@
+// Cheat codes, and the files that collect them. A cheat file has one cheat
+// per line:
+//
+// name = code[,code...]
+//
+// where # starts a comment. Game Genie codes (ABC-DEF or ABC-DEF-GHI) patch
+// the ROM, and GameShark codes (01VVLLHH) write a byte to RAM every frame.
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Read;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    // Reads of addr return value instead, but only if the ROM held compare
+    // there when there is one.
+    GameGenie {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    // value is written to addr at the start of every frame.
+    GameShark { addr: u16, value: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub name: String,
+    pub codes: Vec<Code>,
+    pub enabled: bool,
+}
+
+impl Code {
+    pub fn parse(code: &str) -> Result<Code, String> {
+        if code.is_empty() {
+            return Err("Missing code".to_string());
+        }
+        let digits: Vec<u8> = try!(code.chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| format!("Invalid character in code \"{}\"", code)));
+
+        if code.contains('-') {
+            Code::parse_game_genie(code, &digits)
+        } else if digits.len() == 8 {
+            Code::parse_game_shark(code, &digits)
+        } else {
+            Err(format!("Unrecognised code \"{}\"", code))
+        }
+    }
+
+    // ABC-DEF-GHI: AB is the new value, FCDE the address xored with 0xF000
+    // and GI the compare value, scrambled. H is a check digit and ignored.
+    fn parse_game_genie(code: &str, d: &[u8]) -> Result<Code, String> {
+        let groups: Vec<&str> = code.split('-').collect();
+        if groups.len() > 3 || groups.iter().any(|g| g.len() != 3) {
+            return Err(format!("Game Genie codes look like ABC-DEF or ABC-DEF-GHI, got \"{}\"",
+                               code));
+        }
+
+        let value = d[0] << 4 | d[1];
+        let addr = ((d[5] as u16) << 12 | (d[2] as u16) << 8 | (d[3] as u16) << 4 |
+                    d[4] as u16) ^ 0xF000;
+        if addr >= 0x8000 {
+            return Err(format!("Game Genie code \"{}\" patches 0x{:04x}, outside of ROM",
+                               code,
+                               addr));
+        }
+        let compare = if d.len() == 9 {
+            Some((d[6] << 4 | d[8]).rotate_right(2) ^ 0xBA)
+        } else {
+            None
+        };
+        Ok(Code::GameGenie {
+            addr: addr,
+            value: value,
+            compare: compare,
+        })
+    }
+
+    // TTVVLLHH: type TT, value VV and the address in little endian.
+    fn parse_game_shark(code: &str, d: &[u8]) -> Result<Code, String> {
+        let byte = |i: usize| d[i] << 4 | d[i + 1];
+        if byte(0) != 0x01 {
+            return Err(format!("Unsupported GameShark code type 0x{:02x} in \"{}\"",
+                               byte(0),
+                               code));
+        }
+        let addr = (byte(6) as u16) << 8 | byte(4) as u16;
+        match addr {
+            0xA000...0xDFFF | 0xFF80...0xFFFE => {}
+            _ => {
+                return Err(format!("GameShark code \"{}\" writes 0x{:04x}, outside of RAM",
+                                   code,
+                                   addr))
+            }
+        }
+        Ok(Code::GameShark {
+            addr: addr,
+            value: byte(2),
+        })
+    }
+}
+
+// Parse a cheat file. Cheats start out enabled.
+pub fn parse(text: &str) -> Result<Vec<Cheat>, String> {
+    let mut cheats = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let codes = match parts.next() {
+            Some(c) => c,
+            None => return Err(format!("Line {}: expected name = code[,code...]", i + 1)),
+        };
+        if name.is_empty() {
+            return Err(format!("Line {}: cheat has no name", i + 1));
+        }
+
+        let codes = try!(codes.split(',')
+            .map(|c| Code::parse(c.trim()))
+            .collect::<Result<Vec<Code>, String>>()
+            .map_err(|e| format!("Line {}: {}", i + 1, e)));
+        cheats.push(Cheat {
+            name: name.to_string(),
+            codes: codes,
+            enabled: true,
+        });
+    }
+    Ok(cheats)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load(path: &Path) -> Result<Vec<Cheat>, String> {
+    let mut text = String::new();
+    let mut file = try!(File::open(path).map_err(|e| format!("{}", e)));
+    try!(file.read_to_string(&mut text).map_err(|e| format!("{}", e)));
+    parse(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Code, parse};
+
+    #[test]
+    fn codes() {
+        assert_eq!(Code::parse("3E1-50F"),
+                   Ok(Code::GameGenie {
+                       addr: 0x0150,
+                       value: 0x3E,
+                       compare: None,
+                   }));
+        assert_eq!(Code::parse("3e1-50f-e0a"),
+                   Ok(Code::GameGenie {
+                       addr: 0x0150,
+                       value: 0x3E,
+                       compare: Some(0x00),
+                   }));
+        assert_eq!(Code::parse("010500C0"),
+                   Ok(Code::GameShark {
+                       addr: 0xC000,
+                       value: 0x05,
+                   }));
+    }
+
+    #[test]
+    fn bad_codes() {
+        for code in ["", "3E1-50G", "3E1-50", "3E1-50F-E0A-000", "3E1-507", "020500C0",
+                     "01050080", "010500"]
+            .iter() {
+            assert!(Code::parse(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn file() {
+        let cheats = parse("# Infinite everything\n\
+                            \n\
+                            Lives = 3E1-50F  # Never runs out\n\
+                            Both=3E1-50F-E0A, 010500C0\n")
+            .unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats[0].name, "Lives");
+        assert_eq!(cheats[0].codes.len(), 1);
+        assert_eq!(cheats[1].name, "Both");
+        assert_eq!(cheats[1].codes[1],
+                   Code::GameShark {
+                       addr: 0xC000,
+                       value: 0x05,
+                   });
+        assert!(cheats.iter().all(|c| c.enabled));
+    }
+
+    // Errors name the line that they're on, counting comments and blanks.
+    #[test]
+    fn file_errors() {
+        let cases = [("Lives 3E1-50F", "Line 1:"),
+                     ("# Comment\n\n = 3E1-50F", "Line 3:"),
+                     ("Lives = 3E1-50F\nMoney = 3E1-50F, 99", "Line 2:"),
+                     ("Lives = 3E1-50F,", "Line 1:")];
+        for &(text, line) in cases.iter() {
+            let e = parse(text).unwrap_err();
+            assert!(e.starts_with(line), "{:?} gave {}", text, e);
+        }
+    }
+}
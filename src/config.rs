@@ -0,0 +1,426 @@
+// Persistent defaults for the desktop frontend, read from a small subset of
+// TOML: comments, [sections] and key = value pairs, where a value is either a
+// "string" or an integer. Command line flags override whatever is
+// in the file, which overrides the built in defaults.
+//
+// Unknown keys and sections are warned about rather than rejected, so that a
+// config file written by a newer iogb still works with an older one.
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "gamepad")]
+use input::{PadBindings, pad_button_from_name, pad_button_name};
+use input::{self, KeyBindings, key_from_name, key_name};
+
+use iogb::gameboy::Button;
+
+use args::Args;
+
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub scale: u32,
+    // A preset name or a custom RRGGBB,RRGGBB,RRGGBB,RRGGBB palette.
+    pub palette: Option<String>,
+    pub save_dir: Option<PathBuf>,
+    pub bootrom: Option<PathBuf>,
+    // How many times faster than normal fast-forward may run, or None for
+    // as fast as possible.
+    pub fast_forward_cap: Option<u32>,
+    pub turbo_rate: u32,
+    pub keys: KeyBindings,
+    #[cfg(feature = "gamepad")]
+    pub pad: PadBindings,
+}
+
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Int(i64),
+}
+
+impl Default for AppConfig {
+    fn default() -> AppConfig {
+        AppConfig {
+            scale: 1,
+            palette: None,
+            save_dir: None,
+            bootrom: None,
+            fast_forward_cap: None,
+            turbo_rate: input::DEFAULT_TURBO_RATE,
+            keys: KeyBindings::default(),
+            #[cfg(feature = "gamepad")]
+            pad: PadBindings::default(),
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/iogb/config.toml, falling back to ~/.config.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".config"),
+                None => return None,
+            }
+        }
+    };
+    Some(dir.join("iogb").join("config.toml"))
+}
+
+impl AppConfig {
+    // Read the config file at path. A missing file just means the defaults.
+    pub fn load(path: &Path, warnings: &mut Vec<String>) -> Result<AppConfig, String> {
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut f) => try!(f.read_to_string(&mut text).map_err(|e| format!("{}", e))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(AppConfig::default()),
+            Err(e) => return Err(format!("{}", e)),
+        };
+        AppConfig::parse(&text, warnings)
+    }
+
+    pub fn parse(text: &str, warnings: &mut Vec<String>) -> Result<AppConfig, String> {
+        let mut config = AppConfig::default();
+        let mut section = String::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(format!("line {}: unterminated section header", i + 1));
+                }
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+
+            let eq = match line.find('=') {
+                Some(eq) => eq,
+                None => return Err(format!("line {}: expected key = value", i + 1)),
+            };
+            let key = line[..eq].trim();
+            let value = try!(parse_value(line[eq + 1..].trim())
+                .map_err(|e| format!("line {}: {}", i + 1, e)));
+
+            match config.set(&section, key, value) {
+                Ok(true) => {}
+                Ok(false) => {
+                    let name = if section.is_empty() {
+                        key.to_string()
+                    } else {
+                        format!("{}.{}", section, key)
+                    };
+                    warnings.push(format!("line {}: unknown setting {}", i + 1, name));
+                }
+                Err(e) => return Err(format!("line {}: {}: {}", i + 1, key, e)),
+            }
+        }
+        Ok(config)
+    }
+
+    // Returns false for settings that we don't know about.
+    fn set(&mut self, section: &str, key: &str, value: Value) -> Result<bool, String> {
+        match (section, key) {
+            ("", "scale") => self.scale = try!(int(value)),
+            ("", "palette") => self.palette = Some(try!(string(value))),
+            ("", "save_dir") => self.save_dir = Some(PathBuf::from(try!(string(value)))),
+            ("", "bootrom") => self.bootrom = Some(PathBuf::from(try!(string(value)))),
+            ("", "fast_forward_speed") => {
+                self.fast_forward_cap = match try!(int(value)) {
+                    0 => None,
+                    n => Some(n),
+                }
+            }
+            ("", "turbo_rate") => self.turbo_rate = try!(int(value)),
+            ("keys", button) | ("turbo", button) => {
+                let button = match input::button_from_name(button) {
+                    Some(b) => b,
+                    None => return Ok(false),
+                };
+                let name = try!(string(value));
+                let key = match key_from_name(&name) {
+                    Some(k) => k,
+                    None => return Err(format!("unknown key {}", name)),
+                };
+                if section == "keys" {
+                    self.keys.bind(key, button);
+                } else {
+                    self.keys.bind_turbo(key, button);
+                }
+            }
+            #[cfg(feature = "gamepad")]
+            ("gamepad", button) => {
+                let button = match input::button_from_name(button) {
+                    Some(b) => b,
+                    None => return Ok(false),
+                };
+                let name = try!(string(value));
+                let pad_button = match pad_button_from_name(&name) {
+                    Some(b) => b,
+                    None => return Err(format!("unknown gamepad button {}", name)),
+                };
+                self.pad.bind(pad_button, button);
+            }
+            // Pad bindings are kept in the file even when iogb was built
+            // without gamepad support.
+            #[cfg(not(feature = "gamepad"))]
+            ("gamepad", _) => {}
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Let anything given on the command line take precedence.
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Some(scale) = args.scale {
+            self.scale = scale;
+        }
+        if let Some(palette) = args.palette_custom.as_ref().or(args.palette.as_ref()) {
+            self.palette = Some(palette.clone());
+        }
+        if let Some(ref dir) = args.save_dir {
+            self.save_dir = Some(dir.clone());
+        }
+        if let Some(ref bootrom) = args.bootrom {
+            self.bootrom = Some(bootrom.clone());
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut out = String::from("# iogb configuration. Command line flags override these.\n");
+        out.push_str(&format!("scale = {}\n", self.scale));
+        if let Some(ref palette) = self.palette {
+            out.push_str(&format!("palette = {}\n", quote(palette)));
+        }
+        if let Some(ref dir) = self.save_dir {
+            out.push_str(&format!("save_dir = {}\n", quote(&dir.to_string_lossy())));
+        }
+        if let Some(ref bootrom) = self.bootrom {
+            out.push_str(&format!("bootrom = {}\n", quote(&bootrom.to_string_lossy())));
+        }
+        out.push_str("# 0 means as fast as possible.\n");
+        out.push_str(&format!("fast_forward_speed = {}\n", self.fast_forward_cap.unwrap_or(0)));
+        out.push_str(&format!("turbo_rate = {}\n", self.turbo_rate));
+
+        out.push_str("\n[keys]\n");
+        push_bindings(&mut out, self.keys.bindings(), key_name);
+        out.push_str("\n[turbo]\n");
+        push_bindings(&mut out, self.keys.turbo_bindings(), key_name);
+        #[cfg(feature = "gamepad")]
+        {
+            out.push_str("\n[gamepad]\n");
+            push_bindings(&mut out, self.pad.bindings(), pad_button_name);
+        }
+        out
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir).map_err(|e| format!("{}", e)));
+        }
+        let mut file = try!(File::create(path).map_err(|e| format!("{}", e)));
+        file.write_all(self.to_toml().as_bytes()).map_err(|e| format!("{}", e))
+    }
+}
+
+// One line per binding, in the same order every time.
+fn push_bindings<K: Copy>(out: &mut String,
+                          bindings: &[(K, Button)],
+                          name: fn(K) -> Option<&'static str>) {
+    for &button in input::BUTTONS.iter() {
+        for &(k, _) in bindings.iter().filter(|&&(_, b)| b == button) {
+            if let Some(name) = name(k) {
+                out.push_str(&format!("{} = {}\n", input::button_name(button), quote(name)));
+            }
+        }
+    }
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    if let Some(quoted) = text.strip_prefix('"') {
+        let mut s = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => {
+                    match chars.next() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c) => return Err(format!("unknown escape \\{}", c)),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        let rest = chars.as_str().trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(format!("unexpected {} after string", rest));
+        }
+        return Ok(Value::Str(s));
+    }
+
+    let text = match text.find('#') {
+        Some(hash) => text[..hash].trim(),
+        None => text,
+    };
+    text.parse::<i64>()
+        .map(Value::Int)
+        .map_err(|_| format!("invalid value {}", text))
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::Str(s) => Ok(s),
+        v => Err(format!("expected a string, got {:?}", v)),
+    }
+}
+
+fn int(value: Value) -> Result<u32, String> {
+    match value {
+        Value::Int(n) if n >= 0 && n <= u32::MAX as i64 => Ok(n as u32),
+        v => Err(format!("expected a positive integer, got {:?}", v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    use minifb::Key;
+
+    use iogb::gameboy::Button;
+
+    use args;
+    use super::AppConfig;
+
+    fn parse(text: &str) -> (AppConfig, Vec<String>) {
+        let mut warnings = Vec::new();
+        let config = AppConfig::parse(text, &mut warnings).unwrap();
+        (config, warnings)
+    }
+
+    fn cli(words: &[&str]) -> args::Args {
+        let mut argv = vec!["iogb".to_string(), "game.gb".to_string()];
+        argv.extend(words.iter().map(|w| w.to_string()));
+        args::parse(argv).ok().unwrap()
+    }
+
+    // Built in defaults, then the file, then the command line.
+    #[test]
+    fn precedence() {
+        let (mut config, warnings) = parse("# Mine\n\
+                                            scale = 3\n\
+                                            palette = \"pocket\"\n\
+                                            save_dir = \"/saves\"\n\
+                                            fast_forward_speed = 4 # at most\n");
+        assert!(warnings.is_empty());
+        assert_eq!(config.scale, 3);
+        assert_eq!(config.palette, Some("pocket".to_string()));
+        assert_eq!(config.save_dir, Some(PathBuf::from("/saves")));
+        assert_eq!(config.fast_forward_cap, Some(4));
+        // Not in the file, so still the default.
+        assert_eq!(config.bootrom, None);
+        assert_eq!(config.turbo_rate, AppConfig::default().turbo_rate);
+
+        // Flags that weren't given leave the file's values alone.
+        config.apply_args(&cli(&[]));
+        assert_eq!(config.scale, 3);
+        assert_eq!(config.palette, Some("pocket".to_string()));
+
+        config.apply_args(&cli(&["--scale",
+                                 "5",
+                                 "--save-dir",
+                                 "/elsewhere",
+                                 "--bootrom",
+                                 "/boot.bin",
+                                 "--palette",
+                                 "grey"]));
+        assert_eq!(config.scale, 5);
+        assert_eq!(config.palette, Some("grey".to_string()));
+        assert_eq!(config.save_dir, Some(PathBuf::from("/elsewhere")));
+        assert_eq!(config.bootrom, Some(PathBuf::from("/boot.bin")));
+        assert_eq!(config.fast_forward_cap, Some(4));
+
+        // A custom palette wins over a preset.
+        let custom = "FFFFFF,AAAAAA,555555,000000";
+        config.apply_args(&cli(&["--palette", "grey", "--palette-custom", custom]));
+        assert_eq!(config.palette, Some(custom.to_string()));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let (config, warnings) = parse("scale = 2\n\
+                                        shader = \"crt\"\n\
+                                        [keys]\n\
+                                        a = \"J\"\n\
+                                        turbo = \"K\"\n\
+                                        [network]\n\
+                                        port = 8765\n");
+        assert_eq!(config.scale, 2);
+        assert!(config.keys.bindings().contains(&(Key::J, Button::A)));
+        assert_eq!(warnings,
+                   ["line 2: unknown setting shader",
+                    "line 5: unknown setting keys.turbo",
+                    "line 7: unknown setting network.port"]);
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [("scale 2", "line 1: expected key = value"),
+                     ("[keys", "line 1: unterminated section header"),
+                     ("\nscale = \"big\"", "line 2: scale: expected a positive integer"),
+                     ("scale = -1", "line 1: scale: expected a positive integer"),
+                     ("palette = 3", "line 1: palette: expected a string"),
+                     ("palette = \"grey", "line 1: unterminated string"),
+                     ("palette = \"grey\" x", "line 1: unexpected x after string"),
+                     ("scale = 2x", "line 1: invalid value 2x"),
+                     ("[keys]\na = \"Hyper\"", "line 2: a: unknown key Hyper")];
+        for &(text, error) in cases.iter() {
+            let e = AppConfig::parse(text, &mut Vec::new()).err().unwrap();
+            assert!(e.starts_with(error), "{:?}: {}", text, e);
+        }
+    }
+
+    // What --write-config writes reads back the same.
+    #[test]
+    fn round_trip() {
+        let (mut config, _) = parse("[turbo]\nb = \"D\"\n");
+        config.scale = 4;
+        config.palette = Some("\"quoted\" \\ palette".to_string());
+        config.bootrom = Some(PathBuf::from("/boot/dmg.bin"));
+        config.keys.bind(Key::Space, Button::Start);
+
+        let (read, warnings) = parse(&config.to_toml());
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(read.to_toml(), config.to_toml());
+        assert_eq!(read.palette, config.palette);
+        assert_eq!(read.fast_forward_cap, None);
+        assert!(read.keys.turbo_bindings().contains(&(Key::D, Button::B)));
+        assert!(read.keys.bindings().contains(&(Key::Space, Button::Start)));
+        assert!(!read.keys.bindings().contains(&(Key::Enter, Button::Start)));
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = env::temp_dir().join(format!("iogb-no-config-{}.toml", process::id()));
+        let config = AppConfig::load(&path, &mut Vec::new()).unwrap();
+        assert_eq!(config.scale, AppConfig::default().scale);
+    }
+}
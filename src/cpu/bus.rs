@@ -0,0 +1,101 @@
+// What the CPU sees of the rest of the system: memory, the interrupt
+// controller and the passing of time. On a GameBoy that's the Interconnect,
+// but anything can stand in for it, e.g. FlatBus for trying out instructions
+// without a cartridge.
+use std::fmt;
+
+use interrupt::InterruptController;
+
+pub trait Bus: fmt::Debug + 'static {
+    // An access by the CPU, which may have side effects.
+    fn readb(&mut self, addr: u16) -> u8;
+    fn writeb(&mut self, addr: u16, val: u8);
+
+    // A read for debugging, which mustn't disturb anything.
+    fn peek(&self, addr: u16) -> u8;
+
+    // Run the rest of the system for ticks cycles, returning how many were
+    // run.
+    fn step(&mut self, ticks: u32) -> u32 {
+        ticks
+    }
+
+    // How many cycles can be stepped in one go, with the same result as
+    // stepping them a few at a time, before anything happens that the CPU
+    // could notice, such as an interrupt being requested.
+    fn quiet_cycles(&self) -> u32 {
+        0
+    }
+
+    fn ic(&self) -> &InterruptController;
+    fn ic_mut(&mut self) -> &mut InterruptController;
+}
+
+// 64KB of RAM and nothing else, apart from IF and IE which are the interrupt
+// controller's.
+pub struct FlatBus {
+    pub mem: Vec<u8>,
+    ic: InterruptController,
+}
+
+impl fmt::Debug for FlatBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FlatBus")
+            .field("ic", &self.ic)
+            .finish()
+    }
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus {
+            mem: vec![0; 0x10000],
+            ic: InterruptController::new(),
+        }
+    }
+
+    // A bus with program loaded at addr.
+    pub fn with_program(addr: u16, program: &[u8]) -> FlatBus {
+        let mut bus = FlatBus::new();
+        for (i, &byte) in program.iter().enumerate() {
+            bus.mem[addr.wrapping_add(i as u16) as usize] = byte;
+        }
+        bus
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> FlatBus {
+        FlatBus::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn readb(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn writeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF0F => self.ic.write_if(val),
+            0xFFFF => self.ic.write_ie(val),
+            _ => self.mem[addr as usize] = val,
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF0F => self.ic.read_if(),
+            0xFFFF => self.ic.read_ie(),
+            _ => self.mem[addr as usize],
+        }
+    }
+
+    fn ic(&self) -> &InterruptController {
+        &self.ic
+    }
+
+    fn ic_mut(&mut self) -> &mut InterruptController {
+        &mut self.ic
+    }
+}
@@ -1,14 +1,27 @@
+use savestate::{StateWriter, StateReader};
+
+// Every cycle the CPU has run since power on. A u64 lasts for over a hundred
+// thousand years of emulated time.
 #[derive(Debug, Default)]
 pub struct Clock {
-    cycles: u32,
+    cycles: u64,
 }
 
 impl Clock {
-    pub fn tick(&mut self) {
-        self.cycles += 4;
+    pub fn add_cycles(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
     }
 
-    pub fn add_cycles(&mut self, cycles: u32) {
-        self.cycles += cycles;
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.cycles = try!(r.read_u64());
+        Ok(())
     }
 }
@@ -1,20 +1,180 @@
+use std::cmp;
+use std::collections::HashSet;
 use std::fmt;
-use interconnect;
+use std::mem;
+use interconnect::Interconnect;
+use model::HardwareModel;
+use profile::{Profiler, ProfileReport};
+use savestate::{StateWriter, StateReader};
+use trace::{History, Tracer, TraceEntry};
+use super::bus::Bus;
 use super::clk;
+use super::disasm;
 
+// The most calls that are remembered for backtraces. Deeper ones lose their
+// outermost calls.
+const CALL_STACK_DEPTH: usize = 64;
+// The most an idle CPU lets pass in one step, a scanline, so that a run never
+// overshoots its timeslice by enough to delay input.
+const MAX_IDLE_CYCLES: u32 = 456;
+
+// The CPU, and the bus that it's connected to. On a GameBoy that's the
+// Interconnect, with everything else in the system behind it.
 #[derive(Debug)]
-pub struct Cpu {
+pub struct Cpu<B = Interconnect> {
     clk: clk::Clock,
     regs: Registers,
-    pub interconnect: interconnect::Interconnect,
+    tracer: Option<Tracer>,
+    // The last few instructions, if they're being kept.
+    history: Option<History>,
+    profiler: Option<Profiler>,
+    // Set when the CPU has locked up, or hit something that the emulator
+    // can't do, after which it executes nothing.
+    fault: Option<Fault>,
+    // Set by HALT until an interrupt is pending.
+    halted: bool,
+    // Calls that haven't returned yet, innermost last, so that crashes can
+    // show how they were reached rather than guessing from the stack.
+    calls: Vec<CallFrame>,
+    breakpoints: HashSet<u16>,
+    // Set after stopping at a breakpoint, so that the next step executes the
+    // instruction there rather than stopping again.
+    resuming: bool,
+    // How much of the current instruction the rest of the system has already
+    // been stepped through, a machine cycle per memory access.
+    stepped: u32,
+    pub interconnect: B,
 }
 
-#[derive(Debug)]
-pub enum Flags {
-    C = 0x10,
-    H = 0x20,
-    N = 0x40,
-    Z = 0x80,
+// Something that stopped the CPU for good, as it would on hardware.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    // One of the opcodes that the CPU doesn't define, and the address that it
+    // was fetched from.
+    IllegalOpcode { opcode: u8, addr: u16 },
+    // An instruction that hasn't been written yet. Unlike the others this
+    // is the emulator's fault, not the game's.
+    Unimplemented { opcode: u8, addr: u16 },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::IllegalOpcode { opcode, addr } => {
+                write!(f, "Illegal opcode 0x{:02x} at 0x{:04x}", opcode, addr)
+            }
+            Fault::Unimplemented { opcode, addr } => {
+                write!(f, "Unimplemented opcode 0x{:02x} at 0x{:04x}", opcode, addr)
+            }
+        }
+    }
+}
+
+// A call that hasn't returned yet, made by CALL, RST or an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    pub target: u16,
+    pub ret: u16,
+    // SP once the return address was pushed, which is where RET pops it
+    // from.
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+// Why a run stopped early. Apart from faults, step stops without executing
+// anything.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    // A Mooneye test ROM has reached the LD B,B that ends it. Only with the
+    // mooneye feature.
+    TestFinished { passed: bool },
+    // The instruction that was just executed faulted.
+    Fault(Fault),
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BreakReason::Breakpoint(addr) => write!(f, "Breakpoint at 0x{:04x}", addr),
+            BreakReason::TestFinished { passed: true } => write!(f, "Test passed"),
+            BreakReason::TestFinished { passed: false } => write!(f, "Test failed"),
+            BreakReason::Fault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+// The registers after an instruction, along with the instruction itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    // The instruction's bytes, of which the first len are used. A CB
+    // instruction is two bytes, with the prefix first.
+    pub opcode: [u8; 3],
+    pub len: u8,
+    pub cycles: u32,
+}
+
+impl CpuState {
+    pub fn z(&self) -> bool {
+        Flags::from(self.f).contains(FLAG_Z)
+    }
+
+    pub fn n(&self) -> bool {
+        Flags::from(self.f).contains(FLAG_N)
+    }
+
+    pub fn h(&self) -> bool {
+        Flags::from(self.f).contains(FLAG_H)
+    }
+
+    pub fn c(&self) -> bool {
+        Flags::from(self.f).contains(FLAG_C)
+    }
+
+    // The bytes of the instruction that was executed.
+    pub fn opcode_bytes(&self) -> &[u8] {
+        &self.opcode[..self.len as usize]
+    }
+}
+
+// The bits of F, for check_flag and set_flag. The low nibble doesn't exist,
+// so anything written there is dropped.
+bitflags!(
+    pub flags Flags: u8 {
+        const FLAG_Z = 1 << 7,
+        const FLAG_N = 1 << 6,
+        const FLAG_H = 1 << 5,
+        const FLAG_C = 1 << 4,
+    }
+);
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags::empty()
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(val: u8) -> Flags {
+        Flags::from_bits_truncate(val)
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
+        flags.bits()
+    }
 }
 
 pub enum Condition {
@@ -31,15 +191,16 @@ pub enum RotateDir {
 }
 
 impl Condition {
-    // TODO: We don't want to take a reference to the whole CPU just to get to
-    // the flags...
-    pub fn test(&self, cpu: &Cpu) -> bool {
+    // Whether the condition holds for the flags in F.
+    pub fn test(&self, flags: Flags) -> bool {
         use self::Condition::*;
+        let z = flags.contains(FLAG_Z);
+        let c = flags.contains(FLAG_C);
         match *self {
-            NZ => !cpu.check_flag(self::Flags::Z),
-            Z => cpu.check_flag(self::Flags::Z),
-            NC => !cpu.check_flag(self::Flags::C),
-            C => cpu.check_flag(self::Flags::C),
+            NZ => !z,
+            Z => z,
+            NC => !c,
+            C => c,
         }
     }
 }
@@ -48,9 +209,10 @@ pub struct ImmediateB;
 pub struct ImmediateW;
 pub struct AddressW;
 
+// The 8 bit registers, for read_reg_b and write_reg_b. F is only reachable
+// through AF or the flag methods.
 #[derive(Debug, Copy, Clone)]
 pub enum RegsB {
-    // 8 bit
     A,
     B,
     C,
@@ -60,6 +222,8 @@ pub enum RegsB {
     L,
 }
 
+// The 16 bit registers, for read_reg_w and write_reg_w. The pairs are made
+// up of the 8 bit registers, high byte first.
 #[derive(Debug, Copy, Clone)]
 pub enum RegsW {
     PC,
@@ -93,7 +257,7 @@ struct Registers {
     c: u8,
     d: u8,
     e: u8,
-    f: u8,
+    f: Flags,
     h: u8,
     l: u8,
     pc: u16,
@@ -108,7 +272,7 @@ impl fmt::Debug for Registers {
             .field("C", &format_args!("0x{:02x}", self.c))
             .field("D", &format_args!("0x{:02x}", self.d))
             .field("E", &format_args!("0x{:02x}", self.e))
-            .field("F", &format_args!("0x{:02x}", self.f))
+            .field("F", &format_args!("0x{:02x}", self.f.bits()))
             .field("H", &format_args!("0x{:02x}", self.h))
             .field("L", &format_args!("0x{:02x}", self.l))
             .field("PC", &format_args!("0x{:04x}", self.pc))
@@ -117,62 +281,108 @@ impl fmt::Debug for Registers {
     }
 }
 
+// Operands also report how many cycles accessing them adds to an
+// instruction, on top of the 4 it takes to fetch the opcode.
 trait ReadB {
     // TODO: Having &mut here is ugly
-    fn readb(&self, cpu: &mut Cpu) -> u8;
+    fn readb<B: Bus>(&self, cpu: &mut Cpu<B>) -> u8;
+    fn read_cycles(&self) -> u32;
 }
 
 trait WriteB {
-    fn writeb(&self, cpu: &mut Cpu, val: u8);
+    fn writeb<B: Bus>(&self, cpu: &mut Cpu<B>, val: u8);
+    fn write_cycles(&self) -> u32;
 }
 
 trait ReadW {
     // TODO: Having &mut here is ugly
-    fn readw(&self, cpu: &mut Cpu) -> u16;
+    fn readw<B: Bus>(&self, cpu: &mut Cpu<B>) -> u16;
+    fn read_cycles(&self) -> u32;
+}
+
+impl IndirectAddr {
+    // One memory access, plus fetching any part of the address that follows
+    // the opcode.
+    fn cycles(&self) -> u32 {
+        use self::IndirectAddr::*;
+        match *self {
+            ZeroPage => 8,
+            AddressW => 12,
+            _ => 4,
+        }
+    }
 }
 
 impl ReadB for IndirectAddr {
-    fn readb(&self, cpu: &mut Cpu) -> u8 {
+    fn readb<B: Bus>(&self, cpu: &mut Cpu<B>) -> u8 {
         let addr = cpu.iaddr(*self);
-        cpu.interconnect.readb(addr)
+        cpu.read(addr)
+    }
+
+    fn read_cycles(&self) -> u32 {
+        self.cycles()
     }
 }
 
 impl ReadB for RegsB {
-    fn readb(&self, cpu: &mut Cpu) -> u8 {
+    fn readb<B: Bus>(&self, cpu: &mut Cpu<B>) -> u8 {
         cpu.regs.readb(*self)
     }
+
+    fn read_cycles(&self) -> u32 {
+        0
+    }
 }
 
 impl ReadB for ImmediateB {
-    fn readb(&self, cpu: &mut Cpu) -> u8 {
+    fn readb<B: Bus>(&self, cpu: &mut Cpu<B>) -> u8 {
         cpu.fetchb()
     }
+
+    fn read_cycles(&self) -> u32 {
+        4
+    }
 }
 
 impl WriteB for IndirectAddr {
-    fn writeb(&self, cpu: &mut Cpu, val: u8) {
+    fn writeb<B: Bus>(&self, cpu: &mut Cpu<B>, val: u8) {
         let addr = cpu.iaddr(*self);
-        cpu.interconnect.writeb(addr, val);
+        cpu.write(addr, val);
+    }
+
+    fn write_cycles(&self) -> u32 {
+        self.cycles()
     }
 }
 
 impl WriteB for RegsB {
-    fn writeb(&self, cpu: &mut Cpu, val: u8) {
+    fn writeb<B: Bus>(&self, cpu: &mut Cpu<B>, val: u8) {
         cpu.regs.writeb(*self, val);
     }
+
+    fn write_cycles(&self) -> u32 {
+        0
+    }
 }
 
 impl ReadW for RegsW {
-    fn readw(&self, cpu: &mut Cpu) -> u16 {
+    fn readw<B: Bus>(&self, cpu: &mut Cpu<B>) -> u16 {
         cpu.regs.readw(*self)
     }
+
+    fn read_cycles(&self) -> u32 {
+        0
+    }
 }
 
 impl ReadW for AddressW {
-    fn readw(&self, cpu: &mut Cpu) -> u16 {
+    fn readw<B: Bus>(&self, cpu: &mut Cpu<B>) -> u16 {
         cpu.fetchw()
     }
+
+    fn read_cycles(&self) -> u32 {
+        8
+    }
 }
 
 impl Registers {
@@ -191,9 +401,6 @@ impl Registers {
 
     pub fn writeb(&mut self, reg: RegsB, val: u8) {
         use self::RegsB::*;
-        if cfg!(debug_assertions) {
-            print!("\t {:?}=0x{:02x}", reg, val);
-        }
         match reg {
             A => self.a = val,
             B => self.b = val,
@@ -210,7 +417,7 @@ impl Registers {
         match reg {
             PC => self.pc,
             SP => self.sp,
-            AF => ((self.a as u16) << 8) | (self.f as u16),
+            AF => ((self.a as u16) << 8) | (self.f.bits() as u16),
             BC => ((self.b as u16) << 8) | (self.c as u16),
             DE => ((self.d as u16) << 8) | (self.e as u16),
             HL => ((self.h as u16) << 8) | (self.l as u16),
@@ -219,15 +426,12 @@ impl Registers {
 
     pub fn writew(&mut self, reg: RegsW, val: u16) {
         use self::RegsW::*;
-        if cfg!(debug_assertions) {
-            print!("\t{:?}=0x{:04x}", reg, val);
-        }
         match reg {
             PC => self.pc = val,
             SP => self.sp = val,
             AF => {
                 self.a = (val >> 8) as u8;
-                self.f = val as u8
+                self.f = Flags::from(val as u8)
             }
             BC => {
                 self.b = (val >> 8) as u8;
@@ -245,56 +449,857 @@ impl Registers {
     }
 }
 
-impl Cpu {
-    pub fn new(interconnect: interconnect::Interconnect) -> Cpu {
+// Opcodes are dispatched through tables rather than a match, so that each
+// one costs an index and an indirect call.
+type Handler<B> = fn(&mut Cpu<B>) -> u32;
+
+impl<B: Bus> Cpu<B> {
+
+    // The handler for each opcode.
+    const OPCODES: &'static [Handler<B>; 256] = &{
+        use self::RegsW::*;
+        use self::RegsB::*;
+        [|cpu| cpu.nop(), // 0x00
+         |cpu| cpu.ldw(BC, self::AddressW), // 0x01
+         |cpu| cpu.ld(self::IndirectAddr::BC, A), // 0x02
+         |cpu| cpu.incw(BC), // 0x03
+         |cpu| cpu.inc(B), // 0x04
+         |cpu| cpu.dec(B), // 0x05
+         |cpu| cpu.ld(B, self::ImmediateB), // 0x06
+         |cpu| cpu.rlca(), // 0x07
+         |cpu| cpu.ldw_nn_sp(), // 0x08
+         |cpu| cpu.addw(BC), // 0x09
+         |cpu| cpu.ld(A, self::IndirectAddr::BC), // 0x0A
+         |cpu| cpu.decw(BC), // 0x0B
+         |cpu| cpu.inc(C), // 0x0C
+         |cpu| cpu.dec(C), // 0x0D
+         |cpu| cpu.ld(C, self::ImmediateB), // 0x0E
+         |cpu| cpu.rrca(), // 0x0F
+         |cpu| cpu.stop(), // 0x10
+         |cpu| cpu.ldw(DE, self::AddressW), // 0x11
+         |cpu| cpu.ld(self::IndirectAddr::DE, A), // 0x12
+         |cpu| cpu.incw(DE), // 0x13
+         |cpu| cpu.inc(D), // 0x14
+         |cpu| cpu.dec(D), // 0x15
+         |cpu| cpu.ld(D, self::ImmediateB), // 0x16
+         |cpu| cpu.rla(), // 0x17
+         |cpu| cpu.jr(), // 0x18
+         |cpu| cpu.addw(DE), // 0x19
+         |cpu| cpu.ld(A, self::IndirectAddr::DE), // 0x1A
+         |cpu| cpu.decw(DE), // 0x1B
+         |cpu| cpu.inc(E), // 0x1C
+         |cpu| cpu.dec(E), // 0x1D
+         |cpu| cpu.ld(E, self::ImmediateB), // 0x1E
+         |cpu| cpu.rra(), // 0x1F
+         |cpu| cpu.jr_cond(self::Condition::NZ), // 0x20
+         |cpu| cpu.ldw(HL, self::AddressW), // 0x21
+         |cpu| cpu.ld(self::IndirectAddr::HLP, A), // 0x22
+         |cpu| cpu.incw(HL), // 0x23
+         |cpu| cpu.inc(H), // 0x24
+         |cpu| cpu.dec(H), // 0x25
+         |cpu| cpu.ld(H, self::ImmediateB), // 0x26
+         |cpu| cpu.daa(), // 0x27
+         |cpu| cpu.jr_cond(self::Condition::Z), // 0x28
+         |cpu| cpu.addw(HL), // 0x29
+         |cpu| cpu.ld(A, self::IndirectAddr::HLP), // 0x2A
+         |cpu| cpu.decw(HL), // 0x2B
+         |cpu| cpu.inc(L), // 0x2C
+         |cpu| cpu.dec(L), // 0x2D
+         |cpu| cpu.ld(L, self::ImmediateB), // 0x2E
+         |cpu| cpu.cpl(), // 0x2F
+         |cpu| cpu.jr_cond(self::Condition::NC), // 0x30
+         |cpu| cpu.ldw(SP, self::AddressW), // 0x31
+         |cpu| cpu.ld(self::IndirectAddr::HLM, A), // 0x32
+         |cpu| cpu.incw(SP), // 0x33
+         |cpu| cpu.inc(self::IndirectAddr::HL), // 0x34
+         |cpu| cpu.dec(self::IndirectAddr::HL), // 0x35
+         |cpu| cpu.ld(self::IndirectAddr::HL, self::ImmediateB), // 0x36
+         |cpu| cpu.scf(), // 0x37
+         |cpu| cpu.jr_cond(self::Condition::C), // 0x38
+         |cpu| cpu.addw(SP), // 0x39
+         |cpu| cpu.ld(A, self::IndirectAddr::HLM), // 0x3A
+         |cpu| cpu.decw(SP), // 0x3B
+         |cpu| cpu.inc(A), // 0x3C
+         |cpu| cpu.dec(A), // 0x3D
+         |cpu| cpu.ld(A, self::ImmediateB), // 0x3E
+         |cpu| cpu.ccf(), // 0x3F
+         |cpu| cpu.ld(B, B), // 0x40
+         |cpu| cpu.ld(B, C), // 0x41
+         |cpu| cpu.ld(B, D), // 0x42
+         |cpu| cpu.ld(B, E), // 0x43
+         |cpu| cpu.ld(B, H), // 0x44
+         |cpu| cpu.ld(B, L), // 0x45
+         |cpu| cpu.ld(B, self::IndirectAddr::HL), // 0x46
+         |cpu| cpu.ld(B, A), // 0x47
+         |cpu| cpu.ld(C, B), // 0x48
+         |cpu| cpu.ld(C, C), // 0x49
+         |cpu| cpu.ld(C, D), // 0x4A
+         |cpu| cpu.ld(C, E), // 0x4B
+         |cpu| cpu.ld(C, H), // 0x4C
+         |cpu| cpu.ld(C, L), // 0x4D
+         |cpu| cpu.ld(C, self::IndirectAddr::HL), // 0x4E
+         |cpu| cpu.ld(C, A), // 0x4F
+         |cpu| cpu.ld(D, B), // 0x50
+         |cpu| cpu.ld(D, C), // 0x51
+         |cpu| cpu.ld(D, D), // 0x52
+         |cpu| cpu.ld(D, E), // 0x53
+         |cpu| cpu.ld(D, H), // 0x54
+         |cpu| cpu.ld(D, L), // 0x55
+         |cpu| cpu.ld(D, self::IndirectAddr::HL), // 0x56
+         |cpu| cpu.ld(D, A), // 0x57
+         |cpu| cpu.ld(E, B), // 0x58
+         |cpu| cpu.ld(E, C), // 0x59
+         |cpu| cpu.ld(E, D), // 0x5A
+         |cpu| cpu.ld(E, E), // 0x5B
+         |cpu| cpu.ld(E, H), // 0x5C
+         |cpu| cpu.ld(E, L), // 0x5D
+         |cpu| cpu.ld(E, self::IndirectAddr::HL), // 0x5E
+         |cpu| cpu.ld(E, A), // 0x5F
+         |cpu| cpu.ld(H, B), // 0x60
+         |cpu| cpu.ld(H, C), // 0x61
+         |cpu| cpu.ld(H, D), // 0x62
+         |cpu| cpu.ld(H, E), // 0x63
+         |cpu| cpu.ld(H, H), // 0x64
+         |cpu| cpu.ld(H, L), // 0x65
+         |cpu| cpu.ld(H, self::IndirectAddr::HL), // 0x66
+         |cpu| cpu.ld(H, A), // 0x67
+         |cpu| cpu.ld(L, B), // 0x68
+         |cpu| cpu.ld(L, C), // 0x69
+         |cpu| cpu.ld(L, D), // 0x6A
+         |cpu| cpu.ld(L, E), // 0x6B
+         |cpu| cpu.ld(L, H), // 0x6C
+         |cpu| cpu.ld(L, L), // 0x6D
+         |cpu| cpu.ld(L, self::IndirectAddr::HL), // 0x6E
+         |cpu| cpu.ld(L, A), // 0x6F
+         |cpu| cpu.ld(self::IndirectAddr::HL, B), // 0x70
+         |cpu| cpu.ld(self::IndirectAddr::HL, C), // 0x71
+         |cpu| cpu.ld(self::IndirectAddr::HL, D), // 0x72
+         |cpu| cpu.ld(self::IndirectAddr::HL, E), // 0x73
+         |cpu| cpu.ld(self::IndirectAddr::HL, H), // 0x74
+         |cpu| cpu.ld(self::IndirectAddr::HL, L), // 0x75
+         |cpu| cpu.halt(), // 0x76
+         |cpu| cpu.ld(self::IndirectAddr::HL, A), // 0x77
+         |cpu| cpu.ld(A, B), // 0x78
+         |cpu| cpu.ld(A, C), // 0x79
+         |cpu| cpu.ld(A, D), // 0x7A
+         |cpu| cpu.ld(A, E), // 0x7B
+         |cpu| cpu.ld(A, H), // 0x7C
+         |cpu| cpu.ld(A, L), // 0x7D
+         |cpu| cpu.ld(A, self::IndirectAddr::HL), // 0x7E
+         |cpu| cpu.ld(A, A), // 0x7F
+         |cpu| cpu.add(B), // 0x80
+         |cpu| cpu.add(C), // 0x81
+         |cpu| cpu.add(D), // 0x82
+         |cpu| cpu.add(E), // 0x83
+         |cpu| cpu.add(H), // 0x84
+         |cpu| cpu.add(L), // 0x85
+         |cpu| cpu.add(self::IndirectAddr::HL), // 0x86
+         |cpu| cpu.add(A), // 0x87
+         |cpu| cpu.adc(B), // 0x88
+         |cpu| cpu.adc(C), // 0x89
+         |cpu| cpu.adc(D), // 0x8A
+         |cpu| cpu.adc(E), // 0x8B
+         |cpu| cpu.adc(H), // 0x8C
+         |cpu| cpu.adc(L), // 0x8D
+         |cpu| cpu.adc(self::IndirectAddr::HL), // 0x8E
+         |cpu| cpu.adc(A), // 0x8F
+         |cpu| cpu.sub(B), // 0x90
+         |cpu| cpu.sub(C), // 0x91
+         |cpu| cpu.sub(D), // 0x92
+         |cpu| cpu.sub(E), // 0x93
+         |cpu| cpu.sub(H), // 0x94
+         |cpu| cpu.sub(L), // 0x95
+         |cpu| cpu.sub(self::IndirectAddr::HL), // 0x96
+         |cpu| cpu.sub(A), // 0x97
+         |cpu| cpu.sbc(B), // 0x98
+         |cpu| cpu.sbc(C), // 0x99
+         |cpu| cpu.sbc(D), // 0x9A
+         |cpu| cpu.sbc(E), // 0x9B
+         |cpu| cpu.sbc(H), // 0x9C
+         |cpu| cpu.sbc(L), // 0x9D
+         |cpu| cpu.sbc(self::IndirectAddr::HL), // 0x9E
+         |cpu| cpu.sbc(A), // 0x9F
+         |cpu| cpu.and(B), // 0xA0
+         |cpu| cpu.and(C), // 0xA1
+         |cpu| cpu.and(D), // 0xA2
+         |cpu| cpu.and(E), // 0xA3
+         |cpu| cpu.and(H), // 0xA4
+         |cpu| cpu.and(L), // 0xA5
+         |cpu| cpu.and(self::IndirectAddr::HL), // 0xA6
+         |cpu| cpu.and(A), // 0xA7
+         |cpu| cpu.xor(B), // 0xA8
+         |cpu| cpu.xor(C), // 0xA9
+         |cpu| cpu.xor(D), // 0xAA
+         |cpu| cpu.xor(E), // 0xAB
+         |cpu| cpu.xor(H), // 0xAC
+         |cpu| cpu.xor(L), // 0xAD
+         |cpu| cpu.xor(self::IndirectAddr::HL), // 0xAE
+         |cpu| cpu.xor(A), // 0xAF
+         |cpu| cpu.or(B), // 0xB0
+         |cpu| cpu.or(C), // 0xB1
+         |cpu| cpu.or(D), // 0xB2
+         |cpu| cpu.or(E), // 0xB3
+         |cpu| cpu.or(H), // 0xB4
+         |cpu| cpu.or(L), // 0xB5
+         |cpu| cpu.or(self::IndirectAddr::HL), // 0xB6
+         |cpu| cpu.or(A), // 0xB7
+         |cpu| cpu.cp(B), // 0xB8
+         |cpu| cpu.cp(C), // 0xB9
+         |cpu| cpu.cp(D), // 0xBA
+         |cpu| cpu.cp(E), // 0xBB
+         |cpu| cpu.cp(H), // 0xBC
+         |cpu| cpu.cp(L), // 0xBD
+         |cpu| cpu.cp(self::IndirectAddr::HL), // 0xBE
+         |cpu| cpu.cp(A), // 0xBF
+         |cpu| cpu.ret_cond(self::Condition::NZ), // 0xC0
+         |cpu| cpu.pop(BC), // 0xC1
+         |cpu| cpu.jp_cond(self::Condition::NZ), // 0xC2
+         |cpu| cpu.jp(self::AddressW), // 0xC3
+         |cpu| cpu.call_cond(self::Condition::NZ), // 0xC4
+         |cpu| cpu.push(BC), // 0xC5
+         |cpu| cpu.add(self::ImmediateB), // 0xC6
+         |cpu| cpu.rst(0x00), // 0xC7
+         |cpu| cpu.ret_cond(self::Condition::Z), // 0xC8
+         |cpu| cpu.ret(), // 0xC9
+         |cpu| cpu.jp_cond(self::Condition::Z), // 0xCA
+         |cpu| cpu.cb_dexec(), // 0xCB
+         |cpu| cpu.call_cond(self::Condition::Z), // 0xCC
+         |cpu| cpu.call(), // 0xCD
+         |cpu| cpu.adc(self::ImmediateB), // 0xCE
+         |cpu| cpu.rst(0x08), // 0xCF
+         |cpu| cpu.ret_cond(self::Condition::NC), // 0xD0
+         |cpu| cpu.pop(DE), // 0xD1
+         |cpu| cpu.jp_cond(self::Condition::NC), // 0xD2
+         |cpu| cpu.lock(0xD3), // 0xD3
+         |cpu| cpu.call_cond(self::Condition::NC), // 0xD4
+         |cpu| cpu.push(DE), // 0xD5
+         |cpu| cpu.sub(self::ImmediateB), // 0xD6
+         |cpu| cpu.rst(0x10), // 0xD7
+         |cpu| cpu.ret_cond(self::Condition::C), // 0xD8
+         |cpu| cpu.reti(), // 0xD9
+         |cpu| cpu.jp_cond(self::Condition::C), // 0xDA
+         |cpu| cpu.lock(0xDB), // 0xDB
+         |cpu| cpu.call_cond(self::Condition::C), // 0xDC
+         |cpu| cpu.lock(0xDD), // 0xDD
+         |cpu| cpu.sbc(self::ImmediateB), // 0xDE
+         |cpu| cpu.rst(0x18), // 0xDF
+         |cpu| cpu.ld(self::IndirectAddr::ZeroPage, A), // 0xE0 LDH
+         |cpu| cpu.pop(HL), // 0xE1
+         |cpu| cpu.ld(self::IndirectAddr::ZeroPageC, A), // 0xE2 LDH
+         |cpu| cpu.lock(0xE3), // 0xE3
+         |cpu| cpu.lock(0xE4), // 0xE4
+         |cpu| cpu.push(HL), // 0xE5
+         |cpu| cpu.and(self::ImmediateB), // 0xE6
+         |cpu| cpu.rst(0x20), // 0xE7
+         |cpu| cpu.addw_sp(), // 0xE8
+         |cpu| cpu.jp(HL), // 0xE9
+         |cpu| cpu.ld(self::IndirectAddr::AddressW, A), // 0xEA
+         |cpu| cpu.lock(0xEB), // 0xEB
+         |cpu| cpu.lock(0xEC), // 0xEC
+         |cpu| cpu.lock(0xED), // 0xED
+         |cpu| cpu.xor(self::ImmediateB), // 0xEE
+         |cpu| cpu.rst(0x28), // 0xEF
+         |cpu| cpu.ld(A, self::IndirectAddr::ZeroPage), // 0xF0 LDH
+         |cpu| cpu.pop(AF), // 0xF1
+         |cpu| cpu.ld(A, self::IndirectAddr::ZeroPageC), // 0xF2 LDH
+         |cpu| cpu.di(), // 0xF3
+         |cpu| cpu.lock(0xF4), // 0xF4
+         |cpu| cpu.push(AF), // 0xF5
+         |cpu| cpu.or(self::ImmediateB), // 0xF6
+         |cpu| cpu.rst(0x30), // 0xF7
+         |cpu| cpu.ldw_hl_sp(), // 0xF8
+         |cpu| cpu.ldw(SP, HL), // 0xF9
+         |cpu| cpu.ld(A, self::IndirectAddr::AddressW), // 0xFA
+         |cpu| cpu.ei(), // 0xFB
+         |cpu| cpu.lock(0xFC), // 0xFC
+         |cpu| cpu.lock(0xFD), // 0xFD
+         |cpu| cpu.cp(self::ImmediateB), // 0xFE
+         |cpu| cpu.rst(0x38)] // 0xFF
+    };
+
+    // The handler for each opcode after a 0xCB prefix.
+    const CB_OPCODES: &'static [Handler<B>; 256] = &{
+        use self::RegsB::*;
+        [|cpu| cpu.rlc(B), // 0x00
+         |cpu| cpu.rlc(C), // 0x01
+         |cpu| cpu.rlc(D), // 0x02
+         |cpu| cpu.rlc(E), // 0x03
+         |cpu| cpu.rlc(H), // 0x04
+         |cpu| cpu.rlc(L), // 0x05
+         |cpu| cpu.rlc(self::IndirectAddr::HL), // 0x06
+         |cpu| cpu.rlc(A), // 0x07
+         |cpu| cpu.rrc(B), // 0x08
+         |cpu| cpu.rrc(C), // 0x09
+         |cpu| cpu.rrc(D), // 0x0A
+         |cpu| cpu.rrc(E), // 0x0B
+         |cpu| cpu.rrc(H), // 0x0C
+         |cpu| cpu.rrc(L), // 0x0D
+         |cpu| cpu.rrc(self::IndirectAddr::HL), // 0x0E
+         |cpu| cpu.rrc(A), // 0x0F
+         |cpu| cpu.rl(B), // 0x10
+         |cpu| cpu.rl(C), // 0x11
+         |cpu| cpu.rl(D), // 0x12
+         |cpu| cpu.rl(E), // 0x13
+         |cpu| cpu.rl(H), // 0x14
+         |cpu| cpu.rl(L), // 0x15
+         |cpu| cpu.rl(self::IndirectAddr::HL), // 0x16
+         |cpu| cpu.rl(A), // 0x17
+         |cpu| cpu.rr(B), // 0x18
+         |cpu| cpu.rr(C), // 0x19
+         |cpu| cpu.rr(D), // 0x1A
+         |cpu| cpu.rr(E), // 0x1B
+         |cpu| cpu.rr(H), // 0x1C
+         |cpu| cpu.rr(L), // 0x1D
+         |cpu| cpu.rr(self::IndirectAddr::HL), // 0x1E
+         |cpu| cpu.rr(A), // 0x1F
+         |cpu| cpu.sla(B), // 0x20
+         |cpu| cpu.sla(C), // 0x21
+         |cpu| cpu.sla(D), // 0x22
+         |cpu| cpu.sla(E), // 0x23
+         |cpu| cpu.sla(H), // 0x24
+         |cpu| cpu.sla(L), // 0x25
+         |cpu| cpu.sla(self::IndirectAddr::HL), // 0x26
+         |cpu| cpu.sla(A), // 0x27
+         |cpu| cpu.sra(B), // 0x28
+         |cpu| cpu.sra(C), // 0x29
+         |cpu| cpu.sra(D), // 0x2A
+         |cpu| cpu.sra(E), // 0x2B
+         |cpu| cpu.sra(H), // 0x2C
+         |cpu| cpu.sra(L), // 0x2D
+         |cpu| cpu.sra(self::IndirectAddr::HL), // 0x2E
+         |cpu| cpu.sra(A), // 0x2F
+         |cpu| cpu.swap(B), // 0x30
+         |cpu| cpu.swap(C), // 0x31
+         |cpu| cpu.swap(D), // 0x32
+         |cpu| cpu.swap(E), // 0x33
+         |cpu| cpu.swap(H), // 0x34
+         |cpu| cpu.swap(L), // 0x35
+         |cpu| cpu.swap(self::IndirectAddr::HL), // 0x36
+         |cpu| cpu.swap(A), // 0x37
+         |cpu| cpu.srl(B), // 0x38
+         |cpu| cpu.srl(C), // 0x39
+         |cpu| cpu.srl(D), // 0x3A
+         |cpu| cpu.srl(E), // 0x3B
+         |cpu| cpu.srl(H), // 0x3C
+         |cpu| cpu.srl(L), // 0x3D
+         |cpu| cpu.srl(self::IndirectAddr::HL), // 0x3E
+         |cpu| cpu.srl(A), // 0x3F
+         |cpu| cpu.bit(0, B), // 0x40
+         |cpu| cpu.bit(0, C), // 0x41
+         |cpu| cpu.bit(0, D), // 0x42
+         |cpu| cpu.bit(0, E), // 0x43
+         |cpu| cpu.bit(0, H), // 0x44
+         |cpu| cpu.bit(0, L), // 0x45
+         |cpu| cpu.bit(0, self::IndirectAddr::HL), // 0x46
+         |cpu| cpu.bit(0, A), // 0x47
+         |cpu| cpu.bit(1, B), // 0x48
+         |cpu| cpu.bit(1, C), // 0x49
+         |cpu| cpu.bit(1, D), // 0x4A
+         |cpu| cpu.bit(1, E), // 0x4B
+         |cpu| cpu.bit(1, H), // 0x4C
+         |cpu| cpu.bit(1, L), // 0x4D
+         |cpu| cpu.bit(1, self::IndirectAddr::HL), // 0x4E
+         |cpu| cpu.bit(1, A), // 0x4F
+         |cpu| cpu.bit(2, B), // 0x50
+         |cpu| cpu.bit(2, C), // 0x51
+         |cpu| cpu.bit(2, D), // 0x52
+         |cpu| cpu.bit(2, E), // 0x53
+         |cpu| cpu.bit(2, H), // 0x54
+         |cpu| cpu.bit(2, L), // 0x55
+         |cpu| cpu.bit(2, self::IndirectAddr::HL), // 0x56
+         |cpu| cpu.bit(2, A), // 0x57
+         |cpu| cpu.bit(3, B), // 0x58
+         |cpu| cpu.bit(3, C), // 0x59
+         |cpu| cpu.bit(3, D), // 0x5A
+         |cpu| cpu.bit(3, E), // 0x5B
+         |cpu| cpu.bit(3, H), // 0x5C
+         |cpu| cpu.bit(3, L), // 0x5D
+         |cpu| cpu.bit(3, self::IndirectAddr::HL), // 0x5E
+         |cpu| cpu.bit(3, A), // 0x5F
+         |cpu| cpu.bit(4, B), // 0x60
+         |cpu| cpu.bit(4, C), // 0x61
+         |cpu| cpu.bit(4, D), // 0x62
+         |cpu| cpu.bit(4, E), // 0x63
+         |cpu| cpu.bit(4, H), // 0x64
+         |cpu| cpu.bit(4, L), // 0x65
+         |cpu| cpu.bit(4, self::IndirectAddr::HL), // 0x66
+         |cpu| cpu.bit(4, A), // 0x67
+         |cpu| cpu.bit(5, B), // 0x68
+         |cpu| cpu.bit(5, C), // 0x69
+         |cpu| cpu.bit(5, D), // 0x6A
+         |cpu| cpu.bit(5, E), // 0x6B
+         |cpu| cpu.bit(5, H), // 0x6C
+         |cpu| cpu.bit(5, L), // 0x6D
+         |cpu| cpu.bit(5, self::IndirectAddr::HL), // 0x6E
+         |cpu| cpu.bit(5, A), // 0x6F
+         |cpu| cpu.bit(6, B), // 0x70
+         |cpu| cpu.bit(6, C), // 0x71
+         |cpu| cpu.bit(6, D), // 0x72
+         |cpu| cpu.bit(6, E), // 0x73
+         |cpu| cpu.bit(6, H), // 0x74
+         |cpu| cpu.bit(6, L), // 0x75
+         |cpu| cpu.bit(6, self::IndirectAddr::HL), // 0x76
+         |cpu| cpu.bit(6, A), // 0x77
+         |cpu| cpu.bit(7, B), // 0x78
+         |cpu| cpu.bit(7, C), // 0x79
+         |cpu| cpu.bit(7, D), // 0x7A
+         |cpu| cpu.bit(7, E), // 0x7B
+         |cpu| cpu.bit(7, H), // 0x7C
+         |cpu| cpu.bit(7, L), // 0x7D
+         |cpu| cpu.bit(7, self::IndirectAddr::HL), // 0x7E
+         |cpu| cpu.bit(7, A), // 0x7F
+         |cpu| cpu.res(0, B), // 0x80
+         |cpu| cpu.res(0, C), // 0x81
+         |cpu| cpu.res(0, D), // 0x82
+         |cpu| cpu.res(0, E), // 0x83
+         |cpu| cpu.res(0, H), // 0x84
+         |cpu| cpu.res(0, L), // 0x85
+         |cpu| cpu.res(0, self::IndirectAddr::HL), // 0x86
+         |cpu| cpu.res(0, A), // 0x87
+         |cpu| cpu.res(1, B), // 0x88
+         |cpu| cpu.res(1, C), // 0x89
+         |cpu| cpu.res(1, D), // 0x8A
+         |cpu| cpu.res(1, E), // 0x8B
+         |cpu| cpu.res(1, H), // 0x8C
+         |cpu| cpu.res(1, L), // 0x8D
+         |cpu| cpu.res(1, self::IndirectAddr::HL), // 0x8E
+         |cpu| cpu.res(1, A), // 0x8F
+         |cpu| cpu.res(2, B), // 0x90
+         |cpu| cpu.res(2, C), // 0x91
+         |cpu| cpu.res(2, D), // 0x92
+         |cpu| cpu.res(2, E), // 0x93
+         |cpu| cpu.res(2, H), // 0x94
+         |cpu| cpu.res(2, L), // 0x95
+         |cpu| cpu.res(2, self::IndirectAddr::HL), // 0x96
+         |cpu| cpu.res(2, A), // 0x97
+         |cpu| cpu.res(3, B), // 0x98
+         |cpu| cpu.res(3, C), // 0x99
+         |cpu| cpu.res(3, D), // 0x9A
+         |cpu| cpu.res(3, E), // 0x9B
+         |cpu| cpu.res(3, H), // 0x9C
+         |cpu| cpu.res(3, L), // 0x9D
+         |cpu| cpu.res(3, self::IndirectAddr::HL), // 0x9E
+         |cpu| cpu.res(3, A), // 0x9F
+         |cpu| cpu.res(4, B), // 0xA0
+         |cpu| cpu.res(4, C), // 0xA1
+         |cpu| cpu.res(4, D), // 0xA2
+         |cpu| cpu.res(4, E), // 0xA3
+         |cpu| cpu.res(4, H), // 0xA4
+         |cpu| cpu.res(4, L), // 0xA5
+         |cpu| cpu.res(4, self::IndirectAddr::HL), // 0xA6
+         |cpu| cpu.res(4, A), // 0xA7
+         |cpu| cpu.res(5, B), // 0xA8
+         |cpu| cpu.res(5, C), // 0xA9
+         |cpu| cpu.res(5, D), // 0xAA
+         |cpu| cpu.res(5, E), // 0xAB
+         |cpu| cpu.res(5, H), // 0xAC
+         |cpu| cpu.res(5, L), // 0xAD
+         |cpu| cpu.res(5, self::IndirectAddr::HL), // 0xAE
+         |cpu| cpu.res(5, A), // 0xAF
+         |cpu| cpu.res(6, B), // 0xB0
+         |cpu| cpu.res(6, C), // 0xB1
+         |cpu| cpu.res(6, D), // 0xB2
+         |cpu| cpu.res(6, E), // 0xB3
+         |cpu| cpu.res(6, H), // 0xB4
+         |cpu| cpu.res(6, L), // 0xB5
+         |cpu| cpu.res(6, self::IndirectAddr::HL), // 0xB6
+         |cpu| cpu.res(6, A), // 0xB7
+         |cpu| cpu.res(7, B), // 0xB8
+         |cpu| cpu.res(7, C), // 0xB9
+         |cpu| cpu.res(7, D), // 0xBA
+         |cpu| cpu.res(7, E), // 0xBB
+         |cpu| cpu.res(7, H), // 0xBC
+         |cpu| cpu.res(7, L), // 0xBD
+         |cpu| cpu.res(7, self::IndirectAddr::HL), // 0xBE
+         |cpu| cpu.res(7, A), // 0xBF
+         |cpu| cpu.set(0, B), // 0xC0
+         |cpu| cpu.set(0, C), // 0xC1
+         |cpu| cpu.set(0, D), // 0xC2
+         |cpu| cpu.set(0, E), // 0xC3
+         |cpu| cpu.set(0, H), // 0xC4
+         |cpu| cpu.set(0, L), // 0xC5
+         |cpu| cpu.set(0, self::IndirectAddr::HL), // 0xC6
+         |cpu| cpu.set(0, A), // 0xC7
+         |cpu| cpu.set(1, B), // 0xC8
+         |cpu| cpu.set(1, C), // 0xC9
+         |cpu| cpu.set(1, D), // 0xCA
+         |cpu| cpu.set(1, E), // 0xCB
+         |cpu| cpu.set(1, H), // 0xCC
+         |cpu| cpu.set(1, L), // 0xCD
+         |cpu| cpu.set(1, self::IndirectAddr::HL), // 0xCE
+         |cpu| cpu.set(1, A), // 0xCF
+         |cpu| cpu.set(2, B), // 0xD0
+         |cpu| cpu.set(2, C), // 0xD1
+         |cpu| cpu.set(2, D), // 0xD2
+         |cpu| cpu.set(2, E), // 0xD3
+         |cpu| cpu.set(2, H), // 0xD4
+         |cpu| cpu.set(2, L), // 0xD5
+         |cpu| cpu.set(2, self::IndirectAddr::HL), // 0xD6
+         |cpu| cpu.set(2, A), // 0xD7
+         |cpu| cpu.set(3, B), // 0xD8
+         |cpu| cpu.set(3, C), // 0xD9
+         |cpu| cpu.set(3, D), // 0xDA
+         |cpu| cpu.set(3, E), // 0xDB
+         |cpu| cpu.set(3, H), // 0xDC
+         |cpu| cpu.set(3, L), // 0xDD
+         |cpu| cpu.set(3, self::IndirectAddr::HL), // 0xDE
+         |cpu| cpu.set(3, A), // 0xDF
+         |cpu| cpu.set(4, B), // 0xE0
+         |cpu| cpu.set(4, C), // 0xE1
+         |cpu| cpu.set(4, D), // 0xE2
+         |cpu| cpu.set(4, E), // 0xE3
+         |cpu| cpu.set(4, H), // 0xE4
+         |cpu| cpu.set(4, L), // 0xE5
+         |cpu| cpu.set(4, self::IndirectAddr::HL), // 0xE6
+         |cpu| cpu.set(4, A), // 0xE7
+         |cpu| cpu.set(5, B), // 0xE8
+         |cpu| cpu.set(5, C), // 0xE9
+         |cpu| cpu.set(5, D), // 0xEA
+         |cpu| cpu.set(5, E), // 0xEB
+         |cpu| cpu.set(5, H), // 0xEC
+         |cpu| cpu.set(5, L), // 0xED
+         |cpu| cpu.set(5, self::IndirectAddr::HL), // 0xEE
+         |cpu| cpu.set(5, A), // 0xEF
+         |cpu| cpu.set(6, B), // 0xF0
+         |cpu| cpu.set(6, C), // 0xF1
+         |cpu| cpu.set(6, D), // 0xF2
+         |cpu| cpu.set(6, E), // 0xF3
+         |cpu| cpu.set(6, H), // 0xF4
+         |cpu| cpu.set(6, L), // 0xF5
+         |cpu| cpu.set(6, self::IndirectAddr::HL), // 0xF6
+         |cpu| cpu.set(6, A), // 0xF7
+         |cpu| cpu.set(7, B), // 0xF8
+         |cpu| cpu.set(7, C), // 0xF9
+         |cpu| cpu.set(7, D), // 0xFA
+         |cpu| cpu.set(7, E), // 0xFB
+         |cpu| cpu.set(7, H), // 0xFC
+         |cpu| cpu.set(7, L), // 0xFD
+         |cpu| cpu.set(7, self::IndirectAddr::HL), // 0xFE
+         |cpu| cpu.set(7, A)] // 0xFF
+    };
+}
+
+impl<B: Bus> Cpu<B> {
+    // skip_bootrom must match what the interconnect was created with.
+    pub fn new(interconnect: B, skip_bootrom: bool) -> Cpu<B> {
+        Cpu::new_with_model(interconnect, skip_bootrom, HardwareModel::default())
+    }
+
+    // Like new, but skip_bootrom leaves the registers as model's boot ROM
+    // would.
+    pub fn new_with_model(interconnect: B, skip_bootrom: bool, model: HardwareModel) -> Cpu<B> {
         let mut cpu = Cpu {
             clk: clk::Clock::default(),
             regs: Registers::default(),
+            tracer: None,
+            history: None,
+            profiler: None,
+            fault: None,
+            halted: false,
+            calls: Vec::new(),
+            breakpoints: HashSet::new(),
+            resuming: false,
+            stepped: 0,
             interconnect: interconnect,
         };
 
-        if !cpu.interconnect.brom.is_used() {
-            cpu.fake_boot_regs();
+        if skip_bootrom {
+            cpu.fake_boot_regs(model);
         };
 
         cpu
     }
 
-    fn fake_boot_regs(&mut self) {
-        self.regs.writew(self::RegsW::AF, 0x01B0);
-        self.regs.writew(self::RegsW::BC, 0x0013);
-        self.regs.writew(self::RegsW::DE, 0x00D8);
-        self.regs.writew(self::RegsW::HL, 0x014D);
+    // The number of cycles run since power on, including any run by a save
+    // state that was loaded.
+    pub fn elapsed_cycles(&self) -> u64 {
+        self.clk.total_cycles()
+    }
+
+    // Returns the previous tracer, if any.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    // Keep the last few instructions so that crashes can show how they came
+    // about. This costs a little on every instruction, so it's off to begin
+    // with.
+    pub fn set_history(&mut self, enabled: bool) {
+        if enabled != self.history.is_some() {
+            self.history = if enabled { Some(History::new()) } else { None };
+        }
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Count the instructions executed from now on, discarding any earlier
+    // counts.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = if enabled { Some(Profiler::new()) } else { None };
+    }
+
+    pub fn profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    // Empty unless profiling.
+    pub fn profile_report(&self) -> ProfileReport {
+        let disasm = |pc| self.disassemble(pc).0;
+        match self.profiler {
+            Some(ref profiler) => profiler.report(disasm),
+            None => Profiler::new().report(disasm),
+        }
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.resuming = false;
+    }
+
+    // The registers as they are now, along with the instruction at PC, which
+    // is the next one to be executed, and no cycles.
+    pub fn state(&self) -> CpuState {
+        let pc = self.regs.pc;
+        let len = self.disassemble(pc).1;
+        let mut opcode = [0; 3];
+        for (i, byte) in opcode.iter_mut().enumerate().take(len as usize) {
+            *byte = self.interconnect.peek(pc.wrapping_add(i as u16));
+        }
+        CpuState {
+            a: self.regs.a,
+            f: self.regs.f.bits(),
+            b: self.regs.b,
+            c: self.regs.c,
+            d: self.regs.d,
+            e: self.regs.e,
+            h: self.regs.h,
+            l: self.regs.l,
+            sp: self.regs.sp,
+            pc: pc,
+            ime: self.interconnect.ic().ime(),
+            opcode: opcode,
+            len: len as u8,
+            cycles: 0,
+        }
+    }
+
+    // Execute exactly one instruction, after servicing an interrupt if there
+    // is one, and return the state afterwards. Breakpoints are ignored, as
+    // this is how a debugger steps past them. A locked CPU executes nothing,
+    // so the state has no instruction.
+    pub fn step_instruction(&mut self) -> CpuState {
+        self.resuming = false;
+        if self.fault.is_some() || !self.wake() {
+            let cycles = self.idle(MAX_IDLE_CYCLES);
+            return CpuState {
+                len: 0,
+                cycles: cycles,
+                ..self.state()
+            };
+        }
+
+        let mut dispatch = 0;
+        if self.interconnect.ic().ime() {
+            dispatch = self.handle_interrupts();
+        }
+        let before = self.state();
+        let cycles = self.execute();
+        CpuState {
+            opcode: before.opcode,
+            len: before.len,
+            cycles: dispatch + cycles,
+            ..self.state()
+        }
+    }
+
+    // Leave HALT if an interrupt is pending, whether or not IME means that
+    // it will be serviced. Returns whether the CPU is awake.
+    fn wake(&mut self) -> bool {
+        if self.halted {
+            self.halted = !self.interconnect.ic().has_pending();
+        }
+        !self.halted
+    }
+
+    // Let time pass while the CPU does nothing, locked up or halted. Nothing
+    // changes for it until the bus has something to say, so it can skip
+    // straight there rather than stepping an M-cycle at a time.
+    fn idle(&mut self, limit: u32) -> u32 {
+        let limit = cmp::min(limit, MAX_IDLE_CYCLES);
+        let quiet = cmp::min(self.interconnect.quiet_cycles(), limit);
+        let ticks = cmp::max(quiet & !3, 4);
+        self.clk.add_cycles(ticks);
+        self.interconnect.step(ticks)
+    }
+
+    // Whether step needs to look at each instruction before executing it.
+    fn watching(&self) -> bool {
+        cfg!(feature = "mooneye") || !self.breakpoints.is_empty()
+    }
+
+    // Stop if PC is on a breakpoint, unless we've just stopped there.
+    fn check_breakpoint(&mut self) -> Result<(), BreakReason> {
+        if self.resuming {
+            self.resuming = false;
+            return Ok(());
+        }
+        let pc = self.regs.pc;
+        if self.breakpoints.contains(&pc) {
+            self.resuming = true;
+            return Err(BreakReason::Breakpoint(pc));
+        }
+        // Mooneye's test ROMs finish with LD B,B, having loaded B, C, D, E, H
+        // and L with the Fibonacci numbers if they passed.
+        if cfg!(feature = "mooneye") && self.interconnect.peek(pc) == 0x40 {
+            let r = &self.regs;
+            let passed = [r.b, r.c, r.d, r.e, r.h, r.l] == [3, 5, 8, 13, 21, 34];
+            self.resuming = true;
+            return Err(BreakReason::TestFinished { passed: passed });
+        }
+        Ok(())
+    }
+
+    fn trace_entry(&self) -> TraceEntry {
+        let pc = self.regs.pc;
+        TraceEntry {
+            cycles: self.clk.total_cycles(),
+            a: self.regs.a,
+            f: self.regs.f.bits(),
+            b: self.regs.b,
+            c: self.regs.c,
+            d: self.regs.d,
+            e: self.regs.e,
+            h: self.regs.h,
+            l: self.regs.l,
+            sp: self.regs.sp,
+            pc: pc,
+            mem: [self.interconnect.peek(pc),
+                  self.interconnect.peek(pc.wrapping_add(1)),
+                  self.interconnect.peek(pc.wrapping_add(2)),
+                  self.interconnect.peek(pc.wrapping_add(3))],
+        }
+    }
+
+    fn trace(&mut self) {
+        let entry = self.trace_entry();
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.trace(&entry);
+        }
+    }
+
+    fn fake_boot_regs(&mut self, model: HardwareModel) {
+        let regs = model.boot_regs();
+        self.regs.writew(self::RegsW::AF, regs[0]);
+        self.regs.writew(self::RegsW::BC, regs[1]);
+        self.regs.writew(self::RegsW::DE, regs[2]);
+        self.regs.writew(self::RegsW::HL, regs[3]);
         self.regs.writew(self::RegsW::SP, 0xFFFE);
+        // The boot ROM's last instruction, at 0x00FE, unmaps itself.
+        self.regs.pc = 0x0100;
     }
 
-    fn print_stacktrace(&self) {
-        let mut sp = self.regs.sp;
-        let mut trace = "Stack:\n".to_owned();
-        while sp > 0xFF80 {
-            trace.push_str(&format!("0x{:04x}\n", self.interconnect.readw(sp)));
-            sp -= 2;
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        disasm::disassemble(|a| self.interconnect.peek(a), addr)
+    }
+
+    // Calls that haven't returned yet, outermost first.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.calls
+    }
+
+    fn backtrace(&self) -> String {
+        let mut trace = "Calls, innermost first:".to_owned();
+        for frame in self.calls.iter().rev() {
+            trace.push_str(&format!("\n0x{:04x} {}, returning to 0x{:04x}",
+                                    frame.target,
+                                    if frame.interrupt { "interrupt" } else { "call" },
+                                    frame.ret));
         }
-        println!("{}", trace);
+        trace
     }
 
-    fn crash(&self, cause: String) -> ! {
+    // Record a call once its return address has been pushed.
+    fn enter_call(&mut self, target: u16, ret: u16, interrupt: bool) {
+        let sp = self.regs.sp;
+        // Calls whose return addresses are at or below SP have been
+        // abandoned, e.g. by loading SP.
+        while self.calls.last().map_or(false, |f| f.sp <= sp) {
+            self.calls.pop();
+        }
+        if self.calls.len() == CALL_STACK_DEPTH {
+            self.calls.remove(0);
+        }
+        self.calls.push(CallFrame {
+            target: target,
+            ret: ret,
+            sp: sp,
+            interrupt: interrupt,
+        });
+    }
+
+    // Record a return that popped its address from sp. Calls below sp have
+    // been abandoned too. A return that matches no call, e.g. a PUSH and RET
+    // used as a jump, leaves the rest alone.
+    fn leave_call(&mut self, sp: u16) {
+        while self.calls.last().map_or(false, |f| f.sp < sp) {
+            self.calls.pop();
+        }
+        if self.calls.last().map_or(false, |f| f.sp == sp) {
+            self.calls.pop();
+        }
+    }
+
+    // The bytes around PC, with the last one fetched in brackets.
+    fn code_around_pc(&self) -> String {
         let mut code: String = "Code:".to_owned();
-        for pc in (self.regs.pc - 5)..(self.regs.pc + 4) {
-            if (pc + 1) == self.regs.pc {
-                code.push_str(&format!(" [0x{:02x}]", self.interconnect.readb(pc)));
+        for pc in self.regs.pc.saturating_sub(5)..self.regs.pc.saturating_add(4) {
+            if pc.wrapping_add(1) == self.regs.pc {
+                code.push_str(&format!(" [0x{:02x}]", self.interconnect.peek(pc)));
             } else {
-                code.push_str(&format!(" 0x{:02x}", self.interconnect.readb(pc)));
+                code.push_str(&format!(" 0x{:02x}", self.interconnect.peek(pc)));
             }
         }
-        println!("{}", code);
+        code
+    }
 
-        if self.regs.sp < 0xFFFE {
-            self.print_stacktrace();
+    // Stop the CPU for good, logging everything that might help to work out
+    // how it got here. The rest of the system carries on, so nothing is lost.
+    fn crash(&mut self, fault: Fault) -> u32 {
+        error!("{}, the CPU has stopped", fault);
+        if let Some(ref history) = self.history {
+            error!("The last {} instructions, ending with this one:", history.len());
+            for entry in history.iter() {
+                error!("{}", entry);
+            }
         }
+        error!("{}", self.code_around_pc());
+        error!("Instruction: {}", self.disassemble(self.regs.pc.wrapping_sub(1)).0);
 
-        println!("{:#?}", self);
-        panic!("{}", cause);
+        if !self.calls.is_empty() {
+            error!("{}", self.backtrace());
+        }
+
+        error!("{:#?}", self);
+        self.fault = Some(fault);
+        4
     }
 
     pub fn iaddr(&mut self, ia: IndirectAddr) -> u16 {
@@ -322,595 +1327,253 @@ impl Cpu {
     }
 
     pub fn fetchb(&mut self) -> u8 {
-        let val = self.interconnect.readb(self.regs.pc);
-        self.regs.pc += 1;
+        let pc = self.regs.pc;
+        let val = self.read(pc);
+        self.regs.pc = pc.wrapping_add(1);
         val
     }
 
     pub fn fetchw(&mut self) -> u16 {
-        let val = self.interconnect.readw(self.regs.pc);
-        self.regs.pc += 2;
-        val
+        let lo = self.fetchb() as u16;
+        (self.fetchb() as u16) << 8 | lo
+    }
+
+    // Every memory access that an instruction makes takes a machine cycle,
+    // and the rest of the system is stepped through it before the access
+    // happens. Any cycles that an instruction spends without touching memory
+    // are stepped once it's done.
+    fn read(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.interconnect.readb(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.tick();
+        self.interconnect.writeb(addr, val);
+    }
+
+    fn tick(&mut self) {
+        self.stepped += self.interconnect.step(4);
     }
 
     pub fn set_flag(&mut self, flag: Flags, enable: bool) {
-        let bit = flag as u8;
-        self.regs.f = if enable {
-            self.regs.f | bit
+        if enable {
+            self.regs.f.insert(flag);
         } else {
-            self.regs.f & !bit
-        };
+            self.regs.f.remove(flag);
+        }
     }
 
     pub fn check_flag(&self, flag: Flags) -> bool {
-        self.regs.f & (flag as u8) > 0
+        self.regs.f.contains(flag)
+    }
+
+    pub fn read_reg_b(&self, reg: RegsB) -> u8 {
+        self.regs.readb(reg)
     }
 
-    fn handle_interrupts(&mut self) {
-        let int;
-        match self.interconnect.ic.get_interrupt() {
-            None => return,
-            Some(i) => int = i,
+    pub fn write_reg_b(&mut self, reg: RegsB, val: u8) {
+        self.regs.writeb(reg, val);
+    }
+
+    pub fn read_reg_w(&self, reg: RegsW) -> u16 {
+        self.regs.readw(reg)
+    }
+
+    pub fn write_reg_w(&mut self, reg: RegsW, val: u16) {
+        self.regs.writew(reg, val);
+    }
+
+    // Dispatch takes five machine cycles: two waiting, two pushing PC and
+    // one jumping to the handler. Returns how many cycles that was, if an
+    // interrupt was pending.
+    fn handle_interrupts(&mut self) -> u32 {
+        if self.interconnect.ic().pending().is_none() {
+            return 0;
         }
 
-        self.interconnect.ic.reset_interrupt(int);
+        self.tick();
+        self.tick();
+
+        // PC is pushed a byte at a time, and which interrupt to dispatch is
+        // only decided after the high byte. If that lands on IE and clears
+        // the pending interrupt the dispatch is cancelled, jumping to 0x0000
+        // with IF left alone.
         let pc = self.regs.readw(self::RegsW::PC);
-        self.pushw(pc);
-        self.regs.writew(self::RegsW::PC, int.get_addr());
-        self.interconnect.ic.ime = false;
+        let sp = self.regs.readw(self::RegsW::SP).wrapping_sub(1);
+        self.write(sp, (pc >> 8) as u8);
+        let int = self.interconnect.ic().pending();
+        let sp = sp.wrapping_sub(1);
+        self.write(sp, pc as u8);
+        self.regs.writew(self::RegsW::SP, sp);
+
+        let addr = match int {
+            Some(int) => {
+                self.interconnect.ic_mut().acknowledge(int);
+                int.get_addr()
+            }
+            None => 0x0000,
+        };
+        self.regs.writew(self::RegsW::PC, addr);
+        self.interconnect.ic_mut().set_ime(false);
+        self.enter_call(addr, pc, true);
+        self.tick();
+
+        // The rest of the system has been stepped through all of it, and
+        // none of it belongs to the instruction that follows.
+        let ticks = self.stepped;
+        self.stepped = 0;
+        self.clk.add_cycles(ticks);
+        ticks
+    }
+
+    // Execute one instruction, servicing an interrupt first if there is one,
+    // and return the cycles taken by both. Stopping at a breakpoint executes
+    // nothing and leaves the CPU exactly as it was, and the next step carries
+    // on from there.
+    pub fn step(&mut self) -> Result<u32, BreakReason> {
+        self.step_within(MAX_IDLE_CYCLES)
+    }
+
+    // Like step, but a locked or halted CPU lets no more than limit cycles
+    // pass, or a machine cycle if limit is shorter, so that running to a
+    // budget never overshoots it by more than an instruction.
+    pub fn step_within(&mut self, limit: u32) -> Result<u32, BreakReason> {
+        // A locked CPU does nothing, not even service interrupts, but the
+        // rest of the system carries on. So does a halted one, until there's
+        // an interrupt to wake it.
+        if self.fault.is_some() || !self.wake() {
+            return Ok(self.idle(limit));
+        }
+
+        if self.watching() {
+            try!(self.check_breakpoint());
+        }
+
+        let mut dispatch = 0;
+        if self.interconnect.ic().ime() {
+            dispatch = self.handle_interrupts();
+            // To catch breakpoints on interrupt handlers too, the dispatch is
+            // returned on its own, and the next step checks the handler
+            // before executing it.
+            if dispatch > 0 && self.watching() {
+                return Ok(dispatch);
+            }
+        }
+
+        Ok(dispatch + self.execute())
+    }
+
+    // Execute the instruction at PC and step the rest of the system by as
+    // long as it took.
+    fn execute(&mut self) -> u32 {
+        if self.profiler.is_some() {
+            return self.execute_profiled();
+        }
+        self.execute_unprofiled()
+    }
+
+    // The opcode is read beforehand, as the instruction could switch the bank
+    // it's in.
+    fn execute_profiled(&mut self) -> u32 {
+        let pc = self.regs.pc;
+        let opcode = self.interconnect.peek(pc);
+        let cb = if opcode == 0xCB {
+            Some(self.interconnect.peek(pc.wrapping_add(1)))
+        } else {
+            None
+        };
+        let ticks = self.execute_unprofiled();
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, opcode, cb, ticks);
+        }
+        ticks
     }
 
-    pub fn step(&mut self) -> u32 {
-        if self.interconnect.ic.ime {
-            self.handle_interrupts();
+    fn execute_unprofiled(&mut self) -> u32 {
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        if self.history.is_some() {
+            let entry = self.trace_entry();
+            if let Some(ref mut history) = self.history {
+                history.push(entry);
+            }
+        }
+        if cfg!(feature = "verbose") {
+            debug!("{}", self.trace_entry());
         }
 
+        // An EI before this instruction takes effect once it's done, unless
+        // it's a DI.
+        let ime_pending = self.interconnect.ic().ime_pending();
         let ticks = self.dexec();
         self.clk.add_cycles(ticks);
-
-        if cfg!(debug_assertions) {
-            print!("\t F={:04b}", self.regs.f >> 4);
+        if ime_pending && self.interconnect.ic().ime_pending() {
+            self.interconnect.ic_mut().set_ime(true);
         }
 
-        self.interconnect.step(ticks)
+        // An instruction can't take less time than its memory accesses.
+        debug_assert!(ticks >= self.stepped,
+                      "{} cycles, but {} were stepped",
+                      ticks,
+                      self.stepped);
+        let internal = ticks.saturating_sub(self.stepped);
+        self.stepped = 0;
+        if internal > 0 {
+            self.interconnect.step(internal);
+        }
+        ticks
     }
 
     // Decode and execute, returning the number of ticks that execution took.
     pub fn dexec(&mut self) -> u32 {
-        use self::RegsW::*;
-        use self::RegsB::*;
+        // Called on its own, e.g. by tests, nothing steps whatever is left
+        // of the instruction afterwards.
+        self.stepped = 0;
         let op = self.fetchb();
-        if cfg!(debug_assertions) {
-            print!("\n0x{:02x}@0x{:04x}:", op, self.regs.pc - 1);
-        }
-        match op {
-            0x00 => self.nop(),
-            0x01 => self.ldw(BC, self::AddressW),
-            0x02 => self.ld(self::IndirectAddr::BC, A),
-            0x03 => self.incw(BC),
-            0x04 => self.inc(B),
-            0x05 => self.dec(B),
-            0x06 => self.ld(B, self::ImmediateB),
-            0x07 => self.rlca(),
-            0x08 => self.ldw_nn_sp(),
-            0x09 => self.addw(BC),
-            0x10 => self.stop(),
-            0x0A => self.ld(A, self::IndirectAddr::BC),
-            0x0B => self.decw(BC),
-            0x0C => self.inc(C),
-            0x0D => self.dec(C),
-            0x0E => self.ld(C, self::ImmediateB),
-            0x0F => self.rrca(),
-            0x11 => self.ldw(DE, self::AddressW),
-            0x12 => self.ld(self::IndirectAddr::DE, A),
-            0x13 => self.incw(DE),
-            0x14 => self.inc(D),
-            0x15 => self.dec(D),
-            0x16 => self.ld(D, self::ImmediateB),
-            0x17 => self.rla(),
-            0x18 => self.jr(),
-            0x19 => self.addw(DE),
-            0x1A => self.ld(A, self::IndirectAddr::DE),
-            0x1B => self.decw(DE),
-            0x1C => self.inc(E),
-            0x1D => self.dec(E),
-            0x1E => self.ld(E, self::ImmediateB),
-            0x1F => self.rra(),
-            0x20 => self.jr_cond(self::Condition::NZ),
-            0x21 => self.ldw(HL, self::AddressW),
-            0x22 => self.ld(self::IndirectAddr::HLP, A),
-            0x23 => self.incw(HL),
-            0x24 => self.inc(H),
-            0x25 => self.dec(H),
-            0x26 => self.ld(H, self::ImmediateB),
-            0x28 => self.jr_cond(self::Condition::Z),
-            0x29 => self.addw(HL),
-            0x2A => self.ld(A, self::IndirectAddr::HLP),
-            0x2B => self.decw(HL),
-            0x2C => self.inc(L),
-            0x2D => self.dec(L),
-            0x2E => self.ld(L, self::ImmediateB),
-            0x2F => self.cpl(),
-            0x30 => self.jr_cond(self::Condition::NC),
-            0x31 => self.ldw(SP, self::AddressW),
-            0x32 => self.ld(self::IndirectAddr::HLM, A),
-            0x33 => self.incw(SP),
-            0x34 => self.inc(self::IndirectAddr::HL),
-            0x35 => self.dec(self::IndirectAddr::HL),
-            0x36 => self.ld(self::IndirectAddr::HL, self::ImmediateB),
-            0x37 => self.scf(),
-            0x38 => self.jr_cond(self::Condition::C),
-            0x39 => self.addw(SP),
-            0x3A => self.ld(A, self::IndirectAddr::HLM),
-            0x3B => self.decw(SP),
-            0x3C => self.inc(A),
-            0x3D => self.dec(A),
-            0x3E => self.ld(A, self::ImmediateB),
-            0x3F => self.ccf(),
-            0x40 => self.ld(B, B),
-            0x41 => self.ld(B, C),
-            0x42 => self.ld(B, D),
-            0x43 => self.ld(B, E),
-            0x44 => self.ld(B, H),
-            0x45 => self.ld(B, L),
-            0x46 => self.ld(B, self::IndirectAddr::HL),
-            0x47 => self.ld(B, A),
-            0x48 => self.ld(C, B),
-            0x49 => self.ld(C, C),
-            0x4A => self.ld(C, D),
-            0x4B => self.ld(C, E),
-            0x4C => self.ld(C, H),
-            0x4D => self.ld(C, L),
-            0x4E => self.ld(C, self::IndirectAddr::HL),
-            0x4F => self.ld(C, A),
-            0x50 => self.ld(D, B),
-            0x51 => self.ld(D, C),
-            0x52 => self.ld(D, D),
-            0x53 => self.ld(D, E),
-            0x54 => self.ld(D, H),
-            0x55 => self.ld(D, L),
-            0x56 => self.ld(D, self::IndirectAddr::HL),
-            0x57 => self.ld(D, A),
-            0x58 => self.ld(E, B),
-            0x59 => self.ld(E, C),
-            0x5A => self.ld(E, D),
-            0x5B => self.ld(E, E),
-            0x5C => self.ld(E, H),
-            0x5D => self.ld(E, L),
-            0x5E => self.ld(E, self::IndirectAddr::HL),
-            0x5F => self.ld(E, A),
-            0x60 => self.ld(H, B),
-            0x61 => self.ld(H, C),
-            0x62 => self.ld(H, D),
-            0x63 => self.ld(H, E),
-            0x64 => self.ld(H, H),
-            0x65 => self.ld(H, L),
-            0x66 => self.ld(H, self::IndirectAddr::HL),
-            0x67 => self.ld(H, A),
-            0x68 => self.ld(L, B),
-            0x69 => self.ld(L, C),
-            0x6A => self.ld(L, D),
-            0x6B => self.ld(L, E),
-            0x6C => self.ld(L, H),
-            0x6D => self.ld(L, L),
-            0x6E => self.ld(L, self::IndirectAddr::HL),
-            0x6F => self.ld(L, A),
-            0x70 => self.ld(self::IndirectAddr::HL, B),
-            0x71 => self.ld(self::IndirectAddr::HL, C),
-            0x72 => self.ld(self::IndirectAddr::HL, D),
-            0x73 => self.ld(self::IndirectAddr::HL, E),
-            0x74 => self.ld(self::IndirectAddr::HL, H),
-            0x75 => self.ld(self::IndirectAddr::HL, L),
-            0x77 => self.ld(self::IndirectAddr::HL, A),
-            0x78 => self.ld(A, B),
-            0x79 => self.ld(A, C),
-            0x7A => self.ld(A, D),
-            0x7B => self.ld(A, E),
-            0x7C => self.ld(A, H),
-            0x7D => self.ld(A, L),
-            0x7E => self.ld(A, self::IndirectAddr::HL),
-            0x7F => self.ld(A, A),
-            0x80 => self.add(B),
-            0x81 => self.add(C),
-            0x82 => self.add(D),
-            0x83 => self.add(E),
-            0x84 => self.add(H),
-            0x85 => self.add(L),
-            0x86 => self.add(self::IndirectAddr::HL),
-            0x87 => self.add(A),
-            0x88 => self.adc(B),
-            0x89 => self.adc(C),
-            0x8A => self.adc(D),
-            0x8B => self.adc(E),
-            0x8C => self.adc(H),
-            0x8D => self.adc(L),
-            0x8E => self.adc(self::IndirectAddr::HL),
-            0x8F => self.adc(A),
-            0x90 => self.sub(B),
-            0x91 => self.sub(C),
-            0x92 => self.sub(D),
-            0x93 => self.sub(E),
-            0x94 => self.sub(H),
-            0x95 => self.sub(L),
-            0x96 => self.sub(self::IndirectAddr::HL),
-            0x97 => self.sub(A),
-            0x98 => self.sbc(B),
-            0x99 => self.sbc(C),
-            0x9A => self.sbc(D),
-            0x9B => self.sbc(E),
-            0x9C => self.sbc(H),
-            0x9D => self.sbc(L),
-            0x9E => self.sbc(self::IndirectAddr::HL),
-            0x9F => self.sbc(A),
-            0xA0 => self.and(B),
-            0xA1 => self.and(C),
-            0xA2 => self.and(D),
-            0xA3 => self.and(E),
-            0xA4 => self.and(H),
-            0xA5 => self.and(L),
-            0xA6 => self.and(self::IndirectAddr::HL),
-            0xA7 => self.and(A),
-            0xA8 => self.xor(B),
-            0xA9 => self.xor(C),
-            0xAA => self.xor(D),
-            0xAB => self.xor(E),
-            0xAC => self.xor(H),
-            0xAD => self.xor(L),
-            0xAE => self.xor(self::IndirectAddr::HL),
-            0xAF => self.xor(A),
-            0xB0 => self.or(B),
-            0xB1 => self.or(C),
-            0xB2 => self.or(D),
-            0xB3 => self.or(E),
-            0xB4 => self.or(H),
-            0xB5 => self.or(L),
-            0xB6 => self.or(self::IndirectAddr::HL),
-            0xB7 => self.or(A),
-            0xB8 => self.cp(B),
-            0xB9 => self.cp(C),
-            0xBA => self.cp(D),
-            0xBB => self.cp(E),
-            0xBC => self.cp(H),
-            0xBD => self.cp(L),
-            0xBE => self.cp(self::IndirectAddr::HL),
-            0xBF => self.cp(A),
-            0xC0 => self.ret_cond(self::Condition::NZ),
-            0xC1 => self.pop(BC),
-            0xC2 => self.jp_cond(self::Condition::NZ),
-            0xC3 => self.jp(self::AddressW),
-            0xC4 => self.call_cond(self::Condition::NZ),
-            0xC5 => self.push(BC),
-            0xC6 => self.add(self::ImmediateB),
-            0xC7 => self.rst(0x00),
-            0xC8 => self.ret_cond(self::Condition::Z),
-            0xC9 => self.ret(),
-            0xCA => self.jp_cond(self::Condition::Z),
-            0xCB => self.cb_dexec(),
-            0xCC => self.call_cond(self::Condition::Z),
-            0xCD => self.call(),
-            0xCE => self.adc(self::ImmediateB),
-            0xCF => self.rst(0x08),
-            0xD0 => self.ret_cond(self::Condition::NC),
-            0xD1 => self.pop(DE),
-            0xD2 => self.jp_cond(self::Condition::NC),
-            0xD4 => self.call_cond(self::Condition::NC),
-            0xD5 => self.push(DE),
-            0xD6 => self.sub(self::ImmediateB),
-            0xD7 => self.rst(0x10),
-            0xD8 => self.ret_cond(self::Condition::C),
-            0xD9 => self.reti(),
-            0xDA => self.jp_cond(self::Condition::C),
-            0xDC => self.call_cond(self::Condition::C),
-            0xDE => self.sbc(self::ImmediateB),
-            0xDF => self.rst(0x18),
-            0xE0 => self.ld(self::IndirectAddr::ZeroPage, A), // LDH
-            0xE1 => self.pop(HL),
-            0xE2 => self.ld(self::IndirectAddr::ZeroPageC, A), // LDH
-            0xE5 => self.push(HL),
-            0xE6 => self.and(self::ImmediateB),
-            0xE7 => self.rst(0x20),
-            0xE8 => self.addw_sp(),
-            0xE9 => self.jp(HL),
-            0xEA => self.ld(self::IndirectAddr::AddressW, A),
-            0xEE => self.xor(self::ImmediateB),
-            0xEF => self.rst(0x28),
-            0xF0 => self.ld(A, self::IndirectAddr::ZeroPage), // LDH
-            0xF1 => self.pop(AF),
-            0xF2 => self.ld(A, self::IndirectAddr::ZeroPageC), // LDH
-            0xF3 => self.di(),
-            0xF5 => self.push(AF),
-            0xF6 => self.or(self::ImmediateB),
-            0xF7 => self.rst(0x30),
-            0xF8 => self.ldw_hl_sp(),
-            0xF9 => self.ldw(SP, HL),
-            0xFA => self.ld(A, self::IndirectAddr::AddressW),
-            0xFB => self.ei(),
-            0xFE => self.cp(self::ImmediateB),
-            0xFF => self.rst(0x38),
-            inv => {
-                self.crash(format!("The instruction 0x{:02x}@0x{:04x} isn't implemented",
-                                   inv,
-                                   self.regs.pc));
-            }
-        }
+        Self::OPCODES[op as usize](self)
     }
 
     fn cb_dexec(&mut self) -> u32 {
-        use self::RegsB::*;
         let op = self.fetchb();
-        if cfg!(debug_assertions) {
-            print!("\n0x{:02x}@0x{:04x}?", op, self.regs.pc - 1);
-        }
-        match op {
-            0x00 => self.rlc(B),
-            0x01 => self.rlc(C),
-            0x02 => self.rlc(D),
-            0x03 => self.rlc(E),
-            0x04 => self.rlc(H),
-            0x05 => self.rlc(L),
-            0x06 => self.rlc(self::IndirectAddr::HL),
-            0x07 => self.rlc(A),
-            0x08 => self.rrc(B),
-            0x09 => self.rrc(C),
-            0x0A => self.rrc(D),
-            0x0B => self.rrc(E),
-            0x0C => self.rrc(H),
-            0x0D => self.rrc(L),
-            0x0E => self.rrc(self::IndirectAddr::HL),
-            0x0F => self.rrc(A),
-            0x10 => self.rl(B),
-            0x11 => self.rl(C),
-            0x12 => self.rl(D),
-            0x13 => self.rl(E),
-            0x14 => self.rl(H),
-            0x15 => self.rl(L),
-            0x16 => self.rl(self::IndirectAddr::HL),
-            0x17 => self.rl(A),
-            0x18 => self.rr(B),
-            0x19 => self.rr(C),
-            0x1A => self.rr(D),
-            0x1B => self.rr(E),
-            0x1C => self.rr(H),
-            0x1D => self.rr(L),
-            0x1E => self.rr(self::IndirectAddr::HL),
-            0x1F => self.rr(A),
-            0x20 => self.sla(B),
-            0x21 => self.sla(C),
-            0x22 => self.sla(D),
-            0x23 => self.sla(E),
-            0x24 => self.sla(H),
-            0x25 => self.sla(L),
-            0x26 => self.sla(self::IndirectAddr::HL),
-            0x27 => self.sla(A),
-            0x28 => self.sra(B),
-            0x29 => self.sra(C),
-            0x2A => self.sra(D),
-            0x2B => self.sra(E),
-            0x2C => self.sra(H),
-            0x2D => self.sra(L),
-            0x2E => self.sra(self::IndirectAddr::HL),
-            0x2F => self.sra(A),
-            0x30 => self.swap(B),
-            0x31 => self.swap(C),
-            0x32 => self.swap(D),
-            0x33 => self.swap(E),
-            0x34 => self.swap(H),
-            0x35 => self.swap(L),
-            0x36 => self.swap(self::IndirectAddr::HL),
-            0x37 => self.swap(A),
-            0x38 => self.srl(B),
-            0x39 => self.srl(C),
-            0x3A => self.srl(D),
-            0x3B => self.srl(E),
-            0x3C => self.srl(H),
-            0x3D => self.srl(L),
-            0x3E => self.srl(self::IndirectAddr::HL),
-            0x3F => self.srl(A),
-            0x40 => self.bit(0, B),
-            0x41 => self.bit(0, C),
-            0x42 => self.bit(0, D),
-            0x43 => self.bit(0, E),
-            0x44 => self.bit(0, H),
-            0x45 => self.bit(0, L),
-            0x46 => self.bit(0, self::IndirectAddr::HL),
-            0x47 => self.bit(0, A),
-            0x48 => self.bit(1, B),
-            0x49 => self.bit(1, C),
-            0x4A => self.bit(1, D),
-            0x4B => self.bit(1, E),
-            0x4C => self.bit(1, H),
-            0x4D => self.bit(1, L),
-            0x4E => self.bit(1, self::IndirectAddr::HL),
-            0x4F => self.bit(1, A),
-            0x50 => self.bit(2, B),
-            0x51 => self.bit(2, C),
-            0x52 => self.bit(2, D),
-            0x53 => self.bit(2, E),
-            0x54 => self.bit(2, H),
-            0x55 => self.bit(2, L),
-            0x56 => self.bit(2, self::IndirectAddr::HL),
-            0x57 => self.bit(2, A),
-            0x58 => self.bit(3, B),
-            0x59 => self.bit(3, C),
-            0x5A => self.bit(3, D),
-            0x5B => self.bit(3, E),
-            0x5C => self.bit(3, H),
-            0x5D => self.bit(3, L),
-            0x5E => self.bit(3, self::IndirectAddr::HL),
-            0x5F => self.bit(3, A),
-            0x60 => self.bit(4, B),
-            0x61 => self.bit(4, C),
-            0x62 => self.bit(4, D),
-            0x63 => self.bit(4, E),
-            0x64 => self.bit(4, H),
-            0x65 => self.bit(4, L),
-            0x66 => self.bit(4, self::IndirectAddr::HL),
-            0x67 => self.bit(4, A),
-            0x68 => self.bit(5, B),
-            0x69 => self.bit(5, C),
-            0x6A => self.bit(5, D),
-            0x6B => self.bit(5, E),
-            0x6C => self.bit(5, H),
-            0x6D => self.bit(5, L),
-            0x6E => self.bit(5, self::IndirectAddr::HL),
-            0x6F => self.bit(5, A),
-            0x70 => self.bit(6, B),
-            0x71 => self.bit(6, C),
-            0x72 => self.bit(6, D),
-            0x73 => self.bit(6, E),
-            0x74 => self.bit(6, H),
-            0x75 => self.bit(6, L),
-            0x76 => self.bit(6, self::IndirectAddr::HL),
-            0x77 => self.bit(6, A),
-            0x78 => self.bit(7, B),
-            0x79 => self.bit(7, C),
-            0x7A => self.bit(7, D),
-            0x7B => self.bit(7, E),
-            0x7C => self.bit(7, H),
-            0x7D => self.bit(7, L),
-            0x7E => self.bit(7, self::IndirectAddr::HL),
-            0x7F => self.bit(7, A),
-            0x80 => self.res(0, B),
-            0x81 => self.res(0, C),
-            0x82 => self.res(0, D),
-            0x83 => self.res(0, E),
-            0x84 => self.res(0, H),
-            0x85 => self.res(0, L),
-            0x86 => self.res(0, self::IndirectAddr::HL),
-            0x87 => self.res(0, A),
-            0x88 => self.res(1, B),
-            0x89 => self.res(1, C),
-            0x8A => self.res(1, D),
-            0x8B => self.res(1, E),
-            0x8C => self.res(1, H),
-            0x8D => self.res(1, L),
-            0x8E => self.res(1, self::IndirectAddr::HL),
-            0x8F => self.res(1, A),
-            0x90 => self.res(2, B),
-            0x91 => self.res(2, C),
-            0x92 => self.res(2, D),
-            0x93 => self.res(2, E),
-            0x94 => self.res(2, H),
-            0x95 => self.res(2, L),
-            0x96 => self.res(2, self::IndirectAddr::HL),
-            0x97 => self.res(2, A),
-            0x98 => self.res(3, B),
-            0x99 => self.res(3, C),
-            0x9A => self.res(3, D),
-            0x9B => self.res(3, E),
-            0x9C => self.res(3, H),
-            0x9D => self.res(3, L),
-            0x9E => self.res(3, self::IndirectAddr::HL),
-            0x9F => self.res(3, A),
-            0xA0 => self.res(4, B),
-            0xA1 => self.res(4, C),
-            0xA2 => self.res(4, D),
-            0xA3 => self.res(4, E),
-            0xA4 => self.res(4, H),
-            0xA5 => self.res(4, L),
-            0xA6 => self.res(4, self::IndirectAddr::HL),
-            0xA7 => self.res(4, A),
-            0xA8 => self.res(5, B),
-            0xA9 => self.res(5, C),
-            0xAA => self.res(5, D),
-            0xAB => self.res(5, E),
-            0xAC => self.res(5, H),
-            0xAD => self.res(5, L),
-            0xAE => self.res(5, self::IndirectAddr::HL),
-            0xAF => self.res(5, A),
-            0xB0 => self.res(6, B),
-            0xB1 => self.res(6, C),
-            0xB2 => self.res(6, D),
-            0xB3 => self.res(6, E),
-            0xB4 => self.res(6, H),
-            0xB5 => self.res(6, L),
-            0xB6 => self.res(6, self::IndirectAddr::HL),
-            0xB7 => self.res(6, A),
-            0xB8 => self.res(7, B),
-            0xB9 => self.res(7, C),
-            0xBA => self.res(7, D),
-            0xBB => self.res(7, E),
-            0xBC => self.res(7, H),
-            0xBD => self.res(7, L),
-            0xBE => self.res(7, self::IndirectAddr::HL),
-            0xBF => self.res(7, A),
-            0xC0 => self.set(0, B),
-            0xC1 => self.set(0, C),
-            0xC2 => self.set(0, D),
-            0xC3 => self.set(0, E),
-            0xC4 => self.set(0, H),
-            0xC5 => self.set(0, L),
-            0xC6 => self.set(0, self::IndirectAddr::HL),
-            0xC7 => self.set(0, A),
-            0xC8 => self.set(1, B),
-            0xC9 => self.set(1, C),
-            0xCA => self.set(1, D),
-            0xCB => self.set(1, E),
-            0xCC => self.set(1, H),
-            0xCD => self.set(1, L),
-            0xCE => self.set(1, self::IndirectAddr::HL),
-            0xCF => self.set(1, A),
-            0xD0 => self.set(2, B),
-            0xD1 => self.set(2, C),
-            0xD2 => self.set(2, D),
-            0xD3 => self.set(2, E),
-            0xD4 => self.set(2, H),
-            0xD5 => self.set(2, L),
-            0xD6 => self.set(2, self::IndirectAddr::HL),
-            0xD7 => self.set(2, A),
-            0xD8 => self.set(3, B),
-            0xD9 => self.set(3, C),
-            0xDA => self.set(3, D),
-            0xDB => self.set(3, E),
-            0xDC => self.set(3, H),
-            0xDD => self.set(3, L),
-            0xDE => self.set(3, self::IndirectAddr::HL),
-            0xDF => self.set(3, A),
-            0xE0 => self.set(4, B),
-            0xE1 => self.set(4, C),
-            0xE2 => self.set(4, D),
-            0xE3 => self.set(4, E),
-            0xE4 => self.set(4, H),
-            0xE5 => self.set(4, L),
-            0xE6 => self.set(4, self::IndirectAddr::HL),
-            0xE7 => self.set(4, A),
-            0xE8 => self.set(5, B),
-            0xE9 => self.set(5, C),
-            0xEA => self.set(5, D),
-            0xEB => self.set(5, E),
-            0xEC => self.set(5, H),
-            0xED => self.set(5, L),
-            0xEE => self.set(5, self::IndirectAddr::HL),
-            0xEF => self.set(5, A),
-            0xF0 => self.set(6, B),
-            0xF1 => self.set(6, C),
-            0xF2 => self.set(6, D),
-            0xF3 => self.set(6, E),
-            0xF4 => self.set(6, H),
-            0xF5 => self.set(6, L),
-            0xF6 => self.set(6, self::IndirectAddr::HL),
-            0xF7 => self.set(6, A),
-            0xF8 => self.set(7, B),
-            0xF9 => self.set(7, C),
-            0xFA => self.set(7, D),
-            0xFB => self.set(7, E),
-            0xFC => self.set(7, H),
-            0xFD => self.set(7, L),
-            0xFE => self.set(7, self::IndirectAddr::HL),
-            0xFF => self.set(7, A),
-            inv => {
-                self.crash(format!("The CB instruction 0x{:02x}@0x{:04x} isn't implemented",
-                                   inv,
-                                   self.regs.pc));
-            }
-        }
+        Self::CB_OPCODES[op as usize](self)
+    }
+
+    // Opcodes that exist but haven't been written yet.
+    fn unimplemented(&mut self) -> u32 {
+        let addr = self.regs.pc.wrapping_sub(1);
+        let fault = Fault::Unimplemented {
+            opcode: self.interconnect.peek(addr),
+            addr: addr,
+        };
+        self.crash(fault)
+    }
+
+    // Undefined opcodes hang the CPU.
+    fn lock(&mut self, opcode: u8) -> u32 {
+        let fault = Fault::IllegalOpcode {
+            opcode: opcode,
+            addr: self.regs.pc.wrapping_sub(1),
+        };
+        warn!("{}, the CPU has locked up", fault);
+        self.fault = Some(fault);
+        4
+    }
+
+    // HALT
+    // Z N H C
+    // - - - - : 4
+    fn halt(&mut self) -> u32 {
+        self.halted = true;
+        4
     }
 
     // STOP
     // Z N H C
     // - - - - : 4
-    fn stop(&mut self) -> ! {
-        self.crash("STOP".to_owned());
+    fn stop(&mut self) -> u32 {
+        self.unimplemented()
     }
 
     // INC ss
@@ -935,29 +1598,24 @@ impl Cpu {
     // Z N H C
     // Z 0 H - : 4 | 12
     fn inc<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
-        use self::Flags::*;
         let val = addr.readb(self).wrapping_add(1);
-        self.set_flag(Z, val == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, (val & 0xF) == 0x0);
+        self.set_flag(FLAG_Z, val == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, (val & 0xF) == 0x0);
         addr.writeb(self, val);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        4 + addr.read_cycles() + addr.write_cycles()
     }
 
     // DEC r | (r)
     // Z N H C
-    //
-    // Z 1 H - : 4 | 8
+    // Z 1 H - : 4 | 12
     fn dec<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
-        use self::Flags::*;
         let val = addr.readb(self).wrapping_sub(1);
-        self.set_flag(Z, val == 0);
-        self.set_flag(N, true);
-        self.set_flag(H, (val & 0xF) == 0xF);
+        self.set_flag(FLAG_Z, val == 0);
+        self.set_flag(FLAG_N, true);
+        self.set_flag(FLAG_H, (val & 0xF) == 0xF);
         addr.writeb(self, val);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        4 + addr.read_cycles() + addr.write_cycles()
     }
 
     // LD d s | d (s) | (d) s | (d8) s | d (d8)
@@ -966,17 +1624,17 @@ impl Cpu {
     fn ld<O: WriteB, I: ReadB>(&mut self, o: O, i: I) -> u32 {
         let v = i.readb(self);
         o.writeb(self, v);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        4 + i.read_cycles() + o.write_cycles()
     }
 
-    // LD dd nn | dd d16
+    // LD SP HL | dd d16
     // Z N H C
     // - - - - : 8 | 12
     fn ldw<I: ReadW>(&mut self, dd: RegsW, i: I) -> u32 {
         let v = i.readw(self);
         self.regs.writew(dd, v);
-        8
+        // Copying a register pair takes a cycle of its own.
+        if i.read_cycles() == 0 { 8 } else { 4 + i.read_cycles() }
     }
 
     // LD (nn) SP
@@ -985,7 +1643,8 @@ impl Cpu {
     fn ldw_nn_sp(&mut self) -> u32 {
         let addr = self.fetchw();
         let sp = self.regs.readw(self::RegsW::SP);
-        self.interconnect.writew(addr, sp);
+        self.write(addr, sp as u8);
+        self.write(addr.wrapping_add(1), (sp >> 8) as u8);
         20
     }
 
@@ -993,15 +1652,8 @@ impl Cpu {
     // Z N H C
     // 0 0 H C : 12
     fn ldw_hl_sp(&mut self) -> u32 {
-        use self::Flags::*;
-        let offset = self.fetchb() as i8 as u16;
-        let sp = self.regs.readw(self::RegsW::SP);
-        self.regs.writew(self::RegsW::HL, sp.wrapping_add(offset));
-
-        self.set_flag(Z, false);
-        self.set_flag(N, false);
-        self.set_flag(H, (offset & 0x000F) + (sp & 0x000F) > 0x000F);
-        self.set_flag(C, (offset & 0x00FF) + (sp & 0x00FF) > 0x00FF);
+        let out = self.alu_add_sp();
+        self.regs.writew(self::RegsW::HL, out);
         12
     }
 
@@ -1009,14 +1661,13 @@ impl Cpu {
     // Z N H C
     // - 0 H C : 8
     fn addw(&mut self, ss: RegsW) -> u32 {
-        use self::Flags::*;
         let hl = self.regs.readw(self::RegsW::HL);
         let val = self.regs.readw(ss);
         let out = val.wrapping_add(hl);
 
-        self.set_flag(N, false);
-        self.set_flag(H, (hl & 0x07FF) > 0x07FF + (val & 0x07FF));
-        self.set_flag(C, hl > 0xFFFF - val);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, (hl & 0x0FFF) + (val & 0x0FFF) > 0x0FFF);
+        self.set_flag(FLAG_C, hl > 0xFFFF - val);
         self.regs.writew(self::RegsW::HL, out);
         8
     }
@@ -1026,19 +1677,24 @@ impl Cpu {
     // 0 0 H C : 16
     // TODO: Maybe we could treat r8 like ImmediateB - i.e. a pub struct...
     fn addw_sp(&mut self) -> u32 {
-        use self::Flags::*;
-        let sp = self.regs.readw(self::RegsW::SP);
-        let val = self.fetchb() as i8 as i16 as u16;
-        let out = sp.wrapping_add(val);
-
-        self.set_flag(Z, false);
-        self.set_flag(N, false);
-        self.set_flag(H, (sp & 0x07FF) > 0x07FF + (val & 0x07FF));
-        self.set_flag(C, sp > 0xFFFF - val);
+        let out = self.alu_add_sp();
         self.regs.writew(self::RegsW::SP, out);
         16
     }
 
+    // SP plus a signed immediate. The flags come from adding the offset to
+    // SP's low byte as if it were unsigned, whatever its sign.
+    fn alu_add_sp(&mut self) -> u16 {
+        let sp = self.regs.readw(self::RegsW::SP);
+        let offset = self.fetchb() as i8 as i16 as u16;
+
+        self.set_flag(FLAG_Z, false);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, (sp & 0x000F) + (offset & 0x000F) > 0x000F);
+        self.set_flag(FLAG_C, (sp & 0x00FF) + (offset & 0x00FF) > 0x00FF);
+        sp.wrapping_add(offset)
+    }
+
     // ADD s | (s) | d8
     // Z N H C
     // Z 0 H C : 4 | 8 | 8
@@ -1054,19 +1710,17 @@ impl Cpu {
     }
 
     fn alu_addb<I: ReadB>(&mut self, i: I, use_carry: bool) -> u32 {
-        use self::Flags::*;
         let a = self.regs.readb(self::RegsB::A);
         let val = i.readb(self);
-        let c = (self.check_flag(C) && use_carry) as u8;
+        let c = (self.check_flag(FLAG_C) && use_carry) as u8;
         let out = val.wrapping_add(a).wrapping_add(c);
 
-        self.set_flag(Z, out == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, (a & 0xF) + c > 0xF - (val & 0xF));
-        self.set_flag(C, a + c > 0xFF - val);
+        self.set_flag(FLAG_Z, out == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, (a & 0xF) + (val & 0xF) + c > 0xF);
+        self.set_flag(FLAG_C, a as u16 + val as u16 + c as u16 > 0xFF);
         self.regs.writeb(self::RegsB::A, out);
-        // FIXME: This isn't correct... :-(
-        4
+        4 + i.read_cycles()
     }
 
     // SUB s | (s) | d8
@@ -1084,19 +1738,17 @@ impl Cpu {
     }
 
     fn alu_subb<I: ReadB>(&mut self, i: I, use_carry: bool) -> u32 {
-        use self::Flags::*;
         let a = self.regs.readb(self::RegsB::A);
         let val = i.readb(self);
-        let c = (self.check_flag(C) && use_carry) as u8;
+        let c = (self.check_flag(FLAG_C) && use_carry) as u8;
         let out = a.wrapping_sub(val).wrapping_sub(c);
 
-        self.set_flag(Z, out == 0);
-        self.set_flag(N, true);
-        self.set_flag(H, a & 0xF < (val & 0xF) + c);
-        self.set_flag(C, (a as u16) < (val as u16) + (c as u16));
+        self.set_flag(FLAG_Z, out == 0);
+        self.set_flag(FLAG_N, true);
+        self.set_flag(FLAG_H, a & 0xF < (val & 0xF) + c);
+        self.set_flag(FLAG_C, (a as u16) < (val as u16) + (c as u16));
         self.regs.writeb(self::RegsB::A, out);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        4 + i.read_cycles()
     }
 
     // CP s | (s) | d8
@@ -1115,48 +1767,42 @@ impl Cpu {
     // Z N H C
     // Z 0 0 0 : 4 | 8 | 8
     fn or<I: ReadB>(&mut self, i: I) -> u32 {
-        use self::Flags::*;
         let mut v = i.readb(self);
         v |= self.regs.readb(self::RegsB::A);
         self.regs.writeb(self::RegsB::A, v);
-        self.set_flag(Z, v == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, false);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, false);
+        4 + i.read_cycles()
     }
 
     // XOR s | (s) | d8
     // Z N H C
     // Z 0 0 0 : 4 | 8 | 8
     fn xor<I: ReadB>(&mut self, i: I) -> u32 {
-        use self::Flags::*;
         let mut v = i.readb(self);
         v ^= self.regs.readb(self::RegsB::A);
         self.regs.writeb(self::RegsB::A, v);
-        self.set_flag(Z, v == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, false);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, false);
+        4 + i.read_cycles()
     }
 
     // AND s | (s) | d8
     // Z N H C
     // Z 0 1 0 : 4 | 8 | 8
     fn and<I: ReadB>(&mut self, i: I) -> u32 {
-        use self::Flags::*;
         let mut v = i.readb(self);
         v &= self.regs.readb(self::RegsB::A);
         self.regs.writeb(self::RegsB::A, v);
-        self.set_flag(Z, v == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, true);
-        self.set_flag(C, false);
-        // TODO: Need to reflect how the timing is different for (r) and r.
-        4
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, true);
+        self.set_flag(FLAG_C, false);
+        4 + i.read_cycles()
     }
 
     // NOP
@@ -1171,10 +1817,41 @@ impl Cpu {
     // - 1 1 - : 4
     // Cyberathlete Professional League?
     fn cpl(&mut self) -> u32 {
-        use self::Flags::*;
         let val = !self.regs.readb(self::RegsB::A);
-        self.set_flag(N, true);
-        self.set_flag(H, true);
+        self.set_flag(FLAG_N, true);
+        self.set_flag(FLAG_H, true);
+        self.regs.writeb(self::RegsB::A, val);
+        4
+    }
+
+    // DAA
+    // Z N H C
+    // Z - 0 C : 4
+    //
+    // Correct A after adding or subtracting two BCD numbers, going by N to
+    // tell which it was.
+    fn daa(&mut self) -> u32 {
+        let mut val = self.regs.readb(self::RegsB::A);
+        let mut carry = self.check_flag(FLAG_C);
+        if !self.check_flag(FLAG_N) {
+            if carry || val > 0x99 {
+                val = val.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.check_flag(FLAG_H) || val & 0x0F > 0x09 {
+                val = val.wrapping_add(0x06);
+            }
+        } else {
+            if carry {
+                val = val.wrapping_sub(0x60);
+            }
+            if self.check_flag(FLAG_H) {
+                val = val.wrapping_sub(0x06);
+            }
+        }
+        self.set_flag(FLAG_Z, val == 0);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, carry);
         self.regs.writeb(self::RegsB::A, val);
         4
     }
@@ -1183,11 +1860,10 @@ impl Cpu {
     // Z N H C
     // - 0 0 C : 4
     fn ccf(&mut self) -> u32 {
-        use self::Flags::*;
-        let c = self.check_flag(C);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, !c);
+        let c = self.check_flag(FLAG_C);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, !c);
         4
     }
 
@@ -1195,20 +1871,20 @@ impl Cpu {
     // Z N H C
     // - 0 0 1 : 4
     fn scf(&mut self) -> u32 {
-        use self::Flags::*;
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, true);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, true);
         4
     }
 
-    // JP nn
+    // JP nn | HL
     // Z N H C
-    // - - - - : 4
+    // - - - - : 16 | 4
     fn jp<I: ReadW>(&mut self, i: I) -> u32 {
         let addr = i.readw(self);
         self.regs.writew(self::RegsW::PC, addr);
-        16
+        // Jumping to an immediate address takes a cycle of its own.
+        if i.read_cycles() == 0 { 4 } else { 8 + i.read_cycles() }
     }
 
     // JP cc nn
@@ -1216,7 +1892,7 @@ impl Cpu {
     // - - - - : 16/12
     fn jp_cond(&mut self, c: Condition) -> u32 {
         let addr = self.fetchw();
-        if !c.test(self) {
+        if !c.test(self.regs.f) {
             return 12;
         }
         self.regs.writew(self::RegsW::PC, addr);
@@ -1227,9 +1903,9 @@ impl Cpu {
     // Z N H C
     // - - - - : 12
     fn jr(&mut self) -> u32 {
-        let mut addr = self.fetchb() as i8 as i16;
-        addr += self.regs.readw(self::RegsW::PC) as i16;
-        self.regs.writew(self::RegsW::PC, addr as u16);
+        let offset = self.fetchb() as i8 as u16;
+        let addr = self.regs.readw(self::RegsW::PC).wrapping_add(offset);
+        self.regs.writew(self::RegsW::PC, addr);
         12
     }
 
@@ -1237,13 +1913,13 @@ impl Cpu {
     // Z N H C
     // - - - - : 12/8
     fn jr_cond(&mut self, c: Condition) -> u32 {
-        let mut addr = self.fetchb() as i8 as i16;
-        if !c.test(self) {
+        let offset = self.fetchb() as i8 as u16;
+        if !c.test(self.regs.f) {
             return 8;
         }
 
-        addr += self.regs.readw(self::RegsW::PC) as i16;
-        self.regs.writew(self::RegsW::PC, addr as u16);
+        let addr = self.regs.readw(self::RegsW::PC).wrapping_add(offset);
+        self.regs.writew(self::RegsW::PC, addr);
         12
     }
 
@@ -1256,10 +1932,14 @@ impl Cpu {
         16
     }
 
+    // Decrementing SP takes a machine cycle before anything is written, and
+    // then the high byte is written first.
     fn pushw(&mut self, val: u16) {
-        let sp = self.regs.readw(self::RegsW::SP).wrapping_sub(2);
-        self.interconnect.writew(sp, val);
-        self.regs.writew(self::RegsW::SP, sp);
+        self.tick();
+        let sp = self.regs.readw(self::RegsW::SP);
+        self.write(sp.wrapping_sub(1), (val >> 8) as u8);
+        self.write(sp.wrapping_sub(2), val as u8);
+        self.regs.writew(self::RegsW::SP, sp.wrapping_sub(2));
     }
 
     // POP qq
@@ -1274,9 +1954,9 @@ impl Cpu {
 
     fn popw(&mut self) -> u16 {
         let mut sp = self.regs.readw(self::RegsW::SP);
-        let mut val = self.interconnect.readb(sp) as u16;
+        let mut val = self.read(sp) as u16;
         sp = sp.wrapping_add(1);
-        val |= (self.interconnect.readb(sp) as u16) << 8;
+        val |= (self.read(sp) as u16) << 8;
         self.regs.writew(self::RegsW::SP, sp.wrapping_add(1));
         val
     }
@@ -1290,6 +1970,7 @@ impl Cpu {
 
         self.pushw(pc);
         self.regs.writew(self::RegsW::PC, new_pc);
+        self.enter_call(new_pc, pc, false);
         24
     }
 
@@ -1299,19 +1980,22 @@ impl Cpu {
     fn call_cond(&mut self, cond: Condition) -> u32 {
         let new_pc = self.fetchw();
 
-        if !cond.test(self) {
+        if !cond.test(self.regs.f) {
             return 12;
         }
 
         let pc = self.regs.readw(self::RegsW::PC);
         self.pushw(pc);
         self.regs.writew(self::RegsW::PC, new_pc);
+        self.enter_call(new_pc, pc, false);
         24
     }
 
     fn do_ret(&mut self) -> u32 {
+        let sp = self.regs.sp;
         let pc = self.popw();
         self.regs.writew(self::RegsW::PC, pc);
+        self.leave_call(sp);
         16
     }
 
@@ -1319,7 +2003,7 @@ impl Cpu {
     // Z N H C
     // - - - - 16
     fn reti(&mut self) -> u32 {
-        self.interconnect.ic.ime = true;
+        self.interconnect.ic_mut().set_ime(true);
         self.do_ret()
     }
 
@@ -1334,7 +2018,7 @@ impl Cpu {
     // Z N H C
     // - - - - 20/8
     fn ret_cond(&mut self, cond: Condition) -> u32 {
-        if !cond.test(self) {
+        if !cond.test(self.regs.f) {
             return 8;
         }
         self.do_ret() + 4
@@ -1347,6 +2031,7 @@ impl Cpu {
         let pc = self.regs.readw(self::RegsW::PC);
         self.pushw(pc);
         self.regs.writew(self::RegsW::PC, addr as u16);
+        self.enter_call(addr as u16, pc, false);
         16
     }
 
@@ -1394,113 +2079,111 @@ impl Cpu {
         let mut v = addr.readb(self);
         v = self.alu_rxx(self::RotateDir::L, true, true, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     fn rlc<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_rxx(self::RotateDir::L, false, true, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     fn rr<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_rxx(self::RotateDir::R, true, true, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     fn rrc<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_rxx(self::RotateDir::R, false, true, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     fn alu_rxx(&mut self, dir: RotateDir, include_carry: bool, set_z: bool, val: u8) -> u8 {
-        use self::Flags::*;
         let cout: bool;
         let out: u8;
 
         if dir == self::RotateDir::L {
             cout = (val & 0x80) != 0;
             if include_carry {
-                out = val << 1 | self.check_flag(C) as u8;
+                out = val << 1 | self.check_flag(FLAG_C) as u8;
             } else {
                 out = val.rotate_left(1);
             }
         } else {
             cout = (val & 0x01) != 0;
             if include_carry {
-                out = val >> 1 | (self.check_flag(C) as u8) << 7;
+                out = val >> 1 | (self.check_flag(FLAG_C) as u8) << 7;
             } else {
                 out = val.rotate_right(1);
             }
         }
 
-        self.set_flag(Z, out == 0 && set_z);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, cout);
+        self.set_flag(FLAG_Z, out == 0 && set_z);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, cout);
         out
     }
 
     fn alu_sxx(&mut self, dir: RotateDir, preserve_msb: bool, val: u8) -> u8 {
-        use self::Flags::*;
         let cout: bool;
 
         let out = if dir == self::RotateDir::L {
             cout = (val & 0x80) != 0;
             val << 1
         } else {
-            let msb = (preserve_msb as u8 & (val & 0x80)) << 7;
+            let msb = if preserve_msb { val & 0x80 } else { 0 };
             cout = (val & 0x01) != 0;
             val >> 1 | msb
         };
 
-        self.set_flag(Z, out == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, cout);
+        self.set_flag(FLAG_Z, out == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, cout);
         out
     }
 
     // SLA r | (hl)
     // Z N H C
-    // Z 0 0 C : 8 | 12
+    // Z 0 0 C : 8 | 16
     fn sla<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_sxx(self::RotateDir::L, false, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     // SRA r | (hl)
     // Z N H C
-    // Z 0 0 C : 8 | 12
+    // Z 0 0 C : 8 | 16
     fn sra<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_sxx(self::RotateDir::R, true, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     // SRL r | (hl)
     // Z N H C
-    // Z 0 0 C : 8 | 12
+    // Z 0 0 C : 8 | 16
     fn srl<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
         let mut v = addr.readb(self);
         v = self.alu_sxx(self::RotateDir::R, false, v);
         addr.writeb(self, v);
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     // EI
     // Z N H C
     // - - - - 4
     fn ei(&mut self) -> u32 {
-        self.interconnect.ic.enable_all_interrupts(1);
+        self.interconnect.ic_mut().set_ime_delayed();
         4
     }
 
@@ -1508,55 +2191,120 @@ impl Cpu {
     // Z N H C
     // - - - - 4
     fn di(&mut self) -> u32 {
-        self.interconnect.ic.enable_all_interrupts(0);
+        // Only IME changes, IE is left as the game set it.
+        self.interconnect.ic_mut().set_ime(false);
         4
     }
 
     // BIT b r | b (hl)
     // Z N H C
-    // Z 0 1 - 8 | 16
+    // Z 0 1 - 8 | 12
     fn bit<I: ReadB>(&mut self, b: u8, i: I) -> u32 {
-        use self::Flags::*;
         let z = (i.readb(self) & (1 << b)) == 0;
-        self.set_flag(Z, z);
-        self.set_flag(N, false);
-        self.set_flag(H, true);
-        // TODO: Return correct value...
-        8
+        self.set_flag(FLAG_Z, z);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, true);
+        8 + i.read_cycles()
     }
 
     // SET b r | b (hl)
     // Z N H C
     // - - - - 8 | 16
     fn set<A: ReadB + WriteB>(&mut self, bit: u8, addr: A) -> u32 {
-        let val = addr.readb(self) | 1 << bit;
+        let val = addr.readb(self);
         addr.writeb(self, val | 1 << bit);
-        // TODO: Return correct value...
-        8
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     // RES b r | b (hl)
     // Z N H C
     // - - - - 8 | 16
     fn res<A: ReadB + WriteB>(&mut self, bit: u8, addr: A) -> u32 {
-        let val = addr.readb(self) | 1 << bit;
-        addr.writeb(self, val & 0 << bit);
-        // TODO: Return correct value...
-        8
+        let val = addr.readb(self);
+        addr.writeb(self, val & !(1 << bit));
+        8 + addr.read_cycles() + addr.write_cycles()
     }
 
     // SWAP r | (hl)
     // Z N H C
     // Z - - - 8 | 16
     fn swap<A: ReadB + WriteB>(&mut self, addr: A) -> u32 {
-        use self::Flags::*;
         let v = addr.readb(self);
         let out = (v >> 4) | (v << 4);
-        self.set_flag(Z, out == 0);
-        self.set_flag(N, false);
-        self.set_flag(H, false);
-        self.set_flag(C, false);
-        // TODO: Return correct value...
-        8
+        self.set_flag(FLAG_Z, out == 0);
+        self.set_flag(FLAG_N, false);
+        self.set_flag(FLAG_H, false);
+        self.set_flag(FLAG_C, false);
+        addr.writeb(self, out);
+        8 + addr.read_cycles() + addr.write_cycles()
+    }
+}
+
+// Save states are only made of a whole GameBoy.
+impl Cpu {
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.regs.a);
+        w.write_u8(self.regs.b);
+        w.write_u8(self.regs.c);
+        w.write_u8(self.regs.d);
+        w.write_u8(self.regs.e);
+        w.write_u8(self.regs.f.bits());
+        w.write_u8(self.regs.h);
+        w.write_u8(self.regs.l);
+        w.write_u16(self.regs.pc);
+        w.write_u16(self.regs.sp);
+        self.clk.save_state(w);
+        match self.fault {
+            Some(Fault::IllegalOpcode { opcode, addr }) => {
+                w.write_u8(1);
+                w.write_u8(opcode);
+                w.write_u16(addr);
+            }
+            Some(Fault::Unimplemented { opcode, addr }) => {
+                w.write_u8(2);
+                w.write_u8(opcode);
+                w.write_u16(addr);
+            }
+            None => w.write_u8(0),
+        }
+        w.write_bool(self.halted);
+        self.interconnect.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.regs.a = try!(r.read_u8());
+        self.regs.b = try!(r.read_u8());
+        self.regs.c = try!(r.read_u8());
+        self.regs.d = try!(r.read_u8());
+        self.regs.e = try!(r.read_u8());
+        self.regs.f = Flags::from(try!(r.read_u8()));
+        self.regs.h = try!(r.read_u8());
+        self.regs.l = try!(r.read_u8());
+        self.regs.pc = try!(r.read_u16());
+        self.regs.sp = try!(r.read_u16());
+        try!(self.clk.load_state(r));
+        self.fault = match try!(r.read_u8()) {
+            0 => None,
+            1 => {
+                Some(Fault::IllegalOpcode {
+                    opcode: try!(r.read_u8()),
+                    addr: try!(r.read_u16()),
+                })
+            }
+            2 => {
+                Some(Fault::Unimplemented {
+                    opcode: try!(r.read_u8()),
+                    addr: try!(r.read_u16()),
+                })
+            }
+            inv => return Err(format!("Invalid CPU fault in save state: {}", inv)),
+        };
+        self.halted = try!(r.read_bool());
+        // What ran before doesn't lead up to the loaded state.
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+        self.calls.clear();
+        self.interconnect.load_state(r)
     }
 }
@@ -1,4 +1,11 @@
 mod cpu;
 mod clk;
+mod bus;
+pub mod disasm;
 
-pub use self::cpu::Cpu;
+pub use self::bus::{Bus, FlatBus};
+pub use self::cpu::{BreakReason, CallFrame, Cpu, CpuState, Fault, Flags, RegsB, RegsW};
+pub use self::cpu::{FLAG_C, FLAG_H, FLAG_N, FLAG_Z};
+
+#[cfg(test)]
+mod tests;
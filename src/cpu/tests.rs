@@ -0,0 +1,1075 @@
+// Every implemented opcode, and interrupt dispatch, run against a FlatBus,
+// checking the registers, flags, memory and cycles that they leave behind.
+// They're grouped by instruction family. At the end, a halted CPU on a real
+// Interconnect checks that skipping ahead gives the same results as stepping.
+use std::fmt;
+
+use bootrom::Bootrom;
+use cartridge::Cartridge;
+use interconnect::Interconnect;
+use interrupt::InterruptController;
+use joypad::Button;
+use model::HardwareModel;
+use savestate::StateWriter;
+use super::{BreakReason, Bus, Cpu, Fault, FlatBus, RegsB, RegsW};
+use super::RegsB::*;
+use super::RegsW::{AF, BC, DE, HL, PC, SP};
+
+// Programs are loaded here, after the interrupt vectors and header.
+const START: u16 = 0x0100;
+// Where (HL) points unless a test says otherwise.
+const HL_ADDR: u16 = 0xC000;
+
+// The 8 bit operands in the order that opcodes encode them, with None for
+// (HL).
+const R8: [Option<RegsB>; 8] = [Some(B), Some(C), Some(D), Some(E),
+                                Some(H), Some(L), None, Some(A)];
+
+const RR: [RegsW; 4] = [BC, DE, HL, SP];
+
+// A CPU about to run program from START, with HL pointing at RAM and SP at
+// the top of WRAM.
+fn cpu_with(program: &[u8]) -> Cpu<FlatBus> {
+    let mut cpu = Cpu::new(FlatBus::with_program(START, program), false);
+    cpu.write_reg_w(PC, START);
+    cpu.write_reg_w(SP, 0xDFF0);
+    cpu.write_reg_w(HL, HL_ADDR);
+    cpu
+}
+
+// Execute one instruction, which mustn't fault, returning its cycles.
+fn exec(cpu: &mut Cpu<FlatBus>) -> u32 {
+    let ticks = cpu.dexec();
+    assert_eq!(cpu.fault(), None);
+    ticks
+}
+
+fn f(cpu: &Cpu<FlatBus>) -> u8 {
+    cpu.read_reg_w(AF) as u8
+}
+
+fn set_f(cpu: &mut Cpu<FlatBus>, flags: u8) {
+    let a = cpu.read_reg_b(A);
+    cpu.write_reg_w(AF, (a as u16) << 8 | flags as u16);
+}
+
+fn pc(cpu: &Cpu<FlatBus>) -> u16 {
+    cpu.read_reg_w(PC)
+}
+
+fn set_r8(cpu: &mut Cpu<FlatBus>, r: usize, val: u8) {
+    match R8[r] {
+        Some(reg) => cpu.write_reg_b(reg, val),
+        None => {
+            let hl = cpu.read_reg_w(HL);
+            cpu.interconnect.writeb(hl, val);
+        }
+    }
+}
+
+fn get_r8(cpu: &Cpu<FlatBus>, r: usize) -> u8 {
+    match R8[r] {
+        Some(reg) => cpu.read_reg_b(reg),
+        None => cpu.interconnect.peek(cpu.read_reg_w(HL)),
+    }
+}
+
+// Accessing (HL) costs a machine cycle each way.
+fn r8_cycles(r: usize, base: u32, hl: u32) -> u32 {
+    if R8[r].is_none() { hl } else { base }
+}
+
+fn mem(cpu: &Cpu<FlatBus>, addr: u16) -> u8 {
+    cpu.interconnect.peek(addr)
+}
+
+// A FlatBus that remembers when each write happened, counted in the cycles
+// that the CPU had stepped the system through by then.
+struct RecordingBus {
+    bus: FlatBus,
+    cycles: u32,
+    writes: Vec<(u32, u16, u8)>,
+}
+
+impl fmt::Debug for RecordingBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordingBus")
+            .field("cycles", &self.cycles)
+            .field("writes", &self.writes)
+            .finish()
+    }
+}
+
+impl RecordingBus {
+    fn new(bus: FlatBus) -> RecordingBus {
+        RecordingBus {
+            bus: bus,
+            cycles: 0,
+            writes: Vec::new(),
+        }
+    }
+}
+
+impl Bus for RecordingBus {
+    fn readb(&mut self, addr: u16) -> u8 {
+        self.bus.readb(addr)
+    }
+
+    fn writeb(&mut self, addr: u16, val: u8) {
+        self.writes.push((self.cycles, addr, val));
+        self.bus.writeb(addr, val);
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn step(&mut self, ticks: u32) -> u32 {
+        self.cycles += ticks;
+        ticks
+    }
+
+    fn ic(&self) -> &InterruptController {
+        self.bus.ic()
+    }
+
+    fn ic_mut(&mut self) -> &mut InterruptController {
+        self.bus.ic_mut()
+    }
+}
+
+// A CPU about to execute a NOP at pc with interrupts enabled, if, and ie
+// set, and SP at sp.
+fn interrupt_cpu(pc: u16, sp: u16, iflag: u8, ie: u8) -> Cpu<FlatBus> {
+    let mut cpu = Cpu::new(FlatBus::new(), false);
+    cpu.write_reg_w(RegsW::PC, pc);
+    cpu.write_reg_w(RegsW::SP, sp);
+    cpu.interconnect.writeb(0xFF0F, iflag);
+    cpu.interconnect.writeb(0xFFFF, ie);
+    cpu.interconnect.ic_mut().set_ime(true);
+    cpu
+}
+
+#[test]
+fn interrupt_dispatch() {
+    let mut cpu = interrupt_cpu(0x0200, 0xD000, 0x01, 0x01);
+    cpu.step().unwrap();
+    // The NOP at the VBlank handler has been executed too.
+    assert_eq!(cpu.read_reg_w(RegsW::PC), 0x0041);
+    assert_eq!(cpu.read_reg_w(RegsW::SP), 0xCFFE);
+    assert_eq!(cpu.interconnect.peek(0xCFFF), 0x02);
+    assert_eq!(cpu.interconnect.peek(0xCFFE), 0x00);
+    assert_eq!(cpu.interconnect.ic().read_if() & 0x1F, 0x00);
+    assert!(!cpu.interconnect.ic().ime());
+}
+
+#[test]
+fn interrupt_dispatch_cycles() {
+    let bus = RecordingBus::new(FlatBus::new());
+    let mut cpu = Cpu::new(bus, false);
+    cpu.write_reg_w(RegsW::PC, 0x0234);
+    cpu.write_reg_w(RegsW::SP, 0xD000);
+    cpu.interconnect.writeb(0xFF0F, 0x04);
+    cpu.interconnect.writeb(0xFFFF, 0x04);
+    cpu.interconnect.writes.clear();
+    cpu.interconnect.ic_mut().set_ime(true);
+
+    cpu.step().unwrap();
+    // Two cycles waiting, then a cycle for each byte of PC, high byte first.
+    assert_eq!(cpu.interconnect.writes,
+               vec![(12, 0xCFFF, 0x02), (16, 0xCFFE, 0x34)]);
+    // Another cycle to jump to the handler, and then the NOP there.
+    assert_eq!(cpu.interconnect.cycles, 20 + 4);
+    assert_eq!(cpu.read_reg_w(RegsW::PC), 0x0051);
+}
+
+// PUSH, CALL and RST spend a machine cycle before writing to the stack,
+// like interrupt dispatch does.
+#[test]
+fn stack_write_cycles() {
+    // PUSH BC, CALL nn, CALL NZ,nn and RST 38H, with the value each pushes,
+    // the cycles by which its two bytes are written and its cycles in all.
+    let cases: [(&[u8], u16, [u32; 2], u32); 4] = [(&[0xC5], 0x1234, [12, 16], 16),
+                                                  (&[0xCD, 0x00, 0x30], 0x0103, [20, 24], 24),
+                                                  (&[0xC4, 0x00, 0x30], 0x0103, [20, 24], 24),
+                                                  (&[0xFF], 0x0101, [12, 16], 16)];
+    for &(program, pushed, at, cycles) in cases.iter() {
+        let bus = RecordingBus::new(FlatBus::with_program(START, program));
+        let mut cpu = Cpu::new(bus, false);
+        cpu.write_reg_w(PC, START);
+        cpu.write_reg_w(SP, 0xDFF0);
+        cpu.write_reg_w(BC, 0x1234);
+        cpu.write_reg_w(AF, 0x0000);
+
+        assert_eq!(cpu.step(), Ok(cycles), "{:02x?}", program);
+        assert_eq!(cpu.interconnect.writes,
+                   vec![(at[0], 0xDFEF, (pushed >> 8) as u8), (at[1], 0xDFEE, pushed as u8)],
+                   "{:02x?}",
+                   program);
+        assert_eq!(cpu.interconnect.cycles, cycles, "{:02x?}", program);
+    }
+}
+
+// Dispatch takes as long as the instructions around it as far as the clock
+// and the caller are concerned.
+#[test]
+fn interrupt_dispatch_is_counted() {
+    let mut cpu = interrupt_cpu(0x0200, 0xD000, 0x01, 0x01);
+    assert_eq!(cpu.step(), Ok(20 + 4));
+    assert_eq!(cpu.elapsed_cycles(), 24);
+
+    let mut cpu = interrupt_cpu(0x0200, 0xD000, 0x01, 0x01);
+    assert_eq!(cpu.step_instruction().cycles, 24);
+    assert_eq!(cpu.elapsed_cycles(), 24);
+}
+
+#[test]
+fn interrupt_dispatch_stops_at_handler_breakpoint() {
+    let mut cpu = interrupt_cpu(0x0200, 0xD000, 0x01, 0x01);
+    cpu.add_breakpoint(0x0040);
+    assert_eq!(cpu.step(), Ok(20));
+    assert_eq!(cpu.step(), Err(BreakReason::Breakpoint(0x0040)));
+    assert_eq!(cpu.step(), Ok(4));
+    assert_eq!(cpu.read_reg_w(RegsW::PC), 0x0041);
+    assert_eq!(cpu.elapsed_cycles(), 24);
+}
+
+// Pushing PC's high byte onto IE clears VBlank's enable bit, so there's
+// nothing left to dispatch and the CPU ends up at 0x0000 instead.
+#[test]
+fn interrupt_dispatch_cancelled_by_ie_write() {
+    let mut cpu = interrupt_cpu(0x0200, 0x0000, 0x01, 0x01);
+    cpu.step().unwrap();
+    assert_eq!(cpu.interconnect.ic().read_ie(), 0x02);
+    assert_eq!(cpu.read_reg_w(RegsW::PC), 0x0001);
+    assert_eq!(cpu.read_reg_w(RegsW::SP), 0xFFFE);
+    assert_eq!(cpu.interconnect.peek(0xFFFE), 0x00);
+    // The request is left for later.
+    assert_eq!(cpu.interconnect.ic().read_if() & 0x1F, 0x01);
+    assert!(!cpu.interconnect.ic().ime());
+}
+
+// The interrupt is only picked after the high byte is pushed, so one that
+// the write enables is dispatched instead.
+#[test]
+fn interrupt_dispatch_redirected_by_ie_write() {
+    let mut cpu = interrupt_cpu(0x0400, 0x0000, 0x05, 0x01);
+    cpu.step().unwrap();
+    assert_eq!(cpu.interconnect.ic().read_ie(), 0x04);
+    assert_eq!(cpu.read_reg_w(RegsW::PC), 0x0051);
+    assert_eq!(cpu.interconnect.ic().read_if() & 0x1F, 0x01);
+}
+
+// Misc and control
+
+#[test]
+fn nop() {
+    let mut cpu = cpu_with(&[0x00]);
+    set_f(&mut cpu, 0xF0);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(pc(&cpu), START + 1);
+    assert_eq!(f(&cpu), 0xF0);
+}
+
+#[test]
+fn stop_is_unimplemented() {
+    let mut cpu = cpu_with(&[0x10, 0x00]);
+    cpu.dexec();
+    assert_eq!(cpu.fault(),
+               Some(Fault::Unimplemented {
+                   opcode: 0x10,
+                   addr: START,
+               }));
+}
+
+#[test]
+fn illegal_opcodes_lock() {
+    for &op in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD].iter() {
+        let mut cpu = cpu_with(&[op]);
+        cpu.dexec();
+        assert_eq!(cpu.fault(),
+                   Some(Fault::IllegalOpcode {
+                       opcode: op,
+                       addr: START,
+                   }));
+        // Nothing else happens once it's locked.
+        cpu.step().unwrap();
+        assert_eq!(pc(&cpu), START + 1);
+    }
+}
+
+#[test]
+fn halt() {
+    let mut cpu = cpu_with(&[0x76, 0x00]);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(pc(&cpu), START + 1);
+    // Halted, so nothing is executed.
+    cpu.step().unwrap();
+    assert_eq!(pc(&cpu), START + 1);
+    // A pending interrupt wakes it, even with IME clear.
+    cpu.interconnect.writeb(0xFFFF, 0x01);
+    cpu.interconnect.writeb(0xFF0F, 0x01);
+    cpu.step().unwrap();
+    assert_eq!(pc(&cpu), START + 2);
+}
+
+#[test]
+fn di_ei() {
+    let mut cpu = cpu_with(&[0xF3, 0xFB, 0x00, 0x00]);
+    cpu.interconnect.ic_mut().set_ime(true);
+    assert_eq!(exec(&mut cpu), 4);
+    assert!(!cpu.interconnect.ic().ime());
+    // EI only takes effect after the next instruction.
+    cpu.step().unwrap();
+    assert!(!cpu.interconnect.ic().ime());
+    assert!(cpu.interconnect.ic().ime_pending());
+    cpu.step().unwrap();
+    assert!(cpu.interconnect.ic().ime());
+}
+
+#[test]
+fn cpl_scf_ccf() {
+    let mut cpu = cpu_with(&[0x2F]);
+    cpu.write_reg_w(AF, 0x3590);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(cpu.read_reg_b(A), 0xCA);
+    assert_eq!(f(&cpu), 0xF0);
+
+    let mut cpu = cpu_with(&[0x37]);
+    set_f(&mut cpu, 0xE0);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(f(&cpu), 0x90);
+
+    let mut cpu = cpu_with(&[0x3F, 0x3F]);
+    set_f(&mut cpu, 0xF0);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(f(&cpu), 0x80);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(f(&cpu), 0x90);
+}
+
+#[test]
+fn daa() {
+    // 0x15 + 0x27 in BCD.
+    let mut cpu = cpu_with(&[0x27]);
+    cpu.write_reg_w(AF, 0x3C00);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(cpu.read_reg_b(A), 0x42);
+    assert_eq!(f(&cpu), 0x00);
+
+    // 0x42 - 0x15 in BCD.
+    let mut cpu = cpu_with(&[0x27]);
+    cpu.write_reg_w(AF, 0x2D60);
+    exec(&mut cpu);
+    assert_eq!(cpu.read_reg_b(A), 0x27);
+    assert_eq!(f(&cpu), 0x40);
+
+    // 0x99 + 0x01 in BCD, which carries.
+    let mut cpu = cpu_with(&[0x27]);
+    cpu.write_reg_w(AF, 0x9A00);
+    exec(&mut cpu);
+    assert_eq!(cpu.read_reg_b(A), 0x00);
+    assert_eq!(f(&cpu), 0x90);
+}
+
+// 8 bit loads
+
+#[test]
+fn ld_r_r() {
+    for op in 0x40..0x80 {
+        if op == 0x76 {
+            continue;
+        }
+        let (dst, src) = ((op >> 3) & 7, op & 7);
+        let mut cpu = cpu_with(&[op as u8]);
+        set_f(&mut cpu, 0xF0);
+        set_r8(&mut cpu, src, 0xC5);
+        assert_eq!(exec(&mut cpu),
+                   r8_cycles(src, 4, 8) + r8_cycles(dst, 0, 4),
+                   "opcode 0x{:02x}",
+                   op);
+        assert_eq!(get_r8(&cpu, dst), 0xC5, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 1);
+        assert_eq!(f(&cpu), 0xF0, "opcode 0x{:02x}", op);
+    }
+}
+
+#[test]
+fn ld_r_n() {
+    for r in 0..8 {
+        let op = 0x06 | (r as u8) << 3;
+        let mut cpu = cpu_with(&[op, 0x5A]);
+        assert_eq!(exec(&mut cpu), r8_cycles(r, 8, 12), "opcode 0x{:02x}", op);
+        assert_eq!(get_r8(&cpu, r), 0x5A, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 2);
+    }
+}
+
+#[test]
+fn ld_indirect_a() {
+    // LD (BC),A and LD (DE),A
+    for &(op, rr) in [(0x02, BC), (0x12, DE)].iter() {
+        let mut cpu = cpu_with(&[op]);
+        cpu.write_reg_w(rr, 0xC123);
+        cpu.write_reg_b(A, 0x77);
+        assert_eq!(exec(&mut cpu), 8);
+        assert_eq!(mem(&cpu, 0xC123), 0x77);
+    }
+    // LD A,(BC) and LD A,(DE)
+    for &(op, rr) in [(0x0A, BC), (0x1A, DE)].iter() {
+        let mut cpu = cpu_with(&[op]);
+        cpu.write_reg_w(rr, 0xC123);
+        cpu.interconnect.writeb(0xC123, 0x66);
+        assert_eq!(exec(&mut cpu), 8);
+        assert_eq!(cpu.read_reg_b(A), 0x66);
+    }
+}
+
+#[test]
+fn ld_hl_increment_decrement() {
+    let mut cpu = cpu_with(&[0x22, 0x32]);
+    cpu.write_reg_b(A, 0x11);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(mem(&cpu, HL_ADDR), 0x11);
+    assert_eq!(cpu.read_reg_w(HL), HL_ADDR + 1);
+    cpu.write_reg_b(A, 0x22);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(mem(&cpu, HL_ADDR + 1), 0x22);
+    assert_eq!(cpu.read_reg_w(HL), HL_ADDR);
+
+    let mut cpu = cpu_with(&[0x2A, 0x3A]);
+    cpu.interconnect.writeb(HL_ADDR, 0x33);
+    cpu.interconnect.writeb(HL_ADDR + 1, 0x44);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(cpu.read_reg_b(A), 0x33);
+    assert_eq!(cpu.read_reg_w(HL), HL_ADDR + 1);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(cpu.read_reg_b(A), 0x44);
+    assert_eq!(cpu.read_reg_w(HL), HL_ADDR);
+}
+
+#[test]
+fn ld_high_page() {
+    // LDH (n),A and LDH A,(n)
+    let mut cpu = cpu_with(&[0xE0, 0x80, 0xF0, 0x81]);
+    cpu.write_reg_b(A, 0x12);
+    cpu.interconnect.writeb(0xFF81, 0x34);
+    assert_eq!(exec(&mut cpu), 12);
+    assert_eq!(mem(&cpu, 0xFF80), 0x12);
+    assert_eq!(exec(&mut cpu), 12);
+    assert_eq!(cpu.read_reg_b(A), 0x34);
+    assert_eq!(pc(&cpu), START + 4);
+
+    // LD (C),A and LD A,(C)
+    let mut cpu = cpu_with(&[0xE2, 0xF2]);
+    cpu.write_reg_b(A, 0x56);
+    cpu.write_reg_b(C, 0x82);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(mem(&cpu, 0xFF82), 0x56);
+    cpu.interconnect.writeb(0xFF82, 0x78);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(cpu.read_reg_b(A), 0x78);
+    assert_eq!(pc(&cpu), START + 2);
+}
+
+#[test]
+fn ld_absolute_a() {
+    let mut cpu = cpu_with(&[0xEA, 0x10, 0xC0, 0xFA, 0x11, 0xC0]);
+    cpu.write_reg_b(A, 0x9A);
+    cpu.interconnect.writeb(0xC011, 0xBC);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(mem(&cpu, 0xC010), 0x9A);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(cpu.read_reg_b(A), 0xBC);
+    assert_eq!(pc(&cpu), START + 6);
+}
+
+// 16 bit loads
+
+#[test]
+fn ld_rr_nn() {
+    for (i, &rr) in RR.iter().enumerate() {
+        let op = 0x01 | (i as u8) << 4;
+        let mut cpu = cpu_with(&[op, 0x34, 0x12]);
+        assert_eq!(exec(&mut cpu), 12, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(rr), 0x1234, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 3);
+    }
+}
+
+#[test]
+fn ld_nn_sp() {
+    let mut cpu = cpu_with(&[0x08, 0x10, 0xC0]);
+    cpu.write_reg_w(SP, 0xBEEF);
+    assert_eq!(exec(&mut cpu), 20);
+    assert_eq!(mem(&cpu, 0xC010), 0xEF);
+    assert_eq!(mem(&cpu, 0xC011), 0xBE);
+    assert_eq!(pc(&cpu), START + 3);
+}
+
+#[test]
+fn ld_sp_hl() {
+    let mut cpu = cpu_with(&[0xF9]);
+    cpu.write_reg_w(HL, 0x1234);
+    assert_eq!(exec(&mut cpu), 8);
+    assert_eq!(cpu.read_reg_w(SP), 0x1234);
+}
+
+#[test]
+fn ld_hl_sp_offset() {
+    // Z is cleared even though the result is 0, and H and C come from the
+    // low byte.
+    let mut cpu = cpu_with(&[0xF8, 0x08]);
+    cpu.write_reg_w(SP, 0xFFF8);
+    set_f(&mut cpu, 0xC0);
+    assert_eq!(exec(&mut cpu), 12);
+    assert_eq!(cpu.read_reg_w(HL), 0x0000);
+    assert_eq!(f(&cpu), 0x30);
+
+    let mut cpu = cpu_with(&[0xF8, 0xFE]);
+    cpu.write_reg_w(SP, 0x1000);
+    exec(&mut cpu);
+    assert_eq!(cpu.read_reg_w(HL), 0x0FFE);
+    assert_eq!(f(&cpu), 0x00);
+}
+
+#[test]
+fn push() {
+    for (i, &rr) in [BC, DE, HL, AF].iter().enumerate() {
+        let op = 0xC5 | (i as u8) << 4;
+        let mut cpu = cpu_with(&[op]);
+        cpu.write_reg_w(rr, 0x12F0);
+        assert_eq!(exec(&mut cpu), 16, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(SP), 0xDFEE);
+        assert_eq!(mem(&cpu, 0xDFEF), 0x12, "opcode 0x{:02x}", op);
+        assert_eq!(mem(&cpu, 0xDFEE), 0xF0, "opcode 0x{:02x}", op);
+    }
+}
+
+#[test]
+fn pop() {
+    for (i, &rr) in [BC, DE, HL, AF].iter().enumerate() {
+        let op = 0xC1 | (i as u8) << 4;
+        let mut cpu = cpu_with(&[op]);
+        cpu.interconnect.writeb(0xDFF0, 0xFF);
+        cpu.interconnect.writeb(0xDFF1, 0x12);
+        assert_eq!(exec(&mut cpu), 12, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(SP), 0xDFF2);
+        // The low nibble of F doesn't exist.
+        let want = if i == 3 { 0x12F0 } else { 0x12FF };
+        assert_eq!(cpu.read_reg_w(rr), want, "opcode 0x{:02x}", op);
+    }
+}
+
+// 8 bit arithmetic and logic
+
+// A after each of ADD, ADC, SUB, SBC, AND, XOR, OR and CP on A=0x3C with
+// 0x2F and carry set, and the flags.
+const ALU_2F: [(u8, u8); 8] = [(0x6B, 0x20),
+                               (0x6C, 0x20),
+                               (0x0D, 0x60),
+                               (0x0C, 0x60),
+                               (0x2C, 0x20),
+                               (0x13, 0x00),
+                               (0x3F, 0x00),
+                               (0x3C, 0x60)];
+// The same, but with A as the operand too.
+const ALU_3C: [(u8, u8); 8] = [(0x78, 0x20),
+                               (0x79, 0x20),
+                               (0x00, 0xC0),
+                               (0xFF, 0x70),
+                               (0x3C, 0x20),
+                               (0x00, 0x80),
+                               (0x3C, 0x00),
+                               (0x3C, 0xC0)];
+
+#[test]
+fn alu_r() {
+    for op in 0x80..0xC0 {
+        let (alu, r) = ((op >> 3) & 7, op & 7);
+        let mut cpu = cpu_with(&[op as u8]);
+        cpu.write_reg_w(AF, 0x3C10);
+        let (want, want_f) = if r == 7 {
+            ALU_3C[alu]
+        } else {
+            set_r8(&mut cpu, r, 0x2F);
+            ALU_2F[alu]
+        };
+        assert_eq!(exec(&mut cpu), r8_cycles(r, 4, 8), "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_b(A), want, "opcode 0x{:02x}", op);
+        assert_eq!(f(&cpu), want_f, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 1);
+    }
+}
+
+#[test]
+fn alu_n() {
+    for (alu, &want) in ALU_2F.iter().enumerate() {
+        let op = 0xC6 | (alu as u8) << 3;
+        let mut cpu = cpu_with(&[op, 0x2F]);
+        cpu.write_reg_w(AF, 0x3C10);
+        assert_eq!(exec(&mut cpu), 8, "opcode 0x{:02x}", op);
+        assert_eq!((cpu.read_reg_b(A), f(&cpu)), want, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 2);
+    }
+}
+
+#[test]
+fn inc_dec_r() {
+    for r in 0..8 {
+        let inc = 0x04 | (r as u8) << 3;
+        let dec = inc + 1;
+        // Carry is left alone.
+        for &(op, val, want, want_f) in [(inc, 0x0F, 0x10, 0x30),
+                                         (inc, 0xFF, 0x00, 0xB0),
+                                         (dec, 0x10, 0x0F, 0x70),
+                                         (dec, 0x01, 0x00, 0xD0)]
+            .iter() {
+            let mut cpu = cpu_with(&[op]);
+            set_f(&mut cpu, 0x10);
+            set_r8(&mut cpu, r, val);
+            assert_eq!(exec(&mut cpu), r8_cycles(r, 4, 12), "opcode 0x{:02x}", op);
+            assert_eq!(get_r8(&cpu, r), want, "opcode 0x{:02x}", op);
+            assert_eq!(f(&cpu), want_f, "opcode 0x{:02x}", op);
+        }
+    }
+}
+
+// 16 bit arithmetic
+
+#[test]
+fn inc_dec_rr() {
+    for (i, &rr) in RR.iter().enumerate() {
+        let inc = 0x03 | (i as u8) << 4;
+        let dec = 0x0B | (i as u8) << 4;
+        for &(op, val, want) in [(inc, 0xFFFF, 0x0000), (dec, 0x0000, 0xFFFF)].iter() {
+            let mut cpu = cpu_with(&[op]);
+            set_f(&mut cpu, 0xF0);
+            cpu.write_reg_w(rr, val);
+            assert_eq!(exec(&mut cpu), 8, "opcode 0x{:02x}", op);
+            assert_eq!(cpu.read_reg_w(rr), want, "opcode 0x{:02x}", op);
+            assert_eq!(f(&cpu), 0xF0, "opcode 0x{:02x}", op);
+        }
+    }
+}
+
+#[test]
+fn add_hl_rr() {
+    for (i, &rr) in RR.iter().enumerate() {
+        let op = 0x09 | (i as u8) << 4;
+        let mut cpu = cpu_with(&[op]);
+        set_f(&mut cpu, 0xC0);
+        cpu.write_reg_w(HL, 0x8FFF);
+        // H and C come from bits 11 and 15, and Z is left alone.
+        let want = if i == 2 {
+            0x1FFE
+        } else {
+            cpu.write_reg_w(rr, 0x7001);
+            0x0000
+        };
+        assert_eq!(exec(&mut cpu), 8, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(HL), want, "opcode 0x{:02x}", op);
+        assert_eq!(f(&cpu), 0xB0, "opcode 0x{:02x}", op);
+    }
+
+    // No carry out of bit 11.
+    let mut cpu = cpu_with(&[0x09]);
+    cpu.write_reg_w(HL, 0x0F00);
+    cpu.write_reg_w(BC, 0x00FF);
+    exec(&mut cpu);
+    assert_eq!(cpu.read_reg_w(HL), 0x0FFF);
+    assert_eq!(f(&cpu), 0x00);
+}
+
+#[test]
+fn add_sp_offset() {
+    let mut cpu = cpu_with(&[0xE8, 0x01]);
+    cpu.write_reg_w(SP, 0x00FF);
+    set_f(&mut cpu, 0xC0);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(cpu.read_reg_w(SP), 0x0100);
+    assert_eq!(f(&cpu), 0x30);
+
+    let mut cpu = cpu_with(&[0xE8, 0xFF]);
+    cpu.write_reg_w(SP, 0x0000);
+    exec(&mut cpu);
+    assert_eq!(cpu.read_reg_w(SP), 0xFFFF);
+    assert_eq!(f(&cpu), 0x00);
+    assert_eq!(pc(&cpu), START + 2);
+}
+
+// Rotates and shifts
+
+#[test]
+fn rotate_a() {
+    // Z is always cleared, even for a result of 0.
+    for &(op, a, flags, want, want_f) in [(0x07, 0x85, 0x00, 0x0B, 0x10),
+                                          (0x07, 0x00, 0x80, 0x00, 0x00),
+                                          (0x0F, 0x85, 0x00, 0xC2, 0x10),
+                                          (0x17, 0x85, 0x00, 0x0A, 0x10),
+                                          (0x17, 0x05, 0x10, 0x0B, 0x00),
+                                          (0x1F, 0x85, 0x00, 0x42, 0x10),
+                                          (0x1F, 0x04, 0x10, 0x82, 0x00)]
+        .iter() {
+        let mut cpu = cpu_with(&[op]);
+        cpu.write_reg_w(AF, (a as u16) << 8 | flags as u16);
+        assert_eq!(exec(&mut cpu), 4, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_b(A), want, "opcode 0x{:02x}", op);
+        assert_eq!(f(&cpu), want_f, "opcode 0x{:02x}", op);
+    }
+}
+
+// RLC, RRC, RL, RR, SLA, SRA, SWAP and SRL of 0x85 without carry in.
+const SHIFTS_85: [(u8, u8); 8] = [(0x0B, 0x10),
+                                  (0xC2, 0x10),
+                                  (0x0A, 0x10),
+                                  (0x42, 0x10),
+                                  (0x0A, 0x10),
+                                  (0xC2, 0x10),
+                                  (0x58, 0x00),
+                                  (0x42, 0x10)];
+
+#[test]
+fn cb_rotate_shift() {
+    for op in 0x00..0x40 {
+        let (shift, r) = ((op >> 3) & 7, op & 7);
+        for &(val, flags, (want, want_f)) in [(0x85, 0x00, SHIFTS_85[shift]),
+                                              (0x00, 0x00, (0x00, 0x80))]
+            .iter() {
+            let mut cpu = cpu_with(&[0xCB, op as u8]);
+            set_f(&mut cpu, flags);
+            set_r8(&mut cpu, r, val);
+            assert_eq!(exec(&mut cpu), r8_cycles(r, 8, 16), "opcode 0xcb 0x{:02x}", op);
+            assert_eq!(get_r8(&cpu, r), want, "opcode 0xcb 0x{:02x}", op);
+            assert_eq!(f(&cpu), want_f, "opcode 0xcb 0x{:02x}", op);
+            assert_eq!(pc(&cpu), START + 2);
+        }
+    }
+}
+
+#[test]
+fn cb_rotate_through_carry() {
+    for &(op, val, want, want_f) in [(0x10, 0x05, 0x0B, 0x00), (0x18, 0x05, 0x82, 0x10)].iter() {
+        let mut cpu = cpu_with(&[0xCB, op]);
+        set_f(&mut cpu, 0x10);
+        cpu.write_reg_b(B, val);
+        exec(&mut cpu);
+        assert_eq!(cpu.read_reg_b(B), want, "opcode 0xcb 0x{:02x}", op);
+        assert_eq!(f(&cpu), want_f, "opcode 0xcb 0x{:02x}", op);
+    }
+}
+
+// Single bit operations
+
+#[test]
+fn cb_bit() {
+    for op in 0x40..0x80 {
+        let (bit, r) = ((op >> 3) & 7, op & 7);
+        // Z is set if the bit is clear. C is left alone.
+        for &(val, want_f) in [(1 << bit, 0x30), (!(1 << bit), 0xB0)].iter() {
+            let mut cpu = cpu_with(&[0xCB, op as u8]);
+            set_f(&mut cpu, 0x10);
+            set_r8(&mut cpu, r, val);
+            assert_eq!(exec(&mut cpu), r8_cycles(r, 8, 12), "opcode 0xcb 0x{:02x}", op);
+            assert_eq!(f(&cpu), want_f, "opcode 0xcb 0x{:02x}", op);
+            assert_eq!(get_r8(&cpu, r), val, "opcode 0xcb 0x{:02x}", op);
+        }
+    }
+}
+
+#[test]
+fn cb_res_set() {
+    for op in 0x80..0x100 {
+        let (bit, r) = ((op >> 3) & 7, op & 7);
+        let set = op >= 0xC0;
+        let (val, want) = if set { (0x00, 1 << bit) } else { (0xFF, !(1 << bit)) };
+        let mut cpu = cpu_with(&[0xCB, op as u8]);
+        set_f(&mut cpu, 0xA0);
+        set_r8(&mut cpu, r, val);
+        assert_eq!(exec(&mut cpu), r8_cycles(r, 8, 16), "opcode 0xcb 0x{:02x}", op);
+        assert_eq!(get_r8(&cpu, r), want, "opcode 0xcb 0x{:02x}", op);
+        assert_eq!(f(&cpu), 0xA0, "opcode 0xcb 0x{:02x}", op);
+    }
+}
+
+// Jumps, calls and returns
+
+// Flags that make NZ, Z, NC and C true, and false.
+const CONDITIONS: [(u8, u8); 4] = [(0x00, 0x80), (0x80, 0x00), (0x00, 0x10), (0x10, 0x00)];
+
+#[test]
+fn jp() {
+    let mut cpu = cpu_with(&[0xC3, 0x34, 0x12]);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(pc(&cpu), 0x1234);
+
+    let mut cpu = cpu_with(&[0xE9]);
+    cpu.write_reg_w(HL, 0x4321);
+    assert_eq!(exec(&mut cpu), 4);
+    assert_eq!(pc(&cpu), 0x4321);
+}
+
+#[test]
+fn jp_cond() {
+    for (i, &(taken, not_taken)) in CONDITIONS.iter().enumerate() {
+        let op = 0xC2 | (i as u8) << 3;
+        let mut cpu = cpu_with(&[op, 0x34, 0x12]);
+        set_f(&mut cpu, taken);
+        assert_eq!(exec(&mut cpu), 16, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), 0x1234, "opcode 0x{:02x}", op);
+
+        let mut cpu = cpu_with(&[op, 0x34, 0x12]);
+        set_f(&mut cpu, not_taken);
+        assert_eq!(exec(&mut cpu), 12, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 3, "opcode 0x{:02x}", op);
+    }
+}
+
+#[test]
+fn jr() {
+    // The offset is from the end of the instruction.
+    for &(offset, want) in [(0x05, START + 7), (0xFE, START), (0x80, START + 2 - 128)].iter() {
+        let mut cpu = cpu_with(&[0x18, offset]);
+        assert_eq!(exec(&mut cpu), 12);
+        assert_eq!(pc(&cpu), want);
+    }
+}
+
+#[test]
+fn jr_cond() {
+    for (i, &(taken, not_taken)) in CONDITIONS.iter().enumerate() {
+        let op = 0x20 | (i as u8) << 3;
+        let mut cpu = cpu_with(&[op, 0x05]);
+        set_f(&mut cpu, taken);
+        assert_eq!(exec(&mut cpu), 12, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 7, "opcode 0x{:02x}", op);
+
+        let mut cpu = cpu_with(&[op, 0x05]);
+        set_f(&mut cpu, not_taken);
+        assert_eq!(exec(&mut cpu), 8, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 2, "opcode 0x{:02x}", op);
+    }
+}
+
+// A call from START to 0x1234 should have pushed the return address.
+fn assert_called(cpu: &Cpu<FlatBus>, ret: u16) {
+    assert_eq!(cpu.read_reg_w(SP), 0xDFEE);
+    assert_eq!(mem(cpu, 0xDFEF), (ret >> 8) as u8);
+    assert_eq!(mem(cpu, 0xDFEE), ret as u8);
+}
+
+#[test]
+fn call() {
+    let mut cpu = cpu_with(&[0xCD, 0x34, 0x12]);
+    assert_eq!(exec(&mut cpu), 24);
+    assert_eq!(pc(&cpu), 0x1234);
+    assert_called(&cpu, START + 3);
+}
+
+#[test]
+fn call_cond() {
+    for (i, &(taken, not_taken)) in CONDITIONS.iter().enumerate() {
+        let op = 0xC4 | (i as u8) << 3;
+        let mut cpu = cpu_with(&[op, 0x34, 0x12]);
+        set_f(&mut cpu, taken);
+        assert_eq!(exec(&mut cpu), 24, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), 0x1234, "opcode 0x{:02x}", op);
+        assert_called(&cpu, START + 3);
+
+        let mut cpu = cpu_with(&[op, 0x34, 0x12]);
+        set_f(&mut cpu, not_taken);
+        assert_eq!(exec(&mut cpu), 12, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 3, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(SP), 0xDFF0);
+    }
+}
+
+#[test]
+fn rst() {
+    for n in 0..8 {
+        let op = 0xC7 | (n as u8) << 3;
+        let mut cpu = cpu_with(&[op]);
+        assert_eq!(exec(&mut cpu), 16, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), n * 8, "opcode 0x{:02x}", op);
+        assert_called(&cpu, START + 1);
+    }
+}
+
+// A CPU about to execute op with 0x1234 on the stack.
+fn returning_cpu(op: u8) -> Cpu<FlatBus> {
+    let mut cpu = cpu_with(&[op]);
+    cpu.interconnect.writeb(0xDFF0, 0x34);
+    cpu.interconnect.writeb(0xDFF1, 0x12);
+    cpu
+}
+
+#[test]
+fn ret() {
+    let mut cpu = returning_cpu(0xC9);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(pc(&cpu), 0x1234);
+    assert_eq!(cpu.read_reg_w(SP), 0xDFF2);
+
+    // RETI enables interrupts straight away.
+    let mut cpu = returning_cpu(0xD9);
+    assert_eq!(exec(&mut cpu), 16);
+    assert_eq!(pc(&cpu), 0x1234);
+    assert!(cpu.interconnect.ic().ime());
+}
+
+#[test]
+fn ret_cond() {
+    for (i, &(taken, not_taken)) in CONDITIONS.iter().enumerate() {
+        let op = 0xC0 | (i as u8) << 3;
+        let mut cpu = returning_cpu(op);
+        set_f(&mut cpu, taken);
+        assert_eq!(exec(&mut cpu), 20, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), 0x1234, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(SP), 0xDFF2);
+
+        let mut cpu = returning_cpu(op);
+        set_f(&mut cpu, not_taken);
+        assert_eq!(exec(&mut cpu), 8, "opcode 0x{:02x}", op);
+        assert_eq!(pc(&cpu), START + 1, "opcode 0x{:02x}", op);
+        assert_eq!(cpu.read_reg_w(SP), 0xDFF0);
+    }
+}
+
+// An Interconnect that never says it's quiet, so an idle CPU steps it 4 cycles
+// at a time.
+#[derive(Debug)]
+struct SteppingBus(Interconnect);
+
+impl Bus for SteppingBus {
+    fn readb(&mut self, addr: u16) -> u8 {
+        Bus::readb(&mut self.0, addr)
+    }
+
+    fn writeb(&mut self, addr: u16, val: u8) {
+        Bus::writeb(&mut self.0, addr, val);
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.0.peek(addr)
+    }
+
+    fn step(&mut self, ticks: u32) -> u32 {
+        Bus::step(&mut self.0, ticks)
+    }
+
+    fn ic(&self) -> &InterruptController {
+        self.0.ic()
+    }
+
+    fn ic_mut(&mut self) -> &mut InterruptController {
+        self.0.ic_mut()
+    }
+}
+
+// Sets up the timer to overflow every 2048 cycles and LYC to 64, enables their
+// interrupts and VBlank's, then halts forever. Each handler logs LY and DIV
+// to WRAM.
+fn halting_interconnect(scheduler: bool) -> Interconnect {
+    let mut rom = vec![0; 0x8000];
+    let handler = [0xF0, 0x44, 0x22, 0xF0, 0x04, 0x22, 0xD9];
+    for &vector in &[0x40, 0x48, 0x50] {
+        rom[vector..vector + handler.len()].copy_from_slice(&handler);
+    }
+    let program = [0x21, 0x00, 0xC0, // LD HL,0xC000
+                   0x3E, 0xFE, 0xE0, 0x06, // TMA = 0xFE
+                   0x3E, 0x04, 0xE0, 0x07, // TAC = 1024 cycles
+                   0x3E, 0x40, 0xE0, 0x45, // LYC = 64
+                   0x3E, 0x40, 0xE0, 0x41, // STAT = LYC interrupt
+                   0x3E, 0x07, 0xE0, 0xFF, // IE = VBlank, STAT, timer
+                   0xAF, 0xE0, 0x0F, // IF = 0
+                   0xFB, // EI
+                   0x76, 0x00, 0x18, 0xFC]; // HALT; NOP; JR -4
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    Interconnect::new(Cartridge::from_bytes(rom).unwrap(),
+                      Bootrom::empty(),
+                      true,
+                      scheduler,
+                      HardwareModel::Dmg)
+}
+
+fn regs<B: Bus>(cpu: &Cpu<B>) -> [u16; 6] {
+    [cpu.read_reg_w(AF), cpu.read_reg_w(BC), cpu.read_reg_w(DE), cpu.read_reg_w(HL),
+     cpu.read_reg_w(PC), cpu.read_reg_w(SP)]
+}
+
+// Everything else that the CPU could notice, from LY and DIV to the log in
+// WRAM.
+fn system_state(ic: &mut Interconnect) -> Vec<u8> {
+    ic.sync();
+    let mut w = StateWriter::new(0);
+    ic.save_state(&mut w);
+    w.into_bytes()
+}
+
+#[test]
+fn idle_skips_ahead_like_stepping() {
+    for &scheduler in &[false, true] {
+        let mut fast = Cpu::new(halting_interconnect(scheduler), true);
+        let mut slow = Cpu::new(SteppingBus(halting_interconnect(scheduler)), true);
+        let (mut fast_steps, mut slow_steps) = (0, 0);
+
+        while fast.elapsed_cycles() < 3 * 70_224 {
+            assert!(fast.step().is_ok());
+            fast_steps += 1;
+            while slow.elapsed_cycles() < fast.elapsed_cycles() {
+                assert!(slow.step().is_ok());
+                slow_steps += 1;
+            }
+            assert_eq!(slow.elapsed_cycles(), fast.elapsed_cycles());
+
+            assert_eq!(regs(&slow), regs(&fast), "after {} cycles", fast.elapsed_cycles());
+            assert!(system_state(&mut slow.interconnect.0) ==
+                    system_state(&mut fast.interconnect),
+                    "after {} cycles",
+                    fast.elapsed_cycles());
+        }
+        assert!(fast_steps * 4 < slow_steps,
+                "{} steps skipping ahead, {} stepping",
+                fast_steps,
+                slow_steps);
+    }
+}
+
+// Pressing a button on a real Interconnect, with only the Joypad interrupt
+// enabled, sends the CPU to its handler.
+#[test]
+fn joypad_interrupt() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x60] = 0x18; // JR -2
+    rom[0x61] = 0xFE;
+    let program = [0x3E, 0x10, 0xE0, 0x00, // P1 = select the buttons
+                   0x3E, 0x10, 0xE0, 0xFF, // IE = Joypad
+                   0xFB, // EI
+                   0x18, 0xFE]; // JR -2
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let ic = Interconnect::new(Cartridge::from_bytes(rom).unwrap(),
+                               Bootrom::empty(),
+                               true,
+                               false,
+                               HardwareModel::Dmg);
+    let mut cpu = Cpu::new(ic, false);
+    cpu.write_reg_w(PC, 0x0100);
+    cpu.write_reg_w(SP, 0xDFF0);
+
+    for _ in 0..20 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.read_reg_w(PC), 0x0109);
+    assert!(cpu.interconnect.ic().ime());
+
+    {
+        let ic = &mut cpu.interconnect;
+        ic.joypad.set_button(Button::A, true, &mut ic.ic);
+    }
+    cpu.step().unwrap();
+    assert_eq!(cpu.read_reg_w(PC), 0x0060);
+    assert_eq!(cpu.read_reg_w(SP), 0xDFEE);
+    assert_eq!(cpu.interconnect.readw(0xDFEE), 0x0109);
+    assert!(!cpu.interconnect.ic().ime());
+    assert_eq!(cpu.interconnect.readb(0xFF0F) & 0x10, 0);
+}
@@ -1,34 +1,1059 @@
+// The emulation core (everything reachable from GameBoy) must be
+// deterministic: given the same ROM, bootrom and inputs it has to produce the
+// same frames and serial output on every run. Nothing in here may read the
+// host's wall clock or any other ambient state directly - anything that needs
+// a notion of real time must ask the GameBoy's TimeSource instead.
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
 use cpu;
 use cartridge;
 use interconnect;
 use bootrom;
+use serial;
+use bench;
+use palette::Palette;
+use model::HardwareModel;
+use cheat::{Cheat, Code};
+use trace::{History, Tracer};
+use profile::ProfileReport;
+use savestate::{StateWriter, StateReader};
+
+pub use joypad::{Button, InputMode};
+pub use cpu::{BreakReason, CallFrame, CpuState, Fault, Flags, RegsB, RegsW};
+pub use cpu::{FLAG_C, FLAG_H, FLAG_N, FLAG_Z};
 
 pub const CPU_HZ: u32 = 4_194_304;
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
+// The size of dump_tileset's image, 16 tiles across by 24 down.
+pub const TILESET_W: usize = 128;
+pub const TILESET_H: usize = 192;
+// Background maps are 32 tiles square.
+pub const BG_MAP_W: usize = 256;
+// 154 lines of 456 cycles.
+pub const CYCLES_PER_FRAME: u32 = 70_224;
+// The most that one of two linked GameBoys can get ahead of the other.
+const LINK_SLICE: u32 = 64;
 
-#[derive(Debug)]
 pub struct GameBoy {
     cpu: cpu::Cpu,
+    // Cycles executed past the end of the previous timeslice, which are
+    // deducted from the next one.
+    overshoot: u32,
+    instructions: u64,
+    time_source: TimeSource,
+    palette: Palette,
+    // The GPU's frame count when take_frame last returned true.
+    taken_frames: u64,
+    cheats: Vec<Cheat>,
+    // The ROM bytes that each cheat's Game Genie codes replaced.
+    patched: Vec<Vec<(usize, u8)>>,
+}
+
+// Where the core gets "real" time from, i.e. the clock on MBC3 cartridges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    // Time is derived from the number of emulated cycles. This keeps
+    // emulation deterministic and is the default.
+    Emulated,
+    // Time follows the host clock, measured from when the source was
+    // selected. Emulation is no longer reproducible.
+    #[cfg(not(target_arch = "wasm32"))]
+    Host(Instant),
+}
+
+// Configures a GameBoy before it's powered on. Everything left unset is as
+// GameBoy::new would have it.
+pub struct Builder {
+    cart: cartridge::Cartridge,
+    bootrom: bootrom::Bootrom,
+    skip_bootrom: bool,
+    scheduler: bool,
+    model: HardwareModel,
+}
+
+impl Builder {
+    pub fn bootrom(mut self, bootrom: bootrom::Bootrom) -> Builder {
+        self.bootrom = bootrom;
+        self
+    }
+
+    // Start the machine in the post-boot state even when a boot ROM is
+    // loaded, e.g. to avoid waiting for the logo. It always is without one.
+    pub fn skip_bootrom(mut self, skip_bootrom: bool) -> Builder {
+        self.skip_bootrom = skip_bootrom;
+        self
+    }
+
+    // With the scheduler the peripherals are only stepped when one of them
+    // has something to do or the CPU accesses them, rather than after every
+    // instruction. Emulation is otherwise identical.
+    pub fn scheduler(mut self, scheduler: bool) -> Builder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    // When the boot ROM is skipped, start the machine as model's would have
+    // left it, e.g. to test how a game detects the hardware.
+    pub fn model(mut self, model: HardwareModel) -> Builder {
+        self.model = model;
+        self
+    }
+
+    pub fn build(self) -> GameBoy {
+        let skip_bootrom = self.skip_bootrom || !self.bootrom.is_used();
+        let ic = interconnect::Interconnect::new(self.cart,
+                                                 self.bootrom,
+                                                 skip_bootrom,
+                                                 self.scheduler,
+                                                 self.model);
+        GameBoy {
+            cpu: cpu::Cpu::new_with_model(ic, skip_bootrom, self.model),
+            overshoot: 0,
+            instructions: 0,
+            time_source: TimeSource::Emulated,
+            palette: Palette::default(),
+            taken_frames: 0,
+            cheats: Vec::new(),
+            patched: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RunResult {
+    pub cycles_run: u32,
+    // Set if the run ended early at a breakpoint, when the CPU faulted, or at
+    // the end of a Mooneye test ROM with the mooneye feature.
+    pub stopped: Option<BreakReason>,
+}
+
+impl fmt::Debug for GameBoy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GameBoy")
+            .field("header", self.cart_header())
+            .field("overshoot", &self.overshoot)
+            .field("instructions", &self.instructions)
+            .field("time_source", &self.time_source)
+            .field("palette", &self.palette)
+            .field("taken_frames", &self.taken_frames)
+            .field("cheats", &self.cheats.len())
+            .field("cpu", &self.cpu)
+            .finish()
+    }
 }
 
 impl GameBoy {
+    // Without a boot ROM the machine starts in the state one would have left
+    // it in. Use builder for anything else.
     pub fn new(cart: cartridge::Cartridge, bootrom: bootrom::Bootrom) -> GameBoy {
-        let ic = interconnect::Interconnect::new(cart, bootrom);
-        GameBoy { cpu: cpu::Cpu::new(ic) }
+        GameBoy::builder(cart).bootrom(bootrom).build()
+    }
+
+    pub fn builder(cart: cartridge::Cartridge) -> Builder {
+        Builder {
+            cart: cart,
+            bootrom: bootrom::Bootrom::empty(),
+            skip_bootrom: false,
+            scheduler: false,
+            model: HardwareModel::default(),
+        }
     }
 
-    pub fn run(&mut self, timeslice: u32) -> u32 {
+    // Run for timeslice cycles. An instruction can't be split, so the last
+    // one may run past the end of the timeslice - the extra cycles are
+    // remembered and taken out of the next call's budget, so the total
+    // number of cycles run tracks the total requested.
+    //
+    // A timeslice of 0, or one that is entirely paid for by the previous
+    // overshoot, runs nothing and returns straight away. Any other timeslice
+    // runs at least one instruction, even if it's shorter than the
+    // instruction, unless it stops at a breakpoint first. Stopping gives up
+    // the rest of the timeslice.
+    pub fn run(&mut self, timeslice: u32) -> RunResult {
+        if timeslice == 0 {
+            return RunResult {
+                cycles_run: 0,
+                stopped: None,
+            };
+        }
+
+        if self.overshoot >= timeslice {
+            self.overshoot -= timeslice;
+            return RunResult {
+                cycles_run: 0,
+                stopped: None,
+            };
+        }
+
+        // A cartridge clock only needs to be current to the timeslice.
+        let now = self.now();
+        self.cpu.interconnect.cart_mut().set_time(now);
+
+        let budget = timeslice - self.overshoot;
         let mut ticks = 0;
-        loop {
-            ticks += self.cpu.step();
-            if ticks > timeslice {
-                return ticks;
+        let mut stopped = None;
+        // A CPU that was already stopped carries on doing nothing.
+        let faulted = self.cpu.fault().is_some();
+        while ticks < budget {
+            match self.cpu.step_within(budget - ticks) {
+                Ok(t) => ticks += t,
+                Err(reason) => {
+                    stopped = Some(reason);
+                    break;
+                }
+            }
+            self.instructions += 1;
+            if !faulted {
+                if let Some(fault) = self.cpu.fault() {
+                    stopped = Some(BreakReason::Fault(fault));
+                    break;
+                }
+            }
+        }
+        // Everything outside of run expects the peripherals to be current.
+        self.cpu.interconnect.sync();
+        self.overshoot = ticks.saturating_sub(budget);
+        RunResult {
+            cycles_run: ticks,
+            stopped: stopped,
+        }
+    }
+
+    // Execute a single instruction, for debuggers. Breakpoints don't stop
+    // it.
+    pub fn step_instruction(&mut self) -> CpuState {
+        let now = self.now();
+        self.cpu.interconnect.cart_mut().set_time(now);
+        let state = self.cpu.step_instruction();
+        self.instructions += 1;
+        self.cpu.interconnect.sync();
+        state
+    }
+
+    // The CPU's registers and the instruction it will execute next.
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    // Direct access to the CPU's registers, for debuggers and tests. Changes
+    // are seen by the next instruction executed.
+    pub fn read_reg_b(&self, reg: RegsB) -> u8 {
+        self.cpu.read_reg_b(reg)
+    }
+
+    pub fn write_reg_b(&mut self, reg: RegsB, val: u8) {
+        self.cpu.write_reg_b(reg, val);
+    }
+
+    pub fn read_reg_w(&self, reg: RegsW) -> u16 {
+        self.cpu.read_reg_w(reg)
+    }
+
+    pub fn write_reg_w(&mut self, reg: RegsW, val: u16) {
+        self.cpu.write_reg_w(reg, val);
+    }
+
+    pub fn flag(&self, flag: Flags) -> bool {
+        self.cpu.check_flag(flag)
+    }
+
+    pub fn set_flag(&mut self, flag: Flags, enable: bool) {
+        self.cpu.set_flag(flag, enable);
+    }
+
+    // Stop run before executing the instruction at addr.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.cpu.add_breakpoint(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.cpu.remove_breakpoint(addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.cpu.clear_breakpoints();
+    }
+
+    // Snapshot the whole machine. The serial device, time source and any
+    // link are part of the frontend's setup rather than the machine, so
+    // they're left out.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.cpu.interconnect.cart().rom_hash());
+        w.write_u32(self.overshoot);
+        w.write_u64(self.instructions);
+        self.cpu.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // Restore a snapshot made by save_state. On failure the GameBoy is left
+    // exactly as it was.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        let result = self.load_state_unchecked(state);
+        if result.is_err() {
+            self.load_state_unchecked(&backup).expect("Failed to restore the previous state");
+        }
+        result
+    }
+
+    fn load_state_unchecked(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut r = try!(StateReader::new(state, self.cpu.interconnect.cart().rom_hash()));
+        self.overshoot = try!(r.read_u32());
+        self.instructions = try!(r.read_u64());
+        try!(self.cpu.load_state(&mut r));
+        r.finish()
+    }
+
+    // Run for one frame's worth of cycles.
+    pub fn step_frame(&mut self) -> RunResult {
+        self.run(CYCLES_PER_FRAME)
+    }
+
+    pub fn run_frames(&mut self, n: u32) {
+        for _ in 0..n {
+            self.step_frame();
+        }
+    }
+
+    // Returns true if the GPU has finished a frame since the last call, so
+    // that frontends only need to present frames that have changed.
+    pub fn take_frame(&mut self) -> bool {
+        let frames = self.elapsed_frames();
+        if frames == self.taken_frames {
+            return false;
+        }
+        self.taken_frames = frames;
+        true
+    }
+
+    // A 64 bit FNV-1a hash of the back buffer, so that frames can be
+    // compared against known good output.
+    pub fn frame_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for pixel in self.back_buffer().iter() {
+            hash ^= *pixel as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash
+    }
+
+    // Run for a frame and convert the back buffer into RGBA pixels, which is
+    // all a host without a native frontend (e.g. a browser) needs per frame.
+    pub fn run_frame_into_rgba(&mut self, out: &mut [u8]) {
+        self.step_frame();
+        self.frame_into_rgba(out);
+    }
+
+    // Convert the back buffer into RGBA pixels without running anything.
+    pub fn frame_into_rgba(&self, out: &mut [u8]) {
+        assert!(out.len() >= SCREEN_W * SCREEN_H * 4,
+                "RGBA buffer too small: {} bytes",
+                out.len());
+        for (i, pixel) in self.back_buffer().iter().enumerate() {
+            let rgb = self.palette.rgb(*pixel);
+            out[i * 4] = (rgb >> 16) as u8;
+            out[i * 4 + 1] = (rgb >> 8) as u8;
+            out[i * 4 + 2] = rgb as u8;
+            out[i * 4 + 3] = 0xFF;
+        }
+    }
+
+    // Convert the back buffer into 0x00RRGGBB pixels without running
+    // anything.
+    pub fn frame_into_xrgb(&self, out: &mut [u32]) {
+        assert!(out.len() >= SCREEN_W * SCREEN_H,
+                "XRGB buffer too small: {} pixels",
+                out.len());
+        for (i, pixel) in self.back_buffer().iter().enumerate() {
+            out[i] = self.palette.rgb(*pixel);
+        }
+    }
+
+    // What stopped the CPU, if anything has. Once stopped the CPU stays that
+    // way, while the rest of the system keeps running.
+    pub fn fault(&self) -> Option<Fault> {
+        self.cpu.fault()
+    }
+
+    // The mnemonic and length of the instruction at addr.
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        self.cpu.disassemble(addr)
+    }
+
+    // Trace every instruction executed from now on, or stop tracing with
+    // None. The previous tracer is returned, so that it can be flushed or
+    // dropped.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        self.cpu.set_tracer(tracer)
+    }
+
+    // Keep the last trace::HISTORY_LEN instructions, which are logged if the
+    // CPU crashes.
+    pub fn set_history(&mut self, enabled: bool) {
+        self.cpu.set_history(enabled)
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.cpu.history()
+    }
+
+    // Count how often each opcode and address is executed, for finding what
+    // a slow ROM spends its time on.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.cpu.set_profiling(enabled)
+    }
+
+    pub fn profiling(&self) -> bool {
+        self.cpu.profiling()
+    }
+
+    pub fn profile_report(&self) -> ProfileReport {
+        self.cpu.profile_report()
+    }
+
+    // Calls that haven't returned yet, outermost first.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        self.cpu.call_stack()
+    }
+
+    // Make reads of LY return ly rather than the line being drawn, or stop
+    // with None. Nothing else about the LCD changes.
+    pub fn set_ly_stub(&mut self, ly: Option<u8>) {
+        self.cpu.interconnect.gpu.set_ly_stub(ly);
+    }
+
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match self.cpu.tracer_mut() {
+            Some(t) => t.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // The colours used by frame_into_rgba and frame_into_xrgb. This only
+    // affects presentation, so it isn't part of a save state.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    // Replace the cheats, applying those that are enabled. Like the palette,
+    // cheats are part of the frontend's setup rather than the machine, so
+    // they're left out of save states.
+    pub fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        for i in 0..self.cheats.len() {
+            self.set_cheat_enabled(i, false);
+        }
+        self.patched = vec![Vec::new(); cheats.len()];
+        let enabled: Vec<bool> = cheats.iter().map(|c| c.enabled).collect();
+        self.cheats = cheats;
+        for (i, &e) in enabled.iter().enumerate() {
+            self.cheats[i].enabled = false;
+            self.set_cheat_enabled(i, e);
+        }
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // Disabling a cheat puts back the ROM bytes that it patched.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) {
+        if self.cheats[index].enabled == enabled {
+            return;
+        }
+        self.cheats[index].enabled = enabled;
+
+        let cart = self.cpu.interconnect.cart_mut();
+        if enabled {
+            for code in self.cheats[index].codes.iter() {
+                if let Code::GameGenie { addr, value, compare } = *code {
+                    let originals = cart.patch_rom(addr, value, compare);
+                    self.patched[index].extend(originals);
+                }
+            }
+        } else {
+            cart.unpatch_rom(&self.patched[index]);
+            self.patched[index].clear();
+        }
+
+        let mut pokes = Vec::new();
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            for code in cheat.codes.iter() {
+                if let Code::GameShark { addr, value } = *code {
+                    pokes.push((addr, value));
+                }
             }
         }
+        self.cpu.interconnect.set_pokes(pokes);
+    }
+
+    // The rate, in Hz, that audio_samples produces audio at. It starts out as
+    // 0, which doesn't produce any so that nothing is spent on mixing. The
+    // APU is sampled at CPU_HZ / 4, which is as high as it goes.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.interconnect.apu.set_sample_rate(rate);
+    }
+
+    // The audio produced since the last call, as interleaved left and right
+    // samples. At most 4 frames' worth is kept, so this needs calling at
+    // least that often.
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        self.cpu.interconnect.apu.take_samples()
+    }
+
+    // How many samples have been lost because audio_samples wasn't called in
+    // time.
+    pub fn audio_dropped(&self) -> u64 {
+        self.cpu.interconnect.apu.dropped_samples()
+    }
+
+    // CPU cycles since power on, at 4194304 a second. This never wraps, so
+    // it can be used for pacing and to timestamp events.
+    pub fn elapsed_cycles(&self) -> u64 {
+        self.cpu.elapsed_cycles()
+    }
+
+    pub fn elapsed_instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn elapsed_frames(&self) -> u64 {
+        self.cpu.interconnect.gpu.frames()
+    }
+
+    // The amount of emulated time that has passed, derived from the cycle
+    // count rather than the host clock.
+    pub fn elapsed(&self) -> Duration {
+        let cycles = self.elapsed_cycles();
+        let secs = cycles / CPU_HZ as u64;
+        let nanos = (cycles % CPU_HZ as u64) * 1_000_000_000 / CPU_HZ as u64;
+        Duration::new(secs, nanos as u32)
+    }
+
+    pub fn rom_title(&self) -> &str {
+        self.cpu.interconnect.cart().title()
+    }
+
+    pub fn cart_header(&self) -> &cartridge::CartridgeHeader {
+        self.cpu.interconnect.cart().header()
+    }
+
+    pub fn rom_bank(&self) -> u8 {
+        self.cpu.interconnect.cart().rom_bank()
+    }
+
+    pub fn ram_bank(&self) -> u8 {
+        self.cpu.interconnect.cart().ram_bank()
+    }
+
+    // The line the GPU is on, as read from LY.
+    pub fn ly(&self) -> u8 {
+        self.cpu.interconnect.gpu.read_ly()
+    }
+
+    // The GPU mode, as read from the bottom two bits of STAT.
+    pub fn lcd_mode(&self) -> u8 {
+        self.cpu.interconnect.gpu.read_stat() & 0x03
+    }
+
+    // SCX and SCY, the top left corner of the screen on the background map.
+    pub fn scroll(&self) -> (u8, u8) {
+        let gpu = &self.cpu.interconnect.gpu;
+        (gpu.read_scx(), gpu.read_scy())
+    }
+
+    // BGP, which maps the background's colour numbers to shades.
+    pub fn bg_palette(&self) -> u8 {
+        self.cpu.interconnect.gpu.read_bgp()
+    }
+
+    // Every tile in VRAM as colour numbers (not shades), TILESET_W pixels
+    // wide and TILESET_H high. These are decoded independently of the
+    // renderer, so the two can be compared when something looks wrong.
+    pub fn dump_tileset(&self) -> Vec<u8> {
+        self.cpu.interconnect.gpu.dump_tileset()
+    }
+
+    // Background map 0 (0x9800) or 1 (0x9C00) as colour numbers, BG_MAP_W
+    // pixels square, using the tile data that LCDC selects.
+    pub fn dump_bg_map(&self, map: u8) -> Vec<u8> {
+        self.cpu.interconnect.gpu.dump_bg_map(map)
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let interconnect = &mut self.cpu.interconnect;
+        interconnect.joypad.set_button(button, pressed, &mut interconnect.ic);
+    }
+
+    // In latched mode button changes only reach the game at the start of the
+    // next frame, which keeps recorded inputs deterministic.
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        let interconnect = &mut self.cpu.interconnect;
+        interconnect.joypad.set_mode(mode, &mut interconnect.ic);
+    }
+
+    pub fn input_mode(&self) -> InputMode {
+        self.cpu.interconnect.joypad.mode()
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn serial::SerialDevice + Send>) {
+        self.cpu.interconnect.serial.set_device(device);
+    }
+
+    // Call f whenever a serial transfer starts, with the byte sent by the
+    // GameBoy. f returns the byte that the GameBoy receives, which like a
+    // real link partner's is shifted in a bit at a time: it's only all in
+    // SB, along with the Serial interrupt, once the transfer completes.
+    pub fn set_serial_callback<F>(&mut self, f: F)
+        where F: FnMut(u8) -> u8 + Send + 'static
+    {
+        self.set_serial_device(Box::new(serial::SerialCallback(f)));
+    }
+
+    // Whether the game has selected the internal serial clock, making this
+    // GameBoy the side of the link that drives transfers.
+    pub fn serial_internal_clock(&self) -> bool {
+        self.cpu.interconnect.serial.internal_clock()
+    }
+
+    // How many bits of the current serial transfer have been shifted, which
+    // is 8 once it has completed.
+    pub fn serial_bits_shifted(&self) -> u32 {
+        self.cpu.interconnect.serial.bits_shifted()
+    }
+
+    // Everything sent over the serial port so far, which is how test ROMs
+    // such as Blargg's report their results. Only the last 64KB is kept.
+    pub fn serial_output(&self) -> String {
+        String::from_utf8_lossy(self.serial_bytes()).into_owned()
+    }
+
+    pub fn serial_bytes(&self) -> &[u8] {
+        self.cpu.interconnect.serial.output()
+    }
+
+    pub fn set_serial_linked(&mut self, linked: bool) {
+        self.cpu.interconnect.serial.set_linked(linked);
+    }
+
+    // Run two GameBoys connected by link::connect for cycles, in slices so
+    // that they never drift far apart. Bytes are exchanged at the end of
+    // each slice.
+    pub fn run_linked(a: &mut GameBoy, b: &mut GameBoy, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let slice = cmp::min(remaining, LINK_SLICE);
+            a.run(slice);
+            b.run(slice);
+            a.cpu.interconnect.link_exchange(&mut b.cpu.interconnect);
+            b.cpu.interconnect.link_exchange(&mut a.cpu.interconnect);
+            remaining -= slice;
+        }
+    }
+
+    // Battery backed cartridge RAM, for frontends that persist it.
+    pub fn cart_ram(&self) -> &[u8] {
+        self.cpu.interconnect.cart().ram()
+    }
+
+    pub fn cart_ram_mut(&mut self) -> &mut [u8] {
+        self.cpu.interconnect.cart_mut().ram_mut()
+    }
+
+    // Whether the cartridge RAM survives power off, i.e. whether the game
+    // expects it to be saved.
+    pub fn has_battery(&self) -> bool {
+        self.cpu.interconnect.cart().has_battery()
+    }
+
+    // Restore cartridge RAM from a save file, which must be exactly the size
+    // of the cartridge's RAM.
+    pub fn load_cart_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        self.cpu.interconnect.cart_mut().load_ram(data)
+    }
+
+    // Run for emulated_cycles as fast as possible, timing it with clock, which
+    // returns the host time since any fixed point. The core has no clock of
+    // its own, and the timing is only reported and never affects emulation.
+    pub fn bench_run<F>(&mut self, emulated_cycles: u64, mut clock: F) -> bench::BenchReport
+        where F: FnMut() -> Duration
+    {
+        let cycles = self.elapsed_cycles();
+        let frames = self.elapsed_frames();
+        let instructions = self.instructions;
+        let start = clock();
+
+        let mut remaining = emulated_cycles;
+        while remaining > 0 {
+            let slice = cmp::min(remaining, CYCLES_PER_FRAME as u64) as u32;
+            self.run(slice);
+            remaining -= slice as u64;
+        }
+
+        bench::BenchReport {
+            cycles: self.elapsed_cycles() - cycles,
+            frames: self.elapsed_frames() - frames,
+            instructions: self.instructions - instructions,
+            wall: clock() - start,
+        }
+    }
+
+    pub fn set_time_source(&mut self, time_source: TimeSource) {
+        self.time_source = time_source;
+    }
+
+    pub fn time_source(&self) -> TimeSource {
+        self.time_source
+    }
+
+    // The current time according to the selected TimeSource. Anything in the
+    // core that wants to know how much time has passed must use this.
+    pub fn now(&self) -> Duration {
+        match self.time_source {
+            TimeSource::Emulated => self.elapsed(),
+            #[cfg(not(target_arch = "wasm32"))]
+            TimeSource::Host(start) => start.elapsed(),
+        }
     }
 
     pub fn back_buffer(&self) -> &[u8; SCREEN_W * SCREEN_H] {
         &self.cpu.interconnect.gpu.buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::sync::{Arc, Mutex};
+
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use cheat;
+    use model::HardwareModel;
+    use link;
+    use palette::Palette;
+    use super::{GameBoy, RegsW, LINK_SLICE, SCREEN_H, SCREEN_W};
+
+    // A ROM that spins at 0x0100 forever.
+    fn spin() -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xFE;
+        Cartridge::from_bytes(rom).unwrap()
+    }
+
+    // The registers that the cartridge is entered with, as AF, BC, DE, HL,
+    // SP and PC.
+    fn entry_regs(gb: &GameBoy) -> [u16; 6] {
+        [gb.read_reg_w(RegsW::AF),
+         gb.read_reg_w(RegsW::BC),
+         gb.read_reg_w(RegsW::DE),
+         gb.read_reg_w(RegsW::HL),
+         gb.read_reg_w(RegsW::SP),
+         gb.read_reg_w(RegsW::PC)]
+    }
+
+    #[test]
+    fn dmg_entry() {
+        let gb = GameBoy::builder(spin()).model(HardwareModel::Dmg).build();
+        assert_eq!(entry_regs(&gb), [0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100]);
+        assert!(!gb.serial_internal_clock());
+    }
+
+    #[test]
+    fn mgb_entry() {
+        let gb = GameBoy::builder(spin()).model(HardwareModel::Mgb).build();
+        assert_eq!(entry_regs(&gb), [0xFFB0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100]);
+        assert!(!gb.serial_internal_clock());
+    }
+
+    #[test]
+    fn cgb_entry() {
+        let gb = GameBoy::builder(spin()).model(HardwareModel::Cgb).build();
+        assert_eq!(entry_regs(&gb), [0x1180, 0x0000, 0x0008, 0x007C, 0xFFFE, 0x0100]);
+        assert!(gb.serial_internal_clock());
+    }
+
+    // Even with a bootrom loaded, skipping it starts the cartridge the way
+    // the bootrom would have left it, and leaves the bootrom unmapped.
+    #[test]
+    fn skip_bootrom() {
+        let bootrom = Bootrom::from_bytes(&[0xAA; 0x100]).unwrap();
+        let gb = GameBoy::builder(spin()).bootrom(bootrom).skip_bootrom(true).build();
+        assert_eq!(entry_regs(&gb), [0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100]);
+        assert_eq!(gb.cpu.interconnect.readb(0x0000), 0x00);
+
+        let bootrom = Bootrom::from_bytes(&[0xAA; 0x100]).unwrap();
+        let gb = GameBoy::builder(spin()).bootrom(bootrom).build();
+        assert_eq!(gb.read_reg_w(RegsW::PC), 0x0000);
+        assert_eq!(gb.cpu.interconnect.readb(0x0000), 0xAA);
+    }
+
+    #[test]
+    fn rom_title() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"SYNTHETIC 1");
+        rom[0x147] = 0x01; // MBC1
+        rom[0x14C] = 0x02; // Version
+        let gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+        // The padding is left off.
+        assert_eq!(gb.rom_title(), "SYNTHETIC 1");
+        assert_eq!(gb.cart_header().title, "SYNTHETIC 1");
+        assert_eq!(gb.cart_header().cartridge_type, 0x01);
+        assert_eq!(gb.cart_header().version, 0x02);
+        assert!(format!("{:?}", gb).contains("SYNTHETIC 1"));
+    }
+
+    // Game Genie cheats patch the ROM while they're enabled, and put back
+    // what was there when they're disabled.
+    #[test]
+    fn cheats() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xFE;
+        rom[0x150] = 0x12;
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+        gb.set_cheats(cheat::parse("Patch = 3E1-50F\n\
+                                    Compare = 771-50F-E0A\n\
+                                    Poke = 010500C0")
+            .unwrap());
+        assert_eq!(gb.cheats().len(), 3);
+        // Compare doesn't match the ROM, so it does nothing.
+        assert_eq!(gb.cpu.interconnect.readb(0x0150), 0x3E);
+
+        gb.set_cheat_enabled(0, false);
+        assert!(!gb.cheats()[0].enabled);
+        assert_eq!(gb.cpu.interconnect.readb(0x0150), 0x12);
+        gb.set_cheat_enabled(0, true);
+        assert_eq!(gb.cpu.interconnect.readb(0x0150), 0x3E);
+        gb.set_cheat_enabled(0, false);
+        assert_eq!(gb.cpu.interconnect.readb(0x0150), 0x12);
+
+        gb.step_frame();
+        assert_eq!(gb.cpu.interconnect.readb(0xC000), 0x05);
+        gb.set_cheat_enabled(2, false);
+        gb.cpu.interconnect.writeb(0xC000, 0x00);
+        gb.step_frame();
+        assert_eq!(gb.cpu.interconnect.readb(0xC000), 0x00);
+
+        // Replacing the cheats disables the old ones first.
+        gb.set_cheat_enabled(0, true);
+        gb.set_cheats(Vec::new());
+        assert_eq!(gb.cpu.interconnect.readb(0x0150), 0x12);
+    }
+
+    // Sends 0x41 and then whatever it receives, forever. It waits for each
+    // transfer with HALT, which only the Serial interrupt can end.
+    fn echo() -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        let program = [0x3E, 0x08, 0xE0, 0xFF, // IE = Serial
+                       0x3E, 0x41, // LD A,0x41
+                       0xE0, 0x01, // SB = A
+                       0x3E, 0x81, 0xE0, 0x02, // SC = start, internal clock
+                       0x76, 0x00, // HALT; NOP
+                       0xAF, 0xE0, 0x0F, // IF = 0
+                       0xF0, 0x01, // LD A,SB
+                       0x18, 0xF1]; // JR to SB = A
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        Cartridge::from_bytes(rom).unwrap()
+    }
+
+    // The game sees the callback's byte in SB, and gets the interrupt.
+    #[test]
+    fn serial_callback() {
+        let mut gb = GameBoy::new(echo(), Bootrom::empty());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        gb.set_serial_callback(move |b| {
+            log.lock().unwrap().push(b);
+            b.wrapping_add(1)
+        });
+
+        while gb.serial_bits_shifted() < 8 {
+            gb.step_instruction();
+        }
+        assert_eq!(gb.cpu.interconnect.serial.read_sb(), 0x42);
+        assert!(gb.cpu.interconnect.ic.read_if() & 0x08 != 0);
+
+        // Each transfer takes 4096 cycles.
+        gb.run(10 * 4096);
+        let received = received.lock().unwrap();
+        assert_eq!(&received[..10],
+                   &[0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A]);
+        assert_eq!(gb.serial_bytes(), &received[..]);
+    }
+
+    // Sends first and then one more than whatever it receives, forever,
+    // starting each transfer with sc.
+    fn increment(sc: u8, first: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        let program = [0x3E, 0x08, 0xE0, 0xFF, // IE = Serial
+                       0x3E, first, // LD A,first
+                       0xE0, 0x01, // SB = A
+                       0x3E, sc, 0xE0, 0x02, // SC = sc
+                       0x76, 0x00, // HALT; NOP
+                       0xAF, 0xE0, 0x0F, // IF = 0
+                       0xF0, 0x01, // LD A,SB
+                       0x3C, // INC A
+                       0x18, 0xF0]; // JR to SB = A
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        Cartridge::from_bytes(rom).unwrap()
+    }
+
+    // Each side sends one more than the other sent last, and the side on the
+    // external clock only moves when the other drives it.
+    #[test]
+    fn run_linked() {
+        let mut a = GameBoy::new(increment(0x81, 0x10), Bootrom::empty());
+        let mut b = GameBoy::new(increment(0x80, 0x80), Bootrom::empty());
+        link::connect(&mut a, &mut b);
+
+        // Uneven amounts, so that slices don't always line up with the
+        // calls.
+        for i in 0..1000 {
+            GameBoy::run_linked(&mut a, &mut b, i % 200 + 1);
+            let skew = a.elapsed_cycles() as i64 - b.elapsed_cycles() as i64;
+            assert!(skew.abs() <= LINK_SLICE as i64, "skew {} after {}", skew, i);
+        }
+
+        let a_sent = a.serial_bytes();
+        let b_sent = b.serial_bytes();
+        assert!(a_sent.len() > 10, "{:?}", a_sent);
+        assert_eq!(&a_sent[..6], &[0x10, 0x81, 0x12, 0x83, 0x14, 0x85]);
+        assert_eq!(&b_sent[..6], &[0x80, 0x11, 0x82, 0x13, 0x84, 0x15]);
+        for (i, (&x, &y)) in a_sent.iter().zip(b_sent).enumerate().skip(1) {
+            assert_eq!(x, b_sent[i - 1].wrapping_add(1));
+            assert_eq!(y, a_sent[i - 1].wrapping_add(1));
+        }
+    }
+
+    // Many small timeslices add up to what was asked for, give or take the
+    // last instruction, whether the CPU is running or halted.
+    #[test]
+    fn run_budget() {
+        let mut gb = GameBoy::new(echo(), Bootrom::empty());
+        let mut requested = 0;
+        let mut run = 0;
+        let mut i = 0;
+        while requested < 1_000_000 {
+            let timeslice = cmp::min(i % 300 + 1, 1_000_000 - requested);
+            requested += timeslice;
+            run += gb.run(timeslice).cycles_run;
+            i += 1;
+        }
+        assert_eq!(run as u64, gb.elapsed_cycles());
+        // The longest instruction takes 24 cycles.
+        assert!(run >= requested && run - requested < 24, "{}", run);
+    }
+
+    #[test]
+    fn run_nothing() {
+        let mut gb = GameBoy::new(echo(), Bootrom::empty());
+        let result = gb.run(0);
+        assert_eq!(result.cycles_run, 0);
+        assert!(result.stopped.is_none());
+        assert_eq!(gb.elapsed_cycles(), 0);
+        assert_eq!(gb.elapsed_instructions(), 0);
+    }
+
+    // A budget shorter than an instruction still runs one, and the cycles
+    // that it overran by are owed by the next call.
+    #[test]
+    fn run_one_cycle() {
+        // JR -2 takes 12 cycles.
+        let mut gb = GameBoy::new(spin(), Bootrom::empty());
+        assert_eq!(gb.run(1).cycles_run, 12);
+        assert_eq!(gb.elapsed_instructions(), 1);
+        assert_eq!(gb.run(11).cycles_run, 0);
+        assert_eq!(gb.elapsed_instructions(), 1);
+        assert_eq!(gb.run(1).cycles_run, 12);
+        assert_eq!(gb.elapsed_instructions(), 2);
+        assert_eq!(gb.elapsed_cycles(), 24);
+    }
+
+    // A budget of exactly one instruction runs it and leaves nothing owed.
+    #[test]
+    fn run_one_instruction() {
+        let mut gb = GameBoy::new(spin(), Bootrom::empty());
+        for i in 1..4 {
+            assert_eq!(gb.run(12).cycles_run, 12);
+            assert_eq!(gb.elapsed_instructions(), i);
+            assert_eq!(gb.cpu_state().pc, 0x0100);
+        }
+        assert_eq!(gb.run(0).cycles_run, 0);
+        assert_eq!(gb.elapsed_cycles(), 36);
+    }
+
+    // GameBoy is Send so that frontends can run emulation on its own thread,
+    // see examples/threaded.rs. Anything boxed inside it (serial devices,
+    // callbacks) must be Send too - this fails to compile if that's ever
+    // broken.
+    #[test]
+    fn gameboy_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<GameBoy>();
+    }
+
+    // new is the same as a builder that's left alone.
+    #[test]
+    fn builder_defaults() {
+        let new = GameBoy::new(spin(), Bootrom::empty());
+        let built = GameBoy::builder(spin()).build();
+        assert_eq!(entry_regs(&new), entry_regs(&built));
+        assert_eq!(entry_regs(&built)[0], 0x01B0);
+    }
+
+    // Every pixel comes out in the palette's colour for its shade, the same
+    // in both formats, and converting doesn't run anything.
+    #[test]
+    fn frame_conversion() {
+        // Fill the screen with tile 0, whose rows have two pixels of each
+        // shade.
+        let mut rom = vec![0; 0x8000];
+        let code = [0xAF, // XOR A
+                    0xE0, 0x40, // LDH (LCDC),A
+                    0x21, 0x00, 0x80, // LD HL,0x8000
+                    0x06, 0x08, // LD B,8
+                    0x3E, 0x33, // LD A,0x33
+                    0x22, // LD (HL+),A
+                    0x3E, 0x0F, // LD A,0x0F
+                    0x22, // LD (HL+),A
+                    0x05, // DEC B
+                    0x20, 0xF7, // JR NZ,-9
+                    0x3E, 0xE4, // LD A,0xE4
+                    0xE0, 0x47, // LDH (BGP),A
+                    0x3E, 0x91, // LD A,0x91
+                    0xE0, 0x40, // LDH (LCDC),A
+                    0x18, 0xFE]; // JR -2
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+        let palette = Palette([0x11_2233, 0x44_5566, 0x77_8899, 0xAA_BBCC]);
+        gb.set_palette(palette);
+        gb.run_frames(3);
+
+        let cycles = gb.elapsed_cycles();
+        let mut xrgb = vec![0; SCREEN_W * SCREEN_H];
+        let mut rgba = vec![0; SCREEN_W * SCREEN_H * 4];
+        gb.frame_into_xrgb(&mut xrgb);
+        gb.frame_into_rgba(&mut rgba);
+        assert_eq!(gb.elapsed_cycles(), cycles);
+
+        let shades = gb.back_buffer();
+        for &shade in [0, 1, 2, 3].iter() {
+            assert!(shades.contains(&shade), "no pixels of shade {}", shade);
+        }
+        for (i, (&x, p)) in xrgb.iter().zip(rgba.chunks(4)).enumerate() {
+            assert_eq!(x, palette.rgb(shades[i]), "pixel {}", i);
+            assert_eq!(p, [(x >> 16) as u8, (x >> 8) as u8, x as u8, 0xFF]);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt;
-use gameboy::{SCREEN_W, SCREEN_H};
+use gameboy::{SCREEN_W, SCREEN_H, TILESET_W, TILESET_H, BG_MAP_W};
 use interrupt;
+use savestate::{StateWriter, StateReader};
 
 const VRAM_TILES: usize = 384;
 const TILE_MAP_SZ: usize = 0x400;
@@ -8,7 +9,7 @@ const SPRITE_COUNT: usize = 40;
 const HBLANK_CYCLES: i16 = 204;
 const ACCESSING_OAM_CYCLES: i16 = 80;
 const ACCESSING_VRAM_CYCLES: i16 = 172;
-const VBLANK_FULL_LINE_CYCLES: i16 = 576;
+const VBLANK_FULL_LINE_CYCLES: i16 = 456;
 
 #[derive(PartialEq, Debug)]
 enum Mode {
@@ -54,6 +55,21 @@ struct Tile {
     pixels: [u8; 16],
 }
 
+impl Tile {
+    // Draw the tile's colour numbers into out, which is width pixels wide,
+    // with its top left corner at (x, y). Each row is two bytes, with the
+    // low bits of the colour numbers in the first.
+    fn draw(&self, out: &mut [u8], width: usize, x: usize, y: usize) {
+        for row in 0..8 {
+            let (lo, hi) = (self.pixels[row * 2], self.pixels[row * 2 + 1]);
+            for col in 0..8 {
+                let bit = 7 - col;
+                out[(y + row) * width + x + col] = (hi >> bit & 1) << 1 | lo >> bit & 1;
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Sprite {
     x: u8,
@@ -161,6 +177,9 @@ pub struct Gpu {
     tile_set: [Tile; VRAM_TILES],
     tile_map1: [u8; TILE_MAP_SZ],
     tile_map2: [u8; TILE_MAP_SZ],
+    frames: u64,
+    // What reads of LY return instead of the real line, if anything.
+    ly_stub: Option<u8>,
 }
 
 // TODO: Display the regs as hex
@@ -184,6 +203,7 @@ impl fmt::Debug for Gpu {
             .field("win_y", &format_args!("0x{:02x}", self.win_y))
             .field("ly", &format_args!("0x{:02x}", self.ly))
             .field("lyc", &format_args!("0x{:02x}", self.lyc))
+            .field("frames", &self.frames)
             .finish()
     }
 }
@@ -216,9 +236,27 @@ impl Gpu {
             tile_set: [Tile::default(); VRAM_TILES],
             tile_map1: [0; TILE_MAP_SZ],
             tile_map2: [0; TILE_MAP_SZ],
+            frames: 0,
+            ly_stub: None,
+        }
+    }
+
+    // Cycles until the next mode change.
+    pub fn next_event(&self) -> u32 {
+        if !self.lcd_enable {
+            u32::MAX
+        } else if self.ticks > 0 {
+            self.ticks as u32
+        } else {
+            0
         }
     }
 
+    // The number of frames completed, counted on entry to VBlank.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
     pub fn read_tileset(&self, addr: u16) -> u8 {
         if self.mode == self::Mode::AccessingVram {
             return 0xFF;
@@ -319,7 +357,11 @@ impl Gpu {
     }
 
     pub fn read_ly(&self) -> u8 {
-        self.ly
+        self.ly_stub.unwrap_or(self.ly)
+    }
+
+    pub fn set_ly_stub(&mut self, ly: Option<u8>) {
+        self.ly_stub = ly;
     }
 
     pub fn write_ly(&mut self, val: u8) {
@@ -367,13 +409,78 @@ impl Gpu {
         self.obp1.set_reg(val);
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.mode.as_flag());
+        w.write_u16(self.ticks as u16);
+        for sprite in self.oam.iter() {
+            w.write_u8(sprite.y);
+            w.write_u8(sprite.x);
+            w.write_u8(sprite.tile_index);
+            w.write_u8(sprite.flags.bits());
+        }
+        w.write_bytes(&self.buffer);
+        w.write_u8(self.read_lcdc_reg());
+        w.write_u8(self.stat.bits());
+        w.write_u8(self.scroll_x);
+        w.write_u8(self.scroll_y);
+        w.write_u8(self.win_x);
+        w.write_u8(self.win_y);
+        w.write_u8(self.ly);
+        w.write_u8(self.lyc);
+        w.write_u8(self.bgp.reg);
+        w.write_u8(self.obp0.reg);
+        w.write_u8(self.obp1.reg);
+        for tile in self.tile_set.iter() {
+            w.write_bytes(&tile.pixels);
+        }
+        w.write_bytes(&self.tile_map1);
+        w.write_bytes(&self.tile_map2);
+        w.write_u64(self.frames);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.mode = match try!(r.read_u8()) {
+            0b00 => Mode::HBlank,
+            0b01 => Mode::VBlank,
+            0b10 => Mode::AccessingOam,
+            _ => Mode::AccessingVram,
+        };
+        self.ticks = try!(r.read_u16()) as i16;
+        for sprite in self.oam.iter_mut() {
+            sprite.y = try!(r.read_u8());
+            sprite.x = try!(r.read_u8());
+            sprite.tile_index = try!(r.read_u8());
+            sprite.flags = SpriteFlags::from_bits_truncate(try!(r.read_u8()));
+        }
+        try!(r.read_bytes(&mut self.buffer));
+        let lcdc = try!(r.read_u8());
+        self.write_lcdc_reg(lcdc);
+        self.stat = StatReg::from_bits_truncate(try!(r.read_u8()));
+        self.scroll_x = try!(r.read_u8());
+        self.scroll_y = try!(r.read_u8());
+        self.win_x = try!(r.read_u8());
+        self.win_y = try!(r.read_u8());
+        self.ly = try!(r.read_u8());
+        self.lyc = try!(r.read_u8());
+        self.bgp.set_reg(try!(r.read_u8()));
+        self.obp0.set_reg(try!(r.read_u8()));
+        self.obp1.set_reg(try!(r.read_u8()));
+        for tile in self.tile_set.iter_mut() {
+            try!(r.read_bytes(&mut tile.pixels));
+        }
+        try!(r.read_bytes(&mut self.tile_map1));
+        try!(r.read_bytes(&mut self.tile_map2));
+        self.frames = try!(r.read_u64());
+        Ok(())
+    }
+
     fn check_cmp_int(&mut self, ic: &mut interrupt::InterruptController) {
         if self.ly != self.lyc {
             self.stat.remove(STAT_CMP);
         } else {
             self.stat.insert(STAT_CMP);
             if self.stat.contains(STAT_CMP_INT) {
-                ic.request_interrupt(interrupt::Interrupt::LCDCStat);
+                ic.request(interrupt::INT_LCDC_STAT);
             }
         }
     }
@@ -384,15 +491,17 @@ impl Gpu {
             Mode::HBlank => self.ticks += HBLANK_CYCLES,
             Mode::VBlank => {
                 self.ticks += VBLANK_FULL_LINE_CYCLES;
-                ic.request_interrupt(interrupt::Interrupt::VBlank);
+                self.frames += 1;
+                let mut ints = interrupt::INT_VBLANK;
                 if self.stat.contains(STAT_VBLANK_INT) {
-                    ic.request_interrupt(interrupt::Interrupt::LCDCStat);
+                    ints.insert(interrupt::INT_LCDC_STAT);
                 }
+                ic.request(ints);
             }
             Mode::AccessingOam => {
                 self.ticks += ACCESSING_OAM_CYCLES;
                 if self.stat.contains(STAT_OAM_INT) {
-                    ic.request_interrupt(interrupt::Interrupt::LCDCStat);
+                    ic.request(interrupt::INT_LCDC_STAT);
                 }
             }
             Mode::AccessingVram => self.ticks += ACCESSING_VRAM_CYCLES,
@@ -462,7 +571,8 @@ impl Gpu {
         };
 
         for i in 0..SCREEN_W {
-            let x = i.wrapping_add(self.scroll_x as usize);
+            // The background map wraps around at 256 pixels.
+            let x = (i + self.scroll_x as usize) & 0xFF;
             let bit = ((x % 8) as u8).wrapping_sub(7).wrapping_mul(0xff);
             let column = x >> 3;
             // The first of two rows in the tile that we are going to render.
@@ -507,6 +617,36 @@ impl Gpu {
         }
     }
 
+    pub fn dump_tileset(&self) -> Vec<u8> {
+        let mut out = vec![0; TILESET_W * TILESET_H];
+        let per_row = TILESET_W / 8;
+        for (i, tile) in self.tile_set.iter().enumerate() {
+            let (x, y) = (i % per_row * 8, i / per_row * 8);
+            tile.draw(&mut out, TILESET_W, x, y);
+        }
+        out
+    }
+
+    pub fn dump_bg_map(&self, map: u8) -> Vec<u8> {
+        let tile_map = if map == 0 {
+            &self.tile_map1
+        } else {
+            &self.tile_map2
+        };
+        let mut out = vec![0; BG_MAP_W * BG_MAP_W];
+        for (i, &tile_num) in tile_map.iter().enumerate() {
+            // With the 0x8800 addressing mode, tile numbers are signed
+            // offsets from the tile at 0x9000.
+            let index = if self.bg_tile_set {
+                tile_num as usize
+            } else {
+                (256 + tile_num as i8 as i16) as usize
+            };
+            self.tile_set[index].draw(&mut out, BG_MAP_W, i % 32 * 8, i / 32 * 8);
+        }
+        out
+    }
+
     pub fn read_oam(&self, addr: u16) -> u8 {
         if self.mode == self::Mode::AccessingVram || self.mode == self::Mode::AccessingOam {
             return 0xFF;
@@ -0,0 +1,126 @@
+use gilrs::{self, Gilrs, Axis, EventType};
+
+use iogb::gameboy::Button;
+
+// How far a stick has to be pushed before it counts as a d-pad press.
+const STICK_THRESHOLD: f32 = 0.5;
+
+// The names pad buttons go by in the config file, following gilrs' layout.
+const PAD_BUTTON_NAMES: [(&'static str, gilrs::Button); 17] =
+    [("South", gilrs::Button::South),
+     ("East", gilrs::Button::East),
+     ("North", gilrs::Button::North),
+     ("West", gilrs::Button::West),
+     ("LeftTrigger", gilrs::Button::LeftTrigger),
+     ("LeftTrigger2", gilrs::Button::LeftTrigger2),
+     ("RightTrigger", gilrs::Button::RightTrigger),
+     ("RightTrigger2", gilrs::Button::RightTrigger2),
+     ("Select", gilrs::Button::Select),
+     ("Start", gilrs::Button::Start),
+     ("Mode", gilrs::Button::Mode),
+     ("LeftThumb", gilrs::Button::LeftThumb),
+     ("RightThumb", gilrs::Button::RightThumb),
+     ("DPadUp", gilrs::Button::DPadUp),
+     ("DPadDown", gilrs::Button::DPadDown),
+     ("DPadLeft", gilrs::Button::DPadLeft),
+     ("DPadRight", gilrs::Button::DPadRight)];
+
+pub fn pad_button_from_name(name: &str) -> Option<gilrs::Button> {
+    PAD_BUTTON_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, b)| b)
+}
+
+pub fn pad_button_name(pad_button: gilrs::Button) -> Option<&'static str> {
+    PAD_BUTTON_NAMES.iter().find(|&&(_, b)| b == pad_button).map(|&(n, _)| n)
+}
+
+#[derive(Debug, Clone)]
+pub struct PadBindings {
+    bindings: Vec<(gilrs::Button, Button)>,
+}
+
+impl Default for PadBindings {
+    // The standard layout, which gilrs maps DualShock and Xbox pads onto.
+    fn default() -> PadBindings {
+        PadBindings {
+            bindings: vec![(gilrs::Button::DPadRight, Button::Right),
+                           (gilrs::Button::DPadLeft, Button::Left),
+                           (gilrs::Button::DPadUp, Button::Up),
+                           (gilrs::Button::DPadDown, Button::Down),
+                           (gilrs::Button::East, Button::A),
+                           (gilrs::Button::South, Button::B),
+                           (gilrs::Button::Select, Button::Select),
+                           (gilrs::Button::Start, Button::Start)],
+        }
+    }
+}
+
+impl PadBindings {
+    // Replace whatever pad buttons were bound to button with pad_button.
+    pub fn bind(&mut self, pad_button: gilrs::Button, button: Button) {
+        self.bindings.retain(|&(_, b)| b != button);
+        self.bindings.push((pad_button, button));
+    }
+
+    pub fn bindings(&self) -> &[(gilrs::Button, Button)] {
+        &self.bindings
+    }
+}
+
+pub struct Gamepads {
+    gilrs: Gilrs,
+    bindings: PadBindings,
+}
+
+impl Gamepads {
+    pub fn new(bindings: PadBindings) -> Result<Gamepads, String> {
+        let gilrs = try!(Gilrs::new().map_err(|e| format!("{}", e)));
+        for (_, pad) in gilrs.gamepads() {
+            info!("Found gamepad: {}", pad.name());
+        }
+
+        Ok(Gamepads {
+            gilrs: gilrs,
+            bindings: bindings,
+        })
+    }
+
+    pub fn poll(&mut self, pressed: &mut Vec<Button>) {
+        // Drain the event queue, which is also what keeps gilrs' view of
+        // connected pads up to date when they are plugged in or removed.
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::Connected => {
+                    info!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name())
+                }
+                EventType::Disconnected => info!("Gamepad disconnected"),
+                _ => {}
+            }
+        }
+
+        for (_, pad) in self.gilrs.gamepads() {
+            let mut held = Vec::new();
+            for &(pad_button, button) in self.bindings.bindings.iter() {
+                if pad.is_pressed(pad_button) {
+                    held.push(button);
+                }
+            }
+
+            let x = pad.value(Axis::LeftStickX);
+            let y = pad.value(Axis::LeftStickY);
+            if x > STICK_THRESHOLD {
+                held.push(Button::Right);
+            } else if x < -STICK_THRESHOLD {
+                held.push(Button::Left);
+            }
+            if y > STICK_THRESHOLD {
+                held.push(Button::Up);
+            } else if y < -STICK_THRESHOLD {
+                held.push(Button::Down);
+            }
+
+            for button in held {
+                super::press(pressed, button);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use minifb::{Key, Window};
+
+use iogb::gameboy::Button;
+
+// The names keys go by in the config file.
+const KEY_NAMES: [(&'static str, Key); 96] = [("A", Key::A),
+                                              ("B", Key::B),
+                                              ("C", Key::C),
+                                              ("D", Key::D),
+                                              ("E", Key::E),
+                                              ("F", Key::F),
+                                              ("G", Key::G),
+                                              ("H", Key::H),
+                                              ("I", Key::I),
+                                              ("J", Key::J),
+                                              ("K", Key::K),
+                                              ("L", Key::L),
+                                              ("M", Key::M),
+                                              ("N", Key::N),
+                                              ("O", Key::O),
+                                              ("P", Key::P),
+                                              ("Q", Key::Q),
+                                              ("R", Key::R),
+                                              ("S", Key::S),
+                                              ("T", Key::T),
+                                              ("U", Key::U),
+                                              ("V", Key::V),
+                                              ("W", Key::W),
+                                              ("X", Key::X),
+                                              ("Y", Key::Y),
+                                              ("Z", Key::Z),
+                                              ("0", Key::Key0),
+                                              ("1", Key::Key1),
+                                              ("2", Key::Key2),
+                                              ("3", Key::Key3),
+                                              ("4", Key::Key4),
+                                              ("5", Key::Key5),
+                                              ("6", Key::Key6),
+                                              ("7", Key::Key7),
+                                              ("8", Key::Key8),
+                                              ("9", Key::Key9),
+                                              ("F1", Key::F1),
+                                              ("F2", Key::F2),
+                                              ("F3", Key::F3),
+                                              ("F4", Key::F4),
+                                              ("F5", Key::F5),
+                                              ("F6", Key::F6),
+                                              ("F7", Key::F7),
+                                              ("F8", Key::F8),
+                                              ("F9", Key::F9),
+                                              ("F10", Key::F10),
+                                              ("F11", Key::F11),
+                                              ("F12", Key::F12),
+                                              ("Up", Key::Up),
+                                              ("Down", Key::Down),
+                                              ("Left", Key::Left),
+                                              ("Right", Key::Right),
+                                              ("Enter", Key::Enter),
+                                              ("Space", Key::Space),
+                                              ("Backspace", Key::Backspace),
+                                              ("Tab", Key::Tab),
+                                              ("Escape", Key::Escape),
+                                              ("Insert", Key::Insert),
+                                              ("Delete", Key::Delete),
+                                              ("Home", Key::Home),
+                                              ("End", Key::End),
+                                              ("PageUp", Key::PageUp),
+                                              ("PageDown", Key::PageDown),
+                                              ("LeftShift", Key::LeftShift),
+                                              ("RightShift", Key::RightShift),
+                                              ("LeftCtrl", Key::LeftCtrl),
+                                              ("RightCtrl", Key::RightCtrl),
+                                              ("LeftAlt", Key::LeftAlt),
+                                              ("RightAlt", Key::RightAlt),
+                                              ("Comma", Key::Comma),
+                                              ("Period", Key::Period),
+                                              ("Slash", Key::Slash),
+                                              ("Semicolon", Key::Semicolon),
+                                              ("Apostrophe", Key::Apostrophe),
+                                              ("Minus", Key::Minus),
+                                              ("Equal", Key::Equal),
+                                              ("LeftBracket", Key::LeftBracket),
+                                              ("RightBracket", Key::RightBracket),
+                                              ("Backslash", Key::Backslash),
+                                              ("Backquote", Key::Backquote),
+                                              ("NumPad0", Key::NumPad0),
+                                              ("NumPad1", Key::NumPad1),
+                                              ("NumPad2", Key::NumPad2),
+                                              ("NumPad3", Key::NumPad3),
+                                              ("NumPad4", Key::NumPad4),
+                                              ("NumPad5", Key::NumPad5),
+                                              ("NumPad6", Key::NumPad6),
+                                              ("NumPad7", Key::NumPad7),
+                                              ("NumPad8", Key::NumPad8),
+                                              ("NumPad9", Key::NumPad9),
+                                              ("NumPadEnter", Key::NumPadEnter),
+                                              ("NumPadPlus", Key::NumPadPlus),
+                                              ("NumPadMinus", Key::NumPadMinus),
+                                              ("NumPadAsterisk", Key::NumPadAsterisk),
+                                              ("NumPadSlash", Key::NumPadSlash),
+                                              ("NumPadDot", Key::NumPadDot)];
+
+pub fn key_from_name(name: &str) -> Option<Key> {
+    KEY_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, k)| k)
+}
+
+pub fn key_name(key: Key) -> Option<&'static str> {
+    KEY_NAMES.iter().find(|&&(_, k)| k == key).map(|&(n, _)| n)
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+    bindings: Vec<(Key, Button)>,
+    turbo: Vec<(Key, Button)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            bindings: vec![(Key::Right, Button::Right),
+                           (Key::Left, Button::Left),
+                           (Key::Up, Button::Up),
+                           (Key::Down, Button::Down),
+                           (Key::Z, Button::A),
+                           (Key::X, Button::B),
+                           (Key::Backspace, Button::Select),
+                           (Key::Enter, Button::Start)],
+            turbo: vec![(Key::A, Button::A), (Key::S, Button::B)],
+        }
+    }
+}
+
+impl KeyBindings {
+    // Replace whatever keys were bound to button with key.
+    pub fn bind(&mut self, key: Key, button: Button) {
+        self.bindings.retain(|&(_, b)| b != button);
+        self.bindings.push((key, button));
+    }
+
+    // Replace whatever key was the turbo key for button with key.
+    pub fn bind_turbo(&mut self, key: Key, button: Button) {
+        self.turbo.retain(|&(_, b)| b != button);
+        self.turbo.push((key, button));
+    }
+
+    pub fn bindings(&self) -> &[(Key, Button)] {
+        &self.bindings
+    }
+
+    pub fn turbo_bindings(&self) -> &[(Key, Button)] {
+        &self.turbo
+    }
+
+    pub fn poll(&self, window: &Window, pressed: &mut Vec<Button>) {
+        for &(key, button) in self.bindings.iter() {
+            if window.is_key_down(key) {
+                super::press(pressed, button);
+            }
+        }
+    }
+
+    // The buttons whose turbo keys are held.
+    pub fn poll_turbo(&self, window: &Window, held: &mut Vec<Button>) {
+        for &(key, button) in self.turbo.iter() {
+            if window.is_key_down(key) {
+                super::press(held, button);
+            }
+        }
+    }
+}
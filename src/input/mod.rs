@@ -0,0 +1,53 @@
+// Frontend input: maps host keyboards and gamepads onto GameBoy buttons.
+mod keyboard;
+mod turbo;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+
+pub use self::keyboard::{KeyBindings, key_from_name, key_name};
+pub use self::turbo::{Turbo, DEFAULT_RATE as DEFAULT_TURBO_RATE};
+#[cfg(feature = "gamepad")]
+pub use self::gamepad::{Gamepads, PadBindings, pad_button_from_name, pad_button_name};
+
+use iogb::gameboy::{GameBoy, Button};
+
+pub const BUTTONS: [Button; 8] = [Button::Right,
+                                  Button::Left,
+                                  Button::Up,
+                                  Button::Down,
+                                  Button::A,
+                                  Button::B,
+                                  Button::Select,
+                                  Button::Start];
+
+// The names buttons go by in the config file.
+const BUTTON_NAMES: [(&'static str, Button); 8] = [("right", Button::Right),
+                                                   ("left", Button::Left),
+                                                   ("up", Button::Up),
+                                                   ("down", Button::Down),
+                                                   ("a", Button::A),
+                                                   ("b", Button::B),
+                                                   ("select", Button::Select),
+                                                   ("start", Button::Start)];
+
+pub fn button_from_name(name: &str) -> Option<Button> {
+    BUTTON_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, b)| b)
+}
+
+pub fn button_name(button: Button) -> &'static str {
+    BUTTON_NAMES.iter().find(|&&(_, b)| b == button).map(|&(n, _)| n).unwrap()
+}
+
+// Add button to the set of pressed buttons.
+pub fn press(pressed: &mut Vec<Button>, button: Button) {
+    if !pressed.contains(&button) {
+        pressed.push(button);
+    }
+}
+
+// Pass the buttons held across all input sources on to the GameBoy.
+pub fn apply(gb: &mut GameBoy, pressed: &[Button]) {
+    for &button in BUTTONS.iter() {
+        gb.set_button(button, pressed.contains(&button));
+    }
+}
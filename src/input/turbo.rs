@@ -0,0 +1,87 @@
+// Auto-fire. While a turbo key is held its button is pressed and released
+// on alternate halves of each cycle. This is counted in emulated frames
+// rather than wall time, so the presses that reach the GameBoy are the same
+// on every run.
+
+// Frames per press and release cycle, i.e. 15Hz at ~60 frames per second.
+pub const DEFAULT_RATE: u32 = 4;
+
+#[derive(Debug)]
+pub struct Turbo {
+    rate: u32,
+    frame: u32,
+}
+
+impl Turbo {
+    // rate is the number of frames per cycle, at least 2.
+    pub fn new(rate: u32) -> Turbo {
+        Turbo {
+            rate: if rate < 2 { 2 } else { rate },
+            frame: 0,
+        }
+    }
+
+    // Advance by a frame, returning whether turbo buttons are pressed during
+    // it. Each time a turbo key is first held the cycle starts again with a
+    // press, so a quick tap always registers.
+    pub fn next_frame(&mut self, held: bool) -> bool {
+        if !held {
+            self.frame = 0;
+            return false;
+        }
+
+        let pressed = self.frame < self.rate / 2;
+        self.frame = (self.frame + 1) % self.rate;
+        pressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Turbo, DEFAULT_RATE};
+
+    fn frames(turbo: &mut Turbo, held: &[bool]) -> Vec<bool> {
+        held.iter().map(|&h| turbo.next_frame(h)).collect()
+    }
+
+    #[test]
+    fn default_rate() {
+        let mut turbo = Turbo::new(DEFAULT_RATE);
+        let pressed = frames(&mut turbo, &[true; 12]);
+        let cycle = [true, true, false, false];
+        let expected: Vec<bool> = cycle.iter().cycle().take(12).cloned().collect();
+        assert_eq!(pressed, expected);
+    }
+
+    // An odd rate spends the extra frame released.
+    #[test]
+    fn odd_rate() {
+        let mut turbo = Turbo::new(5);
+        assert_eq!(frames(&mut turbo, &[true; 10]),
+                   [true, true, false, false, false, true, true, false, false, false]);
+    }
+
+    // Anything below 2 couldn't both press and release.
+    #[test]
+    fn minimum_rate() {
+        for &rate in [0, 1, 2].iter() {
+            let mut turbo = Turbo::new(rate);
+            assert_eq!(frames(&mut turbo, &[true; 6]),
+                       [true, false, true, false, true, false],
+                       "rate {}",
+                       rate);
+        }
+    }
+
+    // Letting go releases straight away, and holding again starts with a
+    // press wherever the last cycle got to.
+    #[test]
+    fn restarts() {
+        let mut turbo = Turbo::new(DEFAULT_RATE);
+        assert_eq!(frames(&mut turbo, &[true, true, true, false, false, true, true, true]),
+                   [true, true, false, false, false, true, true, false]);
+        // A single frame tap.
+        assert_eq!(frames(&mut turbo, &[false, true, false, true]),
+                   [false, true, false, true]);
+    }
+}
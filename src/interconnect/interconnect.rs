@@ -1,10 +1,17 @@
+use std::cmp;
 use std::fmt;
 
 use interrupt;
+use cpu;
 use cartridge;
 use timer;
 use gpu;
+use apu;
 use bootrom;
+use serial;
+use joypad;
+use model::HardwareModel;
+use savestate::{StateWriter, StateReader};
 
 const WRAM_SZ: usize = 0x2000;
 const ZRAM_SZ: usize = 0x7F;
@@ -19,10 +26,29 @@ pub struct Interconnect {
     pub ic: interrupt::InterruptController,
     pub timer: timer::Timer,
     pub gpu: gpu::Gpu,
+    pub apu: apu::Apu,
+    pub serial: serial::Serial,
+    pub joypad: joypad::Joypad,
+    // With the scheduler the peripherals are left alone until one of them
+    // has something to do, or the CPU touches their registers. pending is
+    // how many cycles they are behind the CPU, and deadline how far behind
+    // they can get before one of them would do more than count.
+    scheduler: bool,
+    pending: u32,
+    deadline: u32,
+    // GameShark codes, written to RAM at the start of every frame.
+    pokes: Vec<(u16, u8)>,
 }
 
 impl Interconnect {
-    pub fn new(cart: cartridge::Cartridge, bootrom: bootrom::Bootrom) -> Interconnect {
+    // With skip_bootrom the boot ROM is never mapped, and the I/O registers
+    // start out as model's would have left them.
+    pub fn new(cart: cartridge::Cartridge,
+               bootrom: bootrom::Bootrom,
+               skip_bootrom: bool,
+               scheduler: bool,
+               model: HardwareModel)
+               -> Interconnect {
         let mut ic = Interconnect {
             brom: bootrom,
             wram: [0; WRAM_SZ],
@@ -32,50 +58,129 @@ impl Interconnect {
             ic: interrupt::InterruptController::new(),
             timer: timer::Timer::new(),
             gpu: gpu::Gpu::new(),
+            apu: apu::Apu::new(),
+            serial: serial::Serial::new(),
+            joypad: joypad::Joypad::new(),
+            scheduler: scheduler,
+            pending: 0,
+            deadline: 0,
+            pokes: Vec::new(),
         };
 
-        if !ic.brom.is_used() {
-            ic.fake_boot_rom();
+        if skip_bootrom {
+            ic.fake_boot_rom(model);
+            ic.boot_mode = false;
         }
         ic
     }
 
-    pub fn fake_boot_rom(&mut self) {
+    pub fn cart(&self) -> &cartridge::Cartridge {
+        &self.cart
+    }
+
+    pub fn cart_mut(&mut self) -> &mut cartridge::Cartridge {
+        &mut self.cart
+    }
+
+    pub fn set_pokes(&mut self, pokes: Vec<(u16, u8)>) {
+        self.pokes = pokes;
+    }
+
+    // Complete a transfer clocked by this side of a link cable, exchanging
+    // bytes with the peer.
+    pub fn link_exchange(&mut self, peer: &mut Interconnect) {
+        if let Some(out) = self.serial.take_outgoing() {
+            let incoming = peer.serial.clock_external(out, &mut peer.ic);
+            self.serial.finish_transfer(incoming, &mut self.ic);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.wram);
+        w.write_bytes(&self.zram);
+        w.write_bool(self.boot_mode);
+        self.joypad.save_state(w);
+        self.ic.save_state(w);
+        self.timer.save_state(w);
+        self.gpu.save_state(w);
+        self.apu.save_state(w);
+        self.serial.save_state(w);
+        self.cart.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        try!(r.read_bytes(&mut self.wram));
+        try!(r.read_bytes(&mut self.zram));
+        self.boot_mode = try!(r.read_bool());
+        try!(self.joypad.load_state(r));
+        try!(self.ic.load_state(r));
+        try!(self.timer.load_state(r));
+        try!(self.gpu.load_state(r));
+        try!(self.apu.load_state(r));
+        try!(self.serial.load_state(r));
+        self.pending = 0;
+        self.deadline = 0;
+        self.cart.load_state(r)
+    }
+
+    pub fn fake_boot_rom(&mut self, model: HardwareModel) {
         // Taken from the legendary pandocs.
         // http://bgb.bircd.org/pandocs.htm
-        self.writew(0xFF05, 0x00);   // TIMA
-        self.writew(0xFF06, 0x00);   // TMA
-        self.writew(0xFF07, 0x00);   // TAC
-        self.writew(0xFF10, 0x80);   // NR10
-        self.writew(0xFF11, 0xBF);   // NR11
-        self.writew(0xFF12, 0xF3);   // NR12
-        self.writew(0xFF14, 0xBF);   // NR14
-        self.writew(0xFF16, 0x3F);   // NR21
-        self.writew(0xFF17, 0x00);   // NR22
-        self.writew(0xFF19, 0xBF);   // NR24
-        self.writew(0xFF1A, 0x7F);   // NR30
-        self.writew(0xFF1B, 0xFF);   // NR31
-        self.writew(0xFF1C, 0x9F);   // NR32
-        self.writew(0xFF1E, 0xBF);   // NR33
-        self.writew(0xFF20, 0xFF);   // NR41
-        self.writew(0xFF21, 0x00);   // NR42
-        self.writew(0xFF22, 0x00);   // NR43
-        self.writew(0xFF23, 0xBF);   // NR30
-        self.writew(0xFF24, 0x77);   // NR50
-        self.writew(0xFF25, 0xF3);   // NR51
-        self.writew(0xFF26, 0xF1);   // NR52
-        self.writew(0xFF40, 0x91);   // LCDC
-        self.writew(0xFF42, 0x00);   // SCY
-        self.writew(0xFF43, 0x00);   // SCX
-        self.writew(0xFF45, 0x00);   // LYC
-        self.writew(0xFF47, 0xFC);   // BGP
-        self.writew(0xFF48, 0xFF);   // OBP0
-        self.writew(0xFF49, 0xFF);   // OBP1
-        self.writew(0xFF4A, 0x00);   // WY
-        self.writew(0xFF4B, 0x00);   // WX
-        self.writew(0xFFFF, 0x00);   // IE
+        self.writeb(0xFF05, 0x00);   // TIMA
+        self.writeb(0xFF06, 0x00);   // TMA
+        self.writeb(0xFF07, 0x00);   // TAC
+        // The APU ignores writes to its other registers while it's off.
+        self.writeb(0xFF26, 0xF1);   // NR52
+        self.writeb(0xFF10, 0x80);   // NR10
+        self.writeb(0xFF11, 0xBF);   // NR11
+        self.writeb(0xFF12, 0xF3);   // NR12
+        self.writeb(0xFF14, 0xBF);   // NR14
+        self.writeb(0xFF16, 0x3F);   // NR21
+        self.writeb(0xFF17, 0x00);   // NR22
+        self.writeb(0xFF19, 0xBF);   // NR24
+        self.writeb(0xFF1A, 0x7F);   // NR30
+        self.writeb(0xFF1B, 0xFF);   // NR31
+        self.writeb(0xFF1C, 0x9F);   // NR32
+        self.writeb(0xFF1E, 0xBF);   // NR34
+        self.writeb(0xFF20, 0xFF);   // NR41
+        self.writeb(0xFF21, 0x00);   // NR42
+        self.writeb(0xFF22, 0x00);   // NR43
+        self.writeb(0xFF23, 0xBF);   // NR44
+        self.writeb(0xFF24, 0x77);   // NR50
+        self.writeb(0xFF25, 0xF3);   // NR51
+        self.writeb(0xFF40, 0x91);   // LCDC
+        self.writeb(0xFF42, 0x00);   // SCY
+        self.writeb(0xFF43, 0x00);   // SCX
+        self.writeb(0xFF45, 0x00);   // LYC
+        self.writeb(0xFF47, 0xFC);   // BGP
+        self.writeb(0xFF48, 0xFF);   // OBP0
+        self.writeb(0xFF49, 0xFF);   // OBP1
+        self.writeb(0xFF4A, 0x00);   // WY
+        self.writeb(0xFF4B, 0x00);   // WX
+        self.writeb(0xFFFF, 0x00);   // IE
+
+        // The CGB's boot ROM leaves the serial port set to the internal
+        // clock.
+        if model == HardwareModel::Cgb {
+            self.writeb(0xFF02, 0x01);   // SC
+        }
+    }
+
+    fn unimplemented_read(&self, addr: u16) -> u8 {
+        warn!("Read from unimplemented I/O register 0x{:04x}", addr);
+        // Nothing drives the bus, so it floats high.
+        0xFF
     }
 
+    fn unimplemented_write(&self, addr: u16, val: u8) {
+        warn!("Write of 0x{:02x} to unimplemented I/O register 0x{:04x}",
+              val,
+              addr);
+    }
+
+    // Unused addresses read as 0xFF, as on hardware. Some are stubbed rather
+    // than unused: 0xFF03, 0xFF08 -> 0xFF0E and 0xFF4C -> 0xFF7F are unused
+    // or CGB-only on a DMG, while DMA (0xFF46) isn't implemented yet.
     pub fn readb(&self, addr: u16) -> u8 {
         match addr {
             0x0000...0x00FF => {
@@ -94,35 +199,42 @@ impl Interconnect {
             0xC000...0xDFFF => self.wram[addr as usize & 0x1FFF],
             0xE000...0xFDFF => self.wram[addr as usize & 0x1FFF],
             0xFE00...0xFE9F => self.gpu.read_oam(addr & 0x9F),
-            0xFEA0...0xFEFF => 0, // Gap
-            0xFF00...0xFF03 => 0, //MMIO
+            0xFEA0...0xFEFF => 0xFF, // Gap
+            0xFF00 => self.joypad.read_p1(),
+            0xFF01 => self.serial.read_sb(),
+            0xFF02 => self.serial.read_sc(),
+            0xFF03 => self.unimplemented_read(addr), //MMIO
             0xFF04 => self.timer.get_div(),
             0xFF05 => self.timer.get_tima(),
             0xFF06 => self.timer.get_tma(),
             0xFF07 => self.timer.get_tac(),
-            0xFF08...0xFF0E => 0, //MMIO
-            0xFF0F => self.ic.iflag,
-            0xFF10...0xFF3F => 0, //MMIO
+            0xFF08...0xFF0E => self.unimplemented_read(addr), //MMIO
+            0xFF0F => self.ic.read_if(),
+            0xFF10...0xFF3F => self.apu.readb(addr),
             0xFF40 => self.gpu.read_lcdc_reg(),
             0xFF41 => self.gpu.read_stat(),
             0xFF42 => self.gpu.read_scy(),
             0xFF43 => self.gpu.read_scx(),
             0xFF44 => self.gpu.read_ly(),
             0xFF45 => self.gpu.read_lyc(),
-            0xFF46 => 0, //MMIO
+            0xFF46 => self.unimplemented_read(addr), //MMIO
             0xFF47 => self.gpu.read_bgp(), //MMIO
             0xFF48 => self.gpu.read_obp0(), //MMIO
             0xFF49 => self.gpu.read_obp1(), //MMIO
             0xFF4A => self.gpu.read_wy(),
             0xFF4B => self.gpu.read_wx(),
-            0xFF4C...0xFF4F => 0, //MMIO
+            0xFF4C...0xFF4F => self.unimplemented_read(addr), //MMIO
+            // The boot ROM register can't be read back.
+            0xFF50 => 0xFF,
+            0xFF51...0xFF7F => self.unimplemented_read(addr), //MMIO
             0xFF80...0xFFFE => self.zram[addr as usize & 0x7F],
-            0xFFFF => self.ic.ie,
+            0xFFFF => self.ic.read_ie(),
             _ => panic!("Can't read 0x{:04x}", addr),
         }
     }
 
     pub fn writeb(&mut self, addr: u16, val: u8) {
+        self.sync_for(addr);
         match addr {
             0x0000...0x7FFF => self.cart.write_rom(addr, val),
             0x8000...0x97FF => self.gpu.write_tileset(addr & 0x17FF, val),
@@ -134,63 +246,237 @@ impl Interconnect {
             0xE000...0xFDFF => self.wram[addr as usize & 0x1FFF] = val,
             0xFE00...0xFE9F => self.gpu.write_oam(addr & 0x9F, val),
             0xFEA0...0xFEFF => {} // Gap
-            0xFF00...0xFF03 => {} //MMIO
+            0xFF00 => self.joypad.write_p1(val, &mut self.ic),
+            0xFF01 => self.serial.write_sb(val),
+            0xFF02 => self.serial.write_sc(val),
+            0xFF03 => self.unimplemented_write(addr, val), //MMIO
             0xFF04 => self.timer.set_div(val),
             0xFF05 => self.timer.set_tima(val),
             0xFF06 => self.timer.set_tma(val),
             0xFF07 => self.timer.set_tac(val),
-            0xFF08...0xFF0E => {} //MMIO
-            0xFF0F => self.ic.iflag = val,
-            0xFF10...0xFF3F => {} //MMIO
+            0xFF08...0xFF0E => self.unimplemented_write(addr, val), //MMIO
+            0xFF0F => self.ic.write_if(val),
+            0xFF10...0xFF3F => self.apu.writeb(addr, val),
             0xFF40 => self.gpu.write_lcdc_reg(val),
             0xFF41 => self.gpu.write_stat(val),
             0xFF42 => self.gpu.write_scy(val),
             0xFF43 => self.gpu.write_scx(val),
             0xFF44 => self.gpu.write_ly(val),
             0xFF45 => self.gpu.write_lyc(val),
-            0xFF46 => {} //MMIO
+            0xFF46 => self.unimplemented_write(addr, val), //MMIO
             0xFF47 => self.gpu.write_bgp(val), //MMIO
             0xFF48 => self.gpu.write_obp0(val), //MMIO
             0xFF49 => self.gpu.write_obp1(val), //MMIO
             0xFF4A => self.gpu.write_wy(val),
             0xFF4B => self.gpu.write_wx(val),
-            0xFF4C...0xFF4F => {} //MMIO
+            0xFF4C...0xFF4F => self.unimplemented_write(addr, val), //MMIO
             0xFF50 => self.boot_mode = !(val == 1),
-            0xFF51...0xFF7F => {} //MMIO
+            0xFF51...0xFF7F => self.unimplemented_write(addr, val), //MMIO
             0xFF80...0xFFFE => self.zram[addr as usize & 0x7F] = val,
-            0xFFFF => self.ic.enable_all_interrupts(val),
+            0xFFFF => self.ic.write_ie(val),
             _ => panic!("Can't write 0x{:02x} to 0x{:04x}", val, addr),
         }
-        if cfg!(debug_assertions) {
-            print!("\t0x{:04x}=0x{:02x}", addr, val);
+        // The write may have brought a peripheral's next event closer.
+        if self.scheduler && is_io(addr) {
+            self.deadline = self.next_event();
         }
     }
 
     pub fn readw(&self, addr: u16) -> u16 {
-        ((self.readb(addr + 1) as u16) << 8 | (self.readb(addr) as u16))
+        (self.readb(addr.wrapping_add(1)) as u16) << 8 | self.readb(addr) as u16
     }
 
     pub fn writew(&mut self, addr: u16, val: u16) {
         self.writeb(addr, (val & 0xFF) as u8);
-        self.writeb(addr + 1, (val >> 8) as u8);
+        self.writeb(addr.wrapping_add(1), (val >> 8) as u8);
     }
 
     pub fn step(&mut self, ticks: u32) -> u32 {
-        self.timer.step(ticks, &mut self.ic);
-        self.gpu.step(ticks, &mut self.ic);
+        if !self.scheduler {
+            self.advance(ticks);
+            return ticks;
+        }
+
+        if self.pending + ticks < self.deadline {
+            self.pending += ticks;
+            return ticks;
+        }
+        // The instruction that reaches the deadline is stepped on its own, so
+        // that everything happens after the same instruction as it would
+        // without the scheduler.
+        self.sync();
+        self.advance(ticks);
+        self.deadline = self.next_event();
         // TODO, This assumes that gpu and timer stuff takes no ticks...
         ticks
     }
+
+    // Bring the peripherals up to date with the CPU.
+    pub fn sync(&mut self) {
+        if self.pending > 0 {
+            let pending = self.pending;
+            self.pending = 0;
+            self.advance(pending);
+        }
+        self.deadline = self.next_event();
+    }
+
+    // Only the I/O registers can be out of date, so that's all that needs
+    // syncing before the CPU accesses memory.
+    pub fn sync_for(&mut self, addr: u16) {
+        if self.scheduler && self.pending > 0 && is_io(addr) {
+            self.sync();
+        }
+    }
+
+    // Cycles that step can be given in one go, with the same result as
+    // stepping them an instruction at a time.
+    pub fn quiet_cycles(&self) -> u32 {
+        if self.scheduler {
+            // Anything short of the deadline is only added to pending.
+            self.deadline.saturating_sub(self.pending + 1)
+        } else {
+            self.next_event().saturating_sub(1)
+        }
+    }
+
+    // Cycles until a peripheral next does more than count, before which
+    // stepping them once or many times gives the same result.
+    fn next_event(&self) -> u32 {
+        let timer = self.timer.next_event(self.apu.enabled());
+        cmp::min(timer, cmp::min(self.gpu.next_event(), self.serial.next_event()))
+    }
+
+    fn advance(&mut self, ticks: u32) {
+        self.timer.step(ticks, &mut self.ic);
+        let frames = self.gpu.frames();
+        self.gpu.step(ticks, &mut self.ic);
+        if self.gpu.frames() != frames {
+            self.joypad.latch(&mut self.ic);
+            self.poke();
+        }
+        for _ in 0..self.timer.take_div_edges() {
+            self.apu.div_falling_edge();
+        }
+        self.apu.step(ticks);
+        self.serial.step(ticks, &mut self.ic);
+    }
+
+    // Only RAM can be poked, so none of this needs to go through writeb.
+    fn poke(&mut self) {
+        for &(addr, val) in self.pokes.iter() {
+            match addr {
+                0xA000...0xBFFF => self.cart.write_ram(addr, val),
+                0xC000...0xDFFF => self.wram[addr as usize & 0x1FFF] = val,
+                0xFF80...0xFFFE => self.zram[addr as usize & 0x7F] = val,
+                _ => {}
+            }
+        }
+    }
+}
+
+// The CPU's accesses bring the I/O registers up to date first, as they
+// would be if everything ran in lockstep.
+impl cpu::Bus for Interconnect {
+    fn readb(&mut self, addr: u16) -> u8 {
+        self.sync_for(addr);
+        Interconnect::readb(self, addr)
+    }
+
+    fn writeb(&mut self, addr: u16, val: u8) {
+        Interconnect::writeb(self, addr, val);
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        Interconnect::readb(self, addr)
+    }
+
+    fn step(&mut self, ticks: u32) -> u32 {
+        Interconnect::step(self, ticks)
+    }
+
+    fn quiet_cycles(&self) -> u32 {
+        Interconnect::quiet_cycles(self)
+    }
+
+    fn ic(&self) -> &interrupt::InterruptController {
+        &self.ic
+    }
+
+    fn ic_mut(&mut self) -> &mut interrupt::InterruptController {
+        &mut self.ic
+    }
+}
+
+fn is_io(addr: u16) -> bool {
+    (0xFF00..0xFF80).contains(&addr)
 }
 
 impl fmt::Debug for Interconnect {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Interconnect")
             .field("boot_mode", &self.boot_mode)
+            .field("joypad", &self.joypad)
             .field("cart", &self.cart)
             .field("ic", &self.ic)
             .field("timer", &self.timer)
             .field("gpu", &self.gpu)
+            .field("apu", &self.apu)
+            .field("serial", &self.serial)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use model::HardwareModel;
+    use super::Interconnect;
+
+    // 0xFF00 -> 0xFF7F as a DMG game finds them. Unused and stubbed registers
+    // read as 0xFF. The timer, LCD and joypad start from where the fake boot
+    // leaves them rather than where a real boot ROM would: DIV and LY at 0,
+    // STAT in mode 2 rather than VBlank, IF without the VBlank request and
+    // neither P1 group selected.
+    const RESET: [u8; 0x80] = [
+        // P1 -> IF
+        0xFF, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+        // NR10 -> NR34
+        0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00,
+        0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+        // NR41 -> NR52
+        0xFF, 0x00, 0x00, 0xBF, 0x77, 0xF3, 0xF1, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        // Wave RAM
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // LCDC -> WX, with DMA unimplemented
+        0x91, 0x02, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFC,
+        0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+        // The boot ROM register, and then CGB only
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    ];
+
+    #[test]
+    fn reset_values() {
+        let ic = Interconnect::new(Cartridge::from_bytes(vec![0; 0x8000]).unwrap(),
+                                   Bootrom::empty(),
+                                   true,
+                                   false,
+                                   HardwareModel::Dmg);
+        for (i, &expected) in RESET.iter().enumerate() {
+            let addr = 0xFF00 + i as u16;
+            assert_eq!(ic.readb(addr), expected, "0x{:04x}", addr);
+        }
+        for addr in 0xFEA0..0xFF00 {
+            assert_eq!(ic.readb(addr), 0xFF, "0x{:04x}", addr);
+        }
+    }
+}
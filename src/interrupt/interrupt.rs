@@ -1,5 +1,7 @@
 use std::fmt;
 
+use savestate::{StateWriter, StateReader};
+
 #[derive(Clone, Copy)]
 pub enum Interrupt {
     VBlank = 1,
@@ -9,6 +11,41 @@ pub enum Interrupt {
     Joypad = 1 << 4,
 }
 
+// By bit in IF and IE, which is also their priority, highest first.
+const INTERRUPTS: [Interrupt; 5] = [Interrupt::VBlank,
+                                    Interrupt::LCDCStat,
+                                    Interrupt::Timer,
+                                    Interrupt::Serial,
+                                    Interrupt::Joypad];
+
+// Any number of interrupts, as held in IF and IE.
+bitflags!(
+    pub flags InterruptFlags: u8 {
+        const INT_VBLANK = 1,
+        const INT_LCDC_STAT = 1 << 1,
+        const INT_TIMER = 1 << 2,
+        const INT_SERIAL = 1 << 3,
+        const INT_JOYPAD = 1 << 4,
+    }
+);
+
+impl InterruptFlags {
+    // The one that is dispatched first, which is the lowest bit.
+    pub fn highest(&self) -> Option<Interrupt> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(INTERRUPTS[self.bits.trailing_zeros() as usize])
+        }
+    }
+}
+
+impl From<Interrupt> for InterruptFlags {
+    fn from(int: Interrupt) -> InterruptFlags {
+        InterruptFlags::from_bits_truncate(int as u8)
+    }
+}
+
 impl Interrupt {
     pub fn get_addr(&self) -> u16 {
         use self::Interrupt::*;
@@ -22,18 +59,27 @@ impl Interrupt {
     }
 }
 
+// IF, IE and the master enable. Devices request interrupts and the CPU
+// acknowledges them, and everything else goes through the registers.
 pub struct InterruptController {
-    pub ime: bool,
-    pub iflag: u8,
-    pub ie: u8,
+    // The master enable, which only the CPU changes. IE is separate, and
+    // EI and DI leave it alone.
+    ime: bool,
+    // EI only sets ime after the instruction that follows it.
+    ime_pending: bool,
+    iflag: InterruptFlags,
+    // The upper three bits have no interrupts behind them, but unlike IF's
+    // they can be written and read back.
+    ie: InterruptFlags,
 }
 
 impl fmt::Debug for InterruptController {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f,
-                 "int ctrl: ie: 0x{:08b} iflag 0x{:08b}",
-                 self.ie,
-                 self.iflag)
+                 "int ctrl: ie: 0x{:08b} iflag 0x{:08b} ime_pending {}",
+                 self.ie.bits,
+                 self.iflag.bits,
+                 self.ime_pending)
     }
 }
 
@@ -41,41 +87,209 @@ impl InterruptController {
     pub fn new() -> InterruptController {
         InterruptController {
             ime: false,
-            iflag: 0,
-            ie: 0,
+            ime_pending: false,
+            iflag: InterruptFlags::empty(),
+            ie: InterruptFlags::empty(),
         }
     }
 
-    pub fn get_interrupt(&self) -> Option<Interrupt> {
-        use self::Interrupt::*;
-        let interrupt = self.iflag & self.ie;
+    // The interrupt that would be dispatched, if ime allowed it: the highest
+    // priority one that is both requested and enabled.
+    pub fn pending(&self) -> Option<Interrupt> {
+        self.pending_flags().highest()
+    }
 
-        if interrupt == 0x0 || !self.ime {
-            None
-        } else if (interrupt & 1) == 1 {
-            Some(VBlank)
-        } else if (interrupt & (1 << 1)) == 1 << 1 {
-            Some(LCDCStat)
-        } else if (interrupt & (1 << 2)) == 1 << 2 {
-            Some(Timer)
-        } else if (interrupt & (1 << 3)) == 1 << 3 {
-            Some(Serial)
-        } else if (interrupt & (1 << 4)) == 1 << 4 {
-            Some(Joypad)
-        } else {
-            panic!("Invalid interrupt! IE: {:x} IF {:x}", self.ie, self.iflag);
+    // Whether an interrupt is both requested and enabled in IE, which is
+    // enough to wake a halted CPU whatever ime is.
+    pub fn has_pending(&self) -> bool {
+        !self.pending_flags().is_empty()
+    }
+
+    // Every interrupt that is both requested and enabled.
+    pub fn pending_flags(&self) -> InterruptFlags {
+        self.iflag & self.ie & InterruptFlags::all()
+    }
+
+    // Takes either an Interrupt or InterruptFlags, to request several at
+    // once.
+    pub fn request<I: Into<InterruptFlags>>(&mut self, ints: I) {
+        self.iflag.insert(ints.into());
+    }
+
+    // Clear requests once they have been dispatched.
+    pub fn acknowledge<I: Into<InterruptFlags>>(&mut self, ints: I) {
+        self.iflag.remove(ints.into());
+    }
+
+    // The upper three bits of IF don't exist, and read as 1.
+    pub fn read_if(&self) -> u8 {
+        self.iflag.bits | 0xE0
+    }
+
+    pub fn write_if(&mut self, val: u8) {
+        self.iflag = InterruptFlags::from_bits_truncate(val);
+    }
+
+    pub fn read_ie(&self) -> u8 {
+        self.ie.bits
+    }
+
+    pub fn write_ie(&mut self, val: u8) {
+        self.ie = InterruptFlags { bits: val };
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    // Takes effect straight away, cancelling an EI that hasn't yet.
+    pub fn set_ime(&mut self, enabled: bool) {
+        self.ime = enabled;
+        self.ime_pending = false;
+    }
+
+    // EI, which sets ime once the next instruction has executed.
+    pub fn set_ime_delayed(&mut self) {
+        self.ime_pending = true;
+    }
+
+    pub fn ime_pending(&self) -> bool {
+        self.ime_pending
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ime);
+        w.write_bool(self.ime_pending);
+        w.write_u8(self.iflag.bits);
+        w.write_u8(self.ie.bits);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.ime = try!(r.read_bool());
+        self.ime_pending = try!(r.read_bool());
+        self.write_if(try!(r.read_u8()));
+        self.write_ie(try!(r.read_u8()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{INTERRUPTS, Interrupt, InterruptController, InterruptFlags};
+    use super::{INT_JOYPAD, INT_LCDC_STAT, INT_SERIAL, INT_TIMER, INT_VBLANK};
+
+    // Whichever two are pending, the lower bit wins, and the other is next
+    // once it has been acknowledged.
+    #[test]
+    fn priority() {
+        for (i, &high) in INTERRUPTS.iter().enumerate() {
+            for (j, &low) in INTERRUPTS.iter().enumerate().skip(i) {
+                let mut ic = InterruptController::new();
+                ic.write_ie(0x1F);
+                ic.request(low);
+                ic.request(high);
+                assert!(ic.has_pending());
+                assert_eq!(ic.pending().map(|int| int as u8), Some(high as u8));
+
+                ic.acknowledge(high);
+                let next = if i == j { None } else { Some(low as u8) };
+                assert_eq!(ic.pending().map(|int| int as u8), next);
+                assert_eq!(ic.has_pending(), next.is_some());
+            }
         }
     }
 
-    pub fn reset_interrupt(&mut self, int: Interrupt) {
-        self.iflag &= !(int as u8);
+    // Only the five bits with interrupts behind them count.
+    #[test]
+    fn upper_bits_ignored() {
+        let mut ic = InterruptController::new();
+        ic.write_ie(0xFF);
+        ic.write_if(0xE0);
+        assert!(!ic.has_pending());
+        assert!(ic.pending().is_none());
+
+        ic.write_if(0xFF);
+        ic.write_ie(0xE0);
+        assert!(!ic.has_pending());
+        assert!(ic.pending().is_none());
+        assert!(ic.pending_flags().is_empty());
+    }
+
+    // Requested but not enabled in IE doesn't count.
+    #[test]
+    fn disabled_not_pending() {
+        for &int in INTERRUPTS.iter() {
+            let mut ic = InterruptController::new();
+            ic.request(int);
+            ic.write_ie(!(int as u8));
+            assert!(!ic.has_pending());
+            assert!(ic.pending().is_none());
+            ic.write_ie(int as u8);
+            assert_eq!(ic.pending().map(|int| int as u8), Some(int as u8));
+        }
     }
 
-    pub fn request_interrupt(&mut self, int: Interrupt) {
-        self.iflag |= int as u8;
+    // IF's upper three bits always read as 1, whatever was written, while
+    // IE keeps all eight.
+    #[test]
+    fn register_read_back() {
+        let mut ic = InterruptController::new();
+        assert_eq!(ic.read_if(), 0xE0);
+        assert_eq!(ic.read_ie(), 0x00);
+
+        for val in 0..0x100 {
+            let val = val as u8;
+            ic.write_if(val);
+            ic.write_ie(val);
+            assert_eq!(ic.read_if(), val | 0xE0);
+            assert_eq!(ic.read_ie(), val);
+        }
     }
 
-    pub fn enable_all_interrupts(&mut self, val: u8) {
-        self.ime = val != 0
+    // Requests and acknowledgements show up in IF, and leave IE alone.
+    #[test]
+    fn request_read_back() {
+        let mut ic = InterruptController::new();
+        ic.write_ie(0xA5);
+        ic.request(Interrupt::Timer);
+        ic.request(Interrupt::Joypad);
+        assert_eq!(ic.read_if(), 0xE0 | 0x14);
+        ic.acknowledge(Interrupt::Timer);
+        assert_eq!(ic.read_if(), 0xE0 | 0x10);
+        assert_eq!(ic.read_ie(), 0xA5);
+    }
+
+    // Each interrupt is a single flag, and the flag maps back to it.
+    #[test]
+    fn conversions() {
+        for &int in INTERRUPTS.iter() {
+            let flags = InterruptFlags::from(int);
+            assert_eq!(flags.bits(), int as u8);
+            assert_eq!(flags.highest().map(|int| int as u8), Some(int as u8));
+        }
+        assert!(InterruptFlags::empty().highest().is_none());
+    }
+
+    // Several can be requested and acknowledged at once, and pending_flags
+    // is only those that IE enables too.
+    #[test]
+    fn combined_masks() {
+        let mut ic = InterruptController::new();
+        ic.request(INT_VBLANK | INT_LCDC_STAT | INT_SERIAL);
+        assert_eq!(ic.read_if(), 0xE0 | 0x0B);
+
+        ic.write_ie((INT_LCDC_STAT | INT_SERIAL | INT_JOYPAD).bits());
+        assert_eq!(ic.pending_flags(), INT_LCDC_STAT | INT_SERIAL);
+        assert_eq!(ic.pending().map(|int| int as u8), Some(Interrupt::LCDCStat as u8));
+
+        ic.acknowledge(INT_LCDC_STAT | INT_SERIAL);
+        assert_eq!(ic.pending_flags(), InterruptFlags::empty());
+        assert_eq!(ic.read_if(), 0xE0 | 0x01);
+
+        ic.request(INT_TIMER | INT_JOYPAD);
+        assert_eq!(ic.pending_flags(), INT_JOYPAD);
+        ic.write_ie(0x1F);
+        assert_eq!(ic.pending_flags(), INT_VBLANK | INT_TIMER | INT_JOYPAD);
+        assert_eq!(ic.pending().map(|int| int as u8), Some(Interrupt::VBlank as u8));
     }
 }
@@ -1,3 +1,4 @@
 mod interrupt;
 
-pub use self::interrupt::{InterruptController, Interrupt};
+pub use self::interrupt::InterruptController;
+pub use self::interrupt::{INT_JOYPAD, INT_LCDC_STAT, INT_SERIAL, INT_TIMER, INT_VBLANK};
@@ -0,0 +1,240 @@
+// The joypad, read through P1 (0xFF00). The eight buttons are wired as a 2x4
+// matrix: pulling P14 low selects the directions and P15 low the buttons,
+// and a pressed button pulls its line (P10-P13) low. The Joypad interrupt is
+// requested whenever one of those lines falls.
+use std::fmt;
+
+use interrupt::{InterruptController, INT_JOYPAD};
+use savestate::{StateWriter, StateReader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Directions occupy the lower nibble, buttons the upper nibble.
+    fn mask(&self) -> u8 {
+        use self::Button::*;
+        match *self {
+            Right => 1,
+            Left => 1 << 1,
+            Up => 1 << 2,
+            Down => 1 << 3,
+            A => 1 << 4,
+            B => 1 << 5,
+            Select => 1 << 6,
+            Start => 1 << 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputMode {
+    // Button changes are seen by the game as soon as they are made, which
+    // is what interactive play wants.
+    Immediate,
+    // Button changes only take effect at the start of the next frame, so
+    // that a recording of the inputs made each frame replays identically.
+    Latched,
+}
+
+pub struct Joypad {
+    pressed: u8, // A set bit means pressed
+    // What pressed becomes at the next frame in latched mode.
+    next: u8,
+    select: u8, // P14 and P15
+    mode: InputMode,
+}
+
+impl fmt::Debug for Joypad {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Joypad")
+            .field("pressed", &format_args!("0x{:02x}", self.pressed))
+            .field("next", &format_args!("0x{:02x}", self.next))
+            .field("select", &format_args!("0x{:02x}", self.select))
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: 0,
+            next: 0,
+            select: 0x30,
+            mode: InputMode::Immediate,
+        }
+    }
+
+    pub fn mode(&self) -> InputMode {
+        self.mode
+    }
+
+    // Switching back to immediate mode applies anything still waiting for
+    // the next frame.
+    pub fn set_mode(&mut self, mode: InputMode, ic: &mut InterruptController) {
+        self.mode = mode;
+        if mode == InputMode::Immediate {
+            self.latch(ic);
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool, ic: &mut InterruptController) {
+        if pressed {
+            self.next |= button.mask();
+        } else {
+            self.next &= !button.mask();
+        }
+        if self.mode == InputMode::Immediate {
+            self.latch(ic);
+        }
+    }
+
+    // Called at the start of every frame.
+    pub fn latch(&mut self, ic: &mut InterruptController) {
+        let next = self.next;
+        self.update(ic, |j| j.pressed = next);
+    }
+
+    pub fn read_p1(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write_p1(&mut self, val: u8, ic: &mut InterruptController) {
+        self.update(ic, |j| j.select = val & 0x30);
+    }
+
+    // P10-P13. The lines are active low, and a group is only visible when
+    // its select bit is low.
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        lines
+    }
+
+    // Apply a change, requesting an interrupt if any line fell because of
+    // it.
+    fn update<F: FnOnce(&mut Joypad)>(&mut self, ic: &mut InterruptController, f: F) {
+        let before = self.lines();
+        f(self);
+        if before & !self.lines() != 0 {
+            ic.request(INT_JOYPAD);
+        }
+    }
+
+    // The mode belongs to the frontend, so it isn't part of the state.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.pressed);
+        w.write_u8(self.next);
+        w.write_u8(self.select);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pressed = try!(r.read_u8());
+        self.next = try!(r.read_u8());
+        self.select = try!(r.read_u8()) & 0x30;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interrupt::InterruptController;
+
+    use super::{Button, InputMode, Joypad};
+
+    fn joypad_irq(ic: &mut InterruptController) -> bool {
+        let requested = ic.read_if() & 0x10 != 0;
+        ic.write_if(0);
+        requested
+    }
+
+    // Only a line falling requests the interrupt, and a line only falls if
+    // its group is selected.
+    #[test]
+    fn falling_edges() {
+        let mut ic = InterruptController::new();
+        let mut joypad = Joypad::new();
+
+        // Neither group selected.
+        joypad.set_button(Button::A, true, &mut ic);
+        assert!(!joypad_irq(&mut ic));
+
+        // Selecting the buttons brings the held A's line low.
+        joypad.write_p1(0x10, &mut ic);
+        assert!(joypad_irq(&mut ic));
+
+        // Releasing is a rising edge.
+        joypad.set_button(Button::A, false, &mut ic);
+        assert!(!joypad_irq(&mut ic));
+        joypad.set_button(Button::B, true, &mut ic);
+        assert!(joypad_irq(&mut ic));
+
+        // Directions aren't selected.
+        joypad.set_button(Button::Left, true, &mut ic);
+        assert!(!joypad_irq(&mut ic));
+
+        // With both selected, B already holds Left's line low.
+        joypad.write_p1(0x00, &mut ic);
+        assert!(!joypad_irq(&mut ic));
+        joypad.set_button(Button::Right, true, &mut ic);
+        assert!(joypad_irq(&mut ic));
+    }
+
+    // With Right and Start held, each combination of selects shows only the
+    // groups whose bit is low.
+    #[test]
+    fn select_groups() {
+        let mut ic = InterruptController::new();
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Right, true, &mut ic);
+        joypad.set_button(Button::Start, true, &mut ic);
+
+        let cases = [(0x00, 0xC6), (0x10, 0xD7), (0x20, 0xEE), (0x30, 0xFF)];
+        for &(select, p1) in cases.iter() {
+            // The other bits aren't writable.
+            joypad.write_p1(select | 0xCF, &mut ic);
+            assert_eq!(joypad.read_p1(), p1, "select 0x{:02x}", select);
+        }
+    }
+
+    // In latched mode nothing changes, and nothing is requested, until the
+    // next frame.
+    #[test]
+    fn latched() {
+        let mut ic = InterruptController::new();
+        let mut joypad = Joypad::new();
+        joypad.write_p1(0x20, &mut ic);
+        joypad.set_mode(InputMode::Latched, &mut ic);
+
+        joypad.set_button(Button::Down, true, &mut ic);
+        assert_eq!(joypad.read_p1(), 0xEF);
+        assert!(!joypad_irq(&mut ic));
+
+        joypad.latch(&mut ic);
+        assert_eq!(joypad.read_p1(), 0xE7);
+        assert!(joypad_irq(&mut ic));
+
+        // Anything left waiting is applied when going back to immediate.
+        joypad.set_button(Button::Down, false, &mut ic);
+        joypad.set_button(Button::Up, true, &mut ic);
+        assert_eq!(joypad.read_p1(), 0xE7);
+        joypad.set_mode(InputMode::Immediate, &mut ic);
+        assert_eq!(joypad.read_p1(), 0xEB);
+        assert!(joypad_irq(&mut ic));
+    }
+}
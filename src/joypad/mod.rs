@@ -0,0 +1,3 @@
+mod joypad;
+
+pub use self::joypad::{Joypad, Button, InputMode};
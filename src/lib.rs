@@ -0,0 +1,31 @@
+#![deny(trivial_casts, trivial_numeric_casts)]
+
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate log;
+
+pub mod gameboy;
+pub mod cartridge;
+pub mod bootrom;
+mod cpu;
+mod interconnect;
+mod interrupt;
+mod timer;
+mod gpu;
+mod apu;
+mod joypad;
+pub mod serial;
+pub mod link;
+pub mod bench;
+pub mod palette;
+pub mod model;
+pub mod cheat;
+pub mod trace;
+pub mod profile;
+mod savestate;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+
+pub use gameboy::GameBoy;
+pub use cpu::{disasm, Bus, Cpu, FlatBus};
@@ -0,0 +1,396 @@
+// A libretro core, so that iogb can be run by RetroArch and friends.
+// See libretro.h for the meaning of the various structures and callbacks.
+//
+// Every retro_* function is only called by the frontend, and libretro.h
+// documents what it passes to each.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use bootrom::Bootrom;
+use cartridge::Cartridge;
+use gameboy::{GameBoy, Button, SCREEN_W, SCREEN_H, CPU_HZ, CYCLES_PER_FRAME};
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_REGION_NTSC: c_uint = 0;
+// The frontend resamples to whatever its output runs at.
+const SAMPLE_RATE: u32 = 48000;
+
+const JOYPAD_MAP: [(c_uint, Button); 8] = [(0, Button::B),
+                                           (2, Button::Select),
+                                           (3, Button::Start),
+                                           (4, Button::Up),
+                                           (5, Button::Down),
+                                           (6, Button::Left),
+                                           (7, Button::Right),
+                                           (8, Button::A)];
+
+pub type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type VideoRefreshFn = extern "C" fn(data: *const c_void,
+                                        width: c_uint,
+                                        height: c_uint,
+                                        pitch: usize);
+pub type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+pub type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type InputPollFn = extern "C" fn();
+pub type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint)
+                                      -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    pub geometry: GameGeometry,
+    pub timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+struct Core {
+    gb: GameBoy,
+    rom: Vec<u8>,
+    frame: Vec<u32>,
+}
+
+// libretro is a C API built around global state, and the frontend promises
+// to call us from a single thread.
+static mut CORE: Option<Core> = None;
+static mut ENVIRONMENT: Option<EnvironmentFn> = None;
+static mut VIDEO_REFRESH: Option<VideoRefreshFn> = None;
+static mut AUDIO_SAMPLE: Option<AudioSampleFn> = None;
+static mut AUDIO_SAMPLE_BATCH: Option<AudioSampleBatchFn> = None;
+static mut INPUT_POLL: Option<InputPollFn> = None;
+static mut INPUT_STATE: Option<InputStateFn> = None;
+
+unsafe fn core() -> Option<&'static mut Core> {
+    (*ptr::addr_of_mut!(CORE)).as_mut()
+}
+
+// A panic mustn't unwind into the frontend, so the entry points that run the
+// emulator return fallback instead. The core is thrown away, as it could have
+// been left half way through an instruction.
+unsafe fn guard<T, F: FnOnce() -> T>(name: &str, fallback: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = match e.downcast_ref::<&str>() {
+                Some(m) => m.to_string(),
+                None => e.downcast_ref::<String>().cloned().unwrap_or_default(),
+            };
+            error!("{} panicked: {}", name, msg);
+            CORE = None;
+            fallback
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    ENVIRONMENT = Some(cb);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    VIDEO_REFRESH = Some(cb);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_audio_sample(cb: AudioSampleFn) {
+    AUDIO_SAMPLE = Some(cb);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    AUDIO_SAMPLE_BATCH = Some(cb);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    INPUT_POLL = Some(cb);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    INPUT_STATE = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_deinit() {
+    CORE = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"iogb\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gb|dmg\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: SCREEN_W as c_uint,
+            base_height: SCREEN_H as c_uint,
+            max_width: SCREEN_W as c_uint,
+            max_height: SCREEN_H as c_uint,
+            aspect_ratio: SCREEN_W as f32 / SCREEN_H as f32,
+        },
+        timing: SystemTiming {
+            fps: CPU_HZ as f64 / CYCLES_PER_FRAME as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+fn new_core(rom: Vec<u8>) -> Result<Core, String> {
+    let cart = try!(Cartridge::from_bytes(rom.clone()));
+    let mut gb = GameBoy::new(cart, Bootrom::empty());
+    gb.set_sample_rate(SAMPLE_RATE);
+    Ok(Core {
+        gb: gb,
+        rom: rom,
+        frame: vec![0; SCREEN_W * SCREEN_H],
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    guard("retro_load_game", false, || {
+        if game.is_null() || (*game).data.is_null() {
+            return false;
+        }
+
+        if let Some(env) = ENVIRONMENT {
+            let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+            let format_ptr: *mut c_uint = &mut format;
+            if !env(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, format_ptr as *mut c_void) {
+                return false;
+            }
+        }
+
+        let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+        match new_core(rom) {
+            Ok(c) => {
+                CORE = Some(c);
+                true
+            }
+            Err(e) => {
+                if !(*game).path.is_null() {
+                    error!("Failed to load {}: {}",
+                           CStr::from_ptr((*game).path).to_string_lossy(),
+                           e);
+                }
+                false
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_type: c_uint,
+                                          _info: *const GameInfo,
+                                          _num: usize)
+                                          -> bool {
+    false
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unload_game() {
+    CORE = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_reset() {
+    guard("retro_reset", (), || {
+        if let Some(c) = core() {
+            let rom = c.rom.clone();
+            if let Ok(fresh) = new_core(rom) {
+                *c = fresh;
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    guard("retro_run", (), || {
+        let c = match core() {
+            Some(c) => c,
+            None => return,
+        };
+
+        if let (Some(poll), Some(state)) = (INPUT_POLL, INPUT_STATE) {
+            poll();
+            for &(id, button) in JOYPAD_MAP.iter() {
+                c.gb.set_button(button, state(0, RETRO_DEVICE_JOYPAD, 0, id) != 0);
+            }
+        }
+
+        c.gb.step_frame();
+
+        c.gb.frame_into_xrgb(&mut c.frame);
+
+        if let Some(refresh) = VIDEO_REFRESH {
+            refresh(c.frame.as_ptr() as *const c_void,
+                    SCREEN_W as c_uint,
+                    SCREEN_H as c_uint,
+                    SCREEN_W * 4);
+        }
+
+        upload_audio(&c.gb.audio_samples());
+    })
+}
+
+// Hand a frame's audio to the frontend, preferring the batch callback. It
+// may take fewer frames than it was given, in which case the rest are sent
+// again, unless it took none at all.
+unsafe fn upload_audio(samples: &[i16]) {
+    if let Some(batch) = AUDIO_SAMPLE_BATCH {
+        let mut sent = 0;
+        while sent < samples.len() {
+            let frames = batch(samples[sent..].as_ptr(), (samples.len() - sent) / 2);
+            if frames == 0 {
+                break;
+            }
+            sent += frames * 2;
+        }
+    } else if let Some(sample) = AUDIO_SAMPLE {
+        for pair in samples.chunks(2) {
+            sample(pair[0], pair[1]);
+        }
+    }
+}
+
+// A state's size only depends on the cartridge, so it's the same every time.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize_size() -> usize {
+    guard("retro_serialize_size", 0, || {
+        match core() {
+            Some(c) => c.gb.save_state().len(),
+            None => 0,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    guard("retro_serialize", false, || {
+        let c = match core() {
+            Some(c) => c,
+            None => return false,
+        };
+
+        let state = c.gb.save_state();
+        if data.is_null() || size < state.len() {
+            return false;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+        true
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    guard("retro_unserialize", false, || {
+        let c = match core() {
+            Some(c) => c,
+            None => return false,
+        };
+
+        if data.is_null() {
+            return false;
+        }
+        match c.gb.load_state(slice::from_raw_parts(data as *const u8, size)) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to load state: {}", e);
+                false
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    guard("retro_get_memory_data", ptr::null_mut(), || {
+        match core() {
+            Some(c) if id == RETRO_MEMORY_SAVE_RAM && !c.gb.cart_ram().is_empty() => {
+                c.gb.cart_ram_mut().as_mut_ptr() as *mut c_void
+            }
+            _ => ptr::null_mut(),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    guard("retro_get_memory_size", 0, || {
+        match core() {
+            Some(c) if id == RETRO_MEMORY_SAVE_RAM => c.gb.cart_ram().len(),
+            _ => 0,
+        }
+    })
+}
@@ -0,0 +1,15 @@
+// Linking GameBoys running in the same process. Whichever side selects the
+// internal clock drives each transfer, and both sides receive the other's
+// byte and a Serial interrupt when it completes. Linked GameBoys must be run
+// together with GameBoy::run_linked.
+use gameboy::GameBoy;
+
+pub fn connect(a: &mut GameBoy, b: &mut GameBoy) {
+    a.set_serial_linked(true);
+    b.set_serial_linked(true);
+}
+
+pub fn disconnect(a: &mut GameBoy, b: &mut GameBoy) {
+    a.set_serial_linked(false);
+    b.set_serial_linked(false);
+}
@@ -1,113 +1,998 @@
 #![deny(trivial_casts, trivial_numeric_casts)]
 extern crate argparse;
-
+extern crate minifb;
+extern crate env_logger;
 #[macro_use]
-extern crate bitflags;
-extern crate time;
+extern crate log;
+#[cfg(feature = "gamepad")]
+extern crate gilrs;
+extern crate ctrlc;
+extern crate cpal;
 
-extern crate minifb;
+extern crate iogb;
 
-use std::path::PathBuf;
-use std::process;
-use time::{SteadyTime, Duration};
-use argparse::{ArgumentParser, Parse, Print};
-use minifb::{WindowOptions, Window, Scale};
+mod args;
+mod audio;
+mod config;
+mod input;
+mod overlay;
+mod pacer;
+mod paths;
+mod ring;
+mod scaler;
+mod screenshot;
+mod stats;
+mod title;
+mod vram_window;
 
-use gameboy::{SCREEN_W, SCREEN_H};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use minifb::{WindowOptions, Window, Key, KeyRepeat};
 
-mod gameboy;
-mod cpu;
-mod interconnect;
-mod cartridge;
-mod interrupt;
-mod timer;
-mod gpu;
-mod bootrom;
+use iogb::{gameboy, cartridge, bootrom};
+use iogb::palette::{self, Palette};
+use iogb::model::HardwareModel;
+use iogb::cheat;
+use iogb::trace::{self, Tracer, TraceFormat};
+use iogb::serial::{Printer, TcpLink};
+use iogb::gameboy::{SCREEN_W, SCREEN_H};
 
 fn main() {
-    let mut rom = PathBuf::new();
-    let mut bootrom_path = PathBuf::new();
-    let mut scale: u32 = 1;
-
-    {
-        let mut parser = ArgumentParser::new();
-        parser.set_description("A GameBoy emulator written in Rust");
-        parser.add_option(&["-v", "--version"],
-                          Print(format!("iogb: v{}", env!("CARGO_PKG_VERSION"))),
-                          "Show version");
-        parser.refer(&mut rom).add_option(&["-r", "--rom"], Parse, "Path to ROM file").required();
-        parser.refer(&mut scale).add_option(&["-s", "--scale"], Parse, "Display scaling");
-        parser.refer(&mut bootrom_path)
-            .add_option(&["-b", "--bootrom"], Parse, "Path to boot ROM file");
-        parser.parse_args_or_exit();
-    }
-
-    let cart = match cartridge::Cartridge::new(&rom) {
+    env_logger::init().unwrap();
+
+    let args = match args::parse(env::args().collect()) {
+        Ok(a) => a,
+        Err(code) => process::exit(code),
+    };
+
+    let config_path = match args.config.clone().or_else(config::default_path) {
+        Some(p) => p,
+        None => {
+            println!("Couldn't find the config file, pass --config or set $HOME");
+            process::exit(1)
+        }
+    };
+    let mut warnings = Vec::new();
+    let mut config = match config::AppConfig::load(&config_path, &mut warnings) {
         Ok(c) => c,
         Err(e) => {
-            println!("Failed to open cartridge: {} {}", rom.display(), e);
+            println!("Failed to load config: {} {}", config_path.display(), e);
+            process::exit(1)
+        }
+    };
+    for warning in warnings {
+        println!("Warning: {}: {}", config_path.display(), warning);
+    }
+    config.apply_args(&args);
+
+    if args.write_config {
+        match config.write(&config_path) {
+            Ok(()) => {
+                println!("Wrote {}", config_path.display());
+                process::exit(0)
+            }
+            Err(e) => {
+                println!("Failed to write config: {} {}", config_path.display(), e);
+                process::exit(1)
+            }
+        }
+    }
+
+    let rom = args.rom.clone();
+    if !has_rom_extension(&rom) {
+        println!("Warning: {} doesn't look like a GameBoy ROM", rom.display());
+    }
+    let cart = match read_file(&rom) {
+        Ok(data) => {
+            match cartridge::Cartridge::from_bytes(data) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("Failed to load cartridge: {} {}", rom.display(), e);
+                    process::exit(1)
+                }
+            }
+        }
+        Err(e) => {
+            println!("Failed to open cartridge: {} ({:?}: {})", rom.display(), e.kind(), e);
             process::exit(1)
         }
     };
 
-    let bootrom_path = if bootrom_path == PathBuf::from("") {
-        None
-    } else {
-        Some(&bootrom_path)
+    let bootrom = match config.bootrom {
+        Some(ref path) => {
+            match read_file(path) {
+                Ok(data) => {
+                    match bootrom::Bootrom::from_bytes(&data) {
+                        Ok(b) => b,
+                        Err(e) => {
+                            println!("Failed to load bootrom: {} {}", path.display(), e);
+                            process::exit(1)
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to open bootrom: {} ({:?}: {})",
+                             path.display(),
+                             e.kind(),
+                             e);
+                    process::exit(1)
+                }
+            }
+        }
+        None => bootrom::Bootrom::empty(),
     };
 
-    let bootrom = match bootrom::Bootrom::from_pathbuf(bootrom_path) {
-        Ok(b) => b,
+    let (mut scaler, scale) = match scaler::Scaler::new(SCREEN_W, SCREEN_H, config.scale) {
+        Ok(s) => s,
         Err(e) => {
-            println!("Failed to load bootrom: {} {}",
-                     bootrom_path.unwrap().display(),
-                     e);
+            println!("{}", e);
+            println!("Possible scale options: 1 to {}", scaler::MAX_SCALE);
             process::exit(1)
         }
     };
 
-    let scale = match scale {
-        1 => Scale::X1,
-        2 => Scale::X2,
-        4 => Scale::X4,
-        8 => Scale::X8,
-        16 => Scale::X16,
-        32 => Scale::X32,
-        s => {
-            println!("Invalid scale option: {}", s);
-            println!("Possible scale options: 1, 2, 4, 8, 16, 32");
+    // The config file stores custom palettes and preset names in the same
+    // place, and only custom palettes contain commas.
+    let palette = match config.palette {
+        Some(ref custom) if custom.contains(',') => {
+            match Palette::parse(custom) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("Invalid custom palette: {} {}", custom, e);
+                    process::exit(1)
+                }
+            }
+        }
+        Some(ref name) => {
+            match Palette::from_name(name) {
+                Some(p) => p,
+                None => {
+                    println!("Unknown palette: {}", name);
+                    println!("Possible palettes: {}", Palette::names().join(", "));
+                    process::exit(1)
+                }
+            }
+        }
+        None => Palette::default(),
+    };
+
+    let mut paths = paths::Paths::new(&rom);
+    if let Some(ref save_dir) = config.save_dir {
+        if let Err(e) = paths.set_save_dir(save_dir) {
+            println!("Failed to create save directory: {} {}", save_dir.display(), e);
             process::exit(1)
         }
+    }
+    if let Some(ref sav) = args.sav {
+        paths.set_sav(sav);
+    }
+    if let Some(ref screenshot_dir) = args.screenshot_dir {
+        paths.set_screenshot_dir(screenshot_dir);
+    }
+
+    let model = match args.model {
+        Some(ref name) => {
+            match HardwareModel::from_name(name) {
+                Some(m) => m,
+                None => {
+                    println!("Unknown model: {}", name);
+                    println!("Possible models: {}", HardwareModel::names().join(", "));
+                    process::exit(1)
+                }
+            }
+        }
+        None => HardwareModel::default(),
     };
 
-    let mut window = Window::new("iogb",
-                                 160,
-                                 144,
+    let mut gb = gameboy::GameBoy::builder(cart)
+        .bootrom(bootrom)
+        .skip_bootrom(args.skip_bootrom)
+        .model(model)
+        .build();
+    gb.set_palette(palette);
+
+    // Headless and benchmark runs should be reproducible, so they always
+    // start from a blank cartridge.
+    let battery = gb.has_battery() && !args.headless && args.bench_secs == 0;
+    if battery {
+        if let Err(e) = load_sav(&mut gb, &paths.sav()) {
+            println!("Failed to load save: {} {}", paths.sav().display(), e);
+            process::exit(1)
+        }
+    }
+
+    if let Some(ref trace_file) = args.trace_file {
+        let format = match TraceFormat::from_name(&args.trace_format) {
+            Some(f) => f,
+            None => {
+                println!("Invalid trace format: {}", args.trace_format);
+                println!("Possible trace formats: plain, doctor");
+                process::exit(1)
+            }
+        };
+
+        let file = match File::create(trace_file) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Failed to create trace file: {} {}", trace_file.display(), e);
+                process::exit(1)
+            }
+        };
+        gb.set_tracer(Some(Tracer::new(Box::new(BufWriter::new(file)), format, args.trace_after)));
+    }
+    if args.stub_ly {
+        gb.set_ly_stub(Some(trace::DOCTOR_LY));
+    }
+    // Benchmarks measure emulation on its own.
+    gb.set_history(!args.no_history && args.bench_secs == 0);
+    gb.set_profiling(args.profile);
+
+    if let Some(ref load_state) = args.load_state {
+        if let Err(e) = load_state_file(&mut gb, load_state) {
+            println!("Failed to load save state: {} {}", load_state.display(), e);
+            process::exit(1)
+        }
+    }
+
+    if let Some(ref cheats) = args.cheats {
+        match cheat::load(cheats) {
+            Ok(c) => gb.set_cheats(c),
+            Err(e) => {
+                println!("Failed to load cheats: {} {}", cheats.display(), e);
+                process::exit(1)
+            }
+        }
+    }
+
+    if args.bench_secs > 0 {
+        let start = Instant::now();
+        println!("{}",
+                 gb.bench_run(args.bench_secs as u64 * gameboy::CPU_HZ as u64,
+                              || start.elapsed()));
+        flush_trace(&mut gb);
+        print_profile(&gb);
+        process::exit(0);
+    }
+
+    if args.headless {
+        run_headless(&mut gb,
+                     args.frames,
+                     args.expect_hash.as_deref(),
+                     args.expect_serial.as_deref());
+    }
+
+    for &addr in args.breakpoints.iter() {
+        gb.add_breakpoint(addr);
+    }
+
+    if let Some(ref link) = args.link {
+        let device = match *link {
+            args::Link::Listen(port) => {
+                println!("Waiting for a link cable connection on port {}", port);
+                TcpLink::listen(port)
+            }
+            args::Link::Connect(ref addr) => TcpLink::connect(addr.as_str()),
+        };
+        match device {
+            Ok(device) => gb.set_serial_device(Box::new(device)),
+            Err(e) => {
+                println!("Failed to set up link cable: {}", e);
+                process::exit(1)
+            }
+        }
+    }
+
+    if let Some(ref printer_dir) = args.printer_dir {
+        if let Err(e) = fs::create_dir_all(printer_dir) {
+            println!("Failed to create printer directory: {} {}", printer_dir.display(), e);
+            process::exit(1)
+        }
+        let dir = printer_dir.clone();
+        let rom_title = gb.rom_title().to_string();
+        gb.set_serial_device(Box::new(Printer::new(move |print| {
+            match screenshot::save_print(&rom_title, &print, &dir) {
+                Ok(path) => println!("Saved print: {}", path.display()),
+                Err(e) => println!("Failed to save print: {}", e),
+            }
+        })));
+    }
+
+    let mut title = title::TitleBar::new(gb.rom_title());
+    let (width, height) = scaler.size();
+    let mut window = Window::new(&title.text(),
+                                 width,
+                                 height,
                                  WindowOptions { scale: scale, ..WindowOptions::default() })
         .unwrap_or_else(|e| {
             panic!("{}", e);
         });
 
-    let mut gb = gameboy::GameBoy::new(cart, bootrom);
-    let mut ticks = 0;
-    let mut delta: Duration;
-    let mut last_time = SteadyTime::now();
+    let mut vram_window = if args.debug_windows {
+        match vram_window::VramWindow::new() {
+            Ok(w) => Some(w),
+            Err(e) => {
+                println!("Failed to open the VRAM window: {}", e);
+                process::exit(1)
+            }
+        }
+    } else {
+        None
+    };
+
+    // Without a sound card, emulation is paced by the timer instead.
+    let mut audio = if args.no_audio {
+        None
+    } else {
+        match audio::open() {
+            Ok((output, producer)) => {
+                gb.set_sample_rate(output.rate());
+                let sync = audio::AudioSync::new(producer,
+                                                 output.rate(),
+                                                 pacer::MonotonicClock::new());
+                Some((output, sync))
+            }
+            Err(e) => {
+                warn!("Audio output unavailable: {}", e);
+                None
+            }
+        }
+    };
+
+    let keys = config.keys.clone();
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = match input::Gamepads::new(config.pad.clone()) {
+        Ok(g) => Some(g),
+        Err(e) => {
+            warn!("Gamepad support unavailable: {}", e);
+            None
+        }
+    };
+
+    let mut pressed = Vec::new();
+    let mut paused = false;
+    // Buttons pressed since the last frame advance while paused.
+    let mut latched = Vec::new();
+    let mut turbo = input::Turbo::new(config.turbo_rate);
+    let mut turbo_held = Vec::new();
+    let mut pacer = pacer::Pacer::new(pacer::MonotonicClock::new());
+    pacer.set_fast_forward_cap(config.fast_forward_cap);
+    let mut slot = 0;
+    let start = Instant::now();
+    let mut stats = stats::FrameStats::new(start.elapsed(), gb.elapsed_cycles());
 
     let mut buffer: Vec<u32> = vec![0; SCREEN_W * SCREEN_H];
+    let mut overlay = overlay::Overlay::new();
+    // Set when the window needs repainting even though the GPU hasn't
+    // finished a frame, e.g. after a palette change while paused.
+    let mut redraw = true;
+    // The cheat selected in the on-screen list, while it's shown.
+    let mut cheat_menu: Option<usize> = None;
+    let mut frames_run: u64 = 0;
+    let mut crash = None;
+    let mut fault = None;
+
+    // Ctrl-C should save the game just like closing the window does.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    if let Err(e) = ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst)) {
+        warn!("Failed to install Ctrl-C handler: {}", e);
+    }
+
+    while window.is_open() && !window.is_key_down(Key::Escape) &&
+          !interrupted.load(Ordering::SeqCst) {
+        pressed.clear();
+        keys.poll(&window, &mut pressed);
+        #[cfg(feature = "gamepad")]
+        {
+            if let Some(ref mut g) = gamepads {
+                g.poll(&mut pressed);
+            }
+        }
+
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            paused = !paused;
+            latched.clear();
+            if paused {
+                title.set_paused(Some(gb.elapsed_frames()));
+            } else {
+                title.set_paused(None);
+                pacer.reset();
+                stats.reset(start.elapsed(), gb.elapsed_cycles());
+            }
+        }
+
+        if let Some(keys) = window.get_keys_pressed(KeyRepeat::No) {
+            for key in keys {
+                if let Some(s) = slot_for_key(key) {
+                    slot = s;
+                    title.set_slot(slot);
+                }
+
+                match key {
+                    Key::F5 => {
+                        let path = paths.state(slot);
+                        match save_state_file(&gb, &path) {
+                            Ok(()) => title.show_message(format!("Saved slot {}", slot)),
+                            Err(e) => {
+                                println!("Failed to save state: {} {}", path.display(), e);
+                                title.show_message(format!("Failed to save slot {}", slot));
+                            }
+                        }
+                    }
+                    Key::F8 => {
+                        let path = paths.state(slot);
+                        match load_state_file(&mut gb, &path) {
+                            Ok(()) => {
+                                pacer.reset();
+                                stats.reset(start.elapsed(), gb.elapsed_cycles());
+                                if paused {
+                                    title.set_paused(Some(gb.elapsed_frames()));
+                                }
+                                redraw = true;
+                                title.show_message(format!("Loaded slot {}", slot));
+                            }
+                            Err(e) => {
+                                println!("Failed to load state: {} {}", path.display(), e);
+                                title.show_message(format!("Failed to load slot {}: {}", slot, e));
+                            }
+                        }
+                    }
+                    Key::F9 => {
+                        let (name, palette) = next_palette(gb.palette());
+                        gb.set_palette(palette);
+                        redraw = true;
+                        title.show_message(format!("Palette: {}", name));
+                    }
+                    Key::F3 => {
+                        overlay.toggle();
+                        redraw = true;
+                    }
+                    // F6 steps through the cheats and then hides the list,
+                    // and F7 turns the selected one on or off.
+                    Key::F6 => {
+                        cheat_menu = match cheat_menu {
+                            None if !gb.cheats().is_empty() => Some(0),
+                            Some(i) if i + 1 < gb.cheats().len() => Some(i + 1),
+                            _ => None,
+                        };
+                        redraw = true;
+                    }
+                    Key::F7 => {
+                        if let Some(i) = cheat_menu {
+                            let enabled = !gb.cheats()[i].enabled;
+                            gb.set_cheat_enabled(i, enabled);
+                            redraw = true;
+                            title.show_message(format!("{} {}",
+                                                       gb.cheats()[i].name,
+                                                       if enabled { "on" } else { "off" }));
+                        }
+                    }
+                    Key::F12 => {
+                        // With shift, save exactly what's on screen.
+                        let dir = paths.screenshot_dir();
+                        let result = if window.is_key_down(Key::LeftShift) ||
+                                        window.is_key_down(Key::RightShift) {
+                            screenshot::save_xrgb(&gb, &buffer, &dir)
+                        } else {
+                            screenshot::save(&gb, &dir)
+                        };
+                        match result {
+                            Ok(path) => {
+                                println!("Saved screenshot: {}", path.display());
+                                title.show_message("Saved screenshot".to_string());
+                            }
+                            Err(e) => {
+                                println!("Failed to save screenshot: {}", e);
+                                title.show_message("Failed to save screenshot".to_string());
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if paused {
+            // Hold on to anything pressed while paused, so that a quick tap
+            // still reaches the joypad during the next advanced frame.
+            for &button in pressed.iter() {
+                input::press(&mut latched, button);
+            }
+
+            if window.is_key_pressed(Key::N, KeyRepeat::Yes) {
+                input::apply(&mut gb, &latched);
+                match step_frame(&mut gb) {
+                    Ok(result) => {
+                        if let Some(reason) = result.stopped {
+                            show_break(&gb, reason, &mut title);
+                        }
+                    }
+                    Err(e) => {
+                        crash = Some(e);
+                        break;
+                    }
+                }
+                frames_run += 1;
+                latched.clear();
+                title.set_paused(Some(gb.elapsed_frames()));
+            }
+        } else {
+            turbo_held.clear();
+            keys.poll_turbo(&window, &mut turbo_held);
+            if turbo.next_frame(!turbo_held.is_empty()) {
+                for &button in turbo_held.iter() {
+                    input::press(&mut pressed, button);
+                }
+            }
+
+            input::apply(&mut gb, &pressed);
+            match step_frame(&mut gb) {
+                Ok(result) => {
+                    // Pause at breakpoints, so that N carries on a frame at
+                    // a time and P resumes.
+                    if let Some(reason) = result.stopped {
+                        paused = true;
+                        latched.clear();
+                        title.set_paused(Some(gb.elapsed_frames()));
+                        show_break(&gb, reason, &mut title);
+                    }
+                }
+                Err(e) => {
+                    crash = Some(e);
+                    break;
+                }
+            }
+            frames_run += 1;
+        }
+
+        // The window stays open after the CPU stops, showing the last frame,
+        // so say why nothing is happening. Loading a state can start it
+        // again.
+        if gb.fault() != fault {
+            fault = gb.fault();
+            if let Some(f) = fault {
+                println!("{}, the CPU has stopped", f);
+                title.show_message(format!("CPU stopped: {}", f));
+            }
+        }
+
+        // Converting and uploading the frame is wasted work unless it has
+        // changed, but the window still has to be updated to pump events.
+        if gb.take_frame() || redraw || overlay.visible() {
+            gb.frame_into_xrgb(&mut buffer);
+            if overlay.visible() {
+                overlay.draw(&mut buffer, &gb, &stats);
+            }
+            if let Some(i) = cheat_menu {
+                overlay::draw_cheats(&mut buffer, gb.cheats(), i);
+            }
+            window.update_with_buffer(scaler.scale(&buffer));
+            redraw = false;
+        } else {
+            window.update();
+        }
+
+        // Closing the VRAM window doesn't stop the emulator.
+        let vram_closed = match vram_window {
+            Some(ref mut w) => {
+                w.update(&gb);
+                !w.is_open()
+            }
+            None => false,
+        };
+        if vram_closed {
+            vram_window = None;
+        }
 
-    while window.is_open() {
-        let now = SteadyTime::now();
-        delta = now - last_time;
-        last_time = now;
+        // Drained every time round, so that nothing built up while paused
+        // is played later.
+        let samples = gb.audio_samples();
+        if paused {
+            if let Some((_, ref mut sync)) = audio {
+                sync.reset();
+            }
+            thread::sleep(pacer::frame_duration());
+        } else {
+            let fast_forward = window.is_key_down(Key::Tab);
+            pacer.set_fast_forward(fast_forward);
+            match audio {
+                Some((_, ref mut sync)) if !fast_forward => {
+                    sync.wait(&samples);
+                    pacer.reset();
+                }
+                // Fast-forward is paced by the timer, as the sound card would
+                // hold it to normal speed, and its sound is dropped.
+                Some((_, ref mut sync)) => {
+                    sync.reset();
+                    pacer.wait();
+                }
+                None => pacer.wait(),
+            }
+            if stats.frame(start.elapsed(), gb.elapsed_cycles()) {
+                title.set_stats(stats.fps(), stats.speed());
+            }
+        }
+        title.update(&mut window);
+    }
+
+    let sav = if battery { Some(paths.sav()) } else { None };
+    shutdown(&mut gb, sav.as_deref(), frames_run, start.elapsed());
+    // The panic message has already been printed, so the save is all that
+    // was left to rescue.
+    if let Some(e) = crash {
+        panic::resume_unwind(e);
+    }
+}
+
+// The preset after the current palette, starting from the first for a custom
+// palette.
+fn next_palette(current: Palette) -> (&'static str, Palette) {
+    let presets = &palette::PRESETS;
+    let next = match presets.iter().position(|&(_, p)| p == current) {
+        Some(i) => (i + 1) % presets.len(),
+        None => 0,
+    };
+    presets[next]
+}
+
+fn slot_for_key(key: Key) -> Option<u8> {
+    match key {
+        Key::Key0 => Some(0),
+        Key::Key1 => Some(1),
+        Key::Key2 => Some(2),
+        Key::Key3 => Some(3),
+        Key::Key4 => Some(4),
+        Key::Key5 => Some(5),
+        Key::Key6 => Some(6),
+        Key::Key7 => Some(7),
+        Key::Key8 => Some(8),
+        Key::Key9 => Some(9),
+        _ => None,
+    }
+}
+
+// Extensions that GameBoy ROMs are usually distributed with. Anything else is
+// still loaded, as the header is what really matters.
+fn has_rom_extension(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => {
+            let ext = ext.to_lowercase();
+            ext == "gb" || ext == "gbc" || ext == "dmg"
+        }
+        None => false,
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut data));
+    Ok(data)
+}
+
+// A missing save file just means the game hasn't been saved yet.
+fn load_sav(gb: &mut gameboy::GameBoy, path: &Path) -> Result<(), String> {
+    let mut data = vec![];
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}", e)),
+    };
+    try!(file.read_to_end(&mut data).map_err(|e| format!("{}", e)));
+    gb.load_cart_ram(&data)
+}
+
+fn write_sav(gb: &gameboy::GameBoy, path: &Path) -> Result<(), String> {
+    let mut file = try!(File::create(path).map_err(|e| format!("{}", e)));
+    file.write_all(gb.cart_ram()).map_err(|e| format!("{}", e))
+}
+
+fn save_state_file(gb: &gameboy::GameBoy, path: &Path) -> Result<(), String> {
+    let mut file = try!(File::create(path).map_err(|e| format!("{}", e)));
+    file.write_all(&gb.save_state()).map_err(|e| format!("{}", e))
+}
+
+fn load_state_file(gb: &mut gameboy::GameBoy, path: &Path) -> Result<(), String> {
+    let mut state = vec![];
+    let mut file = try!(File::open(path).map_err(|e| format!("{}", e)));
+    try!(file.read_to_end(&mut state).map_err(|e| format!("{}", e)));
+    gb.load_state(&state)
+}
+
+fn show_break(gb: &gameboy::GameBoy, reason: gameboy::BreakReason, title: &mut title::TitleBar) {
+    match reason {
+        gameboy::BreakReason::Breakpoint(addr) => {
+            println!("{}: {}", reason, gb.disassemble(addr).0)
+        }
+        // The main loop reports faults however they come about.
+        gameboy::BreakReason::Fault(_) => return,
+        _ => println!("{}", reason),
+    }
+    title.show_message(format!("{}", reason));
+}
+
+fn flush_trace(gb: &mut gameboy::GameBoy) {
+    if let Err(e) = gb.flush_trace() {
+        println!("Failed to write trace: {}", e);
+    }
+}
+
+// Catch any panic, so that the player's save can still be written before
+// giving up.
+fn step_frame(gb: &mut gameboy::GameBoy) -> thread::Result<gameboy::RunResult> {
+    panic::catch_unwind(AssertUnwindSafe(|| gb.step_frame()))
+}
+
+// Everything that has to happen before the frontend exits, whether the window
+// was closed, Ctrl-C was pressed or the emulator crashed.
+fn shutdown(gb: &mut gameboy::GameBoy, sav: Option<&Path>, frames: u64, elapsed: Duration) {
+    if let Some(path) = sav {
+        if let Err(e) = write_sav(gb, path) {
+            println!("Failed to write save: {} {}", path.display(), e);
+        }
+    }
+    flush_trace(gb);
+
+    let emulated = frames as f64 * gameboy::CYCLES_PER_FRAME as f64 / gameboy::CPU_HZ as f64;
+    let wall = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let speed = if wall > 0.0 { emulated / wall * 100.0 } else { 0.0 };
+    println!("Ran {} frames in {:.1}s ({:.0}% speed)", frames, wall, speed);
+    print_profile(gb);
+}
+
+fn print_profile(gb: &gameboy::GameBoy) {
+    if gb.profiling() {
+        println!("{}", gb.profile_report());
+    }
+}
+
+// Exit codes used when headless.
+const EXIT_MISMATCH: i32 = 1;
+const EXIT_CRASHED: i32 = 2;
+
+fn run_headless(gb: &mut gameboy::GameBoy,
+                frames: u32,
+                expect_hash: Option<&str>,
+                expect_serial: Option<&str>)
+                -> ! {
+    let expected_hash = match expect_hash {
+        Some(h) => {
+            match u64::from_str_radix(h.trim_start_matches("0x"), 16) {
+                Ok(h) => Some(h),
+                Err(e) => {
+                    println!("Invalid hash: {} {}", h, e);
+                    process::exit(EXIT_MISMATCH)
+                }
+            }
+        }
+        None => None,
+    };
+
+    // A panic should fail the run rather than take the reporting down with
+    // it.
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| gb.run_frames(frames))).is_err();
+    // process::exit doesn't run destructors, so the tail of the trace would
+    // otherwise be lost - and after a crash it's the interesting part.
+    flush_trace(gb);
 
-        // TODO: Receive VSYNC event so we can regenerate the texture
-        // from the GPU's back buffer.
-        gb.run((delta * gameboy::CPU_HZ as i32).num_seconds() as u32);
+    let hash = gb.frame_hash();
+    // Test ROMs report their results over the serial port.
+    let serial = gb.serial_output();
+    println!("0x{:016x}", hash);
+    if !serial.is_empty() {
+        println!("serial: {:?}", serial);
+    }
+    print_profile(gb);
+    // Games can lock the CPU up by themselves, but anything unimplemented is
+    // the emulator's fault.
+    let mut crashed = panicked;
+    if let Some(fault) = gb.fault() {
+        println!("{}, the CPU has stopped", fault);
+        if let gameboy::Fault::Unimplemented { .. } = fault {
+            crashed = true;
+        }
+    }
+
+    if crashed {
+        println!("CPU crashed after {} frames", gb.elapsed_frames());
+        process::exit(EXIT_CRASHED);
+    }
+
+    let mut ok = true;
+    if let Some(expected) = expected_hash {
+        if hash != expected {
+            println!("Frame hash mismatch, expected 0x{:016x}", expected);
+            ok = false;
+        }
+    }
+    if let Some(expected) = expect_serial {
+        if !serial.contains(expected) {
+            println!("Serial output doesn't contain {:?}", expected);
+            ok = false;
+        }
+    }
+
+    process::exit(if ok { 0 } else { EXIT_MISMATCH });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{BufWriter, Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::Duration;
+
+    use minifb::Key;
+
+    use iogb::bootrom::Bootrom;
+    use iogb::cartridge::Cartridge;
+    use iogb::gameboy::GameBoy;
+    use iogb::palette::{Palette, PRESETS};
+    use iogb::trace::{TraceFormat, Tracer};
+
+    use super::{has_rom_extension, load_state_file, next_palette, save_state_file, shutdown,
+                slot_for_key};
+
+    // An empty directory of the test's own.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iogb-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A ROM that counts up in A forever, with its first byte changed by
+    // variant so that different ROMs can be told apart.
+    fn gameboy(variant: u8) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = variant;
+        rom[0x100] = 0x3C; // INC A
+        rom[0x101] = 0x18; // JR -3
+        rom[0x102] = 0xFD;
+        GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty())
+    }
+
+    // The hotkey cycles through the presets, and a custom palette goes back
+    // to the first.
+    #[test]
+    fn palette_cycle() {
+        let mut palette = Palette::default();
+        for i in 1..PRESETS.len() + 1 {
+            let (name, next) = next_palette(palette);
+            assert_eq!((name, next), PRESETS[i % PRESETS.len()]);
+            palette = next;
+        }
+        assert_eq!(next_palette(Palette([1, 2, 3, 4])), PRESETS[0]);
+    }
+
+    // Anything else is only warned about.
+    #[test]
+    fn rom_extensions() {
+        for name in ["tetris.gb", "pokemon.GBC", "roms/zelda.dmg", "/a.b/c.Gb"].iter() {
+            assert!(has_rom_extension(Path::new(name)), "{}", name);
+        }
+        for name in ["tetris", "tetris.zip", "tetris.gb.zip", "roms.gb/tetris", ".gb"].iter() {
+            assert!(!has_rom_extension(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn slot_keys() {
+        assert_eq!(slot_for_key(Key::Key0), Some(0));
+        assert_eq!(slot_for_key(Key::Key7), Some(7));
+        assert_eq!(slot_for_key(Key::A), None);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = temp_dir("state-round-trip");
+        let path = dir.join("rom.ss1");
+        let mut gb = gameboy(0);
+        gb.run_frames(3);
+        save_state_file(&gb, &path).unwrap();
+        let saved = gb.cpu_state();
+
+        gb.run_frames(3);
+        load_state_file(&mut gb, &path).unwrap();
+        assert_eq!(gb.cpu_state(), saved);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Every failure is reported, and leaves the running game alone.
+    #[test]
+    fn state_errors() {
+        let dir = temp_dir("state-errors");
+        let mut gb = gameboy(0);
+        gb.run_frames(1);
+        let before = gb.save_state();
+
+        let e = load_state_file(&mut gb, &dir.join("missing.ss0")).unwrap_err();
+        assert!(!e.is_empty());
+
+        let other = dir.join("other.ss0");
+        save_state_file(&gameboy(1), &other).unwrap();
+        let e = load_state_file(&mut gb, &other).unwrap_err();
+        assert!(e.contains("different ROM"), "{}", e);
+
+        // The version follows the four byte magic.
+        let mut state = before.clone();
+        state[4] = state[4].wrapping_add(1);
+        let old = dir.join("old.ss0");
+        File::create(&old).unwrap().write_all(&state).unwrap();
+        let e = load_state_file(&mut gb, &old).unwrap_err();
+        assert!(e.contains("version"), "{}", e);
 
-        // Convert from pixels in range 0..3 to full colours.
-        for (i, pixel) in gb.back_buffer().iter().enumerate() {
-            buffer[i] = (3 - *pixel as u32) * 0x404040;
+        let truncated = dir.join("truncated.ss0");
+        File::create(&truncated).unwrap().write_all(&before[..before.len() / 2]).unwrap();
+        assert!(load_state_file(&mut gb, &truncated).is_err());
+
+        assert_eq!(gb.save_state(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // The battery save is written and the buffered tail of the trace makes
+    // it to disk.
+    #[test]
+    fn shutdown_saves() {
+        let dir = temp_dir("shutdown");
+        let sav = dir.join("rom.sav");
+        let trace = dir.join("trace.log");
+
+        // MBC1 with 8KB of battery backed RAM.
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3C; // INC A
+        rom[0x101] = 0x18; // JR -3
+        rom[0x102] = 0xFD;
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+        assert!(gb.has_battery());
+        for (i, b) in gb.cart_ram_mut().iter_mut().enumerate() {
+            *b = i as u8 ^ 0x5A;
         }
-        window.update_with_buffer(&buffer[..]);
+        let ram = gb.cart_ram().to_vec();
+
+        let file = File::create(&trace).unwrap();
+        gb.set_tracer(Some(Tracer::new(Box::new(BufWriter::new(file)), TraceFormat::Plain, 0)));
+        for _ in 0..10 {
+            gb.step_instruction();
+        }
+        // Well short of filling the buffer.
+        assert_eq!(read(&trace).len(), 0);
+
+        shutdown(&mut gb, Some(&sav), 60, Duration::from_secs(1));
+        assert_eq!(read(&sav), ram);
+        assert_eq!(String::from_utf8(read(&trace)).unwrap().lines().count(), 10);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Failing to save doesn't stop the rest of the cleanup, and without a
+    // save nothing is written.
+    #[test]
+    fn shutdown_failures() {
+        let dir = temp_dir("shutdown-failures");
+        let mut gb = gameboy(0);
+        gb.run_frames(1);
+        shutdown(&mut gb, Some(&dir.join("missing/rom.sav")), 1, Duration::from_secs(0));
+        shutdown(&mut gb, None, 0, Duration::from_secs(0));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
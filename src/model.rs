@@ -0,0 +1,44 @@
+// The GameBoy revisions that a boot ROM can be skipped for.
+
+// Each boot ROM leaves the CPU registers differently, which games can use to
+// tell which hardware they're running on. Most of the I/O registers that are
+// emulated start out the same on all of them, see
+// Interconnect::fake_boot_rom.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HardwareModel {
+    Dmg,
+    // The GameBoy Pocket.
+    Mgb,
+    // A GameBoy Color running a DMG cartridge.
+    Cgb,
+}
+
+pub const MODELS: [(&'static str, HardwareModel); 3] = [("dmg", HardwareModel::Dmg),
+                                                         ("mgb", HardwareModel::Mgb),
+                                                         ("cgb", HardwareModel::Cgb)];
+
+impl Default for HardwareModel {
+    fn default() -> HardwareModel {
+        HardwareModel::Dmg
+    }
+}
+
+impl HardwareModel {
+    pub fn from_name(name: &str) -> Option<HardwareModel> {
+        MODELS.iter().find(|&&(n, _)| n == name).map(|&(_, m)| m)
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        MODELS.iter().map(|&(n, _)| n).collect()
+    }
+
+    // AF, BC, DE and HL when the boot ROM hands over to the cartridge.
+    // Taken from the pandocs.
+    pub fn boot_regs(&self) -> [u16; 4] {
+        match *self {
+            HardwareModel::Dmg => [0x01B0, 0x0013, 0x00D8, 0x014D],
+            HardwareModel::Mgb => [0xFFB0, 0x0013, 0x00D8, 0x014D],
+            HardwareModel::Cgb => [0x1180, 0x0000, 0x0008, 0x007C],
+        }
+    }
+}
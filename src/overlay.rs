@@ -0,0 +1,178 @@
+// A debug overlay drawn straight into the presented frame, after it has been
+// converted from the GameBoy's shades, so the emulated framebuffer is never
+// touched. Text uses a tiny 3x5 font in 4x6 cells, which fits 40 columns
+// across the GameBoy's screen.
+use iogb::cheat::Cheat;
+use iogb::gameboy::{GameBoy, SCREEN_W, SCREEN_H};
+
+use stats::FrameStats;
+
+const CELL_W: usize = 4;
+const CELL_H: usize = 6;
+const GLYPH_W: usize = 3;
+const GLYPH_H: usize = 5;
+// How many of the most recent serial characters are shown.
+const SERIAL_CHARS: usize = 16;
+// How many cheats the list shows at once.
+const CHEAT_LINES: usize = 8;
+const TEXT_COLOUR: u32 = 0xFF_FF_FF;
+const BACKGROUND: u32 = 0x00_00_00;
+
+// Glyphs for ' ' to '_' in ASCII order, eight to a line, as five rows of
+// three bits with the top left pixel in bit 14. Lower case letters are drawn
+// as upper case.
+const FONT: [u16; 64] = [0x0000, 0x2482, 0x5A00, 0x5F7D, 0x3C9E, 0x52A5, 0x2AAB, 0x2400,
+                         0x1491, 0x4494, 0x0AA8, 0x05D0, 0x0014, 0x01C0, 0x0002, 0x12A4,
+                         0x7B6F, 0x2C97, 0x62A7, 0x628E, 0x5BC9, 0x798E, 0x39EF, 0x7292,
+                         0x7BEF, 0x7BCE, 0x0410, 0x0414, 0x1511, 0x0E38, 0x4454, 0x6282,
+                         0x2BE3, 0x2BED, 0x6BAE, 0x3923, 0x6B6E, 0x79A7, 0x79A4, 0x396B,
+                         0x5BED, 0x7497, 0x126A, 0x5BAD, 0x4927, 0x5FED, 0x6B6D, 0x2B6A,
+                         0x6BA4, 0x2B73, 0x6BAD, 0x388E, 0x7492, 0x5B6B, 0x5B52, 0x5BFD,
+                         0x5AAD, 0x5A92, 0x72A7, 0x3493, 0x4889, 0x6496, 0x2A00, 0x0007];
+
+pub struct Overlay {
+    visible: bool,
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay {
+            visible: false,
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    // The lines of text to show for the current state of the GameBoy.
+    pub fn lines(&self, gb: &GameBoy, stats: &FrameStats) -> Vec<String> {
+        let sent = gb.serial_bytes();
+        let serial: String = sent[sent.len().saturating_sub(SERIAL_CHARS)..]
+            .iter()
+            .map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' })
+            .collect();
+        vec![format!("{:.0} FPS {:.0}%", stats.fps(), stats.speed() * 100.0),
+             format!("LY {:3} MODE {}", gb.ly(), gb.lcd_mode()),
+             format!("ROM {:02X} RAM {:02X}", gb.rom_bank(), gb.ram_bank()),
+             format!("SER {}", serial)]
+    }
+
+    // Draw the overlay into an XRGB frame SCREEN_W pixels wide.
+    pub fn draw(&self, frame: &mut [u32], gb: &GameBoy, stats: &FrameStats) {
+        for (i, line) in self.lines(gb, stats).iter().enumerate() {
+            draw_text(frame, SCREEN_W, 1, 1 + i * CELL_H, line);
+        }
+    }
+}
+
+// List the cheats along the bottom of the frame, with the selected one marked
+// and the list scrolled so that it can be seen.
+pub fn draw_cheats(frame: &mut [u32], cheats: &[Cheat], selected: usize) {
+    let first = (selected + 1).saturating_sub(CHEAT_LINES);
+    let shown = cheats.iter().enumerate().skip(first).take(CHEAT_LINES);
+    let top = SCREEN_H - cheats.len().min(CHEAT_LINES) * CELL_H;
+    for (row, (i, cheat)) in shown.enumerate() {
+        let line = format!("{}{} {}",
+                           if i == selected { '>' } else { ' ' },
+                           if cheat.enabled { "ON " } else { "OFF" },
+                           cheat.name);
+        draw_text(frame, SCREEN_W, 1, top + row * CELL_H, &line);
+    }
+}
+
+// Draw text with its top left corner at (x, y), on a solid background so that
+// it can be read over anything. Text that runs off the frame is clipped.
+pub fn draw_text(frame: &mut [u32], width: usize, x: usize, y: usize, text: &str) {
+    let height = frame.len() / width;
+    for (i, c) in text.chars().enumerate() {
+        let glyph = glyph(c);
+        let cell_x = x + i * CELL_W;
+        for row in 0..CELL_H {
+            for col in 0..CELL_W {
+                let (px, py) = (cell_x + col, y + row);
+                if px >= width || py >= height {
+                    continue;
+                }
+                let lit = row < GLYPH_H && col < GLYPH_W &&
+                          glyph & (1 << (14 - row * GLYPH_W - col)) != 0;
+                frame[py * width + px] = if lit { TEXT_COLOUR } else { BACKGROUND };
+            }
+        }
+    }
+}
+
+fn glyph(c: char) -> u16 {
+    let c = c.to_ascii_uppercase();
+    match c {
+        ' '...'_' => FONT[c as usize - ' ' as usize],
+        _ => FONT['?' as usize - ' ' as usize],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{draw_text, glyph, BACKGROUND, TEXT_COLOUR};
+
+    const UNTOUCHED: u32 = 0x12_34_56;
+
+    // The frame as text: '#' for text, '.' for its background and ' ' for
+    // anything left alone.
+    fn render(frame: &[u32], width: usize) -> Vec<String> {
+        frame.chunks(width)
+            .map(|row| {
+                row.iter()
+                    .map(|&p| match p {
+                        TEXT_COLOUR => '#',
+                        BACKGROUND => '.',
+                        _ => ' ',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn text() {
+        let mut frame = vec![UNTOUCHED; 10 * 8];
+        draw_text(&mut frame, 10, 1, 1, "Hi");
+        assert_eq!(render(&frame, 10),
+                   ["          ",
+                    " #.#.###. ",
+                    " #.#..#.. ",
+                    " ###..#.. ",
+                    " #.#..#.. ",
+                    " #.#.###. ",
+                    " ........ ",
+                    "          "]);
+    }
+
+    // Anything off the right or bottom edge is dropped.
+    #[test]
+    fn clipped() {
+        let mut frame = vec![UNTOUCHED; 6 * 4];
+        draw_text(&mut frame, 6, 3, 1, "TT");
+        assert_eq!(render(&frame, 6), ["      ", "   ###", "   .#.", "   .#."]);
+
+        let mut frame = vec![UNTOUCHED; 6 * 4];
+        draw_text(&mut frame, 6, 0, 4, "T");
+        draw_text(&mut frame, 6, 6, 0, "T");
+        assert!(frame.iter().all(|&p| p == UNTOUCHED));
+    }
+
+    #[test]
+    fn glyphs() {
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('z'), glyph('Z'));
+        assert_eq!(glyph(' '), 0);
+        // Anything the font doesn't have is shown as a question mark.
+        for &c in ['{', '~', '\n', 'é', '★'].iter() {
+            assert_eq!(glyph(c), glyph('?'), "{:?}", c);
+        }
+        assert!(glyph('?') != 0);
+    }
+}
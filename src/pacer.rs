@@ -0,0 +1,229 @@
+// Frame pacing for the desktop frontend. The GameBoy runs at
+// CPU_HZ / CYCLES_PER_FRAME (~59.7275) frames per second, which isn't a whole
+// number of nanoseconds per frame, so the remainder is carried from frame to
+// frame to stop the deadline drifting.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// If we fall this many frames behind (e.g. the window was being dragged),
+// give up on catching up rather than running flat out until we have.
+const MAX_LAG_FRAMES: u64 = 4;
+
+pub trait Clock {
+    // Time elapsed since some fixed point. Must never go backwards.
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> MonotonicClock {
+        MonotonicClock { start: Instant::now() }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct Pacer<C: Clock> {
+    clock: C,
+    deadline: Duration,
+    // Fractional nanoseconds, in units of 1 / (CPU_HZ * speed) ns.
+    remainder: u64,
+    fast_forward: bool,
+    // How many times faster than normal fast-forward may run, or None for
+    // as fast as possible.
+    fast_forward_cap: Option<u32>,
+}
+
+impl<C: Clock> Pacer<C> {
+    pub fn new(clock: C) -> Pacer<C> {
+        let now = clock.now();
+        Pacer {
+            clock: clock,
+            deadline: now,
+            remainder: 0,
+            fast_forward: false,
+            fast_forward_cap: None,
+        }
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn set_fast_forward_cap(&mut self, cap: Option<u32>) {
+        self.fast_forward_cap = cap;
+    }
+
+    // Move the deadline on by exactly one frame at the given speed. The
+    // remainder is slightly off for a frame after the speed changes, which
+    // is well under a nanosecond.
+    fn advance(&mut self, speed: u32) {
+        let divisor = CPU_HZ as u64 * speed as u64;
+        self.remainder += CYCLES_PER_FRAME as u64 * NANOS_PER_SEC;
+        let nanos = self.remainder / divisor;
+        self.remainder %= divisor;
+        self.deadline += Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+    }
+
+    fn resync(&mut self, now: Duration) {
+        self.deadline = now;
+        self.remainder = 0;
+    }
+
+    // Forget about any time spent not emulating, e.g. while paused.
+    pub fn reset(&mut self) {
+        let now = self.clock.now();
+        self.resync(now);
+    }
+
+    // Call once per emulated frame. Sleeps until it's time for the next one,
+    // unless fast-forward is engaged without a cap.
+    pub fn wait(&mut self) {
+        let speed = match (self.fast_forward, self.fast_forward_cap) {
+            (false, _) => 1,
+            (true, Some(cap)) => cap,
+            (true, None) => {
+                // Don't bank any time, otherwise releasing fast-forward
+                // would pause until real time caught up.
+                let now = self.clock.now();
+                self.resync(now);
+                return;
+            }
+        };
+        self.advance(speed);
+
+        let now = self.clock.now();
+        if now < self.deadline {
+            let remaining = self.deadline - now;
+            self.clock.sleep(remaining);
+        } else if now - self.deadline > frame_duration() * MAX_LAG_FRAMES as u32 {
+            debug!("Running behind by {:?}, dropping the backlog", now - self.deadline);
+            self.resync(now);
+        }
+    }
+}
+
+// The length of a frame, rounded down to the nanosecond.
+pub fn frame_duration() -> Duration {
+    let nanos = CYCLES_PER_FRAME as u64 * NANOS_PER_SEC / CPU_HZ as u64;
+    Duration::new(0, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+    use super::{Clock, Pacer, NANOS_PER_SEC, frame_duration};
+
+    // Time only passes when the pacer sleeps or the test says so.
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn pacer() -> (Pacer<FakeClock>, Rc<Cell<Duration>>) {
+        let now = Rc::new(Cell::new(Duration::from_secs(1)));
+        (Pacer::new(FakeClock(now.clone())), now)
+    }
+
+    // When n frames at speed should have finished, rounded down to the
+    // nanosecond. The fake clock starts at a second.
+    fn exact(n: u64, speed: u64) -> Duration {
+        let nanos = n * CYCLES_PER_FRAME as u64 * NANOS_PER_SEC / (CPU_HZ as u64 * speed);
+        Duration::from_secs(1 + nanos / NANOS_PER_SEC) +
+        Duration::new(0, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    // However long each frame takes to emulate, every deadline is the exact
+    // time rounded down, rather than frame_duration() added up.
+    #[test]
+    fn no_drift() {
+        let (mut pacer, now) = pacer();
+        for n in 1..10_001 {
+            now.set(now.get() + Duration::from_millis(n % 7 + 1));
+            pacer.wait();
+            assert_eq!(now.get(), exact(n, 1), "frame {}", n);
+        }
+        assert!(frame_duration() * 10_000 < exact(10_000, 1) - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn fast_forward_cap() {
+        let (mut pacer, now) = pacer();
+        pacer.set_fast_forward(true);
+        pacer.set_fast_forward_cap(Some(3));
+        for _ in 0..300 {
+            pacer.wait();
+        }
+        assert_eq!(now.get(), exact(300, 3));
+    }
+
+    // Without a cap fast-forward never sleeps, and doesn't bank time to make
+    // up afterwards.
+    #[test]
+    fn fast_forward_uncapped() {
+        let (mut pacer, now) = pacer();
+        pacer.set_fast_forward(true);
+        for _ in 0..100 {
+            pacer.wait();
+        }
+        assert_eq!(now.get(), Duration::from_secs(1));
+
+        pacer.set_fast_forward(false);
+        pacer.wait();
+        assert_eq!(now.get(), exact(1, 1));
+    }
+
+    // A long stall is forgotten rather than run flat out to catch up on.
+    #[test]
+    fn falls_behind() {
+        let (mut pacer, now) = pacer();
+        pacer.wait();
+        let stall = now.get() + Duration::from_secs(1);
+        now.set(stall);
+        pacer.wait();
+        assert_eq!(now.get(), stall);
+        pacer.wait();
+        assert_eq!(now.get(), stall + exact(1, 1) - Duration::from_secs(1));
+    }
+
+    // A short one is made up for, by not sleeping until it has been.
+    #[test]
+    fn catches_up() {
+        let (mut pacer, now) = pacer();
+        pacer.wait();
+        now.set(now.get() + frame_duration() * 2);
+        pacer.wait();
+        assert_eq!(now.get(), exact(1, 1) + frame_duration() * 2);
+        pacer.wait();
+        assert_eq!(now.get(), exact(3, 1));
+        pacer.wait();
+        assert_eq!(now.get(), exact(4, 1));
+    }
+}
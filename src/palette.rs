@@ -0,0 +1,113 @@
+// The colours that the GameBoy's four shades are displayed with.
+use std::fmt;
+
+// Colours as 0xRRGGBB, from the lightest shade to the darkest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette(pub [u32; 4]);
+
+pub const PRESETS: [(&'static str, Palette); 5] =
+    [("grey", Palette([0xC0C0C0, 0x808080, 0x404040, 0x000000])),
+     ("dmg-green", Palette([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F])),
+     ("pocket", Palette([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F])),
+     ("light", Palette([0x00B581, 0x009A71, 0x00694A, 0x004F3B])),
+     ("inverted", Palette([0x000000, 0x555555, 0xAAAAAA, 0xFFFFFF]))];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        PRESETS[0].1
+    }
+}
+
+impl Palette {
+    pub fn from_name(name: &str) -> Option<Palette> {
+        PRESETS.iter().find(|&&(n, _)| n == name).map(|&(_, p)| p)
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        PRESETS.iter().map(|&(n, _)| n).collect()
+    }
+
+    // Parse four comma separated RRGGBB colours, lightest first.
+    pub fn parse(s: &str) -> Result<Palette, String> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim().trim_start_matches('#')).collect();
+        if parts.len() != 4 {
+            return Err(format!("Expected 4 colours, got {}", parts.len()));
+        }
+
+        let mut colours = [0; 4];
+        for (colour, part) in colours.iter_mut().zip(parts.iter()) {
+            if part.len() != 6 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid colour: {}", part));
+            }
+            *colour = try!(u32::from_str_radix(part, 16)
+                .map_err(|e| format!("Invalid colour: {} {}", part, e)));
+        }
+        Ok(Palette(colours))
+    }
+
+    // The colour of a shade from the back buffer, where 0 is the lightest.
+    pub fn rgb(&self, shade: u8) -> u32 {
+        self.0[shade as usize & 0x03]
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:06X},{:06X},{:06X},{:06X}",
+               self.0[0],
+               self.0[1],
+               self.0[2],
+               self.0[3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Palette, PRESETS};
+
+    #[test]
+    fn names() {
+        assert_eq!(Palette::names(), vec!["grey", "dmg-green", "pocket", "light", "inverted"]);
+        for &(name, palette) in PRESETS.iter() {
+            assert_eq!(Palette::from_name(name), Some(palette));
+        }
+        assert_eq!(Palette::from_name("grey"), Some(Palette::default()));
+        assert_eq!(Palette::from_name("Grey"), None);
+        assert_eq!(Palette::from_name(""), None);
+    }
+
+    #[test]
+    fn parse() {
+        let expected = Palette([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+        assert_eq!(Palette::parse("FFFFFF,AAAAAA,555555,000000"), Ok(expected));
+        assert_eq!(Palette::parse("#ffffff, #aaaaaa, #555555, #000000"), Ok(expected));
+        // What Display prints can be read back.
+        for &(_, palette) in PRESETS.iter() {
+            assert_eq!(Palette::parse(&palette.to_string()), Ok(palette));
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in ["",
+                  "FFFFFF,AAAAAA,555555",
+                  "FFFFFF,AAAAAA,555555,000000,000000",
+                  "FFFFFF,AAAAAA,555555,00000",
+                  "FFFFFF,AAAAAA,555555,0000000",
+                  "FFFFFF,AAAAAA,55555G,000000",
+                  "FFFFFF,AAAAAA,+55555,000000"]
+            .iter() {
+            assert!(Palette::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn shades() {
+        let palette = Palette([1, 2, 3, 4]);
+        assert_eq!(palette.rgb(0), 1);
+        assert_eq!(palette.rgb(3), 4);
+        // Only the shade's two bits count.
+        assert_eq!(palette.rgb(6), 3);
+    }
+}
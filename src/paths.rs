@@ -0,0 +1,139 @@
+// Where the files that belong to a ROM (battery saves, save states and
+// screenshots) are kept. By default they live next to the ROM, but they can
+// all be redirected into one directory, e.g. when the ROM is on a read-only
+// filesystem.
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Paths {
+    rom: PathBuf,
+    save_dir: Option<PathBuf>,
+    sav: Option<PathBuf>,
+    screenshot_dir: Option<PathBuf>,
+}
+
+impl Paths {
+    pub fn new(rom: &Path) -> Paths {
+        Paths {
+            rom: rom.to_path_buf(),
+            save_dir: None,
+            sav: None,
+            screenshot_dir: None,
+        }
+    }
+
+    // Redirect all files into dir, creating it if needed.
+    pub fn set_save_dir(&mut self, dir: &Path) -> Result<(), String> {
+        try!(fs::create_dir_all(dir).map_err(|e| format!("{}", e)));
+        self.save_dir = Some(dir.to_path_buf());
+        Ok(())
+    }
+
+    // Use path for the battery save, regardless of the save directory.
+    pub fn set_sav(&mut self, path: &Path) {
+        self.sav = Some(path.to_path_buf());
+    }
+
+    pub fn set_screenshot_dir(&mut self, dir: &Path) {
+        self.screenshot_dir = Some(dir.to_path_buf());
+    }
+
+    pub fn sav(&self) -> PathBuf {
+        match self.sav {
+            Some(ref p) => p.clone(),
+            None => self.derive("sav"),
+        }
+    }
+
+    // Slot 3 of tetris.gb is tetris.ss3.
+    pub fn state(&self, slot: u8) -> PathBuf {
+        self.derive(&format!("ss{}", slot))
+    }
+
+    pub fn screenshot_dir(&self) -> PathBuf {
+        match (self.screenshot_dir.as_ref(), self.save_dir.as_ref()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(dir)) => dir.clone(),
+            (None, None) => PathBuf::from("."),
+        }
+    }
+
+    // The ROM's file name with its extension replaced by ext, in the save
+    // directory or next to the ROM.
+    fn derive(&self, ext: &str) -> PathBuf {
+        let mut name = match self.rom.file_stem() {
+            Some(stem) => stem.to_os_string(),
+            None => OsString::from("rom"),
+        };
+        name.push(".");
+        name.push(ext);
+
+        let dir = match self.save_dir {
+            Some(ref dir) => dir.as_path(),
+            None => self.rom.parent().unwrap_or(Path::new("")),
+        };
+        dir.join(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use super::Paths;
+
+    fn sav(rom: &str) -> PathBuf {
+        Paths::new(Path::new(rom)).sav()
+    }
+
+    #[test]
+    fn derived_names() {
+        assert_eq!(sav("roms/tetris.gb"), PathBuf::from("roms/tetris.sav"));
+        assert_eq!(sav("/home/me/roms/tetris.gbc"),
+                   PathBuf::from("/home/me/roms/tetris.sav"));
+        // Only the last extension is replaced.
+        assert_eq!(sav("zelda.v1.1.gb"), PathBuf::from("zelda.v1.1.sav"));
+        assert_eq!(sav("roms/tetris"), PathBuf::from("roms/tetris.sav"));
+        assert_eq!(sav("tetris.gb"), PathBuf::from("tetris.sav"));
+        assert_eq!(sav("../tetris.gb"), PathBuf::from("../tetris.sav"));
+        assert_eq!(sav("ポケモン ピカチュウ.gb"), PathBuf::from("ポケモン ピカチュウ.sav"));
+        // A dotfile is all stem.
+        assert_eq!(sav("roms/.gb"), PathBuf::from("roms/.gb.sav"));
+    }
+
+    #[test]
+    fn overrides() {
+        let dir = env::temp_dir().join(format!("iogb-paths-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut paths = Paths::new(Path::new("/roms/tetris.gb"));
+        assert_eq!(paths.screenshot_dir(), PathBuf::from("."));
+
+        paths.set_save_dir(&dir.join("saves")).unwrap();
+        assert!(dir.join("saves").is_dir());
+        assert_eq!(paths.sav(), dir.join("saves/tetris.sav"));
+        assert_eq!(paths.state(2), dir.join("saves/tetris.ss2"));
+        assert_eq!(paths.screenshot_dir(), dir.join("saves"));
+
+        // The battery save and screenshots can each go somewhere else again.
+        paths.set_sav(Path::new("/elsewhere/t.sav"));
+        paths.set_screenshot_dir(Path::new("shots"));
+        assert_eq!(paths.sav(), PathBuf::from("/elsewhere/t.sav"));
+        assert_eq!(paths.state(2), dir.join("saves/tetris.ss2"));
+        assert_eq!(paths.screenshot_dir(), PathBuf::from("shots"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_slots() {
+        let paths = Paths::new(Path::new("roms/tetris.gb"));
+        assert_eq!(paths.state(0), PathBuf::from("roms/tetris.ss0"));
+        assert_eq!(paths.state(9), PathBuf::from("roms/tetris.ss9"));
+    }
+}
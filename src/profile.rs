@@ -0,0 +1,234 @@
+// Counts which instructions a ROM spends its time on.
+use std::cmp;
+use std::fmt;
+
+// How many of the most executed addresses a report lists.
+pub const HOT_PCS: usize = 16;
+// How many opcodes the printed report lists.
+const TOP_OPCODES: usize = 16;
+
+pub struct Profiler {
+    counts: [u64; 256],
+    cycles: [u64; 256],
+    cb_counts: [u64; 256],
+    cb_cycles: [u64; 256],
+    // Executions of the instruction at each address.
+    pcs: Vec<u64>,
+}
+
+impl fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Profiler")
+            .field("instructions", &self.instructions())
+            .finish()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: [0; 256],
+            cycles: [0; 256],
+            cb_counts: [0; 256],
+            cb_cycles: [0; 256],
+            pcs: vec![0; 0x10000],
+        }
+    }
+
+    // Record an instruction at pc that took ticks. CB prefixed instructions
+    // are counted under their second byte, cb, rather than under 0xCB.
+    pub fn record(&mut self, pc: u16, opcode: u8, cb: Option<u8>, ticks: u32) {
+        match cb {
+            Some(op) => {
+                self.cb_counts[op as usize] += 1;
+                self.cb_cycles[op as usize] += ticks as u64;
+            }
+            None => {
+                self.counts[opcode as usize] += 1;
+                self.cycles[opcode as usize] += ticks as u64;
+            }
+        }
+        self.pcs[pc as usize] += 1;
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.counts.iter().chain(self.cb_counts.iter()).sum()
+    }
+
+    // The report, with disasm giving the instruction at each hot address.
+    pub fn report<F: Fn(u16) -> String>(&self, disasm: F) -> ProfileReport {
+        let mut hot: Vec<(u16, u64)> = self.pcs
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(pc, &n)| (pc as u16, n))
+            .collect();
+        // Ties go to the lower address, so reports are reproducible.
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(HOT_PCS);
+
+        ProfileReport {
+            counts: self.counts,
+            cycles: self.cycles,
+            cb_counts: self.cb_counts,
+            cb_cycles: self.cb_cycles,
+            hot_pcs: hot.into_iter()
+                .map(|(pc, n)| {
+                    HotPc {
+                        pc: pc,
+                        count: n,
+                        instruction: disasm(pc),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+// An address that many instructions were executed at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotPc {
+    pub pc: u16,
+    pub count: u64,
+    // The instruction there when the report was made.
+    pub instruction: String,
+}
+
+// Executions and cycles per opcode, indexed by opcode, and the most executed
+// addresses, most executed first.
+#[derive(Clone)]
+pub struct ProfileReport {
+    pub counts: [u64; 256],
+    pub cycles: [u64; 256],
+    pub cb_counts: [u64; 256],
+    pub cb_cycles: [u64; 256],
+    pub hot_pcs: Vec<HotPc>,
+}
+
+impl fmt::Debug for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProfileReport")
+            .field("instructions", &self.instructions())
+            .field("cycles", &self.total_cycles())
+            .field("hot_pcs", &self.hot_pcs)
+            .finish()
+    }
+}
+
+impl ProfileReport {
+    pub fn instructions(&self) -> u64 {
+        self.counts.iter().chain(self.cb_counts.iter()).sum()
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles.iter().chain(self.cb_cycles.iter()).sum()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total_cycles();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / cmp::max(total, 1) as f64;
+
+        try!(writeln!(f, "{} instructions, {} cycles", self.instructions(), total));
+
+        // Both tables together, by the time spent on each opcode.
+        let mut ops: Vec<(String, u64, u64)> = Vec::new();
+        for op in 0..256 {
+            if self.counts[op] > 0 {
+                ops.push((format!("0x{:02x}", op), self.counts[op], self.cycles[op]));
+            }
+            if self.cb_counts[op] > 0 {
+                ops.push((format!("0xcb 0x{:02x}", op), self.cb_counts[op], self.cb_cycles[op]));
+            }
+        }
+        ops.sort_by_key(|op| cmp::Reverse(op.2));
+        try!(writeln!(f, "Opcodes by cycles:"));
+        for &(ref name, count, cycles) in ops.iter().take(TOP_OPCODES) {
+            try!(writeln!(f,
+                          "{:>9} {:>12} executions {:>14} cycles {:5.1}%",
+                          name,
+                          count,
+                          cycles,
+                          percent(cycles)));
+        }
+
+        try!(write!(f, "Hottest addresses:"));
+        for hot in &self.hot_pcs {
+            try!(write!(f,
+                        "\n0x{:04x} {:>12} executions  {}",
+                        hot.pc,
+                        hot.count,
+                        hot.instruction));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use gameboy::GameBoy;
+
+    // Counts down B from 10, swaps A and then spins.
+    fn countdown() -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        let program = [0x06, 0x0A, // LD B,10
+                       0x05, // DEC B
+                       0x20, 0xFD, // JR NZ,-3
+                       0xCB, 0x37, // SWAP A
+                       0x18, 0xFE]; // JR -2
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty())
+    }
+
+    #[test]
+    fn countdown_loop() {
+        let mut gb = countdown();
+        gb.set_profiling(true);
+        for _ in 0..27 {
+            gb.step_instruction();
+        }
+
+        let report = gb.profile_report();
+        assert_eq!(report.instructions(), 27);
+        assert_eq!((report.counts[0x06], report.cycles[0x06]), (1, 8));
+        assert_eq!((report.counts[0x05], report.cycles[0x05]), (10, 40));
+        // Taken nine times, and not taken once.
+        assert_eq!((report.counts[0x20], report.cycles[0x20]), (10, 9 * 12 + 8));
+        assert_eq!((report.cb_counts[0x37], report.cb_cycles[0x37]), (1, 8));
+        assert_eq!(report.counts[0xCB], 0);
+        assert_eq!((report.counts[0x18], report.cycles[0x18]), (5, 60));
+        assert_eq!(report.total_cycles(), 8 + 40 + 116 + 8 + 60);
+        assert_eq!(report.total_cycles(), gb.elapsed_cycles());
+
+        let hot: Vec<(u16, u64)> = report.hot_pcs.iter().map(|h| (h.pc, h.count)).collect();
+        assert_eq!(hot,
+                   vec![(0x0102, 10), (0x0103, 10), (0x0107, 5), (0x0100, 1), (0x0105, 1)]);
+        assert_eq!(report.hot_pcs[0].instruction, gb.disassemble(0x0102).0);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut gb = countdown();
+        for _ in 0..27 {
+            gb.step_instruction();
+        }
+        assert!(!gb.profiling());
+        let report = gb.profile_report();
+        assert_eq!(report.instructions(), 0);
+        assert!(report.hot_pcs.is_empty());
+
+        // Turning it on starts counting from then.
+        gb.set_profiling(true);
+        gb.step_instruction();
+        assert_eq!(gb.profile_report().counts[0x18], 1);
+    }
+}
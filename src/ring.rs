@@ -0,0 +1,178 @@
+// A fixed size ring of samples shared between the emulator thread, which
+// pushes, and the audio callback, which pops. There's exactly one of each, so
+// all they need to agree on is where the other has got to: the producer only
+// moves tail and the consumer only moves head. Neither ever blocks or
+// allocates, which the audio callback mustn't do.
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Ring {
+    samples: UnsafeCell<Box<[i16]>>,
+    capacity: usize,
+    // Counts of samples ever popped and pushed. They wrap together, so
+    // tail - head is always the number waiting.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    // How many times the consumer asked for more than there was.
+    underruns: AtomicUsize,
+}
+
+// The slots between head and tail belong to the consumer and the rest to the
+// producer, so they never touch the same sample at the same time.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
+
+pub struct Producer {
+    ring: Arc<Ring>,
+}
+
+pub struct Consumer {
+    ring: Arc<Ring>,
+}
+
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    let ring = Arc::new(Ring {
+        samples: UnsafeCell::new(vec![0; capacity].into_boxed_slice()),
+        capacity: capacity,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+    });
+    (Producer { ring: ring.clone() }, Consumer { ring: ring })
+}
+
+impl Producer {
+    // The number of samples waiting to be played.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.ring.underruns.load(Ordering::Relaxed)
+    }
+
+    // Add as many samples as there's room for, returning how many that was.
+    pub fn push(&mut self, samples: &[i16]) -> usize {
+        let capacity = self.ring.capacity;
+        let head = self.ring.head.load(Ordering::Acquire);
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let count = cmp::min(samples.len(), capacity - tail.wrapping_sub(head));
+
+        let buf = unsafe { &mut *self.ring.samples.get() };
+        for (i, &s) in samples[..count].iter().enumerate() {
+            buf[tail.wrapping_add(i) % capacity] = s;
+        }
+        self.ring.tail.store(tail.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+impl Consumer {
+    // Fill out with the oldest samples, padding with silence if there aren't
+    // enough, and return how many were real.
+    pub fn pop(&mut self, out: &mut [i16]) -> usize {
+        let capacity = self.ring.capacity;
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        let count = cmp::min(out.len(), tail.wrapping_sub(head));
+
+        let buf = unsafe { &*self.ring.samples.get() };
+        for (i, s) in out[..count].iter_mut().enumerate() {
+            *s = buf[head.wrapping_add(i) % capacity];
+        }
+        for s in out[count..].iter_mut() {
+            *s = 0;
+        }
+        self.ring.head.store(head.wrapping_add(count), Ordering::Release);
+
+        if count < out.len() {
+            self.ring.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::thread;
+
+    use super::ring;
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = ring(5);
+        let mut out = [0; 3];
+        for i in 0..10 {
+            let base = i * 3;
+            assert_eq!(producer.push(&[base, base + 1, base + 2]), 3);
+            assert_eq!(producer.len(), 3);
+            assert_eq!(consumer.pop(&mut out), 3);
+            assert_eq!(out, [base, base + 1, base + 2]);
+        }
+        assert_eq!(producer.len(), 0);
+        assert_eq!(producer.underruns(), 0);
+    }
+
+    #[test]
+    fn full() {
+        let (mut producer, mut consumer) = ring(4);
+        assert_eq!(producer.push(&[1, 2, 3]), 3);
+        // Only the samples that fit are taken.
+        assert_eq!(producer.push(&[4, 5, 6]), 1);
+        assert_eq!(producer.push(&[7]), 0);
+        let mut out = [0; 4];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn underrun_plays_silence() {
+        let (mut producer, mut consumer) = ring(8);
+        producer.push(&[1, 2]);
+        let mut out = [9; 4];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [1, 2, 0, 0]);
+        assert_eq!(producer.underruns(), 1);
+        assert_eq!(consumer.pop(&mut out), 0);
+        assert_eq!(out, [0; 4]);
+        assert_eq!(producer.underruns(), 2);
+    }
+
+    // Everything pushed comes out in order, however the two threads happen
+    // to interleave.
+    #[test]
+    fn threads() {
+        const COUNT: usize = 100_000;
+        let (mut producer, mut consumer) = ring(64);
+        let reader = thread::spawn(move || {
+            let mut next = 0;
+            let mut out = [0; 7];
+            while next < COUNT {
+                let n = consumer.pop(&mut out);
+                for &s in out[..n].iter() {
+                    assert_eq!(s, next as i16);
+                    next += 1;
+                }
+                thread::yield_now();
+            }
+        });
+
+        let mut sent = 0;
+        while sent < COUNT {
+            let chunk: Vec<i16> = (sent..cmp::min(sent + 13, COUNT)).map(|s| s as i16).collect();
+            sent += producer.push(&chunk);
+            thread::yield_now();
+        }
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+// Save states are a flat little-endian dump of every component's state,
+// written and read back in the same order. They start with a header so that
+// loading a state from another ROM or an incompatible version of iogb fails
+// cleanly instead of producing garbage.
+const MAGIC: &'static [u8; 4] = b"IOGB";
+// Bump this whenever the layout of any component's state changes.
+pub const VERSION: u32 = 12;
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut w = StateWriter { buf: Vec::new() };
+        w.write_bytes(MAGIC);
+        w.write_u32(VERSION);
+        w.write_u64(rom_hash);
+        w
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.write_u8(val as u8);
+        self.write_u8((val >> 8) as u8);
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.write_u16(val as u16);
+        self.write_u16((val >> 16) as u16);
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.write_u32(val as u32);
+        self.write_u32((val >> 32) as u32);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8], rom_hash: u64) -> Result<StateReader<'a>, String> {
+        let mut r = StateReader { buf: buf, pos: 0 };
+
+        let mut magic = [0; 4];
+        if r.read_bytes(&mut magic).is_err() || &magic != MAGIC {
+            return Err("Not an iogb save state".to_string());
+        }
+
+        let version = try!(r.read_u32());
+        if version != VERSION {
+            return Err(format!("Save state is version {}, but only version {} is supported",
+                               version,
+                               VERSION));
+        }
+
+        if try!(r.read_u64()) != rom_hash {
+            return Err("Save state was made with a different ROM".to_string());
+        }
+        Ok(r)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        match self.buf.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => Err("Save state is truncated".to_string()),
+        }
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(try!(self.read_u8()) != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let lo = try!(self.read_u8()) as u16;
+        let hi = try!(self.read_u8()) as u16;
+        Ok(hi << 8 | lo)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let lo = try!(self.read_u16()) as u32;
+        let hi = try!(self.read_u16()) as u32;
+        Ok(hi << 16 | lo)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let lo = try!(self.read_u32()) as u64;
+        let hi = try!(self.read_u32()) as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    // Fill buf, whose length must match what was written.
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        let end = self.pos + buf.len();
+        if end > self.buf.len() {
+            return Err("Save state is truncated".to_string());
+        }
+        buf.copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+        Ok(())
+    }
+
+    // Check that the whole state was consumed.
+    pub fn finish(self) -> Result<(), String> {
+        if self.pos != self.buf.len() {
+            return Err(format!("Save state has 0x{:x} unexpected trailing bytes",
+                               self.buf.len() - self.pos));
+        }
+        Ok(())
+    }
+}
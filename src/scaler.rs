@@ -0,0 +1,121 @@
+// Window scaling. minifb can only scale by powers of two, so any other factor
+// is done by blitting each frame into a larger buffer ourselves and letting
+// minifb show that unscaled.
+use minifb::Scale;
+
+pub const MAX_SCALE: u32 = 10;
+
+pub struct Scaler {
+    width: usize,
+    height: usize,
+    factor: usize,
+    buffer: Vec<u32>,
+}
+
+impl Scaler {
+    // Returns the scaler along with the Scale to create the window with.
+    pub fn new(width: usize, height: usize, scale: u32) -> Result<(Scaler, Scale), String> {
+        let (native, factor) = match scale {
+            1 => (Scale::X1, 1),
+            2 => (Scale::X2, 1),
+            4 => (Scale::X4, 1),
+            8 => (Scale::X8, 1),
+            3 | 5 | 6 | 7 | 9 | 10 => (Scale::X1, scale as usize),
+            s => return Err(format!("Invalid scale option: {}", s)),
+        };
+
+        let scaler = Scaler {
+            width: width,
+            height: height,
+            factor: factor,
+            buffer: vec![0; width * height * factor * factor],
+        };
+        Ok((scaler, native))
+    }
+
+    // The size of the buffers that will be handed to the window.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width * self.factor, self.height * self.factor)
+    }
+
+    // Nearest-neighbour scale frame, unless minifb is doing the scaling.
+    pub fn scale<'a>(&'a mut self, frame: &'a [u32]) -> &'a [u32] {
+        if self.factor == 1 {
+            return frame;
+        }
+
+        let out_width = self.width * self.factor;
+        for (y, row) in frame.chunks(self.width).enumerate() {
+            let start = y * self.factor * out_width;
+            {
+                let out = &mut self.buffer[start..start + out_width];
+                for (x, pixel) in row.iter().enumerate() {
+                    for p in out[x * self.factor..(x + 1) * self.factor].iter_mut() {
+                        *p = *pixel;
+                    }
+                }
+            }
+            // The rest of the block of rows is a copy of the first.
+            for i in 1..self.factor {
+                let (done, rest) = self.buffer.split_at_mut(start + i * out_width);
+                rest[..out_width].copy_from_slice(&done[start..start + out_width]);
+            }
+        }
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use minifb::Scale;
+
+    use super::{Scaler, MAX_SCALE};
+
+    fn native(scale: Scale) -> usize {
+        match scale {
+            Scale::X1 => 1,
+            Scale::X2 => 2,
+            Scale::X4 => 4,
+            Scale::X8 => 8,
+            _ => 0,
+        }
+    }
+
+    // minifb does the powers of two that it can, and we do the rest.
+    #[test]
+    fn selection() {
+        for scale in 1..MAX_SCALE + 1 {
+            let (scaler, window) = Scaler::new(160, 144, scale).unwrap();
+            let s = scale as usize;
+            if s.is_power_of_two() {
+                assert_eq!((native(window), scaler.size()), (s, (160, 144)));
+            } else {
+                assert_eq!((native(window), scaler.size()), (1, (160 * s, 144 * s)));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for &scale in [0, MAX_SCALE + 1, 16, 32].iter() {
+            let e = Scaler::new(160, 144, scale).err().unwrap();
+            assert_eq!(e, format!("Invalid scale option: {}", scale));
+        }
+    }
+
+    #[test]
+    fn nearest_neighbour() {
+        let (mut scaler, _) = Scaler::new(2, 2, 3).unwrap();
+        assert_eq!(scaler.scale(&[1, 2, 3, 4]),
+                   &[1, 1, 1, 2, 2, 2,
+                     1, 1, 1, 2, 2, 2,
+                     1, 1, 1, 2, 2, 2,
+                     3, 3, 3, 4, 4, 4,
+                     3, 3, 3, 4, 4, 4,
+                     3, 3, 3, 4, 4, 4][..]);
+
+        // Frames that minifb scales are passed straight through.
+        let (mut scaler, _) = Scaler::new(2, 2, 4).unwrap();
+        assert_eq!(scaler.scale(&[1, 2, 3, 4]), &[1, 2, 3, 4][..]);
+    }
+}
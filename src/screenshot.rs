@@ -0,0 +1,188 @@
+// Screenshots, saved as PNGs. The encoder is just enough to write a single
+// uncompressed RGBA image, which saves pulling in an image crate for one
+// 92KB file.
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use iogb::gameboy::{GameBoy, SCREEN_W, SCREEN_H};
+use iogb::serial::{Printout, PRINTER_W};
+
+// The printer's shades, from white to black.
+const PRINT_SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// The largest amount of data a single stored deflate block can hold.
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// Save the current frame to dir, returning the path that was written.
+pub fn save(gb: &GameBoy, dir: &Path) -> Result<PathBuf, String> {
+    let mut rgba = vec![0; SCREEN_W * SCREEN_H * 4];
+    gb.frame_into_rgba(&mut rgba);
+    write(&file_name(gb.rom_title(), ""), SCREEN_W, SCREEN_H, &rgba, dir)
+}
+
+// Save an XRGB frame, such as the one presented with the overlay drawn on
+// top, to dir.
+pub fn save_xrgb(gb: &GameBoy, frame: &[u32], dir: &Path) -> Result<PathBuf, String> {
+    let mut rgba = Vec::with_capacity(frame.len() * 4);
+    for &pixel in frame.iter() {
+        rgba.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, 0xFF]);
+    }
+    write(&file_name(gb.rom_title(), ""), SCREEN_W, SCREEN_H, &rgba, dir)
+}
+
+// Save a page from the Game Boy Printer to dir.
+pub fn save_print(title: &str, print: &Printout, dir: &Path) -> Result<PathBuf, String> {
+    let mut rgba = Vec::with_capacity(print.pixels.len() * 4);
+    for &shade in print.pixels.iter() {
+        let grey = PRINT_SHADES[shade as usize & 0x03];
+        rgba.extend_from_slice(&[grey, grey, grey, 0xFF]);
+    }
+    write(&file_name(title, "_print"), PRINTER_W, print.height, &rgba, dir)
+}
+
+fn write(name: &str, width: usize, height: usize, rgba: &[u8], dir: &Path)
+         -> Result<PathBuf, String> {
+    let png = encode_png(width as u32, height as u32, rgba);
+
+    let path = dir.join(name);
+    let mut file = try!(File::create(&path).map_err(|e| format!("{}", e)));
+    try!(file.write_all(&png).map_err(|e| format!("{}", e)));
+    Ok(path)
+}
+
+fn file_name(title: &str, suffix: &str) -> String {
+    let title: String = title.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000,
+        Err(_) => 0,
+    };
+    format!("iogb_{}_{}{}.png", title, timestamp, suffix)
+}
+
+pub fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(rgba.len(), width as usize * height as usize * 4);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut ihdr = vec![];
+    push_u32(&mut ihdr, width);
+    push_u32(&mut ihdr, height);
+    // 8 bit RGBA, default compression and filtering, not interlaced.
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &ihdr);
+
+    // Each scanline is preceded by its filter type, which is always none.
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for line in rgba.chunks(width as usize * 4) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Wrap data in a zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    push_u32(png, data.len() as u32);
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    push_u32(png, crc);
+}
+
+fn push_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65_521;
+        b %= 65_521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_STORED_BLOCK, adler32, crc32, encode_png, zlib_stored};
+
+    // tests/screenshot/3x2.png was written by another encoder from the same
+    // pixels.
+    #[test]
+    fn reference() {
+        let rgba: Vec<u8> = (0..24).map(|i| (i * 37) as u8).collect();
+        assert_eq!(encode_png(3, 2, &rgba),
+                   &include_bytes!("../tests/screenshot/3x2.png")[..]);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+        // Long enough that the sums have to be reduced along the way.
+        assert_eq!(adler32(&[0xFF; 100_000]), 0x149A_302C);
+    }
+
+    // A whole screen doesn't fit in one stored block.
+    #[test]
+    fn stored_blocks() {
+        let data: Vec<u8> = (0..MAX_STORED_BLOCK + 10).map(|i| i as u8).collect();
+        let z = zlib_stored(&data);
+        assert_eq!(&z[..2], &[0x78, 0x01]);
+        assert_eq!(&z[2..7], &[0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let second = 7 + MAX_STORED_BLOCK;
+        assert_eq!(&z[second..second + 5], &[0x01, 0x0A, 0x00, 0xF5, 0xFF]);
+        assert_eq!(&z[second + 5..second + 15], &data[MAX_STORED_BLOCK..]);
+        assert_eq!(z.len(), second + 15 + 4);
+
+        // Even nothing needs a final block.
+        assert_eq!(zlib_stored(&[]), vec![0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0, 0, 0, 1]);
+    }
+}
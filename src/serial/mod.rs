@@ -0,0 +1,7 @@
+mod printer;
+mod serial;
+mod tcp;
+
+pub use self::serial::{Serial, SerialDevice, SerialCallback, Disconnected, POLL_CYCLES};
+pub use self::printer::{Printer, Printout, PRINTER_W, decompress};
+pub use self::tcp::TcpLink;
@@ -0,0 +1,380 @@
+// The Game Boy Printer. Games talk to it in packets:
+//
+// 0x88 0x33 command compression length(2) data(length) checksum(2) 0x00 0x00
+//
+// where the checksum is the sum of everything from the command to the end of
+// the data. The printer answers 0x81 to the first of the two trailing bytes
+// to show that it's there, and its status to the second. Image data arrives
+// as tiles, two rows of 20 at a time, and is printed once a PRINT command
+// arrives.
+use std::fmt;
+use std::iter;
+
+use serial::SerialDevice;
+
+pub const PRINTER_W: usize = 160;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// The printer's buffer holds a whole screen's worth of tiles.
+const BUFFER_SIZE: usize = 0x2000;
+const TILE_ROW_BYTES: usize = PRINTER_W / 8 * 16;
+// Games wait for the busy flag to clear before sending more, so it stays set
+// for this many status requests after a PRINT to look like paper is moving.
+const BUSY_REQUESTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    Alive,
+    Status,
+}
+
+// A finished print-out, PRINTER_W pixels wide, with a shade from 0 (white) to
+// 3 (black) for every pixel.
+pub struct Printout {
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    sum: u16,
+    checksum: u16,
+    status: u8,
+    busy_requests: u32,
+    // Tile data received since the last INIT.
+    buffer: Vec<u8>,
+    // Prints without a bottom margin are joined onto the next one, since
+    // long images are sent as several prints.
+    page: Vec<u8>,
+    on_print: Box<dyn FnMut(Printout) + Send>,
+}
+
+impl fmt::Debug for Printer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Printer")
+            .field("state", &self.state)
+            .field("command", &format_args!("0x{:02x}", self.command))
+            .field("status", &format_args!("0x{:02x}", self.status))
+            .field("buffer", &self.buffer.len())
+            .field("page", &(self.page.len() / PRINTER_W))
+            .finish()
+    }
+}
+
+impl Printer {
+    // on_print is called with each page once the paper is fed past it.
+    pub fn new<F: FnMut(Printout) + Send + 'static>(on_print: F) -> Printer {
+        Printer {
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            status: 0,
+            busy_requests: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            on_print: Box::new(on_print),
+        }
+    }
+
+    // Returns the printer's response to a complete packet.
+    fn finish_packet(&mut self) -> u8 {
+        if self.sum != self.checksum {
+            return self.status | STATUS_CHECKSUM_ERROR;
+        }
+
+        // The status is reported as it was before the packet, except that
+        // asking for it is what moves a print along.
+        let status = self.status;
+        match self.command {
+            CMD_INIT => {
+                self.status = 0;
+                self.buffer.clear();
+            }
+            CMD_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(space));
+                // An empty DATA packet marks the end of the image.
+                if self.length > 0 {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() == 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                if sheets > 0 {
+                    self.print(palette);
+                }
+                if margins & 0x0F != 0 {
+                    self.feed();
+                }
+                self.buffer.clear();
+                self.status = STATUS_BUSY | STATUS_FULL;
+                self.busy_requests = BUSY_REQUESTS;
+            }
+            CMD_STATUS => {
+                if self.busy_requests > 0 {
+                    self.busy_requests -= 1;
+                    if self.busy_requests == 0 {
+                        self.status &= !STATUS_BUSY;
+                    }
+                }
+            }
+            cmd => debug!("Ignoring printer command 0x{:02x}", cmd),
+        }
+        status
+    }
+
+    // Add the buffered tiles to the page, mapping their colours through
+    // palette as BGP does.
+    fn print(&mut self, palette: u8) {
+        // 0x00 is treated by the printer like the usual 0xE4.
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        for row in self.buffer.chunks(TILE_ROW_BYTES) {
+            if row.len() < TILE_ROW_BYTES {
+                break;
+            }
+            for y in 0..8 {
+                for tile in row.chunks(16) {
+                    let (lo, hi) = (tile[y * 2], tile[y * 2 + 1]);
+                    for bit in (0..8).rev() {
+                        let colour = (hi >> bit & 1) << 1 | lo >> bit & 1;
+                        self.page.push(palette >> (colour * 2) & 0x03);
+                    }
+                }
+            }
+        }
+    }
+
+    fn feed(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let pixels = self.page.split_off(0);
+        (self.on_print)(Printout {
+            height: pixels.len() / PRINTER_W,
+            pixels: pixels,
+        });
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, out: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic(i) if out == MAGIC[i] => {
+                if i == 0 { State::Magic(1) } else { State::Command }
+            }
+            State::Magic(_) => State::Magic(if out == MAGIC[0] { 1 } else { 0 }),
+            State::Command => {
+                self.command = out;
+                self.sum = out as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = out & 0x01 != 0;
+                self.sum = self.sum.wrapping_add(out as u16);
+                State::Length(0)
+            }
+            State::Length(i) => {
+                self.sum = self.sum.wrapping_add(out as u16);
+                if i == 0 {
+                    self.length = out as u16;
+                    State::Length(1)
+                } else {
+                    self.length |= (out as u16) << 8;
+                    self.data.clear();
+                    if self.length == 0 { State::Checksum(0) } else { State::Data }
+                }
+            }
+            State::Data => {
+                self.sum = self.sum.wrapping_add(out as u16);
+                self.data.push(out);
+                if self.data.len() == self.length as usize {
+                    State::Checksum(0)
+                } else {
+                    State::Data
+                }
+            }
+            State::Checksum(i) => {
+                if i == 0 {
+                    self.checksum = out as u16;
+                    State::Checksum(1)
+                } else {
+                    self.checksum |= (out as u16) << 8;
+                    State::Alive
+                }
+            }
+            State::Alive => {
+                reply = ALIVE;
+                State::Status
+            }
+            State::Status => {
+                reply = self.finish_packet();
+                State::Magic(0)
+            }
+        };
+        reply
+    }
+}
+
+// Expand the printer's run length encoding. Each run starts with a byte that
+// either has bit 7 set, meaning the byte after it is repeated (n & 0x7F) + 2
+// times, or is followed by n + 1 bytes to copy as they are.
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let n = data[i] as usize;
+        i += 1;
+        if n & 0x80 != 0 {
+            if let Some(&b) = data.get(i) {
+                out.extend(iter::repeat(b).take((n & 0x7F) + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + n + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serial::SerialDevice;
+    use super::{Printer, Printout, PRINTER_W, decompress};
+
+    // A packet as a game sends it, including the two bytes that it clocks
+    // in the replies with.
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x88, 0x33, command, compressed as u8, data.len() as u8,
+                         (data.len() >> 8) as u8];
+        p.extend_from_slice(data);
+        let sum = p[2..].iter().fold(0u16, |acc, &b| acc.wrapping_add(b as u16));
+        p.extend_from_slice(&[sum as u8, (sum >> 8) as u8, 0x00, 0x00]);
+        p
+    }
+
+    // Send a packet, returning the alive and status replies.
+    fn send(printer: &mut Printer, packet: &[u8]) -> (u8, u8) {
+        let replies: Vec<u8> = packet.iter().map(|&b| printer.exchange(b)).collect();
+        assert!(replies[..replies.len() - 2].iter().all(|&r| r == 0));
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    #[test]
+    fn runs() {
+        assert_eq!(decompress(&[0x81, 0xAB, 0x01, 0x12, 0x34]),
+                   vec![0xAB, 0xAB, 0xAB, 0x12, 0x34]);
+        assert_eq!(decompress(&[0x80, 0x00]), vec![0x00, 0x00]);
+        // A run cut short takes what there is.
+        assert_eq!(decompress(&[0x03, 0x01]), vec![0x01]);
+    }
+
+    // Two rows of tiles, the first compressed and the second not, printed
+    // with the usual palette.
+    #[test]
+    fn print_page() {
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let pages = printed.clone();
+        let mut printer = Printer::new(move |p: Printout| pages.lock().unwrap().push(p));
+
+        // Columns of solid tiles in each shade. A blank or black tile is one
+        // run, and the others are copied as they are.
+        let mut stripes = Vec::new();
+        for t in 0..20 {
+            match t % 4 {
+                0 => stripes.extend_from_slice(&[0x8E, 0x00]),
+                1 => {
+                    stripes.push(0x0F);
+                    stripes.extend((0..16).map(|i| if i % 2 == 0 { 0xFF } else { 0x00 }));
+                }
+                2 => {
+                    stripes.push(0x0F);
+                    stripes.extend((0..16).map(|i| if i % 2 == 0 { 0x00 } else { 0xFF }));
+                }
+                _ => stripes.extend_from_slice(&[0x8E, 0xFF]),
+            }
+        }
+        // A black and white checkerboard.
+        let checks: Vec<u8> = (0..320).map(|i| if i / 2 % 2 == 0 { 0xAA } else { 0x55 }).collect();
+
+        assert_eq!(send(&mut printer, &packet(0x01, false, &[])), (0x81, 0x00));
+        assert_eq!(send(&mut printer, &packet(0x04, true, &stripes)), (0x81, 0x00));
+        assert_eq!(send(&mut printer, &packet(0x04, false, &checks)), (0x81, 0x08));
+        assert_eq!(send(&mut printer, &packet(0x04, false, &[])), (0x81, 0x08));
+        assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (0x81, 0x08));
+        assert!(printed.lock().unwrap().is_empty());
+
+        // One sheet with a margin after it, so it's fed out straight away.
+        assert_eq!(send(&mut printer, &packet(0x02, false, &[0x01, 0x13, 0xE4, 0x40])),
+                   (0x81, 0x08));
+        // Busy while the paper moves.
+        for _ in 0..4 {
+            assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (0x81, 0x06));
+        }
+        assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (0x81, 0x04));
+
+        let printed = printed.lock().unwrap();
+        assert_eq!(printed.len(), 1);
+        let expected: Vec<u8> = include_str!("../../tests/printer/page.txt")
+            .bytes()
+            .filter(|&b| b != b'\n')
+            .map(|b| b - b'0')
+            .collect();
+        assert_eq!(printed[0].height, 16);
+        for (y, (row, expected)) in printed[0]
+            .pixels
+            .chunks(PRINTER_W)
+            .zip(expected.chunks(PRINTER_W))
+            .enumerate() {
+            assert_eq!(row, expected, "row {}", y);
+        }
+    }
+
+    // A corrupted packet is reported, and not acted on.
+    #[test]
+    fn bad_checksum() {
+        let mut printer = Printer::new(|_| panic!("printed"));
+        let mut data = packet(0x04, false, &[0xFF; 16]);
+        data[10] ^= 0x01;
+        assert_eq!(send(&mut printer, &data), (0x81, 0x01));
+        assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (0x81, 0x00));
+    }
+}
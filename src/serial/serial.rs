@@ -0,0 +1,356 @@
+use std::cmp;
+use std::fmt;
+use interrupt;
+use savestate::{StateWriter, StateReader};
+
+// Bits are shifted at 8192Hz, so a whole byte takes 4096 cycles.
+const BIT_CYCLES: u32 = 512;
+// How often devices are polled for transfers clocked from their end, which
+// is once per bit at the GameBoy's own serial clock.
+pub const POLL_CYCLES: u32 = BIT_CYCLES;
+// How much of what has been sent is kept for output().
+const OUTPUT_LIMIT: usize = 0x10000;
+
+// Whatever is plugged into the other end of the link cable.
+pub trait SerialDevice {
+    // Called when the GameBoy starts clocking a transfer, with the byte that
+    // it will shift out, returning the byte that will be shifted in.
+    fn exchange(&mut self, out: u8) -> u8;
+
+    // Called every POLL_CYCLES with the byte currently in SB. A device that
+    // can drive the clock itself returns the byte it shifted in once it has
+    // done so, which completes a transfer that is waiting on the external
+    // clock.
+    fn poll(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+// Nothing plugged in, so the data line floats high.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _out: u8) -> u8 {
+        0xFF
+    }
+}
+
+// Adapts a closure into a SerialDevice.
+pub struct SerialCallback<F: FnMut(u8) -> u8>(pub F);
+
+impl<F: FnMut(u8) -> u8> SerialDevice for SerialCallback<F> {
+    fn exchange(&mut self, out: u8) -> u8 {
+        (self.0)(out)
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    transfer: bool,
+    internal_clock: bool,
+    // Cycles until the next bit is shifted.
+    ticks: u32,
+    // How many bits of the current transfer have been shifted.
+    bits: u32,
+    // The byte being shifted in, once the device has been asked for it, and
+    // the one that was in SB when the transfer started.
+    incoming: Option<u8>,
+    sent: u8,
+    poll_ticks: u32,
+    device: Box<dyn SerialDevice + Send>,
+    // When linked to another emulated GameBoy, completed transfers are
+    // parked here until the other side can exchange its byte.
+    linked: bool,
+    outgoing: Option<u8>,
+    // Every byte that a transfer has been started with, whatever is plugged
+    // in. Test ROMs report their results this way.
+    output: Vec<u8>,
+}
+
+impl fmt::Debug for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Serial")
+            .field("data", &format_args!("0x{:02x}", self.data))
+            .field("transfer", &self.transfer)
+            .field("internal_clock", &self.internal_clock)
+            .field("ticks", &self.ticks)
+            .field("bits", &self.bits)
+            .field("linked", &self.linked)
+            .finish()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            transfer: false,
+            internal_clock: false,
+            ticks: 0,
+            bits: 0,
+            incoming: None,
+            sent: 0,
+            poll_ticks: 0,
+            device: Box::new(Disconnected),
+            linked: false,
+            outgoing: None,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice + Send>) {
+        self.device = device;
+    }
+
+    pub fn set_linked(&mut self, linked: bool) {
+        self.linked = linked;
+        self.outgoing = None;
+    }
+
+    // The byte sent by a transfer that has completed on this side, which is
+    // waiting for the peer's byte.
+    pub fn take_outgoing(&mut self) -> Option<u8> {
+        self.outgoing.take()
+    }
+
+    pub fn finish_transfer(&mut self, incoming: u8, ic: &mut interrupt::InterruptController) {
+        self.transfer = false;
+        self.data = incoming;
+        self.bits = 8;
+        ic.request(interrupt::INT_SERIAL);
+    }
+
+    // The peer has clocked a transfer, shifting in incoming and shifting out
+    // our byte.
+    pub fn clock_external(&mut self,
+                          incoming: u8,
+                          ic: &mut interrupt::InterruptController)
+                          -> u8 {
+        let out = self.data;
+        if self.transfer && !self.internal_clock {
+            self.finish_transfer(incoming, ic);
+        }
+        out
+    }
+
+    // The external clock side of a link has to wait for its peer to clock
+    // the transfer.
+    pub fn internal_clock(&self) -> bool {
+        self.internal_clock
+    }
+
+    // How many bits of the current (or last) transfer have been shifted, so
+    // that a link can tell how far through a byte each side is.
+    pub fn bits_shifted(&self) -> u32 {
+        self.bits
+    }
+
+    // Cycles until stepping next does more than count: a device is polled,
+    // a transfer completes or a device is asked for its byte.
+    pub fn next_event(&self) -> u32 {
+        let poll = POLL_CYCLES - self.poll_ticks;
+        if !self.transfer || !self.internal_clock || self.outgoing.is_some() {
+            return poll;
+        }
+        if self.incoming.is_none() && !self.linked {
+            return 0;
+        }
+        cmp::min(poll, self.ticks + (7 - self.bits) * BIT_CYCLES)
+    }
+
+    // The bytes sent so far, or the most recent OUTPUT_LIMIT of them.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn read_sb(&self) -> u8 {
+        self.data
+    }
+
+    pub fn write_sb(&mut self, val: u8) {
+        self.data = val;
+    }
+
+    pub fn read_sc(&self) -> u8 {
+        (self.transfer as u8) << 7 | 0x7E | self.internal_clock as u8
+    }
+
+    pub fn write_sc(&mut self, val: u8) {
+        self.transfer = (val & 0x80) != 0;
+        self.internal_clock = (val & 0x01) != 0;
+        if self.transfer {
+            self.ticks = BIT_CYCLES;
+            self.bits = 0;
+            self.incoming = None;
+            self.sent = self.data;
+            if self.output.len() == OUTPUT_LIMIT {
+                self.output.drain(..OUTPUT_LIMIT / 2);
+            }
+            self.output.push(self.data);
+        }
+    }
+
+    // The attached device and link configuration belong to the frontend, so
+    // they aren't part of the state.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.data);
+        w.write_bool(self.transfer);
+        w.write_bool(self.internal_clock);
+        w.write_u32(self.ticks);
+        w.write_u32(self.bits);
+        w.write_bool(self.incoming.is_some());
+        w.write_u8(self.incoming.unwrap_or(0xFF));
+        w.write_u8(self.sent);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.data = try!(r.read_u8());
+        self.transfer = try!(r.read_bool());
+        self.internal_clock = try!(r.read_bool());
+        self.ticks = try!(r.read_u32());
+        self.bits = try!(r.read_u32());
+        if self.bits > 8 {
+            return Err(format!("Invalid serial bit count: {}", self.bits));
+        }
+        let has_incoming = try!(r.read_bool());
+        let incoming = try!(r.read_u8());
+        self.incoming = if has_incoming { Some(incoming) } else { None };
+        self.sent = try!(r.read_u8());
+        self.outgoing = None;
+        Ok(())
+    }
+
+    pub fn step(&mut self, cycles: u32, ic: &mut interrupt::InterruptController) {
+        self.poll_ticks += cycles;
+        while self.poll_ticks >= POLL_CYCLES {
+            self.poll_ticks -= POLL_CYCLES;
+            if let Some(incoming) = self.device.poll(self.data) {
+                self.clock_external(incoming, ic);
+            }
+        }
+
+        // With the external clock nothing happens until the peer clocks
+        // the whole byte through clock_external.
+        if !self.transfer || !self.internal_clock || self.outgoing.is_some() {
+            return;
+        }
+
+        // A device answers with the whole byte, which is then shifted in a
+        // bit at a time. A linked peer only answers once every bit has been
+        // sent, and until then the line floats high.
+        if self.incoming.is_none() && !self.linked {
+            self.incoming = Some(self.device.exchange(self.data));
+        }
+
+        let mut cycles = cycles;
+        while cycles >= self.ticks {
+            cycles -= self.ticks;
+            self.ticks = BIT_CYCLES;
+            self.shift_bit();
+            if self.bits == 8 {
+                self.complete(ic);
+                return;
+            }
+        }
+        self.ticks -= cycles;
+    }
+
+    fn shift_bit(&mut self) {
+        let incoming = self.incoming.unwrap_or(0xFF);
+        let bit = incoming >> (7 - self.bits) & 0x01;
+        self.data = self.data << 1 | bit;
+        self.bits += 1;
+    }
+
+    // Every bit has been shifted out by our clock.
+    fn complete(&mut self, ic: &mut interrupt::InterruptController) {
+        match self.incoming {
+            Some(incoming) => self.finish_transfer(incoming, ic),
+            None => self.outgoing = Some(self.sent),
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interrupt::InterruptController;
+
+    use super::{Serial, SerialCallback};
+
+    fn serial_irq(ic: &mut InterruptController) -> bool {
+        let requested = ic.read_if() & 0x08 != 0;
+        ic.write_if(0);
+        requested
+    }
+
+    // With nothing plugged in the whole byte takes 4096 cycles, and 0xFF is
+    // shifted in.
+    #[test]
+    fn transfer_completes() {
+        let mut ic = InterruptController::new();
+        let mut serial = Serial::new();
+        serial.write_sb(0x5A);
+        serial.write_sc(0x81);
+
+        serial.step(4095, &mut ic);
+        assert!(!serial_irq(&mut ic));
+        assert_eq!(serial.read_sc() & 0x80, 0x80);
+
+        serial.step(1, &mut ic);
+        assert!(serial_irq(&mut ic));
+        assert_eq!(serial.read_sb(), 0xFF);
+        assert_eq!(serial.read_sc() & 0x80, 0);
+        assert_eq!(serial.output(), &[0x5A]);
+    }
+
+    // SB shifts left once every 512 cycles, taking in the device's byte a
+    // bit at a time from the top.
+    #[test]
+    fn bits_shift() {
+        let mut ic = InterruptController::new();
+        let mut serial = Serial::new();
+        serial.set_device(Box::new(SerialCallback(|_| 0x35)));
+        serial.write_sb(0xC6);
+        serial.write_sc(0x81);
+
+        let mut expected = 0xC6u8;
+        for bit in 0..8 {
+            serial.step(511, &mut ic);
+            assert_eq!(serial.read_sb(), expected);
+            assert_eq!(serial.bits_shifted(), bit);
+
+            serial.step(1, &mut ic);
+            expected = expected << 1 | 0x35 >> (7 - bit) & 0x01;
+            assert_eq!(serial.read_sb(), expected);
+            assert_eq!(serial.bits_shifted(), bit + 1);
+            assert_eq!(serial_irq(&mut ic), bit == 7);
+        }
+        assert_eq!(serial.read_sb(), 0x35);
+    }
+
+    // Without a peer to drive the clock the transfer never finishes, until
+    // one clocks the byte in.
+    #[test]
+    fn external_clock_stalls() {
+        let mut ic = InterruptController::new();
+        let mut serial = Serial::new();
+        serial.write_sb(0x42);
+        serial.write_sc(0x80);
+
+        serial.step(100_000, &mut ic);
+        assert!(!serial_irq(&mut ic));
+        assert_eq!(serial.read_sb(), 0x42);
+        assert_eq!(serial.read_sc() & 0x80, 0x80);
+
+        assert_eq!(serial.clock_external(0x99, &mut ic), 0x42);
+        assert!(serial_irq(&mut ic));
+        assert_eq!(serial.read_sb(), 0x99);
+        assert_eq!(serial.read_sc() & 0x80, 0);
+    }
+}
@@ -0,0 +1,313 @@
+// A link cable between two iogb instances over TCP. Each message is a tag
+// byte followed by a fixed size payload:
+//
+// BYTE  - the internally clocked side started a transfer, shifting out a byte
+// REPLY - the byte that the other side shifted out in return
+// SYNC  - how many cycles the sender has run, so neither side gets too far
+//         ahead of the other
+//
+// The side whose game selects the internal clock sends BYTE and waits for the
+// REPLY. If it doesn't arrive in time the transfer completes with 0xFF, just
+// as it would with nothing plugged in.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use serial::{SerialDevice, POLL_CYCLES};
+
+const MSG_BYTE: u8 = 1;
+const MSG_REPLY: u8 = 2;
+const MSG_SYNC: u8 = 3;
+
+// How long to wait for the peer before giving up on it.
+const TIMEOUT_MS: u64 = 500;
+// Cycle counts are exchanged about once a frame, and a side that gets more
+// than a few frames ahead waits for the other to catch up.
+const SYNC_CYCLES: u64 = 65_536;
+const MAX_DRIFT: u64 = 4 * SYNC_CYCLES;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Message {
+    Byte(u8),
+    Reply(u8),
+    Sync(u64),
+}
+
+pub struct TcpLink {
+    stream: Option<TcpStream>, // None once the peer has gone away
+    buf: Vec<u8>,
+    messages: VecDeque<Message>,
+    cycles: u64,
+    peer_cycles: u64,
+    next_sync: u64,
+}
+
+impl TcpLink {
+    // Wait for the other instance to connect.
+    pub fn listen(port: u16) -> Result<TcpLink, String> {
+        let listener = try!(TcpListener::bind(("0.0.0.0", port)).map_err(|e| format!("{}", e)));
+        let (stream, _) = try!(listener.accept().map_err(|e| format!("{}", e)));
+        TcpLink::new(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpLink, String> {
+        let stream = try!(TcpStream::connect(addr).map_err(|e| format!("{}", e)));
+        TcpLink::new(stream)
+    }
+
+    pub fn new(stream: TcpStream) -> Result<TcpLink, String> {
+        // Every message is tiny and someone is waiting on it.
+        try!(stream.set_nodelay(true).map_err(|e| format!("{}", e)));
+        Ok(TcpLink {
+            stream: Some(stream),
+            buf: Vec::new(),
+            messages: VecDeque::new(),
+            cycles: 0,
+            peer_cycles: 0,
+            next_sync: SYNC_CYCLES,
+        })
+    }
+
+    pub fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, msg: Message) {
+        let mut out = Vec::with_capacity(9);
+        match msg {
+            Message::Byte(b) => out.extend_from_slice(&[MSG_BYTE, b]),
+            Message::Reply(b) => out.extend_from_slice(&[MSG_REPLY, b]),
+            Message::Sync(cycles) => {
+                out.push(MSG_SYNC);
+                for i in 0..8 {
+                    out.push((cycles >> (i * 8)) as u8);
+                }
+            }
+        }
+        let result = match self.stream {
+            Some(ref mut s) => s.write_all(&out),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.disconnect(e);
+        }
+    }
+
+    // The next message from the peer, waiting until deadline for one to
+    // arrive or not waiting at all without one.
+    fn receive(&mut self, deadline: Option<Instant>) -> Option<Message> {
+        loop {
+            if let Some(msg) = self.messages.pop_front() {
+                return Some(msg);
+            }
+
+            // Once disconnected, read never finds anything.
+            let timeout = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return None;
+                    }
+                    Some(d - now)
+                }
+                None => None,
+            };
+            match self.read(timeout) {
+                Ok(true) => self.parse(),
+                Ok(false) => return None,
+                Err(e) => {
+                    self.disconnect(e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Read whatever has arrived, blocking for up to timeout if nothing has.
+    // Returns false if nothing was read.
+    fn read(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let stream = match self.stream {
+            Some(ref mut s) => s,
+            None => return Ok(false),
+        };
+        try!(stream.set_nonblocking(timeout.is_none()));
+        try!(stream.set_read_timeout(timeout));
+
+        let mut chunk = [0; 64];
+        match stream.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer disconnected")),
+            Ok(n) => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(&mut self) {
+        loop {
+            let (msg, len) = match self.buf.first() {
+                Some(&MSG_BYTE) if self.buf.len() >= 2 => (Message::Byte(self.buf[1]), 2),
+                Some(&MSG_REPLY) if self.buf.len() >= 2 => (Message::Reply(self.buf[1]), 2),
+                Some(&MSG_SYNC) if self.buf.len() >= 9 => {
+                    let cycles = self.buf[1..9]
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, &b| acc << 8 | b as u64);
+                    (Message::Sync(cycles), 9)
+                }
+                Some(&MSG_BYTE) | Some(&MSG_REPLY) | Some(&MSG_SYNC) | None => return,
+                Some(&tag) => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData,
+                                           format!("unknown message 0x{:02x}", tag));
+                    self.disconnect(e);
+                    return;
+                }
+            };
+            self.buf.drain(..len);
+            self.messages.push_back(msg);
+        }
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        if self.stream.take().is_some() {
+            warn!("Link cable disconnected: {}", e);
+        }
+        self.messages.clear();
+    }
+
+    fn deadline() -> Option<Instant> {
+        Some(Instant::now() + Duration::from_millis(TIMEOUT_MS))
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn exchange(&mut self, out: u8) -> u8 {
+        self.send(Message::Byte(out));
+        let deadline = TcpLink::deadline();
+        while let Some(msg) = self.receive(deadline) {
+            match msg {
+                Message::Reply(b) => return b,
+                Message::Sync(cycles) => self.peer_cycles = cycles,
+                // Both sides are using the internal clock, so neither hears
+                // the other.
+                Message::Byte(_) => self.send(Message::Reply(0xFF)),
+            }
+        }
+        0xFF
+    }
+
+    fn poll(&mut self, out: u8) -> Option<u8> {
+        self.cycles += POLL_CYCLES as u64;
+        if self.cycles >= self.next_sync {
+            self.next_sync += SYNC_CYCLES;
+            let cycles = self.cycles;
+            self.send(Message::Sync(cycles));
+        }
+
+        // Don't wait on the peer unless we're too far ahead of it.
+        let deadline = if self.cycles > self.peer_cycles + MAX_DRIFT {
+            TcpLink::deadline()
+        } else {
+            None
+        };
+        while let Some(msg) = self.receive(deadline) {
+            match msg {
+                Message::Byte(b) => {
+                    self.send(Message::Reply(out));
+                    return Some(b);
+                }
+                Message::Sync(cycles) => {
+                    self.peer_cycles = cycles;
+                    if deadline.is_some() && self.cycles <= self.peer_cycles + MAX_DRIFT {
+                        break;
+                    }
+                }
+                // A reply that arrived after its transfer timed out.
+                Message::Reply(_) => {}
+            }
+        }
+        // The peer didn't catch up in time, so carry on without it rather than
+        // waiting again on every poll.
+        if deadline.is_some() {
+            self.peer_cycles = self.cycles;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use gameboy::GameBoy;
+    use super::TcpLink;
+
+    const CYCLES: u32 = 1_000_000;
+
+    fn cartridge(program: &[u8]) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Cartridge::from_bytes(rom).unwrap()
+    }
+
+    // Drives each transfer, sending 0x10 and then one more than whatever it
+    // received.
+    fn counter() -> Cartridge {
+        cartridge(&[0x3E, 0x08, 0xE0, 0xFF, // IE = Serial
+                    0x3E, 0x10, // LD A,0x10
+                    0xE0, 0x01, // SB = A
+                    0x3E, 0x81, 0xE0, 0x02, // SC = start, internal clock
+                    0x76, 0x00, // HALT; NOP
+                    0xAF, 0xE0, 0x0F, // IF = 0
+                    0xF0, 0x01, // LD A,SB
+                    0x3C, // INC A
+                    0x18, 0xF0]) // JR to SB = A
+    }
+
+    // Waits on the external clock, sending back whatever it received last.
+    fn echo() -> Cartridge {
+        cartridge(&[0x3E, 0x08, 0xE0, 0xFF, // IE = Serial
+                    0x3E, 0x80, 0xE0, 0x02, // SC = start, external clock
+                    0x76, 0x00, // HALT; NOP
+                    0xAF, 0xE0, 0x0F, // IF = 0
+                    0x18, 0xF5]) // JR to SC = start
+    }
+
+    fn run(cart: Cartridge, stream: TcpStream) -> Vec<u8> {
+        let mut gb = GameBoy::new(cart, Bootrom::empty());
+        gb.set_serial_device(Box::new(TcpLink::new(stream).unwrap()));
+        gb.run(CYCLES);
+        gb.serial_bytes().to_vec()
+    }
+
+    // Two GameBoys, each on its own thread, linked through a socket on
+    // localhost.
+    #[test]
+    fn echo_over_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echoer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            run(echo(), stream)
+        });
+        let counted = run(counter(), TcpStream::connect(addr).unwrap());
+        let echoed = echoer.join().unwrap();
+
+        // Only the first transfers are checked, as whichever side finishes
+        // first hangs up on the other.
+        assert!(counted.len() > 100, "{:?}", counted);
+        assert_eq!(&counted[..6], &[0x10, 0x01, 0x11, 0x02, 0x12, 0x03]);
+        assert_eq!(&echoed[..6], &[0x00, 0x10, 0x01, 0x11, 0x02, 0x12]);
+        for i in 0..100 {
+            assert_eq!(echoed[i + 1], counted[i]);
+            assert_eq!(counted[i + 1], echoed[i].wrapping_add(1));
+        }
+    }
+}
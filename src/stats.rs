@@ -0,0 +1,129 @@
+// Measures how well the frontend is keeping up: the number of frames
+// presented per second, and how fast emulation is running compared to a real
+// GameBoy. Timestamps are passed in rather than read from a clock, so any
+// monotonic source will do.
+use std::time::Duration;
+
+use iogb::gameboy::CPU_HZ;
+
+// How often, in seconds, the measurements are updated.
+const WINDOW_SECS: u64 = 1;
+
+pub struct FrameStats {
+    window_start: Duration,
+    window_cycles: u64,
+    frames: u32,
+    fps: f64,
+    speed: f64,
+}
+
+impl FrameStats {
+    pub fn new(now: Duration, cycles: u64) -> FrameStats {
+        FrameStats {
+            window_start: now,
+            window_cycles: cycles,
+            frames: 0,
+            fps: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    // Start a new measurement, discarding the current one. Used after a
+    // pause or a jump in the cycle count, e.g. loading a save state.
+    pub fn reset(&mut self, now: Duration, cycles: u64) {
+        self.window_start = now;
+        self.window_cycles = cycles;
+        self.frames = 0;
+    }
+
+    // Record that a frame has been presented at time now, with the GameBoy's
+    // cycle count at that point. Returns true about once a second, when fps()
+    // and speed() have been updated.
+    pub fn frame(&mut self, now: Duration, cycles: u64) -> bool {
+        self.frames += 1;
+
+        let elapsed = now - self.window_start;
+        if elapsed.as_secs() < WINDOW_SECS {
+            return false;
+        }
+
+        let secs = as_secs_f64(elapsed);
+        self.fps = self.frames as f64 / secs;
+        self.speed = cycles.saturating_sub(self.window_cycles) as f64 / CPU_HZ as f64 / secs;
+        self.reset(now, cycles);
+        true
+    }
+
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    // Emulated seconds per wall second, so 1.0 is full speed.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+    use super::FrameStats;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // 50 frames each 20ms apart, which is a little slower than real time.
+    #[test]
+    fn rates() {
+        let mut stats = FrameStats::new(millis(500), 1000);
+        let mut cycles = 1000;
+        for i in 1..50 {
+            cycles += CYCLES_PER_FRAME as u64;
+            assert!(!stats.frame(millis(500 + i * 20), cycles), "frame {}", i);
+        }
+        cycles += CYCLES_PER_FRAME as u64;
+        assert!(stats.frame(millis(1500), cycles));
+
+        assert!((stats.fps() - 50.0).abs() < 1e-9, "{}", stats.fps());
+        let expected = 50.0 * CYCLES_PER_FRAME as f64 / CPU_HZ as f64;
+        assert!((stats.speed() - expected).abs() < 1e-9, "{}", stats.speed());
+    }
+
+    // Each window stands alone, and the results last until the next one.
+    #[test]
+    fn windows() {
+        let mut stats = FrameStats::new(millis(0), 0);
+        assert!(stats.frame(millis(2000), CPU_HZ as u64));
+        assert!((stats.fps() - 0.5).abs() < 1e-9);
+        assert!((stats.speed() - 0.5).abs() < 1e-9);
+
+        // Fast-forwarding at four times normal speed.
+        for i in 1..240 {
+            assert!(!stats.frame(millis(2000 + i * 1000 / 240), CPU_HZ as u64 * (1 + i / 60)));
+            assert!((stats.fps() - 0.5).abs() < 1e-9);
+        }
+        assert!(stats.frame(millis(3000), CPU_HZ as u64 * 5));
+        assert!((stats.fps() - 240.0).abs() < 1e-9);
+        assert!((stats.speed() - 4.0).abs() < 1e-9);
+    }
+
+    // Time spent paused isn't counted, nor are cycles from a loaded state.
+    #[test]
+    fn reset() {
+        let mut stats = FrameStats::new(millis(0), 0);
+        stats.frame(millis(100), 1000);
+        stats.reset(millis(60_000), 1_000_000_000);
+        assert!(!stats.frame(millis(60_500), 1_000_000_000 + CPU_HZ as u64 / 2));
+        assert!(stats.frame(millis(61_000), 1_000_000_000 + CPU_HZ as u64));
+        assert!((stats.fps() - 2.0).abs() < 1e-9);
+        assert!((stats.speed() - 1.0).abs() < 1e-9);
+    }
+}
@@ -1,7 +1,10 @@
+use std::cmp;
 use std::fmt;
+use gameboy::CPU_HZ;
 use interrupt;
+use savestate::{StateWriter, StateReader};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum InputClockFreq {
     Freq4096 = 4096,
     Freq262144 = 262_144,
@@ -10,35 +13,66 @@ enum InputClockFreq {
 }
 
 impl InputClockFreq {
-    fn to_cycles(&self) -> u32 {
+    // From TAC's clock select, bits 0-1.
+    fn from_bits(bits: u8) -> InputClockFreq {
+        use self::InputClockFreq::*;
+        match bits & 0x03 {
+            0 => Freq4096,
+            1 => Freq262144,
+            2 => Freq65536,
+            _ => Freq16384,
+        }
+    }
+
+    fn bits(&self) -> u8 {
         use self::InputClockFreq::*;
         match *self {
-            Freq4096 => 256,
-            Freq262144 => 4,
-            Freq65536 => 16,
-            Freq16384 => 64,
+            Freq4096 => 0,
+            Freq262144 => 1,
+            Freq65536 => 2,
+            Freq16384 => 3,
         }
     }
+
+    // CPU cycles per TIMA increment. TIMA ticks when the bit of the
+    // internal counter that toggles at twice this rate falls.
+    fn to_cycles(&self) -> u32 {
+        CPU_HZ / *self as u32
+    }
 }
 
+// The APU's frame sequencer is clocked by DIV bit 4 falling, which is bit 12
+// of the internal counter.
+const DIV_EDGE_CYCLES: u32 = 1 << 13;
+// TIMA reads as 0 for this many cycles after overflowing, before TMA is
+// loaded into it and the interrupt is requested.
+const RELOAD_DELAY: u32 = 4;
+
 pub struct Timer {
+    // A free running counter, incremented every cycle. DIV is its upper
+    // byte.
+    div_counter: u16,
     counter: u8,
     modulo: u8,
-    div: u8,
-    idiv: u32,
-    icounter: u32,
     enabled: bool,
     input_freq: InputClockFreq,
+    // Cycles until an overflowed TIMA is reloaded, or 0 if it isn't
+    // overflowing.
+    reload_in: u32,
+    // Falling edges of DIV bit 4 that the APU hasn't been told about yet.
+    div_edges: u32,
 }
 
 impl fmt::Debug for Timer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Timer")
             .field("enabled", &self.enabled)
+            .field("div_counter", &format_args!("0x{:04x}", self.div_counter))
             .field("counter", &format_args!("0x{:02x}", self.counter))
             .field("modulo", &format_args!("0x{:02x}", self.modulo))
-            .field("counter", &format_args!("0x{:02x}", self.counter))
             .field("input_freq", &self.input_freq)
+            .field("reload_in", &self.reload_in)
+            .field("div_edges", &self.div_edges)
             .finish()
     }
 }
@@ -46,90 +80,444 @@ impl fmt::Debug for Timer {
 impl Timer {
     pub fn new() -> Timer {
         Timer {
+            div_counter: 0,
             counter: 0,
             modulo: 0,
-            div: 0,
-            idiv: 0,
-            icounter: 0,
             enabled: false,
             input_freq: InputClockFreq::Freq4096,
+            reload_in: 0,
+            div_edges: 0,
         }
     }
 
     pub fn get_div(&self) -> u8 {
-        self.div
+        (self.div_counter >> 8) as u8
+    }
+
+    // Any write resets the whole counter, which is a falling edge for every
+    // bit that was set. That includes the one TIMA is clocked from.
+    pub fn set_div(&mut self, _val: u8) {
+        if self.div_counter as u32 & (DIV_EDGE_CYCLES / 2) != 0 {
+            self.div_edges += 1;
+        }
+        if self.tima_clock() {
+            self.increment();
+        }
+        self.div_counter = 0;
     }
 
-    pub fn set_div(&mut self, val: u8) {
-        self.div = 0;
+    // The APU's frame sequencer is clocked by DIV bit 4 going low (bit 5 in
+    // CGB double speed mode).
+    pub fn take_div_edges(&mut self) -> u32 {
+        let edges = self.div_edges;
+        self.div_edges = 0;
+        edges
     }
 
     pub fn get_tima(&self) -> u8 {
         self.counter
     }
 
+    // Writing TIMA while it's waiting to be reloaded cancels the reload, and
+    // with it the interrupt.
     pub fn set_tima(&mut self, val: u8) {
         self.counter = val;
+        self.reload_in = 0;
     }
 
     pub fn get_tma(&self) -> u8 {
         self.modulo
     }
 
+    // A pending reload loads whatever TMA is when it happens.
     pub fn set_tma(&mut self, val: u8) {
         self.modulo = val;
     }
 
+    // Bit 2 enables the timer and bits 0-1 select the clock. The rest don't
+    // exist, and read as 1.
     pub fn get_tac(&self) -> u8 {
-        use self::InputClockFreq::*;
-        let ics = match self.input_freq {
-            Freq4096 => 0x00,
-            Freq262144 => 0x01,
-            Freq65536 => 0x10,
-            Freq16384 => 0x11,
-        };
-        (self.enabled as u8) << 2 | ics
+        0xF8 | (self.enabled as u8) << 2 | self.input_freq.bits()
     }
 
+    // Disabling the timer or selecting another bit can take TIMA's clock
+    // from high to low, which ticks it like any other falling edge.
     pub fn set_tac(&mut self, val: u8) {
-        use self::InputClockFreq::*;
-        self.input_freq = match val >> 2 {
-            0x00 => Freq4096,
-            0x01 => Freq262144,
-            0x10 => Freq65536,
-            0x11 => Freq16384,
-            _ => panic!(),
-        };
-        self.enabled = (val & 0x01) != 0;
+        let was_high = self.tima_clock();
+        self.input_freq = InputClockFreq::from_bits(val);
+        self.enabled = (val & 0x04) != 0;
+        if was_high && !self.tima_clock() {
+            self.increment();
+        }
     }
 
     pub fn step(&mut self, cycles: u32, ic: &mut interrupt::InterruptController) {
-        self.step_div(cycles);
+        // Every period divides the counter's range, so the falling edges of
+        // a bit can be counted across it wrapping.
+        let before = self.div_counter as u32;
+        self.div_edges += falling_edges(before, before + cycles, DIV_EDGE_CYCLES);
+
+        // The counter is moved on to each TIMA tick and reload in turn, so
+        // that an overflow part way through is reloaded at the right time.
+        let period = self.input_freq.to_cycles();
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let mut chunk = cycles;
+            if self.enabled {
+                chunk = cmp::min(chunk, until_falling_edge(self.div_counter, period));
+            }
+            if self.reload_in > 0 {
+                chunk = cmp::min(chunk, self.reload_in);
+            }
+
+            let before = self.div_counter as u32;
+            self.div_counter = (before + chunk) as u16;
+            cycles -= chunk;
+
+            if self.reload_in > 0 {
+                self.reload_in -= chunk;
+                if self.reload_in == 0 {
+                    self.counter = self.modulo;
+                    ic.request(interrupt::INT_TIMER);
+                }
+            }
+            if self.enabled && falling_edges(before, before + chunk, period) > 0 {
+                self.increment();
+            }
+        }
+    }
+
+    // Cycles until stepping the timer next does more than count, which is
+    // when TIMA ticks or is reloaded or, with div_edges, DIV bit 4 falls.
+    pub fn next_event(&self, div_edges: bool) -> u32 {
+        let mut next = u32::MAX;
+        if self.enabled {
+            next = until_falling_edge(self.div_counter, self.input_freq.to_cycles());
+        }
+        if self.reload_in > 0 {
+            next = cmp::min(next, self.reload_in);
+        }
+        if div_edges {
+            next = cmp::min(next, until_falling_edge(self.div_counter, DIV_EDGE_CYCLES));
+        }
+        next
+    }
+
+    // TIMA ticks whenever this goes from high to low: the counter bit that
+    // TAC selects, gated by the enable bit.
+    fn tima_clock(&self) -> bool {
+        self.enabled && self.div_counter as u32 & (self.input_freq.to_cycles() / 2) != 0
+    }
+
+    // TMA isn't loaded straight away when TIMA overflows, see step.
+    fn increment(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == 0 {
+            self.reload_in = RELOAD_DELAY;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.div_counter);
+        w.write_u8(self.counter);
+        w.write_u8(self.modulo);
+        w.write_bool(self.enabled);
+        w.write_u8(self.input_freq.bits());
+        w.write_u32(self.reload_in);
+        w.write_u32(self.div_edges);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.div_counter = try!(r.read_u16());
+        self.counter = try!(r.read_u8());
+        self.modulo = try!(r.read_u8());
+        self.enabled = try!(r.read_bool());
+        self.input_freq = match try!(r.read_u8()) {
+            bits @ 0...3 => InputClockFreq::from_bits(bits),
+            inv => return Err(format!("Invalid timer frequency in save state: {}", inv)),
+        };
+        self.reload_in = try!(r.read_u32());
+        self.div_edges = try!(r.read_u32());
+        Ok(())
+    }
+}
+
+// How many times the bit that toggles every period / 2 cycles falls as the
+// counter goes from before to after.
+fn falling_edges(before: u32, after: u32, period: u32) -> u32 {
+    after / period - before / period
+}
+
+fn until_falling_edge(counter: u16, period: u32) -> u32 {
+    period - (counter as u32 & (period - 1))
+}
 
-        if !self.enabled {
-            return;
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use gameboy::CPU_HZ;
+    use interrupt::InterruptController;
+    use savestate::{StateWriter, StateReader};
+
+    use super::Timer;
+
+    // Cycle counts of a typical run of instructions.
+    const INSTRUCTIONS: [u32; 8] = [4, 8, 12, 4, 16, 20, 24, 8];
+
+    // TIMA's rate for each TAC clock select, with the timer enabled.
+    const RATES: [(u8, u32); 4] = [(0x04, 4096), (0x05, 262_144), (0x06, 65_536), (0x07, 16_384)];
+
+    fn timer(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.set_tac(tac);
+        timer
+    }
+
+    // Step the timer through a second of instructions and count how many
+    // times TIMA ticks. With TMA at 0, reloading doesn't change the count.
+    fn ticks_per_second(tac: u8) -> u32 {
+        let mut timer = timer(tac);
+        let mut ic = InterruptController::new();
+        let mut ticks = 0;
+        let mut cycles = 0;
+        for &step in INSTRUCTIONS.iter().cycle() {
+            let step = cmp::min(step, CPU_HZ - cycles);
+            let before = timer.get_tima();
+            timer.step(step, &mut ic);
+            ticks += timer.get_tima().wrapping_sub(before) as u32;
+            cycles += step;
+            if cycles == CPU_HZ {
+                break;
+            }
+        }
+        ticks
+    }
+
+    #[test]
+    fn rates() {
+        for &(tac, rate) in RATES.iter() {
+            assert_eq!(ticks_per_second(tac), rate, "TAC 0x{:02x}", tac);
         }
+    }
 
-        // TODO: Merge icounter and idiv into a single counter that div
-        // can be derived from.
-        self.icounter += cycles;
-        if self.icounter >= self.input_freq.to_cycles() {
-            if self.counter != 0xFF {
-                self.counter = self.counter.wrapping_add(1);
-            } else {
-                self.counter = self.modulo;
-                ic.request_interrupt(interrupt::Interrupt::Timer);
+    // Stepping many cycles at once lands in the same place as stepping them
+    // an instruction at a time.
+    #[test]
+    fn batched() {
+        for &(tac, rate) in RATES.iter() {
+            let mut ic = InterruptController::new();
+            let mut batched = timer(tac);
+            batched.step(1_000_000, &mut ic);
+            let expected = 1_000_000 / (CPU_HZ / rate);
+            assert_eq!(batched.get_tima(), expected as u8, "TAC 0x{:02x}", tac);
+
+            let mut stepped = timer(tac);
+            for _ in 0..1_000_000 / 4 {
+                stepped.step(4, &mut ic);
             }
+            assert_eq!(stepped.get_tima(), batched.get_tima(), "TAC 0x{:02x}", tac);
+            assert_eq!(stepped.get_div(), batched.get_div(), "TAC 0x{:02x}", tac);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x01);
+        timer.step(1_000_000, &mut ic);
+        assert_eq!(timer.get_tima(), 0);
+        assert_eq!(ic.read_if(), 0xE0);
+    }
+
+    // The upper bits don't exist, whatever was written to them.
+    #[test]
+    fn tac_read_back() {
+        for tac in 0..8 {
+            let mut timer = Timer::new();
+            timer.set_tac(tac);
+            assert_eq!(timer.get_tac(), 0xF8 | tac);
+            timer.set_tac(0xF8 | tac);
+            assert_eq!(timer.get_tac(), 0xF8 | tac);
+            assert_eq!(timer.get_tac() & 0xF8, 0xF8);
+        }
+    }
+
+    // Writing DIV while the selected bit is high is a falling edge.
+    #[test]
+    fn div_write_ticks() {
+        for &(tac, rate) in RATES.iter() {
+            let mut ic = InterruptController::new();
+            let mut timer = timer(tac);
+            timer.step(CPU_HZ / rate / 2, &mut ic);
+            assert_eq!(timer.get_tima(), 0, "TAC 0x{:02x}", tac);
+            timer.set_div(0x12);
+            assert_eq!(timer.get_tima(), 1, "TAC 0x{:02x}", tac);
+            assert_eq!(timer.get_div(), 0);
+        }
+    }
+
+    // While it's low there's no edge, only the tick from it falling earlier.
+    #[test]
+    fn div_write_while_low() {
+        for &(tac, rate) in RATES.iter() {
+            let mut ic = InterruptController::new();
+            let mut timer = timer(tac);
+            timer.step(CPU_HZ / rate, &mut ic);
+            assert_eq!(timer.get_tima(), 1, "TAC 0x{:02x}", tac);
+            timer.set_div(0);
+            assert_eq!(timer.get_tima(), 1, "TAC 0x{:02x}", tac);
         }
+
+        // Nor is there with the timer disabled.
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x01);
+        timer.step(8, &mut ic);
+        timer.set_div(0);
+        assert_eq!(timer.get_tima(), 0);
+    }
+
+    // The APU's frame sequencer is clocked by DIV bit 4 falling, which a
+    // DIV write can do too.
+    #[test]
+    fn div_write_clocks_apu() {
+        let mut ic = InterruptController::new();
+        let mut timer = Timer::new();
+        timer.step(0x1000, &mut ic);
+        assert_eq!(timer.get_div(), 0x10);
+        assert_eq!(timer.take_div_edges(), 0);
+        timer.set_div(0);
+        assert_eq!(timer.take_div_edges(), 1);
+
+        timer.step(0x2000, &mut ic);
+        assert_eq!(timer.take_div_edges(), 1);
+        timer.set_div(0);
+        assert_eq!(timer.take_div_edges(), 0);
+    }
+
+    // A timer at 262144Hz that has just overflowed, with TMA set to 0x42.
+    fn overflowed(ic: &mut InterruptController) -> Timer {
+        let mut timer = timer(0x05);
+        timer.set_tima(0xFF);
+        timer.set_tma(0x42);
+        timer.step(16, ic);
+        timer
     }
 
-    fn step_div(&mut self, cycles: u32) {
-        self.idiv += cycles;
-        // Every time the CPU clock does 256 cycles, increment the div reg.
-        // 4Mhz / 256hz = 16Khz
-        if self.idiv >= 0xFF {
-            self.div = self.div.wrapping_add(1);
-            self.idiv -= 0xFF;
+    fn timer_irq(ic: &InterruptController) -> bool {
+        ic.read_if() & 0x04 != 0
+    }
+
+    #[test]
+    fn reload_delay() {
+        let mut ic = InterruptController::new();
+        let mut timer = overflowed(&mut ic);
+        for _ in 0..3 {
+            assert_eq!(timer.get_tima(), 0);
+            assert!(!timer_irq(&ic));
+            timer.step(1, &mut ic);
         }
+        assert_eq!(timer.get_tima(), 0);
+        timer.step(1, &mut ic);
+        assert_eq!(timer.get_tima(), 0x42);
+        assert!(timer_irq(&ic));
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut ic = InterruptController::new();
+        let mut timer = overflowed(&mut ic);
+        timer.step(2, &mut ic);
+        timer.set_tima(0x10);
+        timer.step(8, &mut ic);
+        assert_eq!(timer.get_tima(), 0x10);
+        assert!(!timer_irq(&ic));
+    }
+
+    #[test]
+    fn tma_write_is_reloaded() {
+        let mut ic = InterruptController::new();
+        let mut timer = overflowed(&mut ic);
+        timer.step(2, &mut ic);
+        timer.set_tma(0x77);
+        timer.step(2, &mut ic);
+        assert_eq!(timer.get_tima(), 0x77);
+        assert!(timer_irq(&ic));
+    }
+
+    // The counter bits selected by TAC 0x05, 0x06 and 0x04 are 3, 5 and 9.
+    #[test]
+    fn tac_disable_while_high() {
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x05);
+        timer.step(0x08, &mut ic);
+        timer.set_tac(0x01);
+        assert_eq!(timer.get_tima(), 1);
+    }
+
+    #[test]
+    fn tac_switch_to_low_bit() {
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x05);
+        timer.step(0x08, &mut ic);
+        timer.set_tac(0x04);
+        assert_eq!(timer.get_tima(), 1);
+    }
+
+    #[test]
+    fn tac_switch_to_high_bit() {
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x05);
+        timer.step(0x28, &mut ic);
+        assert_eq!(timer.get_tima(), 2);
+        timer.set_tac(0x06);
+        assert_eq!(timer.get_tima(), 2);
+        // Disabling ticks it, but enabling again while the bit is high is a
+        // rising edge, which doesn't.
+        timer.set_tac(0x02);
+        assert_eq!(timer.get_tima(), 3);
+        timer.set_tac(0x06);
+        assert_eq!(timer.get_tima(), 3);
+    }
+
+    // DIV is the top of the counter, and carries on from 0 when it wraps.
+    #[test]
+    fn div() {
+        let mut ic = InterruptController::new();
+        let mut timer = Timer::new();
+        timer.step(0xFF, &mut ic);
+        assert_eq!(timer.get_div(), 0x00);
+        timer.step(0x01, &mut ic);
+        assert_eq!(timer.get_div(), 0x01);
+        timer.step(0xFEF0, &mut ic);
+        assert_eq!(timer.get_div(), 0xFF);
+        timer.step(0x20, &mut ic);
+        assert_eq!(timer.get_div(), 0x00);
+        timer.step(0x1000, &mut ic);
+        assert_eq!(timer.get_div(), 0x10);
+    }
+
+    // An edge from writing DIV that the APU hasn't been given yet survives
+    // a save state.
+    #[test]
+    fn save_state() {
+        let mut ic = InterruptController::new();
+        let mut timer = timer(0x05);
+        timer.step(0x1008, &mut ic);
+        timer.set_tima(0xF0);
+        timer.set_tma(0x42);
+        timer.set_div(0);
+
+        let mut w = StateWriter::new(0);
+        timer.save_state(&mut w);
+        let state = w.into_bytes();
+        let mut loaded = Timer::new();
+        loaded.load_state(&mut StateReader::new(&state, 0).unwrap()).unwrap();
+
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", timer));
+        assert_eq!(loaded.take_div_edges(), 1);
+        assert_eq!(loaded.get_tima(), 0xF1);
+        assert_eq!(loaded.get_tma(), 0x42);
+        assert_eq!(loaded.get_tac(), 0xFD);
     }
 }
@@ -0,0 +1,87 @@
+// The window title doubles as a status bar, showing the frame rate and
+// emulation speed, the current save state slot and short-lived messages.
+use std::time::{Duration, Instant};
+
+use minifb::Window;
+
+// How long a message stays in the title bar.
+const MESSAGE_SECS: u64 = 3;
+
+pub struct TitleBar {
+    rom_title: String,
+    slot: u8,
+    // Frames per second and speed.
+    stats: Option<(f64, f64)>,
+    paused_frame: Option<u64>,
+    message: Option<(String, Instant)>,
+    dirty: bool,
+}
+
+impl TitleBar {
+    pub fn new(rom_title: &str) -> TitleBar {
+        TitleBar {
+            rom_title: rom_title.to_string(),
+            slot: 0,
+            stats: None,
+            paused_frame: None,
+            message: None,
+            dirty: true,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = format!("iogb — {}", self.rom_title);
+        match self.paused_frame {
+            Some(frame) => text.push_str(&format!(" — paused, frame {}", frame)),
+            None => {
+                if let Some((fps, speed)) = self.stats {
+                    text.push_str(&format!(" — {:.0} fps ({:.0}%)", fps, speed * 100.0));
+                }
+            }
+        }
+        text.push_str(&format!(" — slot {}", self.slot));
+        if let Some((ref message, _)) = self.message {
+            text.push_str(&format!(" — {}", message));
+        }
+        text
+    }
+
+    pub fn set_slot(&mut self, slot: u8) {
+        self.slot = slot;
+        self.dirty = true;
+    }
+
+    pub fn set_stats(&mut self, fps: f64, speed: f64) {
+        self.stats = Some((fps, speed));
+        self.dirty = true;
+    }
+
+    // The frame number to show while paused, or None when running.
+    pub fn set_paused(&mut self, frame: Option<u64>) {
+        self.paused_frame = frame;
+        self.stats = None;
+        self.dirty = true;
+    }
+
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+        self.dirty = true;
+    }
+
+    // Push any changes out to the window.
+    pub fn update(&mut self, window: &mut Window) {
+        let expired = match self.message {
+            Some((_, shown)) => shown.elapsed() >= Duration::from_secs(MESSAGE_SECS),
+            None => false,
+        };
+        if expired {
+            self.message = None;
+            self.dirty = true;
+        }
+
+        if self.dirty {
+            window.set_title(&self.text());
+            self.dirty = false;
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Instruction tracing: one line per executed instruction, written before the
+// instruction runs.
+use std::collections::{vec_deque, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+
+use cpu::disasm;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // PC, opcode, registers and flags, for reading.
+    Plain,
+    // The format expected by Gameboy Doctor, for diffing against other
+    // emulators.
+    Doctor,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "plain" => Some(TraceFormat::Plain),
+            "doctor" => Some(TraceFormat::Doctor),
+            _ => None,
+        }
+    }
+}
+
+// Gameboy Doctor's logs come from emulators whose LY always reads as this,
+// so GameBoy::set_ly_stub needs it too for a doctor trace to match.
+pub const DOCTOR_LY: u8 = 0x90;
+
+// The registers and surrounding memory at the start of an instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub cycles: u64,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    // The four bytes from PC onwards.
+    pub mem: [u8; 4],
+}
+
+// The plain format, ending with the instruction's mnemonic.
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:>12} PC:0x{:04x} OP:0x{:02x} A:0x{:02x} F:{}{}{}{} B:0x{:02x} C:0x{:02x} \
+                D:0x{:02x} E:0x{:02x} H:0x{:02x} L:0x{:02x} SP:0x{:04x} {}",
+               self.cycles,
+               self.pc,
+               self.mem[0],
+               self.a,
+               if self.f & 0x80 != 0 { 'Z' } else { '-' },
+               if self.f & 0x40 != 0 { 'N' } else { '-' },
+               if self.f & 0x20 != 0 { 'H' } else { '-' },
+               if self.f & 0x10 != 0 { 'C' } else { '-' },
+               self.b,
+               self.c,
+               self.d,
+               self.e,
+               self.h,
+               self.l,
+               self.sp,
+               self.instruction())
+    }
+}
+
+impl TraceEntry {
+    // No instruction is longer than the bytes that are kept from PC on.
+    pub fn instruction(&self) -> String {
+        let pc = self.pc;
+        disasm::disassemble(|addr| self.mem[addr.wrapping_sub(pc) as usize & 0x03], pc).0
+    }
+}
+
+// How many instructions History keeps.
+pub const HISTORY_LEN: usize = 64;
+
+// The most recently executed instructions, oldest first, for crash reports.
+pub struct History {
+    entries: VecDeque<TraceEntry>,
+}
+
+impl fmt::Debug for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("History")
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History { entries: VecDeque::with_capacity(HISTORY_LEN) }
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() == HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> vec_deque::Iter<'a, TraceEntry> {
+        self.entries.iter()
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+pub struct Tracer {
+    // Should be buffered, as it's written to for every instruction.
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    // Nothing is written until this many cycles have been run.
+    start_cycle: u64,
+    failed: bool,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("start_cycle", &self.start_cycle)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat, start_cycle: u64) -> Tracer {
+        Tracer {
+            out: out,
+            format: format,
+            start_cycle: start_cycle,
+            failed: false,
+        }
+    }
+
+    pub fn start_cycle(&self) -> u64 {
+        self.start_cycle
+    }
+
+    pub fn trace(&mut self, entry: &TraceEntry) {
+        if self.failed || entry.cycles < self.start_cycle {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Plain => writeln!(self.out, "{}", entry),
+            TraceFormat::Doctor => {
+                writeln!(self.out,
+                         "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} \
+                          L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                         entry.a,
+                         entry.f,
+                         entry.b,
+                         entry.c,
+                         entry.d,
+                         entry.e,
+                         entry.h,
+                         entry.l,
+                         entry.sp,
+                         entry.pc,
+                         entry.mem[0],
+                         entry.mem[1],
+                         entry.mem[2],
+                         entry.mem[3])
+            }
+        };
+
+        // Don't let a full disk take down emulation, just stop tracing.
+        if let Err(e) = result {
+            error!("Failed to write trace, tracing stopped: {}", e);
+            self.failed = true;
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use gameboy::GameBoy;
+    use super::{TraceEntry, TraceFormat, Tracer};
+
+    // Somewhere to trace to that can still be read once the tracer has been
+    // handed over.
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry(cycles: u64) -> TraceEntry {
+        TraceEntry {
+            cycles: cycles,
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc: 0x0100,
+            mem: [0x00, 0xC3, 0x50, 0x01],
+        }
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(TraceFormat::from_name("plain"), Some(TraceFormat::Plain));
+        assert_eq!(TraceFormat::from_name("doctor"), Some(TraceFormat::Doctor));
+        assert_eq!(TraceFormat::from_name("Doctor"), None);
+        assert_eq!(TraceFormat::from_name(""), None);
+    }
+
+    #[test]
+    fn formats() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut tracer = Tracer::new(Box::new(Shared(buf.clone())), TraceFormat::Doctor, 0);
+        tracer.trace(&entry(0));
+        let mut tracer = Tracer::new(Box::new(Shared(buf.clone())), TraceFormat::Plain, 0);
+        tracer.trace(&entry(1234));
+
+        let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines,
+                   ["A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 \
+                     PCMEM:00,C3,50,01",
+                    "        1234 PC:0x0100 OP:0x00 A:0x01 F:Z-HC B:0x00 C:0x13 D:0x00 \
+                     E:0xd8 H:0x01 L:0x4d SP:0xfffe NOP"]);
+    }
+
+    // Nothing is written before the start cycle, and the first line is the
+    // first instruction that starts at or after it.
+    #[test]
+    fn delayed_start() {
+        // INC A then JR back to it, taking 4 and 12 cycles.
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3C;
+        rom[0x101] = 0x18;
+        rom[0x102] = 0xFD;
+        let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let start = 1000;
+        gb.set_tracer(Some(Tracer::new(Box::new(Shared(buf.clone())), TraceFormat::Plain, start)));
+
+        let mut first = None;
+        while gb.elapsed_cycles() < start + 100 {
+            let cycles = gb.elapsed_cycles();
+            if first.is_none() && cycles >= start {
+                first = Some(cycles);
+            }
+            gb.step_instruction();
+        }
+        let first = first.unwrap();
+        assert!(first < start + 12);
+
+        let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        let cycles: Vec<u64> = text.lines()
+            .map(|line| line.split_whitespace().next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(cycles[0], first);
+        assert!(cycles.windows(2).all(|w| w[1] - w[0] == 4 || w[1] - w[0] == 12));
+    }
+}
@@ -0,0 +1,178 @@
+// A second window showing what's in VRAM: every tile on the left and a
+// background map on the right, with the part of the map that the screen
+// shows outlined. M switches between the two maps, and B between showing
+// raw colour numbers and shades mapped through BGP.
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use iogb::gameboy::{GameBoy, SCREEN_W, SCREEN_H, TILESET_W, TILESET_H, BG_MAP_W};
+
+// A column between the tileset and the map.
+const GAP: usize = 4;
+pub const WIDTH: usize = TILESET_W + GAP + BG_MAP_W;
+pub const HEIGHT: usize = BG_MAP_W;
+const BACKGROUND: u32 = 0x20_20_20;
+const VIEWPORT: u32 = 0xFF_00_00;
+
+pub struct VramWindow {
+    window: Window,
+    buffer: Vec<u32>,
+    map: u8,
+    use_bgp: bool,
+}
+
+impl VramWindow {
+    pub fn new() -> Result<VramWindow, String> {
+        let options = WindowOptions { scale: Scale::X2, ..WindowOptions::default() };
+        let window = try!(Window::new("iogb VRAM", WIDTH, HEIGHT, options)
+            .map_err(|e| format!("{}", e)));
+        Ok(VramWindow {
+            window: window,
+            buffer: vec![BACKGROUND; WIDTH * HEIGHT],
+            map: 0,
+            use_bgp: true,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    // Handle the window's keys and redraw it from the GameBoy's VRAM.
+    pub fn update(&mut self, gb: &GameBoy) {
+        if self.window.is_key_pressed(Key::M, KeyRepeat::No) {
+            self.map ^= 1;
+        }
+        if self.window.is_key_pressed(Key::B, KeyRepeat::No) {
+            self.use_bgp = !self.use_bgp;
+        }
+
+        let palette = gb.palette();
+        let mut shades = palette.0;
+        if self.use_bgp {
+            let bgp = gb.bg_palette();
+            for (i, shade) in shades.iter_mut().enumerate() {
+                *shade = palette.rgb(bgp >> (i * 2));
+            }
+        }
+        compose(&gb.dump_tileset(),
+                &gb.dump_bg_map(self.map),
+                gb.scroll(),
+                &shades,
+                &mut self.buffer);
+
+        self.window.set_title(&format!("iogb VRAM - map 0x{:04X}, {}",
+                                       if self.map == 0 { 0x9800 } else { 0x9C00 },
+                                       if self.use_bgp { "BGP" } else { "raw colours" }));
+        self.window.update_with_buffer(&self.buffer);
+    }
+}
+
+// Lay the tileset and a background map side by side in out, which is WIDTH
+// by HEIGHT, turning colour numbers into colours with shades. The screen's
+// viewport starts at scroll and wraps around the map's edges.
+pub fn compose(tileset: &[u8], map: &[u8], scroll: (u8, u8), shades: &[u32; 4], out: &mut [u32]) {
+    for p in out.iter_mut() {
+        *p = BACKGROUND;
+    }
+    for (y, row) in tileset.chunks(TILESET_W).take(TILESET_H).enumerate() {
+        for (x, &colour) in row.iter().enumerate() {
+            out[y * WIDTH + x] = shades[colour as usize & 0x03];
+        }
+    }
+
+    let left = TILESET_W + GAP;
+    for (y, row) in map.chunks(BG_MAP_W).take(HEIGHT).enumerate() {
+        for (x, &colour) in row.iter().enumerate() {
+            out[y * WIDTH + left + x] = shades[colour as usize & 0x03];
+        }
+    }
+
+    let (scx, scy) = (scroll.0 as usize, scroll.1 as usize);
+    let mut plot = |x: usize, y: usize| {
+        out[(y % BG_MAP_W) * WIDTH + left + x % BG_MAP_W] = VIEWPORT;
+    };
+    for x in 0..SCREEN_W {
+        plot(scx + x, scy);
+        plot(scx + x, scy + SCREEN_H - 1);
+    }
+    for y in 0..SCREEN_H {
+        plot(scx, scy + y);
+        plot(scx + SCREEN_W - 1, scy + y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iogb::gameboy::{SCREEN_W, SCREEN_H, TILESET_W, TILESET_H, BG_MAP_W};
+
+    use super::{compose, BACKGROUND, GAP, HEIGHT, VIEWPORT, WIDTH};
+
+    const SHADES: [u32; 4] = [0xE0_E0_E0, 0xA0_A0_A0, 0x60_60_60, 0x10_10_10];
+    const LEFT: usize = TILESET_W + GAP;
+
+    // A tileset whose colour numbers run along each row, and a map whose
+    // colour numbers go down the rows, both with junk in the upper bits.
+    fn compose_at(scroll: (u8, u8)) -> Vec<u32> {
+        let tileset: Vec<u8> = (0..TILESET_W * TILESET_H).map(|i| (i % TILESET_W) as u8).collect();
+        let map: Vec<u8> = (0..BG_MAP_W * BG_MAP_W).map(|i| (i / BG_MAP_W) as u8 | 0xF0).collect();
+        let mut out = vec![0; WIDTH * HEIGHT];
+        compose(&tileset, &map, scroll, &SHADES, &mut out);
+        out
+    }
+
+    fn viewport(out: &[u32]) -> Vec<(usize, usize)> {
+        (0..WIDTH * HEIGHT)
+            .filter(|&i| out[i] == VIEWPORT)
+            .map(|i| (i % WIDTH - LEFT, i / WIDTH))
+            .collect()
+    }
+
+    #[test]
+    fn layout() {
+        let out = compose_at((0, 0));
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let p = out[y * WIDTH + x];
+                if x < TILESET_W && y < TILESET_H {
+                    assert_eq!(p, SHADES[x % 4], "tileset ({}, {})", x, y);
+                } else if x < LEFT {
+                    assert_eq!(p, BACKGROUND, "gap ({}, {})", x, y);
+                } else if p != VIEWPORT {
+                    assert_eq!(p, SHADES[y % 4], "map ({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    // Only the outline is drawn, at the scroll position.
+    #[test]
+    fn outline() {
+        let outline = viewport(&compose_at((0, 0)));
+        assert_eq!(outline.len(), 2 * SCREEN_W + 2 * SCREEN_H - 4);
+        assert!(outline.iter().all(|&(x, y)| {
+            x == 0 || x == SCREEN_W - 1 || y == 0 || y == SCREEN_H - 1
+        }));
+
+        let outline = viewport(&compose_at((8, 16)));
+        assert_eq!(outline.len(), 2 * SCREEN_W + 2 * SCREEN_H - 4);
+        assert!(outline.contains(&(8, 16)));
+        assert!(outline.contains(&(8 + SCREEN_W - 1, 16 + SCREEN_H - 1)));
+        assert!(!outline.contains(&(0, 0)));
+    }
+
+    // A viewport past the bottom right of the map wraps around to the top
+    // left.
+    #[test]
+    fn outline_wraps() {
+        let outline = viewport(&compose_at((200, 150)));
+        assert_eq!(outline.len(), 2 * SCREEN_W + 2 * SCREEN_H - 4);
+        let right = (200 + SCREEN_W - 1) % BG_MAP_W;
+        let bottom = (150 + SCREEN_H - 1) % BG_MAP_W;
+        for &corner in [(200, 150), (right, 150), (200, bottom), (right, bottom)].iter() {
+            assert!(outline.contains(&corner), "{:?}", corner);
+        }
+        assert!(outline.contains(&(BG_MAP_W - 1, 150)) && outline.contains(&(0, 150)));
+        assert!(outline.contains(&(200, BG_MAP_W - 1)) && outline.contains(&(200, 0)));
+        assert!(!outline.contains(&(right + 1, 150)));
+    }
+}
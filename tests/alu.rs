@@ -0,0 +1,274 @@
+// Checks the CPU's arithmetic, rotate, shift, SWAP and DAA instructions
+// against a separately written reference, for every combination of operands
+// and incoming flags. This takes a while in a debug build, so it only runs
+// when asked for:
+//
+// cargo test --release --test alu -- --ignored --nocapture
+//
+// The results are reported per instruction, along with the first case that
+// failed.
+extern crate iogb;
+
+use iogb::{Bus, Cpu, FlatBus};
+use iogb::gameboy::{RegsB, RegsW};
+
+const Z: u8 = 0x80;
+const N: u8 = 0x40;
+const H: u8 = 0x20;
+const C: u8 = 0x10;
+
+// What the reference gives for an operation on A (or B for the CB prefixed
+// ones), the other operand and the incoming flags: the result and the flags.
+type Reference = fn(u8, u8, u8) -> (u8, u8);
+
+// Instructions whose only operands are A and B, so that they can be run by
+// setting those up. CB prefixed instructions operate on B.
+const CASES: [(&'static str, &'static [u8], Reference); 21] =
+    [("ADD A,B", &[0x80], add),
+     ("ADC A,B", &[0x88], adc),
+     ("SUB B", &[0x90], sub),
+     ("SBC A,B", &[0x98], sbc),
+     ("AND B", &[0xA0], and),
+     ("XOR B", &[0xA8], xor),
+     ("OR B", &[0xB0], or),
+     ("CP B", &[0xB8], cp),
+     ("RLCA", &[0x07], rlca),
+     ("RRCA", &[0x0F], rrca),
+     ("RLA", &[0x17], rla),
+     ("RRA", &[0x1F], rra),
+     ("DAA", &[0x27], daa),
+     ("RLC B", &[0xCB, 0x00], rlc),
+     ("RRC B", &[0xCB, 0x08], rrc),
+     ("RL B", &[0xCB, 0x10], rl),
+     ("RR B", &[0xCB, 0x18], rr),
+     ("SLA B", &[0xCB, 0x20], sla),
+     ("SRA B", &[0xCB, 0x28], sra),
+     ("SWAP B", &[0xCB, 0x30], swap),
+     ("SRL B", &[0xCB, 0x38], srl)];
+
+#[test]
+#[ignore]
+fn alu() {
+    let mut failed = 0;
+    for &(name, code, reference) in CASES.iter() {
+        // A fresh CPU for each instruction, as one that faults stays stopped.
+        let mut cpu = Cpu::new(FlatBus::new(), false);
+        for (addr, &byte) in code.iter().enumerate() {
+            cpu.interconnect.writeb(addr as u16, byte);
+        }
+        let on_b = code[0] == 0xCB;
+        let uses_b = code[0] >= 0x80 && code[0] <= 0xBF;
+
+        let (mut ok, mut total, mut first_failure) = (0, 0, None);
+        for a in 0..256 {
+            for b in 0..256 {
+                // Only the upper four bits of F exist.
+                for f in (0..16).map(|f| f << 4) {
+                    let (a, b, f) = (a as u8, b as u8, f as u8);
+                    match run(&mut cpu, on_b, reference, a, b, f) {
+                        Ok(()) => ok += 1,
+                        Err(e) => {
+                            if first_failure.is_none() {
+                                first_failure = Some(format!("A:0x{:02x} B:0x{:02x} F:0x{:02x}: \
+                                                              {}",
+                                                             a,
+                                                             b,
+                                                             f,
+                                                             e));
+                            }
+                        }
+                    }
+                    total += 1;
+                }
+                // The other operand makes no difference to single operand
+                // instructions, so only one is needed for each value of a.
+                if !uses_b {
+                    break;
+                }
+            }
+        }
+
+        println!("{}: {}/{}", name, ok, total);
+        if let Some(failure) = first_failure {
+            println!("    {}", failure);
+            failed += 1;
+        }
+    }
+    assert!(failed == 0, "{} of {} instructions failed", failed, CASES.len());
+}
+
+// For CB prefixed instructions a is the value in B, and b is unused. The
+// single operand instructions are run on every value of a.
+fn run(cpu: &mut Cpu<FlatBus>,
+       on_b: bool,
+       reference: Reference,
+       a: u8,
+       b: u8,
+       f: u8)
+       -> Result<(), String> {
+    let (operand, other) = if on_b { (a, 0x00) } else { (b, a) };
+    let (want, want_f) = reference(a, b, f);
+
+    cpu.write_reg_w(RegsW::AF, (other as u16) << 8 | f as u16);
+    cpu.write_reg_b(RegsB::B, operand);
+    if on_b {
+        cpu.write_reg_b(RegsB::A, 0x00);
+    }
+    cpu.write_reg_w(RegsW::PC, 0x0000);
+    cpu.dexec();
+    if let Some(fault) = cpu.fault() {
+        return Err(format!("{}", fault));
+    }
+
+    let got = if on_b {
+        cpu.read_reg_b(RegsB::B)
+    } else {
+        cpu.read_reg_b(RegsB::A)
+    };
+    let got_f = cpu.read_reg_w(RegsW::AF) as u8;
+    if got != want || got_f != want_f {
+        return Err(format!("got 0x{:02x} F:0x{:02x}, expected 0x{:02x} F:0x{:02x}",
+                           got,
+                           got_f,
+                           want,
+                           want_f));
+    }
+    Ok(())
+}
+
+fn flag(set: bool, flag: u8) -> u8 {
+    if set { flag } else { 0 }
+}
+
+fn carry(f: u8) -> u8 {
+    (f & C) >> 4
+}
+
+fn add_with(a: u8, b: u8, c: u8) -> (u8, u8) {
+    let sum = a as u16 + b as u16 + c as u16;
+    let r = sum as u8;
+    (r,
+     flag(r == 0, Z) | flag((a & 0x0F) + (b & 0x0F) + c > 0x0F, H) | flag(sum > 0xFF, C))
+}
+
+fn sub_with(a: u8, b: u8, c: u8) -> (u8, u8) {
+    let diff = a as i16 - b as i16 - c as i16;
+    let r = diff as u8;
+    (r,
+     flag(r == 0, Z) | N | flag((a & 0x0F) as i16 - (b & 0x0F) as i16 - (c as i16) < 0, H) |
+     flag(diff < 0, C))
+}
+
+fn add(a: u8, b: u8, _: u8) -> (u8, u8) {
+    add_with(a, b, 0)
+}
+
+fn adc(a: u8, b: u8, f: u8) -> (u8, u8) {
+    add_with(a, b, carry(f))
+}
+
+fn sub(a: u8, b: u8, _: u8) -> (u8, u8) {
+    sub_with(a, b, 0)
+}
+
+fn sbc(a: u8, b: u8, f: u8) -> (u8, u8) {
+    sub_with(a, b, carry(f))
+}
+
+fn and(a: u8, b: u8, _: u8) -> (u8, u8) {
+    (a & b, flag(a & b == 0, Z) | H)
+}
+
+fn xor(a: u8, b: u8, _: u8) -> (u8, u8) {
+    (a ^ b, flag(a ^ b == 0, Z))
+}
+
+fn or(a: u8, b: u8, _: u8) -> (u8, u8) {
+    (a | b, flag(a | b == 0, Z))
+}
+
+fn cp(a: u8, b: u8, _: u8) -> (u8, u8) {
+    (a, sub_with(a, b, 0).1)
+}
+
+// The accumulator rotates are the CB ones with Z always clear.
+fn rlca(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let (r, f) = rlc(a, b, f);
+    (r, f & !Z)
+}
+
+fn rrca(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let (r, f) = rrc(a, b, f);
+    (r, f & !Z)
+}
+
+fn rla(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let (r, f) = rl(a, b, f);
+    (r, f & !Z)
+}
+
+fn rra(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let (r, f) = rr(a, b, f);
+    (r, f & !Z)
+}
+
+// The result of a shift or rotate, r, with the bit shifted out.
+fn shifted(r: u8, out: bool) -> (u8, u8) {
+    (r, flag(r == 0, Z) | flag(out, C))
+}
+
+fn rlc(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v.rotate_left(1), v & 0x80 != 0)
+}
+
+fn rrc(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v.rotate_right(1), v & 0x01 != 0)
+}
+
+fn rl(v: u8, _: u8, f: u8) -> (u8, u8) {
+    shifted(v << 1 | carry(f), v & 0x80 != 0)
+}
+
+fn rr(v: u8, _: u8, f: u8) -> (u8, u8) {
+    shifted(v >> 1 | carry(f) << 7, v & 0x01 != 0)
+}
+
+fn sla(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v << 1, v & 0x80 != 0)
+}
+
+fn sra(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v >> 1 | v & 0x80, v & 0x01 != 0)
+}
+
+fn srl(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v >> 1, v & 0x01 != 0)
+}
+
+fn swap(v: u8, _: u8, _: u8) -> (u8, u8) {
+    shifted(v.rotate_left(4), false)
+}
+
+// Adjusts A to be BCD after an addition or subtraction of BCD values,
+// working out which from N.
+fn daa(a: u8, _: u8, f: u8) -> (u8, u8) {
+    let mut r = a;
+    let mut c = f & C != 0;
+    if f & N == 0 {
+        if c || a > 0x99 {
+            r = r.wrapping_add(0x60);
+            c = true;
+        }
+        if f & H != 0 || a & 0x0F > 0x09 {
+            r = r.wrapping_add(0x06);
+        }
+    } else {
+        if c {
+            r = r.wrapping_sub(0x60);
+        }
+        if f & H != 0 {
+            r = r.wrapping_sub(0x06);
+        }
+    }
+    (r, flag(r == 0, Z) | f & N | flag(c, C))
+}
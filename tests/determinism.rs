@@ -0,0 +1,122 @@
+// The core must be deterministic: two GameBoys given the same ROM and the
+// same inputs have to produce the same frames and serial output, frame for
+// frame. Anything that sneaks in the host's clock or other ambient state
+// shows up here as a mismatch.
+extern crate iogb;
+
+use iogb::GameBoy;
+use iogb::bootrom::Bootrom;
+use iogb::cartridge::Cartridge;
+use iogb::gameboy::Button;
+
+const SCROLL: &'static [u8] = include_bytes!("roms/scroll.gb");
+const FRAMES: u32 = 1000;
+const BUTTONS: [Button; 8] = [Button::Right,
+                              Button::Left,
+                              Button::Up,
+                              Button::Down,
+                              Button::A,
+                              Button::B,
+                              Button::Select,
+                              Button::Start];
+
+fn fnv(hash: u64, val: u64) -> u64 {
+    (hash ^ val).wrapping_mul(0x0000_0100_0000_01b3)
+}
+
+// Rolling hashes of every frame and of the serial output after each frame.
+struct Run {
+    gb: GameBoy,
+    frames: u64,
+    serial: u64,
+    serial_len: usize,
+}
+
+impl Run {
+    fn new() -> Run {
+        Run {
+            gb: GameBoy::new(Cartridge::from_bytes(SCROLL.to_vec()).unwrap(),
+                             Bootrom::empty()),
+            frames: 0xcbf2_9ce4_8422_2325,
+            serial: 0xcbf2_9ce4_8422_2325,
+            serial_len: 0,
+        }
+    }
+
+    fn frame(&mut self, buttons: u8) {
+        for (i, &button) in BUTTONS.iter().enumerate() {
+            self.gb.set_button(button, buttons & 1 << i != 0);
+        }
+        self.gb.step_frame();
+        self.frames = fnv(self.frames, self.gb.frame_hash());
+        for &b in &self.gb.serial_bytes()[self.serial_len..] {
+            self.serial = fnv(self.serial, b as u64);
+        }
+        self.serial_len = self.gb.serial_bytes().len();
+    }
+}
+
+// Buttons held for each frame, changing every few frames.
+fn inputs(seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..FRAMES)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 24) as u8
+        })
+        .flat_map(|b| vec![b; 7])
+        .take(FRAMES as usize)
+        .collect()
+}
+
+#[test]
+fn same_inputs_same_output() {
+    let (mut a, mut b) = (Run::new(), Run::new());
+    for (frame, &buttons) in inputs(1).iter().enumerate() {
+        a.frame(buttons);
+        b.frame(buttons);
+        assert_eq!(a.frames, b.frames, "frame {}", frame);
+        assert_eq!(a.serial, b.serial, "frame {}", frame);
+        assert_eq!(a.gb.now(), b.gb.now(), "frame {}", frame);
+    }
+    assert!(a.serial_len > FRAMES as usize * 9 / 10,
+            "{} serial bytes",
+            a.serial_len);
+    assert_eq!(a.gb.save_state(), b.gb.save_state());
+}
+
+// Make sure that the hashes would notice a difference.
+#[test]
+fn different_inputs_different_output() {
+    let (mut a, mut b) = (Run::new(), Run::new());
+    for (&x, &y) in inputs(1).iter().zip(inputs(2).iter()) {
+        a.frame(x);
+        b.frame(y);
+    }
+    assert!(a.frames != b.frames);
+    assert!(a.serial != b.serial);
+}
+
+// The clock on MBC3 cartridges follows the GameBoy's TimeSource, which is
+// emulated time unless the host's clock is asked for.
+#[test]
+fn rtc_follows_emulated_time() {
+    let mut rom = vec![0; 0x8000];
+    let program = [// Enable the clock and select its seconds.
+                   0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x08, 0xEA, 0x00, 0x40,
+                   0x0E, 0x00,
+                   // Latch and read the seconds until they change.
+                   0xAF, 0xEA, 0x00, 0x60, 0x3C, 0xEA, 0x00, 0x60, 0xFA, 0x00, 0xA0,
+                   0xB9, 0x28, 0xF2,
+                   // Send them out of the serial port.
+                   0x4F, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xE9];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    // MBC3 with a clock.
+    rom[0x147] = 0x0F;
+
+    let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+    // The clock is brought up to date at the start of each frame, so this
+    // sees just over three seconds.
+    gb.run_frames(3 * 60 + 5);
+    assert_eq!(gb.serial_bytes(), &[1, 2, 3]);
+}
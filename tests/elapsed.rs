@@ -0,0 +1,36 @@
+// Emulated time as seen through the GameBoy's counters, which frontends use
+// to pace themselves and to report how fast emulation is running.
+extern crate iogb;
+
+use std::cmp;
+use std::time::Duration;
+
+use iogb::GameBoy;
+use iogb::bootrom::Bootrom;
+use iogb::cartridge::Cartridge;
+use iogb::gameboy::{CPU_HZ, CYCLES_PER_FRAME};
+
+// Two seconds is 8,388,608 cycles, or 119.45 frames of 70,224. Frames are
+// only counted while the LCD is on, which it is throughout for a ROM that
+// spins at 0x0100.
+#[test]
+fn two_seconds() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0x18;
+    rom[0x101] = 0xFE;
+    let mut gb = GameBoy::new(Cartridge::from_bytes(rom).unwrap(), Bootrom::empty());
+    let mut run = 0;
+    while run < 2 * CPU_HZ {
+        run += gb.run(cmp::min(CYCLES_PER_FRAME, 2 * CPU_HZ - run)).cycles_run;
+    }
+
+    assert_eq!(gb.elapsed_cycles(), run as u64);
+    // Short of the last instruction.
+    assert!(run - 2 * CPU_HZ < 24, "{} cycles", run);
+    let frames = gb.elapsed_frames();
+    assert!(frames == 119 || frames == 120, "{} frames", frames);
+    let elapsed = gb.elapsed();
+    assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_millis(2001),
+            "{:?}",
+            elapsed);
+}
@@ -0,0 +1,44 @@
+// Golden image tests: run the ROMs in tests/roms headlessly and compare the
+// final frame against a hash of known good output. When a change to the GPU
+// is meant to alter the output, check the new frames by eye (e.g. with
+// iogb --frames N) before updating the hashes here.
+extern crate iogb;
+
+use iogb::GameBoy;
+use iogb::bootrom::Bootrom;
+use iogb::cartridge::Cartridge;
+use iogb::gameboy::Button;
+
+const SCROLL: &'static [u8] = include_bytes!("roms/scroll.gb");
+
+fn scroll() -> GameBoy {
+    GameBoy::new(Cartridge::from_bytes(SCROLL.to_vec()).unwrap(),
+                 Bootrom::empty())
+}
+
+#[test]
+fn scroll_idle() {
+    let mut gb = scroll();
+    gb.run_frames(120);
+    assert_eq!(gb.frame_hash(), 0xa117_aab3_f295_5fd9);
+    // One serial byte per frame, the frame count with nothing pressed.
+    let serial = gb.serial_bytes();
+    assert!(serial.len() >= 110, "{} serial bytes", serial.len());
+    assert!(serial.iter().enumerate().all(|(i, &b)| b == i as u8 + 1));
+}
+
+#[test]
+fn scroll_with_input() {
+    let mut gb = scroll();
+    gb.run_frames(60);
+    gb.set_button(Button::Right, true);
+    gb.set_button(Button::Down, true);
+    // A byte may already be on its way.
+    let pressed = gb.serial_bytes().len() + 1;
+    gb.run_frames(60);
+    assert_eq!(gb.frame_hash(), 0x7794_34cd_2386_b17f);
+    // Right and down are bits 0 and 3 of what's sent.
+    let serial = gb.serial_bytes();
+    assert!(serial.len() >= pressed + 50, "{} serial bytes", serial.len());
+    assert!(serial.iter().enumerate().skip(pressed).all(|(i, &b)| b == (i as u8 + 1) ^ 0x09));
+}
@@ -0,0 +1,133 @@
+// Drive the libretro entry points the way a frontend does: load a game, run
+// a frame and check what comes back through the callbacks.
+//
+// Run with cargo test --features libretro --test libretro.
+extern crate iogb;
+
+use std::mem;
+use std::os::raw::{c_uint, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use iogb::libretro::*;
+
+const SCREEN_W: usize = 160;
+const SCREEN_H: usize = 144;
+
+static PIXEL_FORMAT: AtomicUsize = AtomicUsize::new(0xFF);
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+static POLLS: AtomicUsize = AtomicUsize::new(0);
+static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    // RETRO_ENVIRONMENT_SET_PIXEL_FORMAT
+    if cmd == 10 {
+        PIXEL_FORMAT.store(unsafe { *(data as *const c_uint) } as usize, Ordering::SeqCst);
+    }
+    true
+}
+
+extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert!(!data.is_null());
+    assert_eq!((width as usize, height as usize, pitch), (SCREEN_W, SCREEN_H, SCREEN_W * 4));
+    FRAMES.fetch_add(1, Ordering::SeqCst);
+}
+
+// Takes at most 512 frames at a time, as frontends are allowed to.
+extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+    assert!(!data.is_null());
+    let taken = if frames > 512 { 512 } else { frames };
+    AUDIO_FRAMES.fetch_add(taken, Ordering::SeqCst);
+    taken
+}
+
+extern "C" fn input_poll() {
+    POLLS.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+    0
+}
+
+// A ROM that spins at 0x0100 forever, with the given cartridge type and
+// title.
+fn rom(cartridge_type: u8, title: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0x18;
+    rom[0x101] = 0xFE;
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x147] = cartridge_type;
+    rom
+}
+
+fn load(rom: &[u8]) -> bool {
+    let game = GameInfo {
+        path: ptr::null(),
+        data: rom.as_ptr() as *const c_void,
+        size: rom.len(),
+        meta: ptr::null(),
+    };
+    unsafe { retro_load_game(&game) }
+}
+
+// The entry points share global state, so they're all driven from one test.
+#[test]
+fn libretro() {
+    unsafe {
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+
+        let mut info: SystemAvInfo = mem::zeroed();
+        retro_get_system_av_info(&mut info);
+        let rate = info.timing.sample_rate;
+        assert!(rate > 0.0);
+
+        // Cartridges that can't be loaded are refused rather than panicking.
+        assert!(!load(&rom(0x19, b"MBC5")));
+        assert!(!load(&rom(0x00, b"\xFF\xFE")));
+        assert!(!load(&[0; 0x100]));
+
+        // Running without a game does nothing.
+        retro_run();
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 0);
+
+        assert!(load(&rom(0x00, b"SPIN")));
+        // RETRO_PIXEL_FORMAT_XRGB8888
+        assert_eq!(PIXEL_FORMAT.load(Ordering::SeqCst), 1);
+
+        retro_run();
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 1);
+        assert_eq!(POLLS.load(Ordering::SeqCst), 1);
+        // A frame's worth of audio at the advertised rate, all of it taken
+        // even though it needed more than one call.
+        let expected = rate / info.timing.fps;
+        let frames = AUDIO_FRAMES.load(Ordering::SeqCst) as f64;
+        assert!(frames > expected - 2.0 && frames < expected + 2.0,
+                "{} audio frames, expected {}",
+                frames,
+                expected);
+
+        let size = retro_serialize_size();
+        assert!(size > 0);
+        let mut state = vec![0u8; size];
+        assert!(retro_serialize(state.as_mut_ptr() as *mut c_void, size));
+        assert!(!retro_serialize(state.as_mut_ptr() as *mut c_void, size - 1));
+        assert!(retro_unserialize(state.as_ptr() as *const c_void, size));
+        assert!(!retro_unserialize(state.as_ptr() as *const c_void, size - 1));
+
+        retro_reset();
+        retro_run();
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 2);
+
+        retro_unload_game();
+        retro_run();
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 2);
+        assert_eq!(retro_serialize_size(), 0);
+
+        retro_deinit();
+    }
+}
@@ -0,0 +1,105 @@
+// Runs Mooneye's test ROMs, such as the acceptance suite, looking in
+// subdirectories too. Each ROM ends by executing LD B,B, with the Fibonacci
+// numbers 3, 5, 8, 13, 21 and 34 in B, C, D, E, H and L if it passed.
+//
+// The ROMs aren't part of the repository, so nothing is run unless
+// MOONEYE_DIR points at them:
+//
+// MOONEYE_DIR=path/to/acceptance cargo test --release --features mooneye --test mooneye -- --nocapture
+//
+// The test fails if any ROM didn't pass.
+extern crate iogb;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use iogb::{bootrom, cartridge};
+use iogb::gameboy::{BreakReason, GameBoy};
+
+// Give up on a ROM after this many emulated frames. The tests finish within
+// a second or two.
+const MAX_FRAMES: u32 = 60 * 20;
+
+#[test]
+fn mooneye() {
+    let dir = match env::var("MOONEYE_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            println!("MOONEYE_DIR isn't set, skipping the Mooneye test ROMs");
+            return;
+        }
+    };
+
+    let mut roms = Vec::new();
+    if let Err(e) = find_roms(&dir, &mut roms) {
+        panic!("Failed to read {}: {}", dir.display(), e);
+    }
+    roms.sort();
+    assert!(!roms.is_empty(), "No ROMs in {}", dir.display());
+
+    // Anything that panics is reported as a crash.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut passed = 0;
+    let mut results = Vec::new();
+    for rom in &roms {
+        let name = rom.strip_prefix(&dir).unwrap_or(rom).display();
+        let result = match run_rom(rom) {
+            Ok(result) => result,
+            Err(e) => format!("error: {}", e),
+        };
+        if result == "passed" {
+            passed += 1;
+        }
+        results.push(format!("{}: {}", name, result));
+    }
+    panic::set_hook(hook);
+
+    for result in &results {
+        println!("{}", result);
+    }
+    assert!(passed == roms.len(),
+            "{} of {} passed",
+            passed,
+            roms.len());
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| format!("{}", e)));
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.is_dir() {
+            try!(find_roms(&path, roms));
+        } else if path.extension().map_or(false, |e| e == "gb") {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Returns "passed", "failed", "crashed" or "timed out".
+fn run_rom(path: &Path) -> Result<String, String> {
+    let mut data = Vec::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("{}", e)));
+    let cart = try!(cartridge::Cartridge::from_bytes(data));
+    let mut gb = GameBoy::new(cart, bootrom::Bootrom::empty());
+
+    for _ in 0..MAX_FRAMES {
+        let stopped = match panic::catch_unwind(AssertUnwindSafe(|| gb.step_frame())) {
+            Ok(result) => result.stopped,
+            Err(_) => return Ok("crashed".to_string()),
+        };
+        match stopped {
+            Some(BreakReason::TestFinished { passed: true }) => return Ok("passed".to_string()),
+            Some(BreakReason::TestFinished { passed: false }) => return Ok("failed".to_string()),
+            Some(BreakReason::Fault(_)) => return Ok("crashed".to_string()),
+            _ => {}
+        }
+    }
+    Ok("timed out".to_string())
+}
@@ -0,0 +1,150 @@
+; A small test ROM for iogb: diagonal bands of background tiles and a sprite
+; that scroll every frame. Holding right scrolls faster and holding down
+; scrolls vertically. After each VBlank it sends the frame count XOR the
+; d-pad state out of the serial port.
+;
+; Written for iogb and dedicated to the public domain under CC0 1.0.
+;
+; Build with RGBDS 0.5 or later:
+;   rgbasm -o scroll.o scroll.asm
+;   rgblink -o scroll.gb scroll.o
+;   rgbfix -v -j -l 0x33 -t "IOGB SCROLL" -p 0 scroll.gb
+
+DEF hFrame EQU $FF80
+
+SECTION "VBlank", ROM0[$40]
+    reti
+
+SECTION "Header", ROM0[$100]
+    nop
+    jp Start
+    ds $150 - @, 0
+
+SECTION "Main", ROM0[$150]
+Start:
+    di
+    ld sp, $FFFE
+
+    ; Only turn the LCD off during VBlank.
+.wait:
+    ldh a, [$FF44]
+    cp 144
+    jr c, .wait
+    xor a
+    ldh [$FF40], a
+    ldh [hFrame], a
+
+    ld hl, $8000
+    ld de, Tiles
+    ld b, TilesEnd - Tiles
+.tiles:
+    ld a, [de]
+    inc de
+    ld [hl+], a
+    dec b
+    jr nz, .tiles
+
+    ; Tile (column + row) & 3, for the whole of the first map.
+    ld hl, $9800
+.map:
+    ld a, l
+    and $E0
+    rlca
+    rlca
+    rlca
+    ld c, a
+    ld a, l
+    add c
+    and 3
+    ld [hl+], a
+    ld a, h
+    cp $9C
+    jr nz, .map
+
+    ld hl, $FE00
+    ld b, $A0
+    xor a
+.oam:
+    ld [hl+], a
+    dec b
+    jr nz, .oam
+
+    ; One sprite, using the checkerboard tile.
+    ld hl, $FE00
+    ld a, 80
+    ld [hl+], a
+    ld [hl+], a
+    ld a, 1
+    ld [hl+], a
+    xor a
+    ld [hl], a
+
+    ld a, $E4
+    ldh [$FF47], a
+    ldh [$FF48], a
+    ld a, 1
+    ldh [$FFFF], a
+    xor a
+    ldh [$FF0F], a
+    ; LCD and background on, sprites on, tiles at $8000.
+    ld a, $93
+    ldh [$FF40], a
+    ei
+
+MainLoop:
+    halt
+
+    ; b = the pressed directions: right, left, up, down from bit 0.
+    ld a, $20
+    ldh [$FF00], a
+    ldh a, [$FF00]
+    ldh a, [$FF00]
+    cpl
+    and $0F
+    ld b, a
+
+    ldh a, [hFrame]
+    inc a
+    ldh [hFrame], a
+    xor b
+    ldh [$FF01], a
+    ld a, $81
+    ldh [$FF02], a
+
+    ldh a, [$FF43]
+    inc a
+    bit 0, b
+    jr z, .noRight
+    inc a
+.noRight:
+    ldh [$FF43], a
+
+    ldh a, [$FF42]
+    bit 3, b
+    jr z, .noDown
+    inc a
+.noDown:
+    ldh [$FF42], a
+
+    ld hl, $FE01
+    inc [hl]
+    jr MainLoop
+
+Tiles:
+    ; Blank.
+    ds 16, 0
+    ; Checkerboard.
+    REPT 4
+    db $AA, $AA, $55, $55
+    ENDR
+    ; Stripes.
+    REPT 4
+    db $FF, $00, $00, $00
+    ENDR
+    ; Box.
+    db $FF, $FF
+    REPT 6
+    db $81, $81
+    ENDR
+    db $FF, $FF
+TilesEnd:
@@ -0,0 +1,57 @@
+// The scheduler only changes when the peripherals are stepped, never what
+// they do, so a GameBoy with it has to be indistinguishable from one
+// without: the same registers, the same I/O and the same picture after
+// every timeslice.
+extern crate iogb;
+
+use iogb::GameBoy;
+use iogb::cartridge::Cartridge;
+use iogb::gameboy::{Button, SCREEN_H, SCREEN_W};
+
+const SCROLL: &'static [u8] = include_bytes!("roms/scroll.gb");
+const CYCLES: u32 = 1_000_000;
+// Uneven, so that timeslices end all over the place.
+const TIMESLICES: [u32; 5] = [1, 17, 456, 1000, 4099];
+
+fn gameboy(scheduler: bool) -> GameBoy {
+    GameBoy::builder(Cartridge::from_bytes(SCROLL.to_vec()).unwrap())
+        .scheduler(scheduler)
+        .build()
+}
+
+fn frame(gb: &GameBoy) -> Vec<u8> {
+    let mut out = vec![0; SCREEN_W * SCREEN_H * 4];
+    gb.frame_into_rgba(&mut out);
+    out
+}
+
+#[test]
+fn scheduler_matches_stepping() {
+    let mut stepped = gameboy(false);
+    let mut scheduled = gameboy(true);
+
+    let mut run = 0;
+    let mut i = 0;
+    while run < CYCLES {
+        let timeslice = TIMESLICES[i % TIMESLICES.len()];
+        // Give the joypad interrupt something to do as well.
+        let pressed = i % 50 < 25;
+        stepped.set_button(Button::Right, pressed);
+        scheduled.set_button(Button::Right, pressed);
+
+        let a = stepped.run(timeslice);
+        let b = scheduled.run(timeslice);
+        assert_eq!(a.cycles_run, b.cycles_run, "cycle {}", run);
+        assert_eq!(stepped.cpu_state(), scheduled.cpu_state(), "cycle {}", run);
+        // Everything else that's observable, from the I/O registers to
+        // VRAM, is in the save state.
+        assert!(stepped.save_state() == scheduled.save_state(), "cycle {}", run);
+
+        run += a.cycles_run;
+        i += 1;
+    }
+
+    assert_eq!(stepped.elapsed_frames(), scheduled.elapsed_frames());
+    assert!(frame(&stepped) == frame(&scheduled));
+    assert!(stepped.elapsed_frames() > 0);
+}
@@ -0,0 +1,187 @@
+// Runs the SM83 single instruction tests from
+// https://github.com/SingleStepTests/sm83 against the CPU on a flat 64KB bus.
+// There's a JSON file per opcode, each with thousands of cases giving the
+// registers and memory before and after the instruction. The tests aren't
+// part of the repository, so this only runs when asked for, with SM83_DIR
+// pointing at them:
+//
+// SM83_DIR=path/to/sm83/v1 cargo test --release --test sm83 -- --ignored --nocapture
+//
+// With SM83_OPCODES set to a comma separated list of opcodes (e.g. "8e,cb 46")
+// only those files are run. The results are reported per opcode, along with
+// the first case that failed.
+extern crate iogb;
+extern crate serde_json;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use iogb::{Bus, Cpu, FlatBus};
+use iogb::gameboy::{RegsB, RegsW};
+
+#[test]
+#[ignore]
+fn sm83() {
+    let dir = PathBuf::from(env::var("SM83_DIR").expect("SM83_DIR isn't set"));
+    let only: Vec<String> = env::var("SM83_OPCODES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|o| o.trim().to_lowercase())
+        .filter(|o| !o.is_empty())
+        .collect();
+
+    let mut files: Vec<_> = match fs::read_dir(&dir) {
+        Ok(entries) => {
+            entries.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |e| e == "json"))
+                .filter(|p| {
+                    let stem = p.file_stem().map(|s| s.to_string_lossy().into_owned());
+                    only.is_empty() || stem.map_or(false, |s| only.contains(&s))
+                })
+                .collect()
+        }
+        Err(e) => panic!("Failed to read {}: {}", dir.display(), e),
+    };
+    assert!(!files.is_empty(), "No tests in {}", dir.display());
+    files.sort();
+
+    let (mut passed, mut total, mut failed_opcodes) = (0, 0, 0);
+    for file in &files {
+        let name = file.file_stem().unwrap().to_string_lossy().into_owned();
+        let cases = match load(file) {
+            Ok(cases) => cases,
+            Err(e) => {
+                println!("{}: {}", name, e);
+                failed_opcodes += 1;
+                continue;
+            }
+        };
+
+        let mut first_failure = None;
+        let mut ok = 0;
+        for case in &cases {
+            match run_case(case) {
+                Ok(()) => ok += 1,
+                Err(e) => {
+                    if first_failure.is_none() {
+                        first_failure = Some(format!("{}: {}", case["name"], e));
+                    }
+                }
+            }
+        }
+
+        println!("{}: {}/{}", name, ok, cases.len());
+        if let Some(failure) = first_failure {
+            println!("    {}", failure);
+            failed_opcodes += 1;
+        }
+        passed += ok;
+        total += cases.len();
+    }
+    println!("{} of {} cases passed", passed, total);
+    assert!(failed_opcodes == 0,
+            "{} of {} opcodes failed",
+            failed_opcodes,
+            files.len());
+}
+
+fn load(path: &Path) -> Result<Vec<Value>, String> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("{}", e)));
+    match try!(serde_json::from_str(&text).map_err(|e| format!("{}", e))) {
+        Value::Array(cases) => Ok(cases),
+        _ => Err("Expected an array of test cases".to_string()),
+    }
+}
+
+fn field(state: &Value, name: &str) -> Result<u16, String> {
+    state[name]
+        .as_u64()
+        .map(|v| v as u16)
+        .ok_or_else(|| format!("Missing {}", name))
+}
+
+fn ram(state: &Value) -> Result<Vec<(u16, u8)>, String> {
+    let entries = try!(state["ram"].as_array().ok_or_else(|| "Missing ram".to_string()));
+    entries.iter()
+        .map(|e| match (e[0].as_u64(), e[1].as_u64()) {
+            (Some(addr), Some(val)) => Ok((addr as u16, val as u8)),
+            _ => Err(format!("Invalid ram entry {}", e)),
+        })
+        .collect()
+}
+
+const REGS_B: [(&'static str, RegsB); 7] = [("a", RegsB::A),
+                                            ("b", RegsB::B),
+                                            ("c", RegsB::C),
+                                            ("d", RegsB::D),
+                                            ("e", RegsB::E),
+                                            ("h", RegsB::H),
+                                            ("l", RegsB::L)];
+
+// The tests come from a CPU that fetches each opcode during the previous
+// instruction, so PC starts one past the opcode and ends one past the next.
+fn run_case(case: &Value) -> Result<(), String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut cpu = Cpu::new(FlatBus::new(), false);
+    for &(name, reg) in REGS_B.iter() {
+        cpu.write_reg_b(reg, try!(field(initial, name)) as u8);
+    }
+    let af = cpu.read_reg_w(RegsW::AF) & 0xFF00 | try!(field(initial, "f"));
+    cpu.write_reg_w(RegsW::AF, af);
+    cpu.write_reg_w(RegsW::SP, try!(field(initial, "sp")));
+    cpu.write_reg_w(RegsW::PC, try!(field(initial, "pc")).wrapping_sub(1));
+    cpu.interconnect.ic_mut().set_ime(try!(field(initial, "ime")) != 0);
+    for (addr, val) in try!(ram(initial)) {
+        cpu.interconnect.writeb(addr, val);
+    }
+
+    // dexec rather than step, as the tests don't service interrupts.
+    let ticks = cpu.dexec();
+    if let Some(fault) = cpu.fault() {
+        return Err(format!("{}", fault));
+    }
+
+    let mut errors = Vec::new();
+    let mut check = |what: &str, got: u16, want: u16| {
+        if got != want {
+            errors.push(format!("{} is 0x{:02x}, expected 0x{:02x}", what, got, want));
+        }
+    };
+    for &(name, reg) in REGS_B.iter() {
+        check(name, cpu.read_reg_b(reg) as u16, try!(field(expected, name)));
+    }
+    check("f", cpu.read_reg_w(RegsW::AF) & 0xFF, try!(field(expected, "f")));
+    check("sp", cpu.read_reg_w(RegsW::SP), try!(field(expected, "sp")));
+    check("pc",
+          cpu.read_reg_w(RegsW::PC).wrapping_add(1),
+          try!(field(expected, "pc")));
+    // EI only takes effect after the next instruction, which isn't run.
+    let ic = cpu.interconnect.ic();
+    check("ime",
+          (ic.ime() || ic.ime_pending()) as u16,
+          try!(field(expected, "ime")));
+    for (addr, val) in try!(ram(expected)) {
+        check(&format!("(0x{:04x})", addr),
+              cpu.interconnect.peek(addr) as u16,
+              val as u16);
+    }
+    if let Some(cycles) = case["cycles"].as_array() {
+        check("cycles", ticks as u16 / 4, cycles.len() as u16);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}